@@ -0,0 +1,293 @@
+//! ATA drive access using PIO and 28-bit LBA
+//!
+//! Interrupts are disabled, and the status register is polled
+//! instead. Each command waits for BSY to clear, then for DRQ
+//! before each sector is transferred.
+//!
+//! <https://wiki.osdev.org/ATA_PIO_Mode>
+
+extern crate alloc;
+use alloc::string::String;
+
+use euralios_std::{println,
+                   syscalls::{self, SyscallError},
+                   ports::{outportb, outportw, inportb, inportw},
+                   time};
+
+pub const SECTOR_SIZE: usize = 512;
+/// Most sectors in one command. A sector count of 0 means 256
+pub const MAX_SECTORS: usize = 256;
+/// Number of sectors which 28-bit LBA can address
+const LBA28_SECTORS: u64 = 1 << 28;
+
+/// (name, I/O base, control base) of the standard channels
+pub const CHANNELS: [(&str, u16, u16); 2] = [("primary", 0x1F0, 0x3F6),
+                                              ("secondary", 0x170, 0x376)];
+
+// Register offsets from the I/O base
+const REG_DATA: u16 = 0;
+/// Error when read, features when written
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+/// Status when read, command when written
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+/// Device control register when written, alternate status when read.
+/// The only register at the control base
+const REG_CONTROL: u16 = 0;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+/// Drive fault
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+/// Status read when no drives are attached to a channel
+const STATUS_FLOATING: u8 = 0xFF;
+
+/// Device control: Don't send interrupts
+const CONTROL_NIEN: u8 = 0x02;
+
+/// Drive register: LBA addressing. Bits 0-3 are LBA bits 24-27
+const DRIVE_LBA: u8 = 0xE0;
+/// Drive register: Select the slave rather than the master
+const DRIVE_SLAVE: u8 = 0x10;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_CACHE_FLUSH: u8 = 0xE7;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+// Words in the IDENTIFY data
+/// Model name, 40 ASCII bytes with the bytes of each word swapped
+const IDENTIFY_MODEL: usize = 27;
+const IDENTIFY_MODEL_WORDS: usize = 20;
+const IDENTIFY_CAPABILITIES: usize = 49;
+/// Two words, low word first
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+const CAPABILITY_LBA: u16 = 1 << 9;
+
+/// Longest wait for the drive, including spinning up and cache flush
+const TIMEOUT_US: u64 = 5_000_000;
+
+pub struct Drive {
+    io_base: u16,
+    control_base: u16,
+    slave: bool,
+    /// Number of sectors addressable with 28-bit LBA
+    pub sectors: u64,
+    pub model: String
+}
+
+/// Read the alternate status register four times, giving the drive
+/// 400ns to update the status after a command or drive select
+fn wait_400ns(control_base: u16) {
+    for _ in 0..4 {
+        inportb(control_base + REG_CONTROL);
+    }
+}
+
+/// Drive register bits selecting the master or slave
+fn drive_select(slave: bool) -> u8 {
+    if slave { DRIVE_SLAVE } else { 0 }
+}
+
+/// Wait until BSY clears, returning the status
+fn wait_not_busy(io_base: u16) -> Result<u8, SyscallError> {
+    let start = time::microseconds_monotonic();
+    loop {
+        let status = inportb(io_base + REG_STATUS);
+        if status & STATUS_BSY == 0 {
+            return Ok(status);
+        }
+        if time::microseconds_monotonic() - start > TIMEOUT_US {
+            return Err(syscalls::SYSCALL_ERROR_TIMEOUT);
+        }
+        syscalls::thread_yield();
+    }
+}
+
+/// The model name in IDENTIFY data, without padding
+///
+/// Characters which would need escaping in JSON are replaced
+fn model_name(identify: &[u16; 256]) -> String {
+    let mut model = String::new();
+    for word in &identify[IDENTIFY_MODEL..(IDENTIFY_MODEL + IDENTIFY_MODEL_WORDS)] {
+        for byte in word.to_be_bytes() {
+            model.push(match byte {
+                b'"' | b'\\' => '?',
+                0x20..=0x7E => byte as char,
+                _ => '?'
+            });
+        }
+    }
+    String::from(model.trim())
+}
+
+impl Drive {
+    /// Send IDENTIFY to a drive
+    ///
+    /// Returns None if there is no drive, or it isn't an ATA drive
+    /// supporting LBA, for example an ATAPI CD-ROM
+    pub fn identify(io_base: u16, control_base: u16, slave: bool) -> Option<Drive> {
+        if inportb(io_base + REG_STATUS) == STATUS_FLOATING {
+            return None; // No drives on this channel
+        }
+        outportb(control_base + REG_CONTROL, CONTROL_NIEN);
+
+        outportb(io_base + REG_DRIVE, DRIVE_LBA | drive_select(slave));
+        wait_400ns(control_base);
+
+        outportb(io_base + REG_SECTOR_COUNT, 0);
+        outportb(io_base + REG_LBA_LOW, 0);
+        outportb(io_base + REG_LBA_MID, 0);
+        outportb(io_base + REG_LBA_HIGH, 0);
+        outportb(io_base + REG_COMMAND, COMMAND_IDENTIFY);
+        wait_400ns(control_base);
+
+        if inportb(io_base + REG_STATUS) == 0 {
+            return None; // No drive
+        }
+        wait_not_busy(io_base).ok()?;
+
+        // ATAPI and SATA devices set the LBA registers to a signature
+        if inportb(io_base + REG_LBA_MID) != 0 || inportb(io_base + REG_LBA_HIGH) != 0 {
+            return None;
+        }
+
+        let start = time::microseconds_monotonic();
+        loop {
+            let status = inportb(io_base + REG_STATUS);
+            if status & STATUS_ERR != 0 {
+                return None;
+            }
+            if status & STATUS_DRQ != 0 {
+                break;
+            }
+            if time::microseconds_monotonic() - start > TIMEOUT_US {
+                return None;
+            }
+            syscalls::thread_yield();
+        }
+
+        let mut identify = [0u16; 256];
+        for word in identify.iter_mut() {
+            *word = inportw(io_base + REG_DATA);
+        }
+
+        if identify[IDENTIFY_CAPABILITIES] & CAPABILITY_LBA == 0 {
+            return None; // CHS only
+        }
+        let sectors = (identify[IDENTIFY_LBA28_SECTORS] as u64) |
+            ((identify[IDENTIFY_LBA28_SECTORS + 1] as u64) << 16);
+        if sectors == 0 {
+            return None;
+        }
+
+        Some(Drive {
+            io_base,
+            control_base,
+            slave,
+            sectors: sectors.min(LBA28_SECTORS),
+            model: model_name(&identify)
+        })
+    }
+
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.sectors * SECTOR_SIZE as u64
+    }
+
+    /// Wait for the drive to be ready to transfer a sector
+    fn wait_data(&self) -> Result<(), SyscallError> {
+        let start = time::microseconds_monotonic();
+        loop {
+            let status = wait_not_busy(self.io_base)?;
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                println!("[ata] Drive error: status {:02X} error {:02X}",
+                         status, inportb(self.io_base + REG_ERROR));
+                return Err(syscalls::SYSCALL_ERROR_IO);
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+            if time::microseconds_monotonic() - start > TIMEOUT_US {
+                return Err(syscalls::SYSCALL_ERROR_TIMEOUT);
+            }
+            syscalls::thread_yield();
+        }
+    }
+
+    /// Select the drive and set the sectors for a read or write
+    fn setup(&self, lba: u64, count: usize) -> Result<(), SyscallError> {
+        if count == 0 || count > MAX_SECTORS || lba + count as u64 > self.sectors {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        wait_not_busy(self.io_base)?;
+
+        outportb(self.io_base + REG_DRIVE,
+                 DRIVE_LBA | drive_select(self.slave) | ((lba >> 24) & 0x0F) as u8);
+        wait_400ns(self.control_base);
+
+        outportb(self.io_base + REG_SECTOR_COUNT, count as u8); // 256 is 0
+        outportb(self.io_base + REG_LBA_LOW, lba as u8);
+        outportb(self.io_base + REG_LBA_MID, (lba >> 8) as u8);
+        outportb(self.io_base + REG_LBA_HIGH, (lba >> 16) as u8);
+        Ok(())
+    }
+
+    /// Read whole sectors starting at `lba` into `buffer`
+    ///
+    /// The buffer length must be a multiple of SECTOR_SIZE, and at
+    /// most MAX_SECTORS sectors
+    pub fn read(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), SyscallError> {
+        if buffer.len() % SECTOR_SIZE != 0 {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        self.setup(lba, buffer.len() / SECTOR_SIZE)?;
+        outportb(self.io_base + REG_COMMAND, COMMAND_READ_SECTORS);
+        wait_400ns(self.control_base);
+
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            self.wait_data()?;
+            for bytes in sector.chunks_exact_mut(2) {
+                bytes.copy_from_slice(&inportw(self.io_base + REG_DATA).to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    /// Write whole sectors starting at `lba`, then flush the drive's
+    /// write cache
+    ///
+    /// The same length limits as `read` apply
+    pub fn write(&mut self, lba: u64, buffer: &[u8]) -> Result<(), SyscallError> {
+        if buffer.len() % SECTOR_SIZE != 0 {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        self.setup(lba, buffer.len() / SECTOR_SIZE)?;
+        outportb(self.io_base + REG_COMMAND, COMMAND_WRITE_SECTORS);
+        wait_400ns(self.control_base);
+
+        for sector in buffer.chunks_exact(SECTOR_SIZE) {
+            self.wait_data()?;
+            for bytes in sector.chunks_exact(2) {
+                outportw(self.io_base + REG_DATA, u16::from_le_bytes([bytes[0], bytes[1]]));
+            }
+        }
+
+        outportb(self.io_base + REG_COMMAND, COMMAND_CACHE_FLUSH);
+        wait_400ns(self.control_base);
+        let status = wait_not_busy(self.io_base)?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            println!("[ata] Cache flush failed: status {:02X} error {:02X}",
+                     status, inportb(self.io_base + REG_ERROR));
+            return Err(syscalls::SYSCALL_ERROR_IO);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+#![no_std]
+#![no_main]
+
+//! ATA PIO disk driver
+//!
+//! Serves the first ATA drive found on the primary or secondary
+//! channel as a block device. Handles accept the usual file
+//! messages, but offsets and lengths must be whole sectors
+//! (SECTOR_SIZE bytes):
+//!  - Short(READ, offset, length) -> Long(DATA, length, handle)
+//!    reads at most MAX_SECTORS sectors
+//!  - Long(WRITE, length, handle) -> Short(OK, written, position)
+//!    writes at the current position. Requires O_WRITE
+//!  - Short(SEEK, offset, whence) -> Short(OK, position, 0)
+//!  - Short(QUERY, 0, 0) -> Long(JSON, length, handle) with the
+//!    "size" in bytes, "sector_size" and drive "model"
+//!
+//! Other sizes reply Short(ERROR, SYSCALL_ERROR_PARAM, 0), and
+//! errors reported by the drive SYSCALL_ERROR_IO.
+
+extern crate alloc;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use euralios_std::{println,
+                   syscalls::{self, CommHandle, MemoryHandle, SyscallError, STDIN},
+                   message::{self, Message, MessageData},
+                   server,
+                   thread};
+
+mod drive;
+use drive::{Drive, CHANNELS, MAX_SECTORS, SECTOR_SIZE};
+
+#[no_mangle]
+fn main() {
+    println!("[ata] Starting driver");
+
+    let mut drives = Vec::new();
+    for (channel, io_base, control_base) in CHANNELS {
+        for slave in [false, true] {
+            if let Some(drive) = Drive::identify(io_base, control_base, slave) {
+                println!("[ata] {} {}: {} ({} MiB)",
+                         channel, if slave { "slave" } else { "master" },
+                         drive.model, drive.size() >> 20);
+                drives.push(drive);
+            }
+        }
+    }
+
+    if drives.len() > 1 {
+        println!("[ata] Serving the first of {} drives", drives.len());
+    }
+    let drive = match drives.into_iter().next() {
+        Some(drive) => Arc::new(Mutex::new(drive)),
+        None => {
+            println!("[ata] No drives found");
+            server::signal_failed(syscalls::SYSCALL_ERROR_NOTFOUND);
+            return;
+        }
+    };
+
+    server::signal_ready();
+
+    loop {
+        let flags = match syscalls::receive(&STDIN) {
+            Ok(Message::Short(tag, _, _)) |
+            Ok(Message::Long(tag, _, _)) if tag & !message::OPEN_FLAGS_MASK == message::OPEN => {
+                tag & message::OPEN_FLAGS_MASK
+            }
+            Ok(message) => {
+                println!("[ata] Unknown message {:?}", message);
+                continue;
+            }
+            Err(err) => {
+                println!("[ata] Receive error {}", err);
+                // Wait and try again
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+
+        let (handle, client_handle) = match syscalls::new_rendezvous() {
+            Ok(handles) => handles,
+            Err(err) => {
+                println!("[ata] Couldn't create Rendezvous {}", err);
+                syscalls::send(&STDIN, Message::Short(message::ERROR, err.as_u64(), 0));
+                continue;
+            }
+        };
+
+        let drive = drive.clone();
+        let writable = flags & message::O_WRITE != 0;
+        if let Err(err) = thread::spawn(move || drive_handler(drive, handle, writable)) {
+            println!("[ata] Couldn't start thread: {}", err);
+            syscalls::send(&STDIN, Message::Short(message::ERROR, err.as_u64(), 0));
+            continue;
+        }
+        syscalls::send(&STDIN,
+                       Message::Long(
+                           message::COMM_HANDLE,
+                           client_handle.into(), 0.into()));
+    }
+}
+
+fn error(err: SyscallError) -> Message {
+    Message::Short(message::ERROR, err.as_u64(), 0)
+}
+
+/// Check that a byte offset is a whole number of sectors,
+/// returning the sector number
+fn sector(offset: u64) -> Result<u64, SyscallError> {
+    if offset % SECTOR_SIZE as u64 != 0 {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+    Ok(offset / SECTOR_SIZE as u64)
+}
+
+/// Read up to MAX_SECTORS sectors at a byte offset
+fn read(drive: &Mutex<Drive>, offset: u64, length: u64) -> Result<(MemoryHandle, usize), SyscallError> {
+    let lba = sector(offset)?;
+    let count = sector(length)?;
+    let mut drive = drive.lock();
+    if lba >= drive.sectors {
+        return Err(syscalls::SYSCALL_ERROR_NO_DATA);
+    }
+    let count = count.min(drive.sectors - lba).min(MAX_SECTORS as u64) as usize;
+    if count == 0 {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+
+    let length = count * SECTOR_SIZE;
+    let (mut data, _) = syscalls::malloc(length as u64, 0)?;
+    drive.read(lba, data.as_mut_slice::<u8>(length))?;
+    Ok((data, length))
+}
+
+/// Write whole sectors at a byte offset
+fn write(drive: &Mutex<Drive>, offset: u64, data: &[u8]) -> Result<(), SyscallError> {
+    let lba = sector(offset)?;
+    sector(data.len() as u64)?;
+    let mut drive = drive.lock();
+    if lba + (data.len() / SECTOR_SIZE) as u64 > drive.sectors {
+        return Err(syscalls::SYSCALL_ERROR_NO_SPACE);
+    }
+    for (i, chunk) in data.chunks(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+        drive.write(lba + (i * MAX_SECTORS) as u64, chunk)?;
+    }
+    Ok(())
+}
+
+/// New position after a SEEK. See message::SEEK
+fn seek(size: u64, offset: u64, whence: u64) -> Result<u64, SyscallError> {
+    let position = match whence {
+        message::SEEK_START => offset,
+        message::SEEK_END => u64::try_from(size as i128 + offset as i64 as i128)
+            .map_err(|_| syscalls::SYSCALL_ERROR_PARAM)?,
+        _ => return Err(syscalls::SYSCALL_ERROR_PARAM)
+    };
+    sector(position)?;
+    Ok(position.min(size))
+}
+
+/// Serve one handle until it is closed
+fn drive_handler(drive: Arc<Mutex<Drive>>, comm_handle: CommHandle, writable: bool) {
+    let (size, model) = {
+        let drive = drive.lock();
+        (drive.size(), drive.model.clone())
+    };
+    let mut position: u64 = 0;
+
+    loop {
+        let reply = match syscalls::receive(&comm_handle) {
+            Ok(Message::Short(message::READ, offset, length)) => {
+                match read(&drive, offset, length) {
+                    Ok((data, length)) => {
+                        position = offset + length as u64;
+                        Message::Long(message::DATA,
+                                      (length as u64).into(),
+                                      data.into())
+                    }
+                    Err(err) => error(err)
+                }
+            }
+            Ok(Message::Long(message::WRITE,
+                             MessageData::Value(length),
+                             MessageData::MemoryHandle(data))) => {
+                if !writable {
+                    error(syscalls::SYSCALL_ERROR_DENIED)
+                } else if data.size().map_or(true, |size| length as usize > size) {
+                    // Don't read past the end of the chunk
+                    error(syscalls::SYSCALL_ERROR_PARAM)
+                } else {
+                    match write(&drive, position, data.as_slice::<u8>(length as usize)) {
+                        Ok(()) => {
+                            position += length;
+                            Message::Short(message::OK, length, position)
+                        }
+                        Err(err) => error(err)
+                    }
+                }
+            }
+            Ok(Message::Short(message::SEEK, offset, whence)) => {
+                match seek(size, offset, whence) {
+                    Ok(new_position) => {
+                        position = new_position;
+                        Message::Short(message::OK, position, 0)
+                    }
+                    Err(err) => error(err)
+                }
+            }
+            Ok(Message::Short(message::QUERY, _, _)) => {
+                let info = format!("{{\"type\": \"block\", \"size\": {}, \"sector_size\": {}, \"model\": \"{}\"}}",
+                                   size, SECTOR_SIZE, model);
+                Message::Long(message::JSON,
+                              (info.len() as u64).into(),
+                              MemoryHandle::from_u8_slice(info.as_bytes()).into())
+            }
+            Ok(Message::Short(message::SYNC, _, _)) => {
+                // Each write flushes the drive cache
+                Message::Short(message::OK, 0, 0)
+            }
+            Ok(_) => Message::Short(message::ERROR_UNKNOWN_MESSAGE, 0, 0),
+            Err(syscalls::SYSCALL_ERROR_CLOSED) => return,
+            Err(err) => {
+                println!("[ata] Receive error {}", err);
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+        syscalls::send(&comm_handle, reply);
+    }
+}
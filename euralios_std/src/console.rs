@@ -25,3 +25,15 @@ pub mod sequences {
     pub const ArrowRight: u64 = 0x1b_9b_43; // ESC [ C
     pub const ArrowLeft: u64 = 0x1b_9b_44; // ESC [ D
 }
+
+/// Names of the colors which consoles can display, in ANSI order:
+/// color N is set with ESC [ 3N m (foreground) or ESC [ 4N m (background)
+pub const COLOR_NAMES: [&str; 8] = ["black", "red", "green", "yellow",
+                                    "blue", "magenta", "cyan", "white"];
+
+/// The ANSI number of a color, from its name in COLOR_NAMES
+pub fn color_number(name: &str) -> Option<u8> {
+    COLOR_NAMES.iter()
+        .position(|color| color.eq_ignore_ascii_case(name.trim()))
+        .map(|n| n as u8)
+}
@@ -1,19 +1,169 @@
 extern crate alloc;
 use alloc::string::String;
+use alloc::vec::{self, Vec};
+use core::fmt;
 
+use crate::fs::File;
+use crate::path::{self, Path, PathBuf};
+use crate::syscalls::{self, SyscallError};
+
+/// Iterator over the arguments of a process, returned by `args()`
 pub struct Args {
+    inner: vec::IntoIter<&'static [u8]>
 }
 
+/// The arguments this process was started with
+///
+/// Invalid UTF-8 is replaced with U+FFFD. Programs started
+/// with `syscalls::exec` have no arguments.
 pub fn args() -> Args {
-    Args{}
+    Args{inner: syscalls::args().into_iter()}
 }
 
 impl Iterator for Args {
     type Item = String;
     fn next(&mut self) -> Option<String> {
-        None
+        self.inner.next()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (0, Some(0))
+        self.inner.size_hint()
+    }
+}
+
+/// The current working directory. See syscalls::getcwd
+pub fn current_dir() -> Result<PathBuf, SyscallError> {
+    Ok(syscalls::getcwd())
+}
+
+/// Change the current working directory
+///
+/// `path` may be relative to the current directory. It must be an
+/// existing directory, checked with its server (SYSCALL_ERROR_NOTFOUND
+/// or SYSCALL_ERROR_NOT_DIR), and is stored in canonical form. See
+/// fs::canonicalize.
+pub fn set_current_dir<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
+    let path = path::absolute(path);
+    let path = path.as_os_str().to_str().ok_or(syscalls::SYSCALL_ERROR_UTF8)?;
+    syscalls::chdir(path)
+}
+
+/// File containing environment variables
+///
+/// Each line is KEY=VALUE. Blank lines and lines starting with '#'
+/// are ignored. init writes the default file at startup.
+///
+/// EuraliOS only
+pub const ENVIRONMENT_PATH: &str = "/ramdisk/etc/environment";
+
+/// The error type for operations on environment variables
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VarError {
+    /// The variable is not set, or the environment can't be read
+    NotPresent,
+    /// The environment file is not valid UTF-8
+    NotUnicode
+}
+
+impl fmt::Display for VarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VarError::NotPresent => write!(f, "environment variable not found"),
+            VarError::NotUnicode => write!(f, "environment variable was not valid unicode")
+        }
+    }
+}
+
+/// Read the environment file
+fn read_environment() -> Result<String, VarError> {
+    let mut data = Vec::new();
+    File::open(ENVIRONMENT_PATH)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|_| VarError::NotPresent)?;
+    String::from_utf8(data).map_err(|_| VarError::NotUnicode)
+}
+
+/// Parse KEY=VALUE lines. Later lines override earlier ones.
+fn parse_environment(text: &str) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim();
+            vars.retain(|(k, _)| k != key);
+            vars.push((String::from(key), String::from(value)));
+        }
+    }
+    vars
+}
+
+/// Fetch the environment variable `key`
+///
+/// Variables set in the kernel with `set_var` are used first, then
+/// the environment file. Processes started with exec inherit the
+/// kernel variables.
+///
+/// Usage:
+///
+/// ```ignore
+/// let prompt = env::var("PS1").unwrap_or(String::from("$ "));
+/// ```
+pub fn var(key: &str) -> Result<String, VarError> {
+    if let Some(value) = syscalls::getenv(key) {
+        return Ok(value);
+    }
+    parse_environment(&read_environment()?)
+        .into_iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value)
+        .ok_or(VarError::NotPresent)
+}
+
+/// All environment variables, as (key, value) pairs
+///
+/// Variables from the environment file, then kernel variables
+/// which replace any with the same key.
+pub fn vars() -> Vec<(String, String)> {
+    let mut vars = read_environment()
+        .map(|text| parse_environment(&text))
+        .unwrap_or_default();
+    let kernel_vars = syscalls::listenv().unwrap_or_default();
+    vars.retain(|(key, _)| !kernel_vars.iter().any(|(k, _)| k == key));
+    vars.extend(kernel_vars);
+    vars
+}
+
+/// Set an environment variable for this process and the processes
+/// it starts
+///
+/// The environment file isn't changed. Only processes with I/O
+/// privileges can set variables; see syscalls::setenv for this and
+/// limits on keys and values.
+pub fn set_var(key: &str, value: &str) -> Result<(), SyscallError> {
+    syscalls::setenv(key, value)
+}
+
+/// Remove an environment variable set with `set_var`
+///
+/// Variables in the environment file are still returned by `var`.
+/// Removing a variable which isn't set does nothing.
+pub fn remove_var(key: &str) -> Result<(), SyscallError> {
+    match syscalls::unsetenv(key) {
+        Err(syscalls::SYSCALL_ERROR_NOTFOUND) => Ok(()),
+        result => result
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse_lines() {
+        let vars = parse_environment("# Colors\nTERM_FG=red\n\n PS1 =\\w $ \nTERM_FG=blue\nnonsense\n");
+        assert_eq!(vars, [(String::from("PS1"), String::from("\\w $ ")),
+                          (String::from("TERM_FG"), String::from("blue"))]);
     }
 }
@@ -1,15 +1,16 @@
 //! Filesystem
 
 extern crate alloc;
-use alloc::string::String;
-use alloc::vec::Vec;
+use alloc::string::{String, ToString};
+use alloc::vec::{self, Vec};
 use core::str;
 use core::fmt;
+use core::cmp;
 use core::convert::AsRef;
 use serde_json::Value;
 
-use crate::{path::{Path, PathBuf, Component},
-            println,
+use crate::{path::{self, Path, PathBuf, Component},
+            io::{self, SeekFrom},
             syscalls::{self, CommHandle, SyscallError, MemoryHandle},
             message::{self, rcall, Message, MessageData}};
 
@@ -18,7 +19,8 @@ pub struct OpenOptions {
     write: bool,
     append: bool,
     create: bool,
-    truncate: bool
+    truncate: bool,
+    directory: bool
 }
 
 impl OpenOptions {
@@ -38,7 +40,8 @@ impl OpenOptions {
         OpenOptions{write: false,
                     append: false,
                     create: false,
-                    truncate: false}
+                    truncate: false,
+                    directory: false}
     }
 
     /// Sets the option for read access.
@@ -161,6 +164,17 @@ impl OpenOptions {
         self.create = create; self
     }
 
+    /// Sets the option for opening a directory.
+    ///
+    /// If true then opening a file fails with SYSCALL_ERROR_NOT_DIR.
+    /// If false (the default) then opening a directory fails with
+    /// SYSCALL_ERROR_IS_DIR.
+    ///
+    /// EuraliOS only
+    pub fn directory(&mut self, directory: bool) -> &mut OpenOptions {
+        self.directory = directory; self
+    }
+
     /// Opens a file at `path` with the options specified by `self`.
     ///
     pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<File, SyscallError> {
@@ -171,12 +185,23 @@ impl OpenOptions {
         let flags = message::O_READ +
             if self.write || self.append { message::O_WRITE } else { 0 } +
             if self.create { message::O_CREATE } else { 0 } +
-            if self.truncate { message::O_TRUNCATE } else { 0 };
-        let handle = syscalls::open(path.as_os_str(), flags)?;
-        Ok(File(handle))
+            if self.truncate { message::O_TRUNCATE } else { 0 } +
+            if self.directory { message::O_DIRECTORY } else { 0 } +
+            if self.append { message::O_APPEND } else { 0 };
+        let handle = syscalls::open(resolve(path)?.as_os_str(), flags)?;
+        Ok(File::new(handle))
     }
 }
 
+/// The absolute, normalized form of a path to send to the VFS
+///
+/// Relative paths are relative to the current working directory.
+/// The path is normalized so that `..` can't be used to go from
+/// one mount to another, as the VFS only compares path prefixes.
+fn resolve(path: &Path) -> Result<PathBuf, SyscallError> {
+    path::absolute(path).normalize_lexically()
+}
+
 /// Represents a file
 ///
 /// Intended to have the same API as `std::file::File`
@@ -184,7 +209,11 @@ impl OpenOptions {
 ///
 /// Wrapper around a CommHandle
 #[derive(Debug)]
-pub struct File(CommHandle);
+pub struct File {
+    handle: CommHandle,
+    /// Offset in bytes of the next read
+    position: u64
+}
 
 /// The result of a File query.
 ///
@@ -194,59 +223,50 @@ pub struct File(CommHandle);
 pub struct FileQuery(Value);
 
 impl File {
-    const MAX_SIZE: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+    /// Number of bytes requested in each read by read_to_end
+    const READ_CHUNK: usize = 64 * 1024;
+
+    /// Most bytes read_to_end reserves from the length reported by
+    /// the server. Larger files grow the buffer as they are read.
+    const MAX_SIZE_HINT: usize = 16 * 1024 * 1024;
+
+    fn new(handle: CommHandle) -> File {
+        File{handle, position: 0}
+    }
 
     /// Opens a file in write-only mode.
     ///
     /// This function will create a file if it does not exist, and
     /// will truncate it if it does.
     pub fn create<P: AsRef<Path>>(path: P) -> Result<File, SyscallError> {
-        let handle = syscalls::open(path.as_ref().as_os_str(), message::O_WRITE + message::O_CREATE + message::O_TRUNCATE)?;
-        Ok(File(handle))
+        let handle = syscalls::open(resolve(path.as_ref())?.as_os_str(), message::O_WRITE + message::O_CREATE + message::O_TRUNCATE)?;
+        Ok(File::new(handle))
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> Result<File, SyscallError> {
-        let handle = syscalls::open(path.as_ref().as_os_str(), message::O_READ)?;
-        Ok(File(handle))
+        let handle = syscalls::open(resolve(path.as_ref())?.as_os_str(), message::O_READ)?;
+        Ok(File::new(handle))
     }
 
     /// Convert to CommHandle
     ///
     /// EuraliOS only
     pub fn to_CommHandle(self) -> CommHandle {
-        self.0
+        self.handle
     }
 
     /// Query a file handle
     ///
+    /// Returns the server's error if the call fails, and
+    /// SYSCALL_ERROR_UNEXPECTED if the reply isn't JSON. If the JSON
+    /// isn't valid UTF-8 returns SYSCALL_ERROR_UTF8, and if it can't
+    /// be parsed SYSCALL_ERROR_INVALID_DATA.
+    ///
     /// EuraliOS only
     pub fn query(&self) -> Result<FileQuery, SyscallError> {
-        match rcall(&self.0,
-                    message::QUERY,
-                    0.into(), 0.into(), None) {
-            Ok((message::JSON,
-                MessageData::Value(length),
-                MessageData::MemoryHandle(handle))) => {
-
-                let u8_slice = handle.as_slice::<u8>(length as usize);
-                if let Ok(s) = str::from_utf8(u8_slice) {
-                    match serde_json::from_str::<Value>(s) {
-                        Ok(v) => Ok(FileQuery(v)),
-                        Err(err) => {
-                            println!("File::query error {:?} parsing {}",
-                                     err, s);
-                            Err(syscalls::SYSCALL_ERROR_PARAM)
-                        }
-                    }
-                } else {
-                    Err(syscalls::SYSCALL_ERROR_PARAM)
-                }
-            },
-            message => {
-                println!("[query] received {:?}", message);
-                Err(syscalls::SYSCALL_ERROR_PARAM)
-            }
-        }
+        parse_query_reply(rcall(&self.handle,
+                                message::QUERY,
+                                0.into(), 0.into(), None))
     }
 
     /// Remote call. Send a message and wait for a reply
@@ -259,7 +279,7 @@ impl File {
         data3: MessageData
     ) -> Result<(u64, MessageData, MessageData),
                 (SyscallError, Message)> {
-        rcall(&self.0,
+        rcall(&self.handle,
               data1, data2, data3,
               None)
     }
@@ -267,69 +287,274 @@ impl File {
     /// Write a buffer into this writer, returning how many bytes were
     /// written.
     ///
+    /// Writing starts at the current position, or at the end of the
+    /// file if it was opened in append mode.
+    ///
+    /// The buffer is sent in one message, so at most
+    /// syscalls::MAX_CHUNK_SIZE bytes are written by each call.
+    /// Use `write_all` to write larger buffers.
+    ///
     /// Note: This is part of the io::Write trait impl
     pub fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let buf = &buf[..buf.len().min(syscalls::MAX_CHUNK_SIZE)];
         // Copy buffer into pages which can be sent
-        match rcall(&self.0,
+        match rcall(&self.handle,
                     message::WRITE,
                     (buf.len() as u64).into(),
                     MemoryHandle::from_u8_slice(buf).into(),
                     None) {
             Ok((message::OK,
-                MessageData::Value(sent_length), _)) => Ok(sent_length as usize),
+                MessageData::Value(sent_length),
+                MessageData::Value(position))) => {
+                // Writes move the position, and may be at the end (append)
+                self.position = position;
+                Ok(sent_length as usize)
+            },
             Err((err, _message)) => Err(err),
-            result => {
-                println!("File::write unexpected result {:?}", result);
-                Err(syscalls::SYSCALL_ERROR_PARAM)
-            }
+            _ => Err(syscalls::SYSCALL_ERROR_UNEXPECTED)
         }
     }
 
-    /// Read all bytes until EOF in this source, placing them into buf
-    pub fn read_to_end(&mut self, buf: &mut Vec<u8>)
-                       -> Result<usize, SyscallError> {
-        match rcall(&self.0,
-                    message::READ, 0.into(), Self::MAX_SIZE.into(),
+    /// Reserve space for the file to grow to `len` bytes
+    ///
+    /// The file length and contents are not changed: writes append
+    /// to the end of the file as before, but won't fail for lack of
+    /// space until they go past `len` bytes. Writes beyond `len` are
+    /// allowed, but may then fail with SYSCALL_ERROR_NO_SPACE.
+    /// Truncating the file (set_len or O_TRUNCATE) releases any
+    /// space reserved beyond the new length.
+    ///
+    /// Returns SYSCALL_ERROR_NO_SPACE immediately if the server
+    /// can't reserve the space.
+    pub fn allocate(&mut self, len: u64) -> Result<(), SyscallError> {
+        match rcall(&self.handle,
+                    message::FALLOCATE, len.into(), 0.into(),
+                    None) {
+            Ok((message::OK, _, _)) => Ok(()),
+            Err((err, _message)) => Err(err),
+            _ => Err(syscalls::SYSCALL_ERROR_UNEXPECTED)
+        }
+    }
+
+    /// Write any data buffered by the file server to storage
+    ///
+    /// Returns once the server replies. See also `sync`, which
+    /// flushes all file systems.
+    pub fn sync_all(&self) -> Result<(), SyscallError> {
+        match rcall(&self.handle,
+                    message::SYNC, 0.into(), 0.into(),
+                    None) {
+            Ok((message::OK, _, _)) => Ok(()),
+            Err((err, _message)) => Err(err),
+            _ => Err(syscalls::SYSCALL_ERROR_UNEXPECTED)
+        }
+    }
+
+    /// Pull some bytes from this source into the specified buffer,
+    /// returning how many bytes were read.
+    ///
+    /// Reading starts at the current position in the file, which is
+    /// advanced by the number of bytes read. Returns 0 at the end of
+    /// the file, or if `buf` is empty.
+    ///
+    /// Note: This is part of the io::Read trait impl
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, SyscallError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match rcall(&self.handle,
+                    message::READ, self.position.into(), (buf.len() as u64).into(),
                     None) {
             Ok((message::DATA, MessageData::Value(length), MessageData::MemoryHandle(data))) => {
-                let length = length as usize;
-                buf.extend_from_slice(data.as_slice::<u8>(length));
+                let length = cmp::min(length as usize, buf.len());
+                buf[..length].copy_from_slice(data.as_slice::<u8>(length));
+                self.position += length as u64;
                 Ok(length)
             },
+            // Servers reply NO_DATA when reading from the end of a file
+            Err((syscalls::SYSCALL_ERROR_NO_DATA, _message)) => Ok(0),
             Err((err, _message)) => Err(err),
-            result => {
-                println!("File::read_to_end unexpected result {:?}", result);
-                Err(syscalls::SYSCALL_ERROR_PARAM)
+            _ => Err(syscalls::SYSCALL_ERROR_UNEXPECTED)
+        }
+    }
+
+    /// Seek to an offset, in bytes, in a stream.
+    ///
+    /// Returns the new position from the start of the file. Seeking
+    /// before the start is an error (SYSCALL_ERROR_PARAM). Files
+    /// opened for writing can seek past the end; read-only files
+    /// stop at the end.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, SyscallError> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (offset, message::SEEK_START),
+            SeekFrom::End(offset) => (offset as u64, message::SEEK_END),
+            SeekFrom::Current(offset) => {
+                // The server doesn't know the current position
+                let position = self.position as i128 + offset as i128;
+                (u64::try_from(position).map_err(|_| syscalls::SYSCALL_ERROR_PARAM)?,
+                 message::SEEK_START)
+            }
+        };
+        match rcall(&self.handle,
+                    message::SEEK, offset.into(), whence.into(),
+                    None) {
+            Ok((message::OK, MessageData::Value(position), _)) => {
+                self.position = position;
+                Ok(position)
+            },
+            Err((err, _message)) => Err(err),
+            _ => Err(syscalls::SYSCALL_ERROR_UNEXPECTED)
+        }
+    }
+
+    /// Queries metadata about the underlying file
+    ///
+    /// Fields which the server doesn't provide have default values:
+    /// zero length, and not a directory.
+    pub fn metadata(&self) -> Result<Metadata, SyscallError> {
+        Ok(Metadata::from_query(&self.query()?.0))
+    }
+
+    /// Read all bytes until EOF in this source, placing them into buf
+    ///
+    /// Returns the number of bytes appended to buf
+    ///
+    /// Files which fit in one chunk are read without asking the
+    /// server for the length. For larger files the length is only a
+    /// hint: at most MAX_SIZE_HINT bytes are reserved, and failing
+    /// to reserve them isn't an error.
+    pub fn read_to_end(&mut self, buf: &mut Vec<u8>)
+                       -> Result<usize, SyscallError> {
+        let start_len = buf.len();
+        let mut size_hint_used = false;
+        loop {
+            let len = buf.len();
+            buf.resize(len + Self::READ_CHUNK, 0);
+            let result = self.read(&mut buf[len..]);
+            buf.truncate(len + *result.as_ref().unwrap_or(&0));
+            match result {
+                Ok(0) => return Ok(len - start_len),
+                Ok(n) if n == Self::READ_CHUNK && !size_hint_used => {
+                    size_hint_used = true;
+                    if let Ok(metadata) = self.metadata() {
+                        let remaining = metadata.len().saturating_sub(self.position);
+                        let hint = cmp::min(remaining, Self::MAX_SIZE_HINT as u64);
+                        // Leave room for the chunk which reads EOF
+                        let _ = buf.try_reserve(hint as usize + Self::READ_CHUNK);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => return Err(err)
             }
         }
     }
 }
 
+impl io::Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, SyscallError> {
+        File::read(self, buf)
+    }
+
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, SyscallError> {
+        File::read_to_end(self, buf)
+    }
+}
+
+impl io::Write for File {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError> {
+        File::write(self, buf)
+    }
+
+    /// Does nothing: File doesn't buffer writes. Use `sync_all` to
+    /// flush data buffered by the server to storage.
+    fn flush(&mut self) -> Result<(), SyscallError> {
+        Ok(())
+    }
+}
+
+/// The type of a file or directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Dir,
+    File,
+    /// The server described a type which isn't known
+    Unknown
+}
+
+impl FileType {
+    /// Read the type of a JSON object, from a "type" string ("dir"
+    /// or "file") or an "is_dir" boolean. Returns `default` if
+    /// neither field is present.
+    fn from_json(obj: &Value, default: FileType) -> FileType {
+        match (obj["type"].as_str(), obj["is_dir"].as_bool()) {
+            (Some("dir"), _) => FileType::Dir,
+            (Some("file"), _) => FileType::File,
+            (Some(_), _) => FileType::Unknown,
+            (None, Some(true)) => FileType::Dir,
+            (None, Some(false)) => FileType::File,
+            (None, None) => default
+        }
+    }
+
+    /// Test whether this file type represents a directory
+    pub fn is_dir(&self) -> bool {
+        *self == FileType::Dir
+    }
+
+    /// Test whether this file type represents a regular file
+    pub fn is_file(&self) -> bool {
+        *self == FileType::File
+    }
+}
+
 /// Metadata information about a file.
 #[derive(Clone)]
 pub struct Metadata {
-    is_dir : bool
+    file_type: FileType,
+    len: u64
 }
 
 impl Metadata {
+    /// Read the "type" and "size" fields of a File query
+    fn from_query(query: &Value) -> Metadata {
+        Metadata {
+            file_type: FileType::from_json(query, FileType::File),
+            len: query["size"].as_u64().unwrap_or(0)
+        }
+    }
+
+    /// Returns the file type for this metadata.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
     /// Returns true if this metadata is for a directory. The result
     /// is mutually exclusive to the result of is_file
     pub fn is_dir(&self) -> bool {
-        self.is_dir
+        self.file_type.is_dir()
     }
 
     /// Returns true if this metadata is for a regular file.
     pub fn is_file(&self) -> bool {
-        !self.is_dir
+        self.file_type.is_file()
+    }
+
+    /// Returns the size of the file, in bytes, this metadata is for.
+    pub fn len(&self) -> u64 {
+        self.len
     }
 }
 
 impl fmt::Debug for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Metadata")
-            //.field("file_type", &self.file_type())
+            .field("file_type", &self.file_type())
             .field("is_dir", &self.is_dir())
             .field("is_file", &self.is_file())
+            .field("len", &self.len())
             //.field("permissions", &self.permissions())
             //.field("modified", &self.modified())
             //.field("accessed", &self.accessed())
@@ -352,26 +577,96 @@ impl DirEntry {
     }
 
     /// Returns the metadata for the file that this entry points at.
+    ///
+    /// Only the type, and the length if the server lists it, are
+    /// known from the directory query.
     pub fn metadata(&self) -> Result<Metadata, SyscallError> {
         Ok(self.meta.clone())
     }
+
+    /// Returns the file type for the file that this entry points at,
+    /// without a query to the file.
+    pub fn file_type(&self) -> FileType {
+        self.meta.file_type
+    }
+
+    /// True if this entry is a directory
+    ///
+    /// EuraliOS only
+    pub fn is_dir(&self) -> bool {
+        self.meta.is_dir()
+    }
 }
 
 /// Iterator yielding Result<DirEntry>
+///
+/// Files are yielded first then subdirectories, each in the order
+/// the server listed them. Entries which the server described with
+/// malformed JSON are yielded as Err(SYSCALL_ERROR_INVALID_DATA), so
+/// they can be distinguished from real files.
 #[derive(Debug)]
 pub struct ReadDir {
-    entries: Vec<DirEntry>
+    entries: vec::IntoIter<Result<DirEntry, SyscallError>>
 }
 
 impl Iterator for ReadDir {
     type Item = Result<DirEntry, SyscallError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(entry) = self.entries.pop() {
-            return Some(Ok(entry));
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+/// Convert the reply to a QUERY message into a FileQuery. See File::query
+fn parse_query_reply(
+    reply: Result<(u64, MessageData, MessageData), (SyscallError, Message)>
+) -> Result<FileQuery, SyscallError> {
+    match reply {
+        Ok((message::JSON,
+            MessageData::Value(length),
+            MessageData::MemoryHandle(handle))) => {
+            let s = str::from_utf8(handle.as_slice::<u8>(length as usize))
+                .map_err(|_| syscalls::SYSCALL_ERROR_UTF8)?;
+            serde_json::from_str::<Value>(s)
+                .map(FileQuery)
+                .map_err(|_| syscalls::SYSCALL_ERROR_INVALID_DATA)
+        }
+        Err((err, _message)) => Err(err),
+        _ => Err(syscalls::SYSCALL_ERROR_UNEXPECTED)
+    }
+}
+
+/// Convert a directory query into a list of entries
+fn parse_dir_query(query: &Value) -> Vec<Result<DirEntry, SyscallError>> {
+    // Entries without a type are files, or directories if they're
+    // listed in "subdirs"
+    let entry = |obj: &Value, default_type: FileType| {
+        match obj["name"].as_str() {
+            Some(name) => Ok(DirEntry{
+                name: String::from(name),
+                meta: Metadata {
+                    file_type: FileType::from_json(obj, default_type),
+                    len: obj["size"].as_u64().unwrap_or(0)
+                }
+            }),
+            None => Err(syscalls::SYSCALL_ERROR_INVALID_DATA)
         }
-        None
+    };
+
+    let mut entries: Vec<_> = match query["files"].as_array() {
+        Some(vec) => vec.iter().map(|obj| entry(obj, FileType::File)).collect(),
+        _ => Vec::new()
+    };
+
+    if let Some(vec) = query["subdirs"].as_array() {
+        // Some directories
+        entries.extend(vec.iter().map(|obj| entry(obj, FileType::Dir)));
     }
+    entries
 }
 
 pub fn read_dir<P: AsRef<Path>>(
@@ -379,42 +674,139 @@ pub fn read_dir<P: AsRef<Path>>(
 ) -> Result<ReadDir, SyscallError> {
     let path: &Path = path.as_ref();
 
-    let f = File::open(path)?;
+    let f = OpenOptions::new().directory(true).open(path)?;
     let query = f.query()?;
 
-    let mut entries = match query.0["files"].as_array() {
-        Some(vec) => {
-            // Transform into a Vec of DirEntry objects
-            vec.iter().map(|obj| DirEntry{
-                name: String::from(obj["name"].as_str().unwrap_or("_bad_")),
-                meta: Metadata {
-                    is_dir: false
-                }
-            }).collect()
-        }
-        _ => Vec::new()
-    };
+    Ok(ReadDir{
+        entries: parse_dir_query(&query.0).into_iter()
+    })
+}
 
-    if let Some(vec) = query.0["subdirs"].as_array() {
-        // Some directories
-        for obj in vec {
-            entries.push(DirEntry{
-                name: String::from(obj["name"].as_str().unwrap_or("_bad_")),
-                meta: Metadata {
-                    is_dir: true
+/// Default limit on the depth of directories read by walk_dir
+pub const WALK_DIR_MAX_DEPTH: usize = 64;
+
+/// A file or directory found by walk_dir
+#[derive(Debug)]
+pub struct WalkEntry {
+    path: PathBuf,
+    depth: usize,
+    entry: DirEntry
+}
+
+impl WalkEntry {
+    /// The path passed to walk_dir, joined with the path to this entry
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of directories below the root. Entries in the root
+    /// directory have depth 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The entry in its directory
+    pub fn dir_entry(&self) -> &DirEntry {
+        &self.entry
+    }
+}
+
+/// Iterator over all the files and directories below a root
+///
+/// Directories are yielded before their contents, and are only read
+/// when the iterator reaches them, so memory use depends on the depth
+/// of the tree rather than its size.
+#[derive(Debug)]
+pub struct WalkDir {
+    /// Directory to read on the next call to next
+    pending: Option<PathBuf>,
+    /// Directories being read, deepest last
+    stack: Vec<(PathBuf, ReadDir)>,
+    max_depth: usize
+}
+
+impl WalkDir {
+    /// Set the depth of the deepest entries. Directories at this
+    /// depth are yielded but not read.
+    ///
+    /// This prevents endless recursion if a file system is ever
+    /// mounted inside itself. The default is WALK_DIR_MAX_DEPTH.
+    pub fn max_depth(mut self, depth: usize) -> WalkDir {
+        self.max_depth = depth;
+        self
+    }
+}
+
+impl Iterator for WalkDir {
+    type Item = Result<WalkEntry, SyscallError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(path) = self.pending.take() {
+            match read_dir(&path) {
+                Ok(entries) => self.stack.push((path, entries)),
+                Err(err) => return Some(Err(err))
+            }
+        }
+        loop {
+            let depth = self.stack.len();
+            let (dir_path, entries) = self.stack.last_mut()?;
+            match entries.next() {
+                Some(Ok(entry)) => {
+                    let path = dir_path.join(entry.file_name());
+                    if entry.is_dir() && depth < self.max_depth {
+                        self.pending = Some(path.to_path_buf());
+                    }
+                    return Some(Ok(WalkEntry{path, depth, entry}));
+                }
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    // Finished this directory
+                    self.stack.pop();
                 }
-            });
+            }
         }
     }
+}
 
-    Ok(ReadDir{
-        entries
-    })
+/// Recursively iterate over all files and directories below `path`
+///
+/// Usage:
+///
+/// ```ignore
+/// for entry in fs::walk_dir("/ramdisk") {
+///     println!("{:?}", entry?.path());
+/// }
+/// ```
+pub fn walk_dir<P: AsRef<Path>>(path: P) -> WalkDir {
+    WalkDir {
+        pending: Some(path.as_ref().to_path_buf()),
+        stack: Vec::new(),
+        max_depth: WALK_DIR_MAX_DEPTH
+    }
 }
 
-/// Delete a file
-pub fn remove_file<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
+/// Query metadata about a file or directory
+///
+/// Opens the path, queries it, then closes it.
+pub fn metadata<P: AsRef<Path>>(path: P) -> Result<Metadata, SyscallError> {
     let path: &Path = path.as_ref();
+    match File::open(path) {
+        Ok(file) => file.metadata(),
+        Err(syscalls::SYSCALL_ERROR_IS_DIR) => {
+            let dir = OpenOptions::new().directory(true).open(path)?;
+            let mut metadata = dir.metadata()?;
+            metadata.file_type = FileType::Dir;
+            Ok(metadata)
+        }
+        Err(err) => Err(err)
+    }
+}
+
+/// Send a message with the final part of `path` to the parent directory
+///
+/// Used for MKDIR, DELETE and RMDIR, which reply OK or an error
+fn parent_rcall(path: &Path, tag: u64) -> Result<(), SyscallError> {
+    let path = resolve(path)?;
 
     // Get the directory containing the file
     let parent = match path.parent() {
@@ -428,82 +820,477 @@ pub fn remove_file<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
         None => { return Err(syscalls::SYSCALL_ERROR_PARAM); }
     };
 
-    // Open the directory containing this file
-    let f = File::open(parent)?;
+    // Open the directory containing this file for modifying
+    let f = OpenOptions::new().write(true).directory(true).open(parent)?;
 
-    // Send a delete message
     let bytes = file_name.bytes();
-    match f.rcall(message::DELETE,
+    match f.rcall(tag,
                   (bytes.len() as u64).into(),
                   MemoryHandle::from_u8_slice(bytes).into()) {
         Err((err, _)) => Err(err),
         Ok((message::OK, _, _)) => Ok(()),
+        Ok((message::ERROR_DENIED, _, _)) => Err(syscalls::SYSCALL_ERROR_DENIED),
         _ => Err(syscalls::SYSCALL_ERROR_PARAM)
     }
 }
 
-pub fn create_dir<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
+/// Delete a file
+///
+/// Handles which are already open to the file can still be used,
+/// and the contents are freed once they are all closed.
+pub fn remove_file<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
+    parent_rcall(path.as_ref(), message::DELETE)
+}
+
+/// Delete an empty directory
+///
+/// Fails with SYSCALL_ERROR_NOT_EMPTY if the directory has contents.
+pub fn remove_dir<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
+    parent_rcall(path.as_ref(), message::RMDIR)
+}
+
+/// Delete a directory and everything in it
+///
+/// Stops at the first error. Directories deeper than
+/// WALK_DIR_MAX_DEPTH are not read, so can't be removed.
+pub fn remove_dir_all<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
     let path: &Path = path.as_ref();
+    // Files are removed as they are found. Directories are removed
+    // after their contents, so in the reverse of the walk order
+    let mut dirs = Vec::new();
+    for entry in walk_dir(path) {
+        let entry = entry?;
+        if entry.dir_entry().is_dir() {
+            dirs.push(entry.path);
+        } else {
+            remove_file(entry.path())?;
+        }
+    }
+    for dir in dirs.iter().rev() {
+        remove_dir(dir)?;
+    }
+    remove_dir(path)
+}
 
-    // Get the directory's parent
-    let parent = match path.parent() {
-        Some(parent) => parent,
-        None => { return Err(syscalls::SYSCALL_ERROR_PARAM); }
-    };
+/// Default time allowed for all file systems to flush, in microseconds
+pub const SYNC_TIMEOUT_US: u64 = 5_000_000;
 
-    // Get the final part of the path
-    let new_dir_name = match path.file_name() {
-        Some(name) => name,
-        None => { return Err(syscalls::SYSCALL_ERROR_PARAM); }
-    };
+/// Flush buffered writes in all mounted file systems
+///
+/// Waits at most SYNC_TIMEOUT_US. See syscalls::sync
+pub fn sync() -> Result<(), SyscallError> {
+    syscalls::sync(SYNC_TIMEOUT_US)
+}
 
-    // Open the parent directory for modifying
-    let f = OpenOptions::new().write(true).open(parent)?;
+/// Create a new, empty directory
+///
+/// Fails with SYSCALL_ERROR_EXISTS if the path already exists. See
+/// also create_dir_all
+pub fn create_dir<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
+    parent_rcall(path.as_ref(), message::MKDIR)
+}
 
-    // Send a MKDIR message
-    let bytes = new_dir_name.bytes();
-    match f.rcall(message::MKDIR,
-                  (bytes.len() as u64).into(),
-                  MemoryHandle::from_u8_slice(bytes).into()) {
-        Err((err, _)) => Err(err),
-        Ok((message::OK, _, _)) => Ok(()),
-        _ => Err(syscalls::SYSCALL_ERROR_PARAM)
+/// Recursively create a directory and all of its missing parents
+///
+/// Directories which already exist are not an error, but other
+/// files are: Fails with SYSCALL_ERROR_EXISTS if part of the path
+/// is a file. Only missing directories (SYSCALL_ERROR_NOTFOUND) are
+/// created; other errors, such as SYSCALL_ERROR_DENIED, are returned.
+pub fn create_dir_all<P: AsRef<Path>>(path: P) -> Result<(), SyscallError> {
+    let mut dir = PathBuf::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::RootDir => {
+                dir.push("/");
+                continue;
+            }
+            Component::Normal(s) => dir.push(s),
+            _ => return Err(syscalls::SYSCALL_ERROR_PARAM)
+        }
+        match metadata(&dir) {
+            Ok(meta) if meta.is_dir() => {} // Already exists
+            Ok(_) => return Err(syscalls::SYSCALL_ERROR_EXISTS),
+            Err(syscalls::SYSCALL_ERROR_NOTFOUND) => create_dir(&dir)?,
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+/// Move a file, replacing any existing file at `to`
+///
+/// If both paths are on the same mount, the server moves the file
+/// atomically: other programs see it at either `from` or `to`, never
+/// both or neither. Handles open to the file are still valid.
+///
+/// Between mounts the contents are copied to `to` and then `from` is
+/// deleted. This is not atomic: if an error occurs part way, `to` may
+/// be incomplete or `from` may still exist.
+///
+/// Fails with SYSCALL_ERROR_PARAM if `to` is a directory.
+pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<(), SyscallError> {
+    let (from, to) = (resolve(from.as_ref())?, resolve(to.as_ref())?);
+    let from_str = from.as_os_str().to_str()
+        .ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+    let to_str = to.as_os_str().to_str()
+        .ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+
+    let (handle, from_len) = syscalls::open_mount(from_str)?;
+    let (_, to_len) = syscalls::open_mount(to_str)?;
+
+    if from_str[..from_len] == to_str[..to_len] {
+        // Same mount: Paths relative to the mount
+        let mut obj = serde_json::Map::new();
+        obj.insert(String::from("path"), Value::from(&from_str[from_len..]));
+        obj.insert(String::from("to"), Value::from(&to_str[to_len..]));
+        let request = Value::Object(obj).to_string();
+        let bytes = request.as_bytes();
+        return match rcall(&handle,
+                           message::RENAME,
+                           (bytes.len() as u64).into(),
+                           MemoryHandle::from_u8_slice(bytes).into(),
+                           None) {
+            Ok((message::OK, _, _)) => Ok(()),
+            Ok((message::ERROR_DENIED, _, _)) => Err(syscalls::SYSCALL_ERROR_DENIED),
+            Err((err, _message)) => Err(err),
+            _ => Err(syscalls::SYSCALL_ERROR_PARAM)
+        };
+    }
+
+    // Different mounts: Copy then delete
+    if metadata(&to).map(|meta| meta.is_dir()).unwrap_or(false) {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+    let mut data = Vec::new();
+    File::open(&from)?.read_to_end(&mut data)?;
+    let mut file = File::create(&to)?;
+    let mut written = 0;
+    while written < data.len() {
+        match file.write(&data[written..])? {
+            0 => return Err(syscalls::SYSCALL_ERROR_NO_SPACE),
+            n => written += n
+        }
+    }
+    remove_file(from)
+}
+
+/// One operation in a Batch
+#[derive(Debug, Clone)]
+enum BatchOp {
+    Create(PathBuf),
+    CreateDir(PathBuf),
+    Remove(PathBuf),
+    Rename(PathBuf, PathBuf)
+}
+
+/// A list of directory operations sent in a single message
+///
+/// EuraliOS only. Created with `fs::batch()`.
+///
+/// All paths must be on the same mount. The batch is best-effort,
+/// not atomic: operations are applied in order and each one has its
+/// own result. See `message::BATCH` for the wire format.
+///
+/// # Examples
+///
+/// ```no_run
+/// let results = fs::batch()
+///     .create_dir("/ramdisk/out")
+///     .create("/ramdisk/out/a.txt")
+///     .remove_file("/ramdisk/tmp.txt")
+///     .commit()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Batch {
+    ops: Vec<BatchOp>
+}
+
+/// Start a batch of directory operations
+pub fn batch() -> Batch {
+    Batch{ops: Vec::new()}
+}
+
+impl Batch {
+    /// Create an empty file, replacing any existing file
+    pub fn create<P: AsRef<Path>>(&mut self, path: P) -> &mut Batch {
+        self.ops.push(BatchOp::Create(path.as_ref().to_path_buf())); self
+    }
+
+    /// Create a directory
+    pub fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> &mut Batch {
+        self.ops.push(BatchOp::CreateDir(path.as_ref().to_path_buf())); self
+    }
+
+    /// Delete a file
+    pub fn remove_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Batch {
+        self.ops.push(BatchOp::Remove(path.as_ref().to_path_buf())); self
+    }
+
+    /// Move a file, replacing any existing file at `to`
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> &mut Batch {
+        self.ops.push(BatchOp::Rename(from.as_ref().to_path_buf(),
+                                      to.as_ref().to_path_buf())); self
+    }
+
+    /// Number of operations in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Send the operations to the server in one message
+    ///
+    /// Returns an error if the batch couldn't be sent, including if
+    /// the paths are on more than one mount (SYSCALL_ERROR_PARAM).
+    /// Otherwise returns one result per operation, in order.
+    pub fn commit(&self) -> Result<Vec<Result<(), SyscallError>>, SyscallError> {
+        if self.ops.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut mount: Option<(CommHandle, String)> = None;
+        let mut ops = Vec::new();
+        for op in &self.ops {
+            let (name, paths) = match op {
+                BatchOp::Create(path) => ("create", [Some(path), None]),
+                BatchOp::CreateDir(path) => ("mkdir", [Some(path), None]),
+                BatchOp::Remove(path) => ("remove", [Some(path), None]),
+                BatchOp::Rename(from, to) => ("rename", [Some(from), Some(to)])
+            };
+            // Paths relative to the mount
+            let mut relative = Vec::new();
+            for path in paths.iter().flatten() {
+                let path = resolve(path)?;
+                let path = path.as_os_str().to_str()
+                    .ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+                let (handle, match_len) = syscalls::open_mount(path)?;
+                match &mount {
+                    Some((_, mount_path)) if mount_path != &path[..match_len] => {
+                        // Batch spans multiple mounts
+                        return Err(syscalls::SYSCALL_ERROR_PARAM);
+                    }
+                    Some(_) => {} // Drops handle
+                    None => {
+                        mount = Some((handle, String::from(&path[..match_len])));
+                    }
+                }
+                relative.push(Value::from(&path[match_len..]));
+            }
+            let mut obj = serde_json::Map::new();
+            obj.insert(String::from("op"), Value::from(name));
+            obj.insert(String::from("path"), relative[0].clone());
+            if let Some(to) = relative.get(1) {
+                obj.insert(String::from("to"), to.clone());
+            }
+            ops.push(Value::Object(obj));
+        }
+        let (handle, _) = mount.unwrap();
+
+        let request = Value::Array(ops).to_string();
+        let bytes = request.as_bytes();
+        match rcall(&handle,
+                    message::BATCH,
+                    (bytes.len() as u64).into(),
+                    MemoryHandle::from_u8_slice(bytes).into(),
+                    None) {
+            Ok((message::JSON,
+                MessageData::Value(length),
+                MessageData::MemoryHandle(data))) => {
+                let slice = data.as_slice::<u8>(length as usize);
+                let codes = str::from_utf8(slice).ok()
+                    .and_then(|s| serde_json::from_str::<Value>(s).ok())
+                    .ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+                let codes = codes.as_array()
+                    .filter(|codes| codes.len() == self.ops.len())
+                    .ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+                Ok(codes.iter().map(|code| match code.as_u64() {
+                    Some(0) => Ok(()),
+                    Some(code) => Err(SyscallError::new(code)),
+                    None => Err(syscalls::SYSCALL_ERROR_PARAM)
+                }).collect())
+            }
+            Ok((message::ERROR_DENIED, _, _)) => Err(syscalls::SYSCALL_ERROR_DENIED),
+            Err((err, _message)) => Err(err),
+            _ => Err(syscalls::SYSCALL_ERROR_UNEXPECTED)
+        }
     }
 }
 
 /// Returns the canonical, absolute form of a path with all intermediate
-/// components normalized and symbolic links resolved.
+/// components normalized.
+///
+/// `.` and `..` components are resolved, and each component is
+/// checked with its server as it is reached: components followed by
+/// others must be directories (SYSCALL_ERROR_NOT_DIR), and all must
+/// exist. Directories above mount points, such as `/dev` when
+/// `/dev/sda` is mounted, have no server and are assumed to exist.
+///
+/// Relative paths are relative to the current working directory
+/// (syscalls::getcwd). A `..` which would go above the root is
+/// SYSCALL_ERROR_PARAM, rather than staying at the root, so that
+/// paths built from user input can't escape a directory.
+///
+/// See Path::normalize_lexically to normalize without the filesystem.
 pub fn canonicalize<P: AsRef<Path>>(
     path: P
-) -> Result<PathBuf, ()> {
-    let path: &Path = path.as_ref();
+) -> Result<PathBuf, SyscallError> {
+    canonicalize_impl(path.as_ref(), false)
+}
+
+/// Like canonicalize, but the last component must also be a
+/// directory. Used by syscalls::chdir
+pub(crate) fn canonicalize_dir(path: &Path) -> Result<PathBuf, SyscallError> {
+    canonicalize_impl(path, true)
+}
+
+fn canonicalize_impl(path: &Path, final_dir: bool) -> Result<PathBuf, SyscallError> {
+    let path = path::absolute(path);
+    let (mounts, length) = syscalls::list_mounts()?;
+    let mounts = str::from_utf8(mounts.as_slice::<u8>(length as usize))
+        .map_err(|_| syscalls::SYSCALL_ERROR_UTF8)?;
 
     let mut pathbuf = PathBuf::new();
-    for component in path.components() {
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
         match component {
             Component::RootDir => {
                 pathbuf.push("/");
             }
+            Component::CurDir => {}
             Component::ParentDir => {
-                pathbuf.pop();
+                if !pathbuf.pop() {
+                    // Already at the root
+                    return Err(syscalls::SYSCALL_ERROR_PARAM);
+                }
             }
             Component::Normal(s) => {
                 pathbuf.push(s);
+                let path_str = pathbuf.as_os_str().to_str()
+                    .ok_or(syscalls::SYSCALL_ERROR_UTF8)?;
+                if !is_above_mount(path_str, mounts) {
+                    let metadata = metadata(&pathbuf)?;
+                    if (final_dir || components.peek().is_some()) && !metadata.is_dir() {
+                        return Err(syscalls::SYSCALL_ERROR_NOT_DIR);
+                    }
+                }
             }
-            _ => {}
         }
     }
-    return Ok(pathbuf)
+    Ok(pathbuf)
+}
+
+/// True if `path` is a directory containing a mount point, given
+/// the list_mounts JSON
+fn is_above_mount(path: &str, mounts: &str) -> bool {
+    // List of quoted paths: ["/path","/other",]
+    mounts.split('"').skip(1).step_by(2).any(|mount| {
+        mount.len() > path.len() &&
+            mount.starts_with(path) &&
+            mount.as_bytes()[path.len()] == b'/'
+    })
 }
 
 #[cfg(test)]
 pub mod tests {
-    use super::canonicalize;
+    use super::{canonicalize, is_above_mount, parse_dir_query, parse_query_reply,
+                ReadDir, Metadata, FileType};
+    use crate::message::{self, Message, MessageData};
     use crate::path::PathBuf;
+    use alloc::{string::String, vec::Vec};
+    use crate::syscalls;
+    use serde_json::Value;
 
     #[test_case]
     fn canonicalize() {
-        let path_buf = canonicalize("/a/b/../c/./d").unwrap();
-        assert_eq!(path_buf, PathBuf::from("/a/c/d"));
+        // Checked before contacting any server
+        assert_eq!(canonicalize("/.."), Err(syscalls::SYSCALL_ERROR_PARAM));
+        // Lexical part
+        assert_eq!(PathBuf::from("/a/b/../c/./d").normalize_lexically(),
+                   Ok(PathBuf::from("/a/c/d")));
+    }
+
+    #[test_case]
+    fn query_reply_errors() {
+        let json = |bytes: &[u8]| Ok((message::JSON,
+                                      MessageData::Value(bytes.len() as u64),
+                                      MessageData::MemoryHandle(
+                                          syscalls::MemoryHandle::from_u8_slice(bytes))));
+
+        let query = parse_query_reply(json(br#"{"type": "dir"}"#)).unwrap();
+        assert!(query.0["type"] == "dir");
+        assert_eq!(parse_query_reply(json(b"\xff\xfe")).err(),
+                   Some(syscalls::SYSCALL_ERROR_UTF8));
+        assert_eq!(parse_query_reply(json(b"{\"type\": ")).err(),
+                   Some(syscalls::SYSCALL_ERROR_INVALID_DATA));
+        assert_eq!(parse_query_reply(Ok((message::OK, 0.into(), 0.into()))).err(),
+                   Some(syscalls::SYSCALL_ERROR_UNEXPECTED));
+        // Errors from the server are returned unchanged
+        assert_eq!(parse_query_reply(Err((syscalls::SYSCALL_ERROR_NOTFOUND,
+                                          Message::Short(message::QUERY, 0, 0)))).err(),
+                   Some(syscalls::SYSCALL_ERROR_NOTFOUND));
+    }
+
+    #[test_case]
+    fn above_mount_points() {
+        let mounts = r#"["/ramdisk","/dev/sda","/dev/mouse",]"#;
+        assert!(is_above_mount("/dev", mounts));
+        // Mount points themselves are served
+        assert!(!is_above_mount("/dev/sda", mounts));
+        assert!(!is_above_mount("/ram", mounts));
+        assert!(!is_above_mount("/tmp", mounts));
+    }
+
+    #[test_case]
+    fn read_dir_malformed_entry() {
+        let query: Value = serde_json::from_str(
+            r#"{"files": [{"name": "good"}, {"nom": "bad"}], "subdirs": [{"name": 42}]}"#).unwrap();
+        let entries = parse_dir_query(&query);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].as_ref().map(|e| e.file_name()), Ok("good"));
+        assert_eq!(entries[1].as_ref().err(), Some(&syscalls::SYSCALL_ERROR_INVALID_DATA));
+        assert_eq!(entries[2].as_ref().err(), Some(&syscalls::SYSCALL_ERROR_INVALID_DATA));
+    }
+
+    #[test_case]
+    fn metadata_from_query() {
+        let query: Value = serde_json::from_str(r#"{"type": "file", "size": 1234}"#).unwrap();
+        let metadata = Metadata::from_query(&query);
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 1234);
+
+        let query: Value = serde_json::from_str(r#"{"type": "dir"}"#).unwrap();
+        assert!(Metadata::from_query(&query).is_dir());
+
+        // Missing fields
+        let metadata = Metadata::from_query(&Value::Null);
+        assert!(!metadata.is_dir());
+        assert_eq!(metadata.len(), 0);
+    }
+
+    #[test_case]
+    fn dir_entry_types() {
+        let query: Value = serde_json::from_str(
+            r#"{"files": [{"name": "a"}, {"name": "b", "type": "dir"},
+                          {"name": "c", "is_dir": true}, {"name": "d", "type": "fifo"}],
+                "subdirs": [{"name": "e"}]}"#).unwrap();
+        let types: Vec<FileType> = parse_dir_query(&query).into_iter()
+            .map(|entry| entry.unwrap().file_type())
+            .collect();
+        assert_eq!(types, [FileType::File, FileType::Dir, FileType::Dir,
+                           FileType::Unknown, FileType::Dir]);
+    }
+
+    #[test_case]
+    fn read_dir_order() {
+        let query: Value = serde_json::from_str(
+            r#"{"files": [{"name": "a"}, {"name": "b"}], "subdirs": [{"name": "c"}]}"#).unwrap();
+        let read_dir = ReadDir{entries: parse_dir_query(&query).into_iter()};
+        let names: Vec<String> = read_dir
+            .map(|entry| String::from(entry.unwrap().file_name()))
+            .collect();
+        assert_eq!(names, ["a", "b", "c"]);
     }
 }
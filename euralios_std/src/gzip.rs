@@ -0,0 +1,477 @@
+//! Gzip decompression
+//!
+//! A small inflate (RFC 1951) for gzip (RFC 1952) streams, used to
+//! store program binaries compressed and expand them before `exec`
+//! (see `syscalls::exec_compressed`). Written to be small rather
+//! than fast.
+//!
+//! Streams are produced with e.g. `gzip -9 -n`. Only a single
+//! gzip member is decompressed.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::syscalls::{self, SyscallError};
+
+/// Largest expansion which deflate can achieve. Streams claiming
+/// more than this are rejected before allocating any memory.
+const MAX_RATIO: usize = 1032;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not a gzip stream, or an unsupported header
+    Header,
+    /// Data ended before the end of the stream
+    Truncated,
+    /// Invalid block type, Huffman code or distance
+    Corrupt,
+    /// Length or CRC in the trailer doesn't match the data
+    Checksum,
+    /// Decompressed size is over the limit, or implausibly large
+    TooLarge,
+    /// Couldn't allocate the output buffer
+    OutOfMemory,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Error::Header => "Not a gzip stream",
+            Error::Truncated => "Compressed data truncated",
+            Error::Corrupt => "Compressed data corrupt",
+            Error::Checksum => "Compressed data checksum mismatch",
+            Error::TooLarge => "Decompressed size too large",
+            Error::OutOfMemory => "Out of memory for decompressed data",
+        })
+    }
+}
+
+impl From<Error> for SyscallError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::TooLarge => syscalls::SYSCALL_ERROR_PARAM,
+            Error::OutOfMemory => syscalls::SYSCALL_ERROR_MEMORY,
+            _ => syscalls::SYSCALL_ERROR_INVALID_DATA
+        }
+    }
+}
+
+/// Reads bits starting from the least significant bit of each byte
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize, // Next byte to read
+    buffer: u32,
+    count: u32  // Number of bits in buffer
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Bits{data, pos: 0, buffer: 0, count: 0}
+    }
+
+    /// Read `n` bits, at most 16
+    fn bits(&mut self, n: u32) -> Result<u32, Error> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(Error::Truncated)?;
+            self.pos += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Discard bits up to the next byte boundary
+    fn align(&mut self) {
+        // Bytes are only read when needed, so fewer than 8 bits remain
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code
+struct Huffman {
+    /// Number of symbols of each code length
+    counts: [u16; 16],
+    /// Symbols ordered by code
+    symbols: [u16; 288]
+}
+
+impl Huffman {
+    /// Build from the code length of each symbol
+    fn new(lengths: &[u8]) -> Result<Self, Error> {
+        let mut huffman = Huffman{counts: [0; 16], symbols: [0; 288]};
+        for &length in lengths {
+            huffman.counts[length as usize] += 1;
+        }
+
+        // Reject codes which use more codes than exist
+        let mut left: i32 = 1;
+        for length in 1..16 {
+            left = (left << 1) - huffman.counts[length] as i32;
+            if left < 0 {
+                return Err(Error::Corrupt);
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + huffman.counts[length];
+        }
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                huffman.symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(huffman)
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, Error> {
+        let mut code: i32 = 0;  // Bits read so far
+        let mut first: i32 = 0; // First code of this length
+        let mut index: i32 = 0; // Index of first code in symbols
+        for length in 1..16 {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::Corrupt)
+    }
+}
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Order in which code length code lengths are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// State of a decompression into a buffer of fixed capacity
+struct Inflate<'a> {
+    bits: Bits<'a>,
+    output: Vec<u8>,
+    limit: usize
+}
+
+impl<'a> Inflate<'a> {
+    /// Check that `length` more bytes fit in the output
+    fn reserve(&self, length: usize) -> Result<(), Error> {
+        if self.output.len() + length > self.limit {
+            // More data than the trailer claims
+            return Err(Error::Checksum);
+        }
+        Ok(())
+    }
+
+    fn stored(&mut self) -> Result<(), Error> {
+        self.bits.align();
+        let length = self.bits.bits(16)?;
+        let complement = self.bits.bits(16)?;
+        if length != !complement & 0xFFFF {
+            return Err(Error::Corrupt);
+        }
+        let length = length as usize;
+        let start = self.bits.pos;
+        let data = self.bits.data.get(start..(start + length))
+            .ok_or(Error::Truncated)?;
+        self.reserve(length)?;
+        self.output.extend_from_slice(data);
+        self.bits.pos += length;
+        Ok(())
+    }
+
+    fn codes(&mut self, lencode: &Huffman, distcode: &Huffman) -> Result<(), Error> {
+        loop {
+            let symbol = lencode.decode(&mut self.bits)? as usize;
+            if symbol < 256 {
+                // Literal
+                self.reserve(1)?;
+                self.output.push(symbol as u8);
+            } else if symbol == 256 {
+                // End of block
+                return Ok(());
+            } else {
+                let symbol = symbol - 257;
+                if symbol >= LENGTH_BASE.len() {
+                    return Err(Error::Corrupt);
+                }
+                let length = LENGTH_BASE[symbol] as usize +
+                    self.bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+                let symbol = distcode.decode(&mut self.bits)? as usize;
+                if symbol >= DIST_BASE.len() {
+                    return Err(Error::Corrupt);
+                }
+                let distance = DIST_BASE[symbol] as usize +
+                    self.bits.bits(DIST_EXTRA[symbol] as u32)? as usize;
+                if distance > self.output.len() {
+                    // Refers to data before the start
+                    return Err(Error::Corrupt);
+                }
+
+                self.reserve(length)?;
+                // Copy byte by byte, because the source can overlap
+                for _ in 0..length {
+                    let byte = self.output[self.output.len() - distance];
+                    self.output.push(byte);
+                }
+            }
+        }
+    }
+
+    fn fixed(&mut self) -> Result<(), Error> {
+        let mut lengths = [0u8; 288];
+        lengths[..144].fill(8);
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        lengths[280..].fill(8);
+        let lencode = Huffman::new(&lengths)?;
+        let distcode = Huffman::new(&[5; 30])?;
+        self.codes(&lencode, &distcode)
+    }
+
+    fn dynamic(&mut self) -> Result<(), Error> {
+        let nlen = self.bits.bits(5)? as usize + 257;
+        let ndist = self.bits.bits(5)? as usize + 1;
+        let ncode = self.bits.bits(4)? as usize + 4;
+        if nlen > 286 || ndist > 30 {
+            return Err(Error::Corrupt);
+        }
+
+        let mut lengths = [0u8; 286 + 30];
+        for &symbol in &CODE_LENGTH_ORDER[..ncode] {
+            lengths[symbol] = self.bits.bits(3)? as u8;
+        }
+        let lencode = Huffman::new(&lengths[..19])?;
+
+        // Literal/length and distance code lengths
+        let mut index = 0;
+        while index < nlen + ndist {
+            let symbol = lencode.decode(&mut self.bits)?;
+            if symbol < 16 {
+                lengths[index] = symbol as u8;
+                index += 1;
+                continue;
+            }
+            let (length, repeat) = match symbol {
+                16 => {
+                    // Repeat previous length
+                    if index == 0 {
+                        return Err(Error::Corrupt);
+                    }
+                    (lengths[index - 1], 3 + self.bits.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.bits.bits(3)? as usize),
+                _ => (0, 11 + self.bits.bits(7)? as usize)
+            };
+            if index + repeat > nlen + ndist {
+                return Err(Error::Corrupt);
+            }
+            lengths[index..(index + repeat)].fill(length);
+            index += repeat;
+        }
+
+        if lengths[256] == 0 {
+            // No end of block code
+            return Err(Error::Corrupt);
+        }
+        let lencode = Huffman::new(&lengths[..nlen])?;
+        let distcode = Huffman::new(&lengths[nlen..(nlen + ndist)])?;
+        self.codes(&lencode, &distcode)
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
+        loop {
+            let last = self.bits.bits(1)?;
+            match self.bits.bits(2)? {
+                0 => self.stored()?,
+                1 => self.fixed()?,
+                2 => self.dynamic()?,
+                _ => return Err(Error::Corrupt)
+            }
+            if last == 1 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+/// Find the start of the deflate data after a gzip header
+fn skip_header(data: &[u8]) -> Result<usize, Error> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+
+    if data.len() < 18 {
+        return Err(Error::Header);
+    }
+    if data[0] != 0x1f || data[1] != 0x8b || data[2] != 8 {
+        // Bad magic number, or not deflate
+        return Err(Error::Header);
+    }
+    let flags = data[3];
+    if flags & 0xE0 != 0 {
+        // Reserved flags
+        return Err(Error::Header);
+    }
+
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let extra = data.get(pos..(pos + 2)).ok_or(Error::Truncated)?;
+        pos += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // Zero-terminated string
+            let length = data.get(pos..).ok_or(Error::Truncated)?
+                .iter().position(|&b| b == 0).ok_or(Error::Truncated)?;
+            pos += length + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    if pos > data.len() - 8 {
+        return Err(Error::Truncated);
+    }
+    Ok(pos)
+}
+
+/// Decompress a gzip stream
+///
+/// Fails with Error::TooLarge, without allocating, if the size in
+/// the gzip trailer is more than `limit` bytes or more than deflate
+/// could produce from the input. The output buffer is allocated
+/// once, and the data must match that size and the CRC.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+    let start = skip_header(data)?;
+    let (deflated, trailer) = data[start..].split_at(data.len() - start - 8);
+    let crc = read_u32(&trailer[0..4]);
+    let size = read_u32(&trailer[4..8]) as usize;
+
+    if size > limit || size > deflated.len().saturating_mul(MAX_RATIO) {
+        return Err(Error::TooLarge);
+    }
+
+    let mut output = Vec::new();
+    if output.try_reserve_exact(size).is_err() {
+        return Err(Error::OutOfMemory);
+    }
+
+    let mut inflate = Inflate{bits: Bits::new(deflated), output, limit: size};
+    inflate.run()?;
+    let output = inflate.output;
+
+    if output.len() != size || crc32(&output) != crc {
+        return Err(Error::Checksum);
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"EuraliOS is a hobby operating system written in Rust. Programs are loaded from ELF binaries, which init embeds with include_bytes. Storing them compressed makes the kernel image smaller.\n";
+
+    /// TEXT from gzip -9, using a dynamic Huffman block
+    const DYNAMIC: [u8; 159] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x15, 0x8d,
+        0x5d, 0x0a, 0x83, 0x40, 0x0c, 0x06, 0xdf, 0x7b, 0x8a, 0xef, 0x00, 0xc5,
+        0x5b, 0xd8, 0xa7, 0x42, 0x4b, 0x3d, 0x40, 0xc9, 0xba, 0xa9, 0x1b, 0xdc,
+        0x1f, 0x49, 0x22, 0xe2, 0xed, 0xbb, 0xbe, 0x0e, 0xcc, 0xcc, 0xb8, 0x2b,
+        0x65, 0x79, 0x4d, 0x10, 0x03, 0x21, 0xb5, 0x10, 0x4e, 0xb4, 0x8d, 0x95,
+        0x5c, 0xea, 0x02, 0x3b, 0xcd, 0xb9, 0xe0, 0x50, 0x71, 0xe7, 0x0a, 0xa9,
+        0xf8, 0xec, 0xe6, 0x03, 0xde, 0xda, 0x16, 0xa5, 0xd2, 0x15, 0x65, 0xe4,
+        0x46, 0x91, 0x23, 0x7e, 0xda, 0x0a, 0xc6, 0xe7, 0x03, 0x41, 0x2a, 0xa9,
+        0xb0, 0xdd, 0x71, 0x24, 0x99, 0x53, 0xb7, 0xc4, 0xc1, 0x25, 0x70, 0x34,
+        0x1c, 0xe2, 0x17, 0x98, 0xf3, 0x1e, 0xf9, 0x1b, 0x4e, 0x67, 0x1b, 0x30,
+        0x79, 0xd3, 0x6b, 0xe6, 0xa9, 0xaf, 0xe6, 0x56, 0x36, 0x65, 0xb3, 0x1e,
+        0x2c, 0xb4, 0xb2, 0x5d, 0x14, 0x2b, 0x6b, 0xe5, 0x0c, 0x29, 0xb4, 0x30,
+        0xac, 0x50, 0xce, 0xac, 0xc3, 0xed, 0x0f, 0x44, 0xaf, 0xc6, 0xcc, 0xbb,
+        0x00, 0x00, 0x00];
+
+    /// "Hello, EuraliOS! " * 3 + "\n", using a fixed Huffman block
+    const FIXED: [u8; 41] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xf3, 0x48,
+        0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0x70, 0x2d, 0x2d, 0x4a, 0xcc, 0xc9, 0xf4,
+        0x0f, 0x56, 0x54, 0xf0, 0x20, 0x24, 0xc0, 0x05, 0x00, 0x19, 0x9a, 0xe5,
+        0x6d, 0x33, 0x00, 0x00, 0x00];
+
+    /// "stored" from gzip -0
+    const STORED: [u8; 29] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x06,
+        0x00, 0xf9, 0xff, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x64, 0x0b, 0xf9, 0x43,
+        0x56, 0x06, 0x00, 0x00, 0x00];
+
+    #[test_case]
+    fn decompress_blocks() {
+        assert_eq!(decompress(&DYNAMIC, 4096).unwrap(), TEXT);
+        assert_eq!(decompress(&FIXED, 4096).unwrap(),
+                   b"Hello, EuraliOS! Hello, EuraliOS! Hello, EuraliOS!\n");
+        assert_eq!(decompress(&STORED, 4096).unwrap(), b"stored");
+    }
+
+    #[test_case]
+    fn decompress_corrupt() {
+        let mut data = DYNAMIC;
+        data[40] ^= 0x10;
+        assert!(decompress(&data, 4096).is_err());
+
+        // Deflate data cut short, but keeping the trailer
+        let mut data = Vec::from(&DYNAMIC[..100]);
+        data.extend_from_slice(&DYNAMIC[(DYNAMIC.len() - 8)..]);
+        assert_eq!(decompress(&data, 4096), Err(Error::Truncated));
+        assert_eq!(decompress(&TEXT, 4096), Err(Error::Header));
+    }
+
+    #[test_case]
+    fn decompress_size_limit() {
+        assert_eq!(decompress(&DYNAMIC, TEXT.len() - 1), Err(Error::TooLarge));
+
+        // Claim a size deflate can't reach
+        let mut data = STORED;
+        data[28] = 0x10;
+        assert_eq!(decompress(&data, usize::MAX), Err(Error::TooLarge));
+    }
+}
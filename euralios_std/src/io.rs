@@ -3,15 +3,166 @@
 use core::fmt;
 
 extern crate alloc;
-use alloc::string::String;
+use alloc::{string::String, vec::Vec};
 
 use crate::{syscalls::{self, CommHandle, SyscallError, STDIN, STDOUT},
-            message::{self, rcall}};
+            message::{self, rcall, MessageData}};
 
-struct Writer<'a> {
+mod buffered;
+pub use buffered::{BufReader, BufWriter};
+
+/// Enumeration of possible methods to seek within a File
+///
+/// Same as `std::io::SeekFrom`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    /// Number of bytes from the start of the file
+    Start(u64),
+    /// Number of bytes from the end of the file
+    End(i64),
+    /// Number of bytes from the current position
+    Current(i64),
+}
+
+/// A source of bytes, such as a File
+///
+/// Same as `std::io::Read`, but with SyscallError as the error type
+pub trait Read {
+    /// Read into a buffer, returning the number of bytes read.
+    /// Returns 0 at the end of the input.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, SyscallError>;
+
+    /// Read until the end of the input, appending to `buf`
+    ///
+    /// Returns the number of bytes appended
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, SyscallError> {
+        let start_len = buf.len();
+        let mut chunk = [0; 512];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(buf.len() - start_len),
+                n => buf.extend_from_slice(&chunk[..n])
+            }
+        }
+    }
+
+    /// Fill the whole buffer, calling `read` until it is full.
+    ///
+    /// Fails with SYSCALL_ERROR_NO_DATA if the input ends first.
+    fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<(), SyscallError> {
+        while !buf.is_empty() {
+            match self.read(buf)? {
+                0 => return Err(syscalls::SYSCALL_ERROR_NO_DATA),
+                n => {
+                    let rest = buf;
+                    buf = &mut rest[n..];
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reading a slice returns its contents, then shortens it
+impl Read for &[u8] {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, SyscallError> {
+        let length = self.len().min(buf.len());
+        let (data, rest) = self.split_at(length);
+        buf[..length].copy_from_slice(data);
+        *self = rest;
+        Ok(length)
+    }
+}
+
+/// A sink of bytes, such as a File
+///
+/// Same as `std::io::Write`, but with SyscallError as the error type
+pub trait Write {
+    /// Write part of a buffer, returning the number of bytes written
+    fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError>;
+
+    /// Send any data buffered by this writer
+    fn flush(&mut self) -> Result<(), SyscallError>;
+
+    /// Write the whole buffer, calling `write` until all bytes are
+    /// written or it returns an error.
+    ///
+    /// Fails with SYSCALL_ERROR_NO_SPACE if `write` returns 0
+    /// before the buffer is written.
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<(), SyscallError> {
+        while !buf.is_empty() {
+            match self.write(buf)? {
+                0 => return Err(syscalls::SYSCALL_ERROR_NO_SPACE),
+                n => buf = &buf[n..]
+            }
+        }
+        Ok(())
+    }
+
+    /// Write formatted text with `write_all`. Used by the `write!`
+    /// and `writeln!` macros.
+    fn write_fmt(&mut self, args: fmt::Arguments) -> Result<(), SyscallError> {
+        // Keeps the first error, because fmt::Error has no details
+        struct Adapter<'a, T: ?Sized> {
+            inner: &'a mut T,
+            error: Result<(), SyscallError>
+        }
+
+        impl<T: Write + ?Sized> fmt::Write for Adapter<'_, T> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.inner.write_all(s.as_bytes()).map_err(|err| {
+                    self.error = Err(err);
+                    fmt::Error
+                })
+            }
+        }
+
+        let mut adapter = Adapter{inner: self, error: Ok(())};
+        match fmt::write(&mut adapter, args) {
+            Ok(()) => Ok(()),
+            // A formatting trait failed rather than the writer
+            Err(_) => adapter.error.and(Err(syscalls::SYSCALL_ERROR_PARAM))
+        }
+    }
+}
+
+/// Writes to a communication handle with WRITE messages, as used by
+/// `fprint!`. Each write is one message, so wrap in a BufWriter to
+/// combine small writes.
+pub struct Writer<'a> {
     handle: &'a CommHandle
 }
 
+impl<'a> Writer<'a> {
+    pub fn new(handle: &'a CommHandle) -> Self {
+        Writer{handle}
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let buf = &buf[..buf.len().min(syscalls::MAX_CHUNK_SIZE)];
+        match rcall(self.handle,
+                    message::WRITE,
+                    (buf.len() as u64).into(),
+                    syscalls::MemoryHandle::from_u8_slice(buf).into(),
+                    None) {
+            Ok((message::OK, _, _)) => Ok(buf.len()),
+            Ok((message::ERROR, MessageData::Value(code), _)) => Err(SyscallError::new(code)),
+            Ok(_) => Err(syscalls::SYSCALL_ERROR_UNEXPECTED),
+            Err((err, _)) => Err(err)
+        }
+    }
+
+    /// Does nothing: each write is sent immediately
+    fn flush(&mut self) -> Result<(), SyscallError> {
+        Ok(())
+    }
+}
+
 impl fmt::Write for Writer<'_> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         if s.len() == 0 {
@@ -28,7 +179,13 @@ impl fmt::Write for Writer<'_> {
 
 pub fn _print(handle: &CommHandle, args: fmt::Arguments) {
     use core::fmt::Write;
-    Writer{handle}.write_fmt(args).unwrap();
+    if let Some(s) = args.as_str() {
+        Writer{handle}.write_str(s).unwrap();
+    } else {
+        // Format first so that the output is sent in one message,
+        // and isn't interleaved with output from other programs
+        Writer{handle}.write_str(&alloc::fmt::format(args)).unwrap();
+    }
 }
 
 #[macro_export]
@@ -58,6 +215,26 @@ macro_rules! fprintln {
 ////////////////////////////////////////////
 //
 
+pub struct Stdout {}
+
+/// Constructs a new handle to the standard output of the current process.
+///
+/// Output isn't buffered, as in `print!`. Wrap in a BufWriter to
+/// send many small writes in a few messages.
+pub fn stdout() -> Stdout {
+    Stdout{}
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError> {
+        Writer::new(&STDOUT).write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), SyscallError> {
+        Ok(())
+    }
+}
+
 pub struct Stdin {}
 
 /// Constructs a new handle to the standard input of the current process.
@@ -126,3 +303,72 @@ impl Stdin {
     }
 }
 
+/// Reads the characters sent to stdin, as UTF-8 bytes
+///
+/// Unlike `read_line`, characters are not echoed and backspace is
+/// not handled. Waits for at least one character, and returns 0
+/// once the handle is closed. Fails with SYSCALL_ERROR_PARAM if a
+/// character doesn't fit in `buf`, so use a buffer of at least 4
+/// bytes, e.g. with BufReader.
+impl Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, SyscallError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match syscalls::receive(&STDIN) {
+                Ok(syscalls::Message::Short(message::CHAR, ch, _)) => {
+                    let utf_ch = match char::from_u32(ch as u32) {
+                        Some(utf_ch) => utf_ch,
+                        None => continue
+                    };
+                    if utf_ch.len_utf8() > buf.len() {
+                        return Err(syscalls::SYSCALL_ERROR_PARAM);
+                    }
+                    return Ok(utf_ch.encode_utf8(buf).len());
+                }
+                Err(syscalls::SYSCALL_ERROR_CLOSED) => return Ok(0),
+                Err(err) => return Err(err),
+                Ok(_) => {
+                    // Ignore
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Writes at most `limit` bytes each call
+    struct ShortWriter {
+        data: Vec<u8>,
+        limit: usize
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError> {
+            let n = buf.len().min(self.limit);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> Result<(), SyscallError> {
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn write_all_short_writes() {
+        let mut writer = ShortWriter{data: Vec::new(), limit: 3};
+        assert_eq!(writer.write_all(b"hello world"), Ok(()));
+        assert_eq!(writer.data, b"hello world");
+
+        assert_eq!(write!(writer, " {}", 42), Ok(()));
+        assert_eq!(writer.data, b"hello world 42");
+
+        let mut full = ShortWriter{data: Vec::new(), limit: 0};
+        assert_eq!(full.write_all(b"x"), Err(syscalls::SYSCALL_ERROR_NO_SPACE));
+        assert_eq!(write!(full, "{}", 1), Err(syscalls::SYSCALL_ERROR_NO_SPACE));
+    }
+}
@@ -0,0 +1,289 @@
+//! Buffered readers and writers
+//!
+//! Each read or write of a File is a message round trip to the
+//! server. Buffering combines many small reads or writes into a few
+//! large ones.
+
+extern crate alloc;
+use alloc::{string::String, vec, vec::Vec};
+use core::str;
+
+use crate::syscalls::{self, SyscallError};
+use super::{Read, Write};
+
+/// Buffer size used by `new`
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// Collects small writes, and sends them to the inner writer
+/// when the buffer is full, on `flush`, or when dropped.
+///
+/// Errors from the flush when dropped are ignored. Call `flush`
+/// before dropping to handle them.
+///
+/// Same as `std::io::BufWriter`
+pub struct BufWriter<W: Write> {
+    /// Only None after `into_inner`
+    inner: Option<W>,
+    buf: Vec<u8>,
+    capacity: usize
+}
+
+impl<W: Write> BufWriter<W> {
+    /// Wrap a writer with a buffer of DEFAULT_BUF_SIZE bytes
+    pub fn new(inner: W) -> BufWriter<W> {
+        BufWriter::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Wrap a writer, sending data when `capacity` bytes are buffered
+    pub fn with_capacity(capacity: usize, inner: W) -> BufWriter<W> {
+        BufWriter{inner: Some(inner),
+                  buf: Vec::with_capacity(capacity),
+                  capacity}
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Writing directly to the inner writer may reorder the output
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Data which hasn't been written yet
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Flush the buffer and return the inner writer
+    pub fn into_inner(mut self) -> Result<W, SyscallError> {
+        self.flush_buf()?;
+        Ok(self.inner.take().unwrap())
+    }
+
+    /// Write the buffer to the inner writer, removing the bytes
+    /// which were written even if there is an error
+    fn flush_buf(&mut self) -> Result<(), SyscallError> {
+        let inner = self.inner.as_mut().unwrap();
+        let mut written = 0;
+        let result = loop {
+            if written == self.buf.len() {
+                break Ok(());
+            }
+            match inner.write(&self.buf[written..]) {
+                Ok(0) => break Err(syscalls::SYSCALL_ERROR_NO_SPACE),
+                Ok(n) => written += n,
+                Err(err) => break Err(err)
+            }
+        };
+        self.buf.drain(..written);
+        result
+    }
+}
+
+impl<W: Write> Write for BufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError> {
+        if self.buf.len() + buf.len() > self.capacity {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.capacity {
+            // Too large to buffer
+            self.get_mut().write(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), SyscallError> {
+        self.flush_buf()?;
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for BufWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() && !self.buf.is_empty() {
+            let _ = self.flush_buf();
+        }
+    }
+}
+
+/// Reads large chunks from the inner reader, and serves small
+/// reads from the buffer.
+///
+/// Same as `std::io::BufReader`, with the `std::io::BufRead`
+/// methods implemented directly.
+pub struct BufReader<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    /// Next byte to return
+    pos: usize,
+    /// End of the data read into `buf`
+    filled: usize
+}
+
+impl<R: Read> BufReader<R> {
+    /// Wrap a reader with a buffer of DEFAULT_BUF_SIZE bytes
+    pub fn new(inner: R) -> BufReader<R> {
+        BufReader::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Wrap a reader, reading `capacity` bytes at a time
+    pub fn with_capacity(capacity: usize, inner: R) -> BufReader<R> {
+        BufReader{inner,
+                  buf: vec![0; capacity],
+                  pos: 0,
+                  filled: 0}
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Reading directly from the inner reader skips any data
+    /// in the buffer
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Return the inner reader. Buffered data is lost
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Data which has been read but not returned
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The buffered data, reading more if it is empty.
+    /// Empty at the end of the input.
+    ///
+    /// Call `consume` to mark data as used.
+    pub fn fill_buf(&mut self) -> Result<&[u8], SyscallError> {
+        if self.pos == self.filled {
+            self.filled = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(self.buffer())
+    }
+
+    /// Mark `amount` bytes returned by `fill_buf` as used
+    pub fn consume(&mut self, amount: usize) {
+        self.pos = (self.pos + amount).min(self.filled);
+    }
+
+    /// Read until a newline (0xA byte) or the end of the input,
+    /// appending to `buf`. The newline is included.
+    ///
+    /// Returns the number of bytes read, which is 0 at the end of
+    /// the input. Fails with SYSCALL_ERROR_UTF8 if the line is not
+    /// valid UTF-8, and then nothing is appended.
+    pub fn read_line(&mut self, buf: &mut String) -> Result<usize, SyscallError> {
+        let mut line = Vec::new();
+        loop {
+            let available = self.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            match available.iter().position(|&byte| byte == b'\n') {
+                Some(index) => {
+                    line.extend_from_slice(&available[..=index]);
+                    self.consume(index + 1);
+                    break;
+                }
+                None => {
+                    let length = available.len();
+                    line.extend_from_slice(available);
+                    self.consume(length);
+                }
+            }
+        }
+        buf.push_str(str::from_utf8(&line).map_err(|_| syscalls::SYSCALL_ERROR_UTF8)?);
+        Ok(line.len())
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, SyscallError> {
+        if self.pos == self.filled && buf.len() >= self.capacity() {
+            // Nothing buffered, and too large to buffer
+            return self.inner.read(buf);
+        }
+        let available = self.fill_buf()?;
+        let length = available.len().min(buf.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.consume(length);
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Records the size of each write
+    struct CountingWriter {
+        data: Vec<u8>,
+        writes: Vec<usize>
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, SyscallError> {
+            self.data.extend_from_slice(buf);
+            self.writes.push(buf.len());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> Result<(), SyscallError> {
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn buf_writer_combines_writes() {
+        let mut writer = BufWriter::with_capacity(
+            8, CountingWriter{data: Vec::new(), writes: Vec::new()});
+        for _ in 0..5 {
+            writer.write_all(b"ab").unwrap();
+        }
+        // Flushed once when full
+        assert_eq!(writer.get_ref().writes, [8]);
+        assert_eq!(writer.buffer(), b"ab");
+
+        // Too large to buffer: Flushes then writes directly
+        writer.write_all(b"0123456789").unwrap();
+        assert_eq!(writer.get_ref().writes, [8, 2, 10]);
+
+        writer.write_all(b"end").unwrap();
+        let inner = writer.into_inner().unwrap();
+        assert_eq!(inner.data, b"ababababab0123456789end");
+    }
+
+    #[test_case]
+    fn buf_reader_lines() {
+        let mut reader = BufReader::with_capacity(4, &b"first\nsecond\nend"[..]);
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line), Ok(6));
+        assert_eq!(line, "first\n");
+
+        // Only the buffered data is returned
+        let mut bytes = [0; 3];
+        assert_eq!(reader.read(&mut bytes), Ok(2));
+        assert_eq!(&bytes[..2], b"se");
+
+        line.clear();
+        assert_eq!(reader.read_line(&mut line), Ok(5));
+        assert_eq!(reader.read_line(&mut line), Ok(3));
+        assert_eq!(line, "cond\nend");
+        assert_eq!(reader.read_line(&mut line), Ok(0));
+    }
+}
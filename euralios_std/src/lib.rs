@@ -17,17 +17,23 @@ pub mod debug;
 pub mod env;
 pub mod ffi;
 pub mod fs;
+pub mod gzip;
 pub mod io;
 pub mod memory;
 pub mod message; // EuraliOS-only
 pub mod net;
 pub mod path;
 pub mod ports;
+pub mod retry;
 pub mod syscalls; // EuraliOS-only
 pub mod thread;
 pub mod time;
 pub mod sys;
 pub mod server; // EuraliOS-only
+pub mod signal; // EuraliOS-only
+pub mod sync;
+
+pub use retry::retry;
 
 use core::panic::PanicInfo;
 #[panic_handler]
@@ -47,12 +53,15 @@ pub unsafe extern "sysv64" fn _start() -> ! {
     // Information passed from the operating system
     let heap_start: usize;
     let heap_size: usize;
+    let args_address: usize;
     asm!("",
          lateout("rax") heap_start,
          lateout("rcx") heap_size,
+         lateout("rdx") args_address,
          options(pure, nomem, nostack)
     );
     memory::init(heap_start, heap_size);
+    syscalls::set_args_address(args_address);
 
     // Call the user program
     #[cfg(not(test))]
@@ -2,11 +2,24 @@
 extern crate alloc;
 use linked_list_allocator::LockedHeap;
 
+#[cfg(feature = "alloc_profile")]
+pub mod profile;
+
+#[cfg(not(feature = "alloc_profile"))]
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+#[cfg(feature = "alloc_profile")]
+#[global_allocator]
+static ALLOCATOR: profile::ProfilingHeap = profile::ProfilingHeap::empty();
+
 pub fn init(heap_start: usize, heap_size: usize) {
-    unsafe {ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);}
+    #[cfg(not(feature = "alloc_profile"))]
+    let heap: &LockedHeap = &ALLOCATOR;
+    #[cfg(feature = "alloc_profile")]
+    let heap: &LockedHeap = ALLOCATOR.heap();
+
+    unsafe {heap.lock().init(heap_start as *mut u8, heap_size);}
 }
 
 // Allocator error handler
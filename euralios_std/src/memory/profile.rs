@@ -0,0 +1,228 @@
+//! Heap allocation profiling
+//!
+//! Enabled with the `alloc_profile` feature of euralios_std. The
+//! global allocator then records the number of allocations and bytes
+//! allocated by each call site, identified by a return address.
+//!
+//! Call sites are found by following the frame pointer (RBP) chain,
+//! so programs should be built with frame pointers:
+//!
+//!     RUSTFLAGS="-C force-frame-pointers=yes"
+//!
+//! Without them the recorded addresses are unreliable.
+//! Addresses can be converted to source lines with
+//! `addr2line -e <binary> <address>`.
+//!
+//! # Example
+//!
+//! ```no_run
+//! euralios_std::memory::profile::print_top(10);
+//! ```
+
+extern crate alloc;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::arch::asm;
+use linked_list_allocator::LockedHeap;
+use spin::Mutex;
+
+use crate::println;
+
+/// Maximum number of call sites recorded
+pub const TABLE_SIZE: usize = 64;
+
+/// Number of frames above the allocator to go before recording
+/// the return address. Skips the allocator shim (__rust_alloc)
+/// and standard library containers (e.g. RawVec).
+const CALLER_DEPTH: usize = 3;
+
+/// User thread stacks are all in one 2Mb region (kernel
+/// memory.rs THREAD_STACK_PAGE_INDEX)
+const STACK_REGION_START: usize = 5 << 39;
+const STACK_REGION_END: usize = STACK_REGION_START + 0x20_0000;
+
+/// Allocation statistics for one call site
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Site {
+    /// Return address identifying the call site
+    pub address: usize,
+    /// Number of allocations
+    pub count: u64,
+    /// Total number of bytes allocated
+    pub bytes: u64
+}
+
+impl Site {
+    const EMPTY: Site = Site{address: 0, count: 0, bytes: 0};
+}
+
+/// A bounded table of call sites
+pub struct Table {
+    sites: [Site; TABLE_SIZE]
+}
+
+impl Table {
+    pub const fn new() -> Self {
+        Table{sites: [Site::EMPTY; TABLE_SIZE]}
+    }
+
+    /// Record an allocation of `size` bytes from `address`
+    ///
+    /// If the table is full then the least used site is replaced.
+    /// Does not allocate memory.
+    pub fn record(&mut self, address: usize, size: usize) {
+        let index = match self.sites.iter().position(|site| site.address == address) {
+            Some(index) => index,
+            None => {
+                // Empty site, or the one with fewest allocations
+                let index = self.sites.iter().enumerate()
+                    .min_by_key(|(_, site)| site.count)
+                    .map(|(index, _)| index).unwrap();
+                self.sites[index] = Site{address, ..Site::EMPTY};
+                index
+            }
+        };
+        let site = &mut self.sites[index];
+        site.count += 1;
+        site.bytes += size as u64;
+    }
+
+    /// Sites sorted by number of bytes, largest first
+    pub fn sorted(&self) -> [Site; TABLE_SIZE] {
+        let mut sites = self.sites;
+        sites.sort_unstable_by(|a, b| b.bytes.cmp(&a.bytes));
+        sites
+    }
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table::new());
+
+/// Find the return address `depth` frames above the caller,
+/// by following saved frame pointers.
+///
+/// Stops early (returning the last address found) if the
+/// chain leaves the stack or doesn't move up it, which detects
+/// code built without frame pointers.
+#[inline(always)]
+fn caller_address(depth: usize) -> usize {
+    let (mut rbp, rsp): (usize, usize);
+    unsafe {
+        asm!("mov {}, rbp", "mov {}, rsp", out(reg) rbp, out(reg) rsp,
+             options(nomem, nostack));
+    }
+    let mut address = 0;
+    for _ in 0..depth {
+        if !is_stack_frame(rbp, rsp) {
+            break;
+        }
+        let (next_rbp, return_address) = unsafe {
+            let frame = rbp as *const usize;
+            (*frame, *frame.add(1))
+        };
+        address = return_address;
+        if next_rbp <= rbp {
+            // Stack grows down, so callers have higher addresses
+            break;
+        }
+        rbp = next_rbp;
+    }
+    address
+}
+
+/// True if `rbp` could point to a saved frame pointer and return
+/// address on the stack whose top is `rsp`
+fn is_stack_frame(rbp: usize, rsp: usize) -> bool {
+    rbp % 8 == 0 && rbp >= rsp &&
+        rbp >= STACK_REGION_START &&
+        rbp <= STACK_REGION_END - 16
+}
+
+/// Global allocator which records allocations in TABLE
+pub struct ProfilingHeap {
+    heap: LockedHeap
+}
+
+impl ProfilingHeap {
+    pub const fn empty() -> Self {
+        ProfilingHeap{heap: LockedHeap::empty()}
+    }
+
+    /// The underlying heap
+    pub fn heap(&self) -> &LockedHeap {
+        &self.heap
+    }
+}
+
+unsafe impl GlobalAlloc for ProfilingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        TABLE.lock().record(caller_address(CALLER_DEPTH), layout.size());
+        self.heap.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
+
+/// Get a copy of the recorded sites, largest first
+pub fn sites() -> [Site; TABLE_SIZE] {
+    TABLE.lock().sorted()
+}
+
+/// Clear all recorded sites
+pub fn reset() {
+    *TABLE.lock() = Table::new();
+}
+
+/// Print the `n` call sites which allocated the most bytes
+pub fn print_top(n: usize) {
+    // Copy before printing, because printing allocates
+    let sites = sites();
+    println!("   address        count        bytes");
+    for site in sites.iter().take(n).filter(|site| site.count > 0) {
+        println!("{:#12x} {:>12} {:>12}", site.address, site.count, site.bytes);
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test_case]
+    fn table_aggregates() {
+        let mut table = Table::new();
+        table.record(0x1000, 8);
+        table.record(0x2000, 100);
+        table.record(0x1000, 16);
+        let sites = table.sorted();
+        assert_eq!(sites[0], Site{address: 0x2000, count: 1, bytes: 100});
+        assert_eq!(sites[1], Site{address: 0x1000, count: 2, bytes: 24});
+    }
+
+    #[test_case]
+    fn table_evicts_least_used() {
+        let mut table = Table::new();
+        for address in 1..=TABLE_SIZE {
+            table.record(address, 1);
+            table.record(address, 1);
+        }
+        table.record(1, 1);
+        // Full: replaces a site with two allocations, not site 1
+        table.record(0x9999, 4);
+        let sites = table.sorted();
+        assert!(sites.iter().any(|site| site.address == 0x9999));
+        assert!(sites.iter().any(|site| *site == Site{address: 1, count: 3, bytes: 3}));
+    }
+
+    #[test_case]
+    fn stack_frame_bounds() {
+        let rsp = STACK_REGION_START + 0x1000;
+        assert!(is_stack_frame(rsp + 0x100, rsp));
+        assert!(is_stack_frame(STACK_REGION_END - 16, rsp));
+        // Below the stack pointer, misaligned, or outside the stacks
+        assert!(!is_stack_frame(rsp - 8, rsp));
+        assert!(!is_stack_frame(rsp + 0x104, rsp));
+        assert!(!is_stack_frame(STACK_REGION_END - 8, rsp));
+        assert!(!is_stack_frame(0, 0));
+        assert!(!is_stack_frame(0x20_0000, 0));
+    }
+}
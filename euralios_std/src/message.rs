@@ -6,6 +6,7 @@
 use core::convert::From;
 
 use crate::syscalls::{self, CommHandle, MemoryHandle, SyscallError};
+use crate::time;
 
 #[derive(Debug)]
 pub enum MessageData {
@@ -162,6 +163,8 @@ impl Message {
 
 /// Remote call.
 /// Wrapper around send_receive syscall
+///
+/// Waits for a reply indefinitely. See rcall_timeout
 pub fn rcall(
     handle: &CommHandle,
     data1: u64,
@@ -169,6 +172,38 @@ pub fn rcall(
     data3: MessageData,
     expect_rdata1: Option<u64>
 ) -> Result<(u64, MessageData, MessageData), (SyscallError, Message)> {
+    rcall_until(handle, data1, data2, data3, expect_rdata1, None)
+}
+
+/// Remote call which waits at most `timeout_us` microseconds
+///
+/// The timeout covers retries while the handle is busy, the message
+/// being received, and the reply. If there is no reply in time then
+/// SYSCALL_ERROR_TIMEOUT is returned, and any later reply is
+/// discarded (see syscalls::send_receive_timeout). This stops a
+/// hung server from hanging its clients.
+pub fn rcall_timeout(
+    handle: &CommHandle,
+    data1: u64,
+    data2: MessageData,
+    data3: MessageData,
+    expect_rdata1: Option<u64>,
+    timeout_us: u64
+) -> Result<(u64, MessageData, MessageData), (SyscallError, Message)> {
+    let deadline = time::microseconds_monotonic().saturating_add(timeout_us);
+    rcall_until(handle, data1, data2, data3, expect_rdata1, Some(deadline))
+}
+
+/// Remote call, giving up at `deadline` in microseconds since boot
+/// if not None
+fn rcall_until(
+    handle: &CommHandle,
+    data1: u64,
+    data2: MessageData,
+    data3: MessageData,
+    expect_rdata1: Option<u64>,
+    deadline: Option<u64>
+) -> Result<(u64, MessageData, MessageData), (SyscallError, Message)> {
 
     let mut message = match (data2, data3) {
         (MessageData::Value(value2), MessageData::Value(value3)) => Message::Short(data1, value2, value3),
@@ -180,9 +215,16 @@ pub fn rcall(
     let mut retry = 0;
     loop {
         // Try sending
-        let result = syscalls::send_receive(
-            handle,
-            message);
+        let result = match deadline {
+            None => syscalls::send_receive(handle, message),
+            Some(deadline) => {
+                let remaining = deadline.saturating_sub(time::microseconds_monotonic());
+                if remaining == 0 {
+                    return Err((syscalls::SYSCALL_ERROR_TIMEOUT, message));
+                }
+                syscalls::send_receive_timeout(handle, message, remaining)
+            }
+        };
 
         match result {
             Err((syscalls::SYSCALL_ERROR_SEND_BLOCKING, ret_message)) |
@@ -231,8 +273,17 @@ pub fn rcall(
 }
 
 /// General message types
-pub const READ: u64 = 1;  // Short(READ, offset, length
-pub const WRITE: u64 = 2; // Long(WRITE, length, handle)
+///
+/// Offsets and lengths are always full 64-bit values, so files
+/// larger than 4 GiB can be addressed. Servers reject offsets which
+/// don't fit in a usize rather than truncating them, and clamp
+/// lengths to the end of the file.
+///
+/// READ replies with Long(DATA, length, handle) containing at most
+/// the requested length, or ERROR NO_DATA if the offset is at or
+/// after the end of the file.
+pub const READ: u64 = 1;  // Short(READ, offset, length)
+pub const WRITE: u64 = 2; // Long(WRITE, length, handle) -> Short(OK, written, position)
 pub const DATA: u64 = 2;  // Same as write
 pub const CHAR: u64 = 3;
 pub const JSON: u64 = 4;  // Information in JSON format
@@ -245,9 +296,50 @@ pub const QUERY: u64 = 7;
 /// Short acknowlegement that data was processed
 pub const OK: u64 = 8;
 
-/// Open message types, numbered as OPEN (16) + flags for write (1), create (2) and truncate (4)
+/// Sent by a parent to ask a service whether it has finished
+/// initializing, and the service's reply when it has.
+/// See server::signal_ready
+pub const READY: u64 = 9;
+
+/// Reserve space in a file: Short(FALLOCATE, length, 0)
+///
+/// Replies OK, or ERROR with SYSCALL_ERROR_NO_SPACE if the space
+/// isn't available. See File::allocate
+pub const FALLOCATE: u64 = 10;
+
+/// Diagnostic command to a driver: Short(DIAG, command, argument)
+///
+/// Commands are listed in the diag module. Unlike QUERY, which
+/// describes a file or directory, this reports the internal state of
+/// the running driver (buffer occupancy, error counts, configuration).
+/// The reply is Long(JSON, length, handle) or Short(ERROR, code, 0).
+/// See server::DirLike::diag
+pub const DIAG: u64 = 11;
+
+/// Write buffered data to storage: Short(SYNC, 0, 0)
+///
+/// Sent to a file to flush it, or to a directory to flush everything
+/// below it. Replies OK once flushed, or Short(ERROR, code, 0).
+/// See File::sync_all and syscalls::sync
+pub const SYNC: u64 = 12;
+
+/// Move the position in a file: Short(SEEK, offset, whence)
+///
+/// whence is SEEK_START, with offset a u64, or SEEK_END with offset
+/// an i64 relative to the file length. Positions relative to the
+/// current position are resolved by the client, since servers don't
+/// keep track of it. Replies Short(OK, position, 0) with the new
+/// absolute position, or Short(ERROR, SYSCALL_ERROR_PARAM, 0) if it
+/// would be before the start. Read-only files clamp positions to the
+/// end of the file. See File::seek
+pub const SEEK: u64 = 13;
+pub const SEEK_START: u64 = 0;
+pub const SEEK_END: u64 = 2;
+
+/// Open message types, numbered as OPEN (16) + flags for write (1), create (2),
+/// truncate (4), directory (8) and append (32)
 pub const OPEN: u64 = 16;
-pub const OPEN_FLAGS_MASK: u64 = 15;
+pub const OPEN_FLAGS_MASK: u64 = 15 + O_APPEND;
 pub const O_READ: u64  = 0;
 pub const OPEN_READONLY: u64 = OPEN + O_READ;
 pub const O_WRITE: u64  = 1;
@@ -256,13 +348,54 @@ pub const O_CREATE: u64 = 2;
 pub const OPEN_CREATE: u64 = OPEN_READWRITE + O_CREATE;
 pub const O_TRUNCATE: u64 = 4;
 pub const OPEN_OVERWRITE: u64 = OPEN_CREATE + O_TRUNCATE;
+/// Path must be a directory, otherwise open fails with
+/// SYSCALL_ERROR_NOT_DIR. Without this flag, opening a directory
+/// fails with SYSCALL_ERROR_IS_DIR.
+pub const O_DIRECTORY: u64 = 8;
+/// Each write is at the end of the file. Bit 4 is OPEN itself,
+/// so this is the next bit up.
+pub const O_APPEND: u64 = 32;
 
 pub const CLOSE: u64 = 32;
 
+/// Delete a file: Long(DELETE, length, handle) with the file name.
+/// Replies OK, or Short(ERROR, code, 0). Handles already open to the
+/// file keep working until closed.
 pub const DELETE: u64 = 33;
 
 pub const MKDIR: u64 = 64;
 
+/// Delete an empty directory: Long(RMDIR, length, handle) with the
+/// directory name. Replies OK, or Short(ERROR, code, 0) with
+/// SYSCALL_ERROR_NOT_EMPTY if the directory has contents.
+pub const RMDIR: u64 = 66;
+
+/// Apply a batch of directory operations in one message
+///
+/// Long(BATCH, length, handle) where the memory handle contains a
+/// UTF-8 JSON array of operations, with paths relative to the
+/// directory the message is sent to:
+///
+///     [{"op": "create", "path": "a/file.txt"},
+///      {"op": "mkdir", "path": "b"},
+///      {"op": "remove", "path": "old"},
+///      {"op": "rename", "path": "a/file.txt", "to": "b/file.txt"}]
+///
+/// The reply is Long(JSON, length, handle) containing an array with
+/// one status for each operation, in order: 0 for success or a
+/// syscall error code.
+///
+/// Batches are best-effort, not atomic: operations are applied in
+/// order, and an error in one doesn't stop the others.
+pub const BATCH: u64 = 65;
+
+/// Move a file: Long(RENAME, length, handle) where the memory handle
+/// contains UTF-8 JSON {"path": from, "to": to}, with both paths
+/// relative to the directory the message is sent to. An existing file
+/// at `to` is replaced. Replies OK, or Short(ERROR, code, 0) with
+/// SYSCALL_ERROR_PARAM if `to` is a directory.
+pub const RENAME: u64 = 67;
+
 pub const EMPTY: u64 = 128;
 pub const ERROR: u64 = 129;
 pub const ERROR_INVALID_FORMAT: u64 = 130;
@@ -273,10 +406,31 @@ pub const ERROR_DENIED: u64 = 134;
 
 /// Message types for the system PCI program
 pub mod pci {
+    extern crate alloc;
+    use alloc::vec::Vec;
+    use core::str;
+    use serde_json::Value;
+
+    use super::{rcall, MessageData, JSON};
+    use crate::syscalls::{self, CommHandle, MemoryHandle, SyscallError};
+
     // Calls
     pub const FIND_DEVICE: u64 = 256;
     pub const READ_BAR: u64 = 257;
     pub const ENABLE_BUS_MASTERING: u64 = 258;
+    /// Short(QUERY_DEVICES, class, 0) -> Long(JSON, length, handle)
+    ///
+    /// The JSON is an array of devices with the class code, or
+    /// every device if the class is ANY_CLASS. See `query_devices`.
+    pub const QUERY_DEVICES: u64 = 259;
+    /// Short(REQUEST_BAR, address, bar_id) -> Short(IO_BAR, value, 0)
+    /// or Long(MEMORY_BAR, value, handle)
+    ///
+    /// Enables bus mastering and decoding of the BAR's address space,
+    /// then replies with BarInfo::to_value. For memory BARs the handle
+    /// maps the pages containing the BAR. Replies NOTFOUND if the BAR
+    /// isn't implemented. See `request_bar`.
+    pub const REQUEST_BAR: u64 = 261;
 
     // Replies
 
@@ -284,6 +438,272 @@ pub mod pci {
     pub const ADDRESS: u64 = 384;
     pub const NOTFOUND: u64 = 385;
     pub const BAR: u64 = 386;
+    pub const IO_BAR: u64 = 387;
+    pub const MEMORY_BAR: u64 = 388;
+
+    /// QUERY_DEVICES class matching every device
+    pub const ANY_CLASS: u64 = 0xFFFF_FFFF_FFFF_FFFF;
+
+    /// Class code of network controllers
+    pub const CLASS_NETWORK: u8 = 0x02;
+
+    /// Interrupt line of devices which don't use an interrupt
+    pub const NO_INTERRUPT_LINE: u8 = 0xFF;
+
+    /// A device found by the PCI bus scan
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct DeviceInfo {
+        /// The bus/slot/function address, as in ADDRESS messages
+        pub address: u32,
+        pub bus: u8,
+        pub device: u8,
+        pub function: u8,
+        pub vendor_id: u16,
+        pub device_id: u16,
+        pub class: u8,
+        pub subclass: u8,
+        pub prog_if: u8,
+        /// Base Address Registers, as read. Zero if not implemented
+        pub bars: Vec<u32>
+    }
+
+    /// Parse a number, or a hexadecimal string starting with "0x"
+    fn number(value: &Value) -> Option<u64> {
+        match value.as_str() {
+            Some(s) => u64::from_str_radix(s.strip_prefix("0x")?, 16).ok(),
+            None => value.as_u64()
+        }
+    }
+
+    impl DeviceInfo {
+        /// Read a device from the JSON sent by the PCI driver
+        pub fn from_json(value: &Value) -> Option<DeviceInfo> {
+            Some(DeviceInfo {
+                address: number(&value["address"])? as u32,
+                bus: number(&value["bus"])? as u8,
+                device: number(&value["device"])? as u8,
+                function: number(&value["function"])? as u8,
+                vendor_id: number(&value["vendor_id"])? as u16,
+                device_id: number(&value["device_id"])? as u16,
+                class: number(&value["class"])? as u8,
+                subclass: number(&value["subclass"])? as u8,
+                prog_if: number(&value["prog_if"])? as u8,
+                bars: value["bars"].as_array()?
+                    .iter()
+                    .map(|bar| number(bar).map(|bar| bar as u32))
+                    .collect::<Option<Vec<u32>>>()?
+            })
+        }
+    }
+
+    /// The address and size of a BAR, and the device's interrupt line
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct BarInfo {
+        /// I/O port or physical address. At most 48 bits
+        pub base: u64,
+        /// Bytes or ports. Always a power of two
+        pub size: u64,
+        pub interrupt_line: u8
+    }
+
+    impl BarInfo {
+        /// | interrupt_line (8) | log2(size) (8) | base (48) |
+        pub fn to_value(&self) -> u64 {
+            (self.base & 0xFFFF_FFFF_FFFF) |
+            ((self.size.trailing_zeros() as u64) << 48) |
+            ((self.interrupt_line as u64) << 56)
+        }
+
+        pub fn from_value(value: u64) -> BarInfo {
+            BarInfo {
+                base: value & 0xFFFF_FFFF_FFFF,
+                size: 1 << ((value >> 48) & 0x3F),
+                interrupt_line: (value >> 56) as u8
+            }
+        }
+    }
+
+    /// A BAR returned by `request_bar`
+    #[derive(Debug)]
+    pub enum Bar {
+        Io {port: u16, size: u32},
+        /// `memory` points to the physical `address`
+        Memory {address: u64, size: u64, memory: MemoryHandle}
+    }
+
+    /// Enable a device and get one of its Base Address Registers
+    ///
+    /// `address` is the device's bus/slot/function address. Returns
+    /// the BAR and the interrupt line, which is NO_INTERRUPT_LINE if
+    /// the device doesn't use one.
+    ///
+    /// ```ignore
+    /// if let (Bar::Io{port, ..}, irq) = pci::request_bar(&handle, address, 0)? {
+    ///     ...
+    /// }
+    /// ```
+    pub fn request_bar(handle: &CommHandle, address: u64, bar_id: u8) -> Result<(Bar, u8), SyscallError> {
+        match rcall(handle, REQUEST_BAR, address.into(), (bar_id as u64).into(), None) {
+            Ok((IO_BAR, MessageData::Value(value), _)) => {
+                let info = BarInfo::from_value(value);
+                Ok((Bar::Io{port: info.base as u16, size: info.size as u32},
+                    info.interrupt_line))
+            }
+            Ok((MEMORY_BAR,
+                MessageData::Value(value),
+                MessageData::MemoryHandle(memory))) => {
+                let info = BarInfo::from_value(value);
+                // Received chunks are mapped at a page boundary
+                let virtaddr = memory.as_u64() + (info.base & 0xFFF);
+                core::mem::forget(memory);
+                Ok((Bar::Memory{address: info.base, size: info.size,
+                                memory: MemoryHandle::new(virtaddr)},
+                    info.interrupt_line))
+            }
+            Ok((NOTFOUND, _, _)) => Err(syscalls::SYSCALL_ERROR_NOTFOUND),
+            Ok((super::ERROR, MessageData::Value(code), _)) => Err(SyscallError::new(code)),
+            Ok(_) => Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+            Err((err, _)) => Err(err)
+        }
+    }
+
+    /// List the devices with a class code, or every device if None
+    ///
+    /// `handle` is a handle to the PCI driver, usually opened at /pci
+    ///
+    /// ```ignore
+    /// let handle = syscalls::open("/pci", message::O_READ)?;
+    /// let nic = pci::query_devices(&handle, Some(pci::CLASS_NETWORK))?
+    ///     .into_iter()
+    ///     .find(|device| device.vendor_id == 0x10EC && device.device_id == 0x8139);
+    /// ```
+    pub fn query_devices(handle: &CommHandle, class: Option<u8>) -> Result<Vec<DeviceInfo>, SyscallError> {
+        let class = class.map(|class| class as u64).unwrap_or(ANY_CLASS);
+        match rcall(handle, QUERY_DEVICES, class.into(), 0.into(), Some(JSON)) {
+            Ok((_, MessageData::Value(length), MessageData::MemoryHandle(handle))) => {
+                let u8_slice = handle.as_slice::<u8>(length as usize);
+                let s = str::from_utf8(u8_slice)
+                    .map_err(|_| syscalls::SYSCALL_ERROR_UTF8)?;
+                let value = serde_json::from_str::<Value>(s)
+                    .map_err(|_| syscalls::SYSCALL_ERROR_INVALID_DATA)?;
+                value.as_array()
+                    .ok_or(syscalls::SYSCALL_ERROR_INVALID_DATA)?
+                    .iter()
+                    .map(DeviceInfo::from_json)
+                    .collect::<Option<Vec<DeviceInfo>>>()
+                    .ok_or(syscalls::SYSCALL_ERROR_INVALID_DATA)
+            }
+            Ok(_) => Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+            Err((err, _)) => Err(err)
+        }
+    }
+
+    #[test_case]
+    fn parse_device() {
+        let value = serde_json::from_str::<Value>(
+            "{\"name\": \"10EC_8139\", \"address\": \"0x80001800\",
+              \"bus\": 0, \"device\": 3, \"function\": 0,
+              \"vendor_id\": \"0x10EC\", \"device_id\": \"0x8139\",
+              \"class\": 2, \"subclass\": 0, \"prog_if\": 0,
+              \"bars\": [49153, 4273803264, 0, 0, 0, 0]}").unwrap();
+        let device = DeviceInfo::from_json(&value).unwrap();
+        assert_eq!(device.address, 0x8000_1800);
+        assert_eq!((device.bus, device.device, device.function), (0, 3, 0));
+        assert_eq!((device.vendor_id, device.device_id), (0x10EC, 0x8139));
+        assert_eq!(device.class, CLASS_NETWORK);
+        assert_eq!(device.bars[..2], [0xC001, 0xFEBD_1000]);
+
+        let value = serde_json::from_str::<Value>("{\"address\": \"0x80001800\"}").unwrap();
+        assert_eq!(DeviceInfo::from_json(&value), None);
+    }
+
+    #[test_case]
+    fn bar_info_round_trip() {
+        let info = BarInfo{base: 0xFEBD_1000, size: 256, interrupt_line: 11};
+        assert_eq!(BarInfo::from_value(info.to_value()), info);
+
+        let info = BarInfo{base: 0x8_0000_0000, size: 1 << 34,
+                           interrupt_line: NO_INTERRUPT_LINE};
+        assert_eq!(BarInfo::from_value(info.to_value()), info);
+    }
+}
+
+/// Commands sent in DIAG messages
+pub mod diag {
+    use core::str;
+    use serde_json::Value;
+
+    use super::{rcall, MessageData, JSON};
+    use crate::syscalls::{self, CommHandle, SyscallError};
+
+    /// Reply with the driver's internal state
+    pub const DUMP_STATE: u64 = 1;
+    /// Zero statistics counters, then reply with the state
+    pub const RESET_COUNTERS: u64 = 2;
+    /// Set logging verbosity to the argument, then reply with the state
+    pub const SET_LOG_LEVEL: u64 = 3;
+
+    // Log levels, from least to most verbose
+    pub const LOG_ERROR: u64 = 0;
+    pub const LOG_WARN: u64 = 1;
+    pub const LOG_INFO: u64 = 2;
+    pub const LOG_DEBUG: u64 = 3;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Command {
+        DumpState,
+        ResetCounters,
+        SetLogLevel(u64)
+    }
+
+    impl Command {
+        /// Decode the data in a Short(DIAG, command, argument) message
+        pub fn from_message(command: u64, argument: u64) -> Option<Command> {
+            match command {
+                DUMP_STATE => Some(Command::DumpState),
+                RESET_COUNTERS => Some(Command::ResetCounters),
+                SET_LOG_LEVEL if argument <= LOG_DEBUG => Some(Command::SetLogLevel(argument)),
+                _ => None
+            }
+        }
+
+        /// The (command, argument) data of a DIAG message
+        pub fn to_message(self) -> (u64, u64) {
+            match self {
+                Command::DumpState => (DUMP_STATE, 0),
+                Command::ResetCounters => (RESET_COUNTERS, 0),
+                Command::SetLogLevel(level) => (SET_LOG_LEVEL, level)
+            }
+        }
+    }
+
+    /// Send a diagnostic command to a driver, returning its state
+    pub fn request(handle: &CommHandle, command: Command) -> Result<Value, SyscallError> {
+        let (data2, data3) = command.to_message();
+        match rcall(handle, super::DIAG, data2.into(), data3.into(), Some(JSON)) {
+            Ok((_, MessageData::Value(length), MessageData::MemoryHandle(handle))) => {
+                let u8_slice = handle.as_slice::<u8>(length as usize);
+                let s = str::from_utf8(u8_slice)
+                    .map_err(|_| syscalls::SYSCALL_ERROR_UTF8)?;
+                serde_json::from_str::<Value>(s)
+                    .map_err(|_| syscalls::SYSCALL_ERROR_INVALID_DATA)
+            }
+            Ok(_) => Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+            Err((err, _)) => Err(err)
+        }
+    }
+
+    #[test_case]
+    fn command_round_trip() {
+        for command in [Command::DumpState,
+                        Command::ResetCounters,
+                        Command::SetLogLevel(LOG_INFO)] {
+            let (data2, data3) = command.to_message();
+            assert_eq!(Command::from_message(data2, data3), Some(command));
+        }
+        assert_eq!(Command::from_message(SET_LOG_LEVEL, LOG_DEBUG + 1), None);
+        assert_eq!(Command::from_message(0, 0), None);
+    }
 }
 
 /// Message types specific to Network Interface Cards
@@ -292,3 +712,334 @@ pub mod nic {
 
     pub const MAC_ADDRESS: u64 = 300;
 }
+
+/// Messages for the graphics (linear framebuffer) mode of the VGA driver
+///
+/// The driver receives Long(VIDEO_MEMORY, value, handle) at startup.
+/// If `value` has the FRAMEBUFFER bit set then the rest of it encodes
+/// a `Mode`, and the handle maps the framebuffer. Otherwise `value`
+/// is the length of the VGA text mode memory.
+///
+/// Pixels are 32 bits per pixel, BGRX byte order: a little-endian
+/// u32 0x00RRGGBB, where the top byte is ignored. Rows are `pitch`
+/// bytes apart, which may be more than 4 * width. Other formats are
+/// rejected by the driver.
+///
+/// Handles opened with OPEN_READWRITE accept these messages, all
+/// replying OK or Short(ERROR, code, 0). Rectangles are clipped to
+/// the screen.
+pub mod video {
+    /// Set in the VIDEO_MEMORY value for a framebuffer
+    pub const FRAMEBUFFER: u64 = 1 << 63;
+
+    /// Short(SET_PIXEL, x | (y << 32), color)
+    pub const SET_PIXEL: u64 = 512;
+    /// Short(FILL_RECT, Rect::to_value, color)
+    pub const FILL_RECT: u64 = 513;
+    /// Long(BLIT, Rect::to_value, handle) with width * height pixels,
+    /// row by row without padding, in the framebuffer format
+    pub const BLIT: u64 = 514;
+
+    /// Bytes per pixel in the supported format
+    pub const BYTES_PER_PIXEL: usize = 4;
+
+    /// Framebuffer geometry
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Mode {
+        /// Pixels
+        pub width: u16,
+        /// Pixels
+        pub height: u16,
+        /// Bytes between the start of each row
+        pub pitch: u32,
+        pub bits_per_pixel: u8
+    }
+
+    impl Mode {
+        /// Encode as a VIDEO_MEMORY value, including FRAMEBUFFER
+        ///
+        /// | FRAMEBUFFER (1) | bpp (8) | pitch (23) | height (16) | width (16) |
+        pub fn to_value(&self) -> u64 {
+            FRAMEBUFFER |
+            ((self.bits_per_pixel as u64) << 55) |
+            (((self.pitch & 0x7F_FFFF) as u64) << 32) |
+            ((self.height as u64) << 16) |
+            (self.width as u64)
+        }
+
+        /// Decode a VIDEO_MEMORY value. None if the FRAMEBUFFER bit
+        /// isn't set i.e. text mode.
+        pub fn from_value(value: u64) -> Option<Mode> {
+            if value & FRAMEBUFFER == 0 {
+                return None;
+            }
+            Some(Mode {
+                width: value as u16,
+                height: (value >> 16) as u16,
+                pitch: ((value >> 32) & 0x7F_FFFF) as u32,
+                bits_per_pixel: (value >> 55) as u8
+            })
+        }
+
+        /// Length of the framebuffer in bytes
+        pub fn length(&self) -> usize {
+            self.pitch as usize * self.height as usize
+        }
+    }
+
+    /// A rectangle in pixels, from (x, y) at the top left
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Rect {
+        pub x: u16,
+        pub y: u16,
+        pub width: u16,
+        pub height: u16
+    }
+
+    impl Rect {
+        /// | height (16) | width (16) | y (16) | x (16) |
+        pub fn to_value(&self) -> u64 {
+            (self.x as u64) |
+            ((self.y as u64) << 16) |
+            ((self.width as u64) << 32) |
+            ((self.height as u64) << 48)
+        }
+
+        pub fn from_value(value: u64) -> Rect {
+            Rect {
+                x: value as u16,
+                y: (value >> 16) as u16,
+                width: (value >> 32) as u16,
+                height: (value >> 48) as u16
+            }
+        }
+    }
+
+    #[test_case]
+    fn mode_round_trip() {
+        let mode = Mode{width: 1024, height: 768, pitch: 4096, bits_per_pixel: 32};
+        assert_eq!(Mode::from_value(mode.to_value()), Some(mode));
+        assert_eq!(mode.length(), 4096 * 768);
+        // Text mode length
+        assert_eq!(Mode::from_value(0x20000), None);
+
+        let rect = Rect{x: 1, y: 2, width: 300, height: 0xFFFF};
+        assert_eq!(Rect::from_value(rect.to_value()), rect);
+    }
+}
+
+/// Messages from the PS/2 mouse driver, mounted at /dev/mouse
+///
+/// Open the path with OPEN_READONLY to get a handle. The driver then
+/// sends Short(MOVE, Event::to_value, 0) for each packet from the
+/// mouse. A client which doesn't receive an event within a short
+/// time misses it, and closed handles are dropped.
+pub mod mouse {
+    /// Short(MOVE, Event::to_value, 0)
+    pub const MOVE: u64 = 768;
+
+    /// Button bits in Event::buttons
+    pub const BUTTON_LEFT: u8 = 1;
+    pub const BUTTON_RIGHT: u8 = 2;
+    pub const BUTTON_MIDDLE: u8 = 4;
+
+    /// Always set in the first byte of a packet. Used to find the
+    /// start of a packet if a byte is lost.
+    pub const PACKET_SYNC: u8 = 0x08;
+    const PACKET_X_SIGN: u8 = 0x10;
+    const PACKET_Y_SIGN: u8 = 0x20;
+    const PACKET_X_OVERFLOW: u8 = 0x40;
+    const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+    /// Movement since the last event, and buttons held down
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Event {
+        /// Positive to the right
+        pub dx: i16,
+        /// Positive upwards, as reported by the mouse
+        pub dy: i16,
+        pub buttons: u8
+    }
+
+    impl Event {
+        /// | buttons (8) | dy (16) | dx (16) |
+        pub fn to_value(&self) -> u64 {
+            (self.dx as u16 as u64) |
+            ((self.dy as u16 as u64) << 16) |
+            ((self.buttons as u64) << 32)
+        }
+
+        pub fn from_value(value: u64) -> Event {
+            Event {
+                dx: value as u16 as i16,
+                dy: (value >> 16) as u16 as i16,
+                buttons: (value >> 32) as u8
+            }
+        }
+
+        /// Decode a standard 3 byte PS/2 packet
+        ///
+        /// The movements are 9 bit two's complement, with the sign
+        /// bits in the first byte. None if the first byte doesn't
+        /// have PACKET_SYNC set, or if either movement overflowed.
+        pub fn from_packet(packet: [u8; 3]) -> Option<Event> {
+            let flags = packet[0];
+            if flags & PACKET_SYNC == 0 ||
+                flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+                    return None;
+                }
+            let movement = |value: u8, negative: bool| {
+                if negative {
+                    value as i16 - 256
+                } else {
+                    value as i16
+                }
+            };
+            Some(Event {
+                dx: movement(packet[1], flags & PACKET_X_SIGN != 0),
+                dy: movement(packet[2], flags & PACKET_Y_SIGN != 0),
+                buttons: flags & (BUTTON_LEFT | BUTTON_RIGHT | BUTTON_MIDDLE)
+            })
+        }
+    }
+
+    #[test_case]
+    fn decode_packets() {
+        // Left button, moving right and down
+        let event = Event::from_packet([0x29, 5, 0xFE]).unwrap();
+        assert_eq!(event, Event{dx: 5, dy: -2, buttons: BUTTON_LEFT});
+        assert_eq!(Event::from_packet([0x18, 0, 0]).unwrap().dx, -256);
+        // Overflow and missing sync bit
+        assert_eq!(Event::from_packet([0x48, 1, 1]), None);
+        assert_eq!(Event::from_packet([0x01, 1, 1]), None);
+
+        let event = Event{dx: -1, dy: 300, buttons: BUTTON_RIGHT | BUTTON_MIDDLE};
+        assert_eq!(Event::from_value(event.to_value()), event);
+    }
+}
+
+/// Messages for the 16550 serial port driver, mounted at /dev/serial
+///
+/// Handles opened with OPEN_READWRITE accept Long(WRITE, length,
+/// handle), replying Short(OK, written, 0), and SET_BAUD. Handles
+/// opened with OPEN_READONLY are sent Short(CHAR, byte, 0) for each
+/// received byte, so they can be used as a program's STDIN. A
+/// client which doesn't receive a byte within a short time misses
+/// it.
+///
+/// Output "\n" is sent as "\r\n", and received '\r' is passed on as
+/// '\n', as terminal emulators expect.
+pub mod serial {
+    /// Short(SET_BAUD, baud, 0) -> OK or Short(ERROR, code, 0)
+    pub const SET_BAUD: u64 = 1024;
+
+    /// UART clock divided by 16. Baud rates must divide this exactly.
+    pub const MAX_BAUD: u64 = 115200;
+    pub const DEFAULT_BAUD: u64 = 115200;
+
+    /// The divisor latch value for a baud rate, or None if the rate
+    /// can't be set exactly
+    pub fn baud_divisor(baud: u64) -> Option<u16> {
+        if baud == 0 || MAX_BAUD % baud != 0 {
+            return None;
+        }
+        Some((MAX_BAUD / baud) as u16)
+    }
+
+    #[test_case]
+    fn baud_divisors() {
+        assert_eq!(baud_divisor(115200), Some(1));
+        assert_eq!(baud_divisor(9600), Some(12));
+        assert_eq!(baud_divisor(50), Some(2304));
+        assert_eq!(baud_divisor(0), None);
+        assert_eq!(baud_divisor(1000), None);
+        assert_eq!(baud_divisor(230400), None);
+    }
+}
+
+/// Messages for the keyboard driver, mounted at /dev/keyboard
+///
+/// Handles opened with OPEN_READWRITE accept SET_LAYOUT. Decoded
+/// keys are sent to init rather than to these handles.
+pub mod keyboard {
+    /// Short(SET_LAYOUT, layout, 0) -> OK or
+    /// Short(ERROR, SYSCALL_ERROR_PARAM, 0) if the layout is unknown
+    pub const SET_LAYOUT: u64 = 1280;
+
+    /// Keyboard layout identifiers. LAYOUT_US is the default.
+    pub const LAYOUT_US: u64 = 0;
+    pub const LAYOUT_UK: u64 = 1;
+    pub const LAYOUT_DE: u64 = 2;
+    pub const LAYOUT_DVORAK: u64 = 3;
+}
+
+/// Messages for UDP sockets, opened at /tcp/udp
+///
+/// Each handle is one socket. Bind it to a local port first; datagrams
+/// to that port are then kept until received. Checksums are computed
+/// and verified by the network server.
+pub mod udp {
+    use crate::net::Ipv4Addr;
+
+    /// Short(BIND, port, 0) -> Short(OK, port, 0)
+    ///
+    /// Port 0 chooses an ephemeral port. Replies Short(ERROR,
+    /// SYSCALL_ERROR_EXISTS, 0) if the port is in use.
+    pub const BIND: u64 = 1536;
+    /// Long(SEND_TO, Endpoint::to_value, handle) -> OK or
+    /// Short(ERROR, code, 0)
+    pub const SEND_TO: u64 = 1537;
+    /// Short(RECV_FROM, timeout_us, 0) -> Long(DATA,
+    /// Endpoint::to_value, handle), or Short(EMPTY, 0, 0) if no
+    /// datagram arrived within the timeout. The server waits at most
+    /// MAX_RECV_WAIT_US, so clients wanting longer send it again.
+    pub const RECV_FROM: u64 = 1538;
+
+    /// Longest time the server waits in one RECV_FROM
+    pub const MAX_RECV_WAIT_US: u64 = 100_000;
+
+    /// The remote address and port of a datagram, and its length
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Endpoint {
+        pub address: Ipv4Addr,
+        pub port: u16,
+        /// Bytes of payload
+        pub length: u16
+    }
+
+    impl Endpoint {
+        /// | length (16) | port (16) | address (32, first octet highest) |
+        pub fn to_value(&self) -> u64 {
+            (u32::from_be_bytes(self.address.octets()) as u64) |
+            ((self.port as u64) << 32) |
+            ((self.length as u64) << 48)
+        }
+
+        pub fn from_value(value: u64) -> Endpoint {
+            let octets = (value as u32).to_be_bytes();
+            Endpoint {
+                address: Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]),
+                port: (value >> 32) as u16,
+                length: (value >> 48) as u16
+            }
+        }
+    }
+
+    #[test_case]
+    fn endpoint_round_trip() {
+        let endpoint = Endpoint{address: Ipv4Addr::new(10, 0, 2, 3),
+                                port: 53, length: 512};
+        assert_eq!(Endpoint::from_value(endpoint.to_value()), endpoint);
+        assert_eq!(endpoint.to_value() & 0xFFFF_FFFF, 0x0A00_0203);
+    }
+}
+
+/// Messages for ICMP echo (ping), opened at /tcp/ping
+pub mod icmp {
+    /// Short(ECHO, address, timeout_us) -> Short(OK, rtt_us, 0)
+    ///
+    /// Sends one echo request to the IPv4 address (first octet
+    /// highest) and waits for the reply. Replies Short(ERROR,
+    /// SYSCALL_ERROR_TIMEOUT, 0) if none arrives within the timeout.
+    pub const ECHO: u64 = 1792;
+}
@@ -1,6 +1,14 @@
 //! Network related data structures and functions
 
+extern crate alloc;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
+use spin::Mutex;
+
+use crate::message::{self, rcall, MessageData};
+use crate::syscalls::{self, CommHandle, MemoryHandle, SyscallError};
+use crate::time;
 
 /// Represent a Media Access Control (MAC) address
 ///
@@ -50,3 +58,417 @@ impl fmt::Display for MacAddress {
         write!(f, "{:02X}", self.octet[5])
     }
 }
+
+/// An IPv4 address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Addr {
+    octets: [u8; 4]
+}
+
+impl Ipv4Addr {
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Self {
+        Ipv4Addr{octets: [a, b, c, d]}
+    }
+
+    pub fn octets(&self) -> [u8; 4] {
+        self.octets
+    }
+
+    /// Parse dotted decimal e.g. "10.0.2.2"
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Ipv4Addr{octets})
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}",
+               self.octets[0], self.octets[1], self.octets[2], self.octets[3])
+    }
+}
+
+/// Path to open for a UDP socket
+///
+/// EuraliOS only
+pub const UDP_PATH: &str = "/tcp/udp";
+
+/// A UDP socket, bound to a local port
+///
+/// Similar to std::net::UdpSocket, but only IPv4.
+///
+/// ```ignore
+/// let socket = UdpSocket::bind(0)?;
+/// socket.send_to(b"hello", (Ipv4Addr::new(10, 0, 2, 2), 7))?;
+/// socket.set_read_timeout(Some(1_000_000));
+/// let (data, (address, port)) = socket.recv_from()?;
+/// ```
+pub struct UdpSocket {
+    handle: CommHandle,
+    port: u16,
+    /// Microseconds. None waits forever
+    read_timeout: Option<u64>
+}
+
+impl UdpSocket {
+    /// Bind to a local port. Port 0 chooses an ephemeral port.
+    pub fn bind(port: u16) -> Result<Self, SyscallError> {
+        let handle = syscalls::open(UDP_PATH, message::O_WRITE)?;
+        match rcall(&handle, message::udp::BIND, (port as u64).into(), 0.into(), None) {
+            Ok((message::OK, MessageData::Value(port), _)) => {
+                Ok(UdpSocket{handle, port: port as u16, read_timeout: None})
+            }
+            Ok((message::ERROR, MessageData::Value(code), _)) => Err(SyscallError::new(code)),
+            Ok(_) => Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+            Err((err, _)) => Err(err)
+        }
+    }
+
+    /// The local port
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// How long `recv_from` waits, in microseconds
+    pub fn set_read_timeout(&mut self, timeout_us: Option<u64>) {
+        self.read_timeout = timeout_us;
+    }
+
+    /// Send a datagram
+    pub fn send_to(&self, data: &[u8], (address, port): (Ipv4Addr, u16)) -> Result<(), SyscallError> {
+        if data.len() > u16::MAX as usize {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        let endpoint = message::udp::Endpoint{address, port, length: data.len() as u16};
+        match rcall(&self.handle, message::udp::SEND_TO,
+                    endpoint.to_value().into(),
+                    MemoryHandle::from_u8_slice(data).into(),
+                    None) {
+            Ok((message::OK, _, _)) => Ok(()),
+            Ok((message::ERROR, MessageData::Value(code), _)) => Err(SyscallError::new(code)),
+            Ok(_) => Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+            Err((err, _)) => Err(err)
+        }
+    }
+
+    /// Receive a datagram, and the address and port it came from
+    ///
+    /// Returns SYSCALL_ERROR_TIMEOUT if the read timeout passes first
+    pub fn recv_from(&self) -> Result<(Vec<u8>, (Ipv4Addr, u16)), SyscallError> {
+        let deadline = self.read_timeout.map(
+            |timeout| time::microseconds_monotonic().saturating_add(timeout));
+        loop {
+            // The server waits up to MAX_RECV_WAIT_US each time
+            let timeout = match deadline {
+                Some(deadline) => deadline.saturating_sub(time::microseconds_monotonic()),
+                None => message::udp::MAX_RECV_WAIT_US
+            };
+            match rcall(&self.handle, message::udp::RECV_FROM,
+                        timeout.into(), 0.into(), None) {
+                Ok((message::DATA,
+                    MessageData::Value(value),
+                    MessageData::MemoryHandle(data))) => {
+                    let endpoint = message::udp::Endpoint::from_value(value);
+                    return Ok((Vec::from(data.as_slice::<u8>(endpoint.length as usize)),
+                               (endpoint.address, endpoint.port)));
+                }
+                Ok((message::EMPTY, _, _)) => {
+                    if deadline.map_or(false, |deadline| time::microseconds_monotonic() >= deadline) {
+                        return Err(syscalls::SYSCALL_ERROR_TIMEOUT);
+                    }
+                }
+                Ok((message::ERROR, MessageData::Value(code), _)) => {
+                    return Err(SyscallError::new(code));
+                }
+                Ok(_) => return Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+                Err((err, _)) => return Err(err)
+            }
+        }
+    }
+}
+
+/// Path to open for ICMP echo requests
+///
+/// EuraliOS only
+pub const PING_PATH: &str = "/tcp/ping";
+
+/// Send an ICMP echo request, and wait for the reply
+///
+/// Returns the round trip time in microseconds, or
+/// SYSCALL_ERROR_TIMEOUT if no reply arrived within `timeout_us`.
+///
+/// ```ignore
+/// let rtt_us = net::ping(Ipv4Addr::new(10, 0, 2, 2), 1_000_000)?;
+/// ```
+pub fn ping(address: Ipv4Addr, timeout_us: u64) -> Result<u64, SyscallError> {
+    let handle = syscalls::open(PING_PATH, message::O_WRITE)?;
+    match rcall(&handle, message::icmp::ECHO,
+                (u32::from_be_bytes(address.octets()) as u64).into(),
+                timeout_us.into(), None) {
+        Ok((message::OK, MessageData::Value(rtt_us), _)) => Ok(rtt_us),
+        Ok((message::ERROR, MessageData::Value(code), _)) => Err(SyscallError::new(code)),
+        Ok(_) => Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+        Err((err, _)) => Err(err)
+    }
+}
+
+// Domain Name System (DNS) resolver
+//
+// Queries are sent through the tcp server, which relays them over
+// UDP to the DNS server it was configured with (usually by DHCP).
+// See RFC 1035 for the message format.
+
+/// Path of the DNS query relay
+///
+/// EuraliOS only
+pub const DNS_PATH: &str = "/tcp/dns";
+
+const DNS_TYPE_A: u16 = 1;
+const DNS_CLASS_IN: u16 = 1;
+/// Header flags: Recursion desired
+const DNS_FLAG_RD: u16 = 0x0100;
+/// Header flags: This is a response
+const DNS_FLAG_QR: u16 = 0x8000;
+const DNS_RCODE_MASK: u16 = 0xF;
+const DNS_RCODE_NXDOMAIN: u16 = 3;
+/// Top bits of a length byte which mark a compression pointer
+const DNS_POINTER: u8 = 0xC0;
+const DNS_HEADER_LEN: usize = 12;
+
+/// Number of lookups to remember
+const DNS_CACHE_SIZE: usize = 32;
+/// Longest time to cache a lookup, whatever its TTL
+const DNS_MAX_TTL_SECONDS: u64 = 3600;
+
+struct CacheEntry {
+    name: String,
+    address: Ipv4Addr,
+    /// time::microseconds_monotonic when the TTL runs out
+    expires: u64
+}
+
+static DNS_CACHE: Mutex<Vec<CacheEntry>> = Mutex::new(Vec::new());
+
+/// Encode a query for the A record of `name`
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>, SyscallError> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+    let mut datagram = Vec::with_capacity(DNS_HEADER_LEN + name.len() + 6);
+    datagram.extend_from_slice(&id.to_be_bytes());
+    datagram.extend_from_slice(&DNS_FLAG_RD.to_be_bytes());
+    datagram.extend_from_slice(&1u16.to_be_bytes()); // Questions
+    datagram.extend_from_slice(&[0; 6]); // Answer, authority, additional
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        datagram.push(label.len() as u8);
+        datagram.extend_from_slice(label.as_bytes());
+    }
+    datagram.push(0); // Root label
+    datagram.extend_from_slice(&DNS_TYPE_A.to_be_bytes());
+    datagram.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    Ok(datagram)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<u16, SyscallError> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+        None => Err(syscalls::SYSCALL_ERROR_INVALID_DATA)
+    }
+}
+
+/// The position after the name starting at `pos`. A name ends with a
+/// zero length label, or a compression pointer to the rest of it.
+fn skip_name(data: &[u8], mut pos: usize) -> Result<usize, SyscallError> {
+    loop {
+        let length = *data.get(pos).ok_or(syscalls::SYSCALL_ERROR_INVALID_DATA)?;
+        if length & DNS_POINTER == DNS_POINTER {
+            return Ok(pos + 2);
+        }
+        pos += 1;
+        if length == 0 {
+            return Ok(pos);
+        }
+        pos += length as usize;
+    }
+}
+
+/// The first A record in the response to query `id`, and its
+/// TTL in seconds
+///
+/// # Errors
+///  - SYSCALL_ERROR_NOTFOUND if the name doesn't exist (NXDOMAIN)
+///  - SYSCALL_ERROR_NO_DATA if there is no A record
+///  - SYSCALL_ERROR_INVALID_DATA if the response is malformed, or
+///    reports another error
+fn parse_response(id: u16, data: &[u8]) -> Result<(Ipv4Addr, u32), SyscallError> {
+    let flags = read_u16(data, 2)?;
+    if read_u16(data, 0)? != id || flags & DNS_FLAG_QR == 0 {
+        return Err(syscalls::SYSCALL_ERROR_INVALID_DATA);
+    }
+    match flags & DNS_RCODE_MASK {
+        0 => {}
+        DNS_RCODE_NXDOMAIN => return Err(syscalls::SYSCALL_ERROR_NOTFOUND),
+        _ => return Err(syscalls::SYSCALL_ERROR_INVALID_DATA)
+    }
+    let questions = read_u16(data, 4)?;
+    let answers = read_u16(data, 6)?;
+
+    let mut pos = DNS_HEADER_LEN;
+    for _ in 0..questions {
+        pos = skip_name(data, pos)? + 4; // Type and class
+    }
+    for _ in 0..answers {
+        pos = skip_name(data, pos)?;
+        let rtype = read_u16(data, pos)?;
+        let class = read_u16(data, pos + 2)?;
+        let ttl = ((read_u16(data, pos + 4)? as u32) << 16) | read_u16(data, pos + 6)? as u32;
+        let length = read_u16(data, pos + 8)? as usize;
+        pos += 10;
+        let rdata = data.get(pos..pos + length)
+            .ok_or(syscalls::SYSCALL_ERROR_INVALID_DATA)?;
+        if rtype == DNS_TYPE_A && class == DNS_CLASS_IN && length == 4 {
+            return Ok((Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]), ttl));
+        }
+        // e.g. a CNAME before the A record
+        pos += length;
+    }
+    Err(syscalls::SYSCALL_ERROR_NO_DATA)
+}
+
+fn cache_lookup(name: &str) -> Option<Ipv4Addr> {
+    let now = time::microseconds_monotonic();
+    let mut cache = DNS_CACHE.lock();
+    cache.retain(|entry| entry.expires > now);
+    cache.iter().find(|entry| entry.name == name).map(|entry| entry.address)
+}
+
+fn cache_insert(name: &str, address: Ipv4Addr, ttl: u32) {
+    let ttl = (ttl as u64).min(DNS_MAX_TTL_SECONDS);
+    if ttl == 0 {
+        return;
+    }
+    let mut cache = DNS_CACHE.lock();
+    cache.retain(|entry| entry.name != name);
+    if cache.len() >= DNS_CACHE_SIZE {
+        // Drop the oldest
+        cache.remove(0);
+    }
+    cache.push(CacheEntry{name: String::from(name),
+                          address,
+                          expires: time::microseconds_monotonic() + ttl * 1_000_000});
+}
+
+/// Find the IPv4 address of a host
+///
+/// Sends a query for the A record to the configured DNS server, and
+/// returns the first A record in the answer. Lookups are cached for
+/// their TTL. Dotted decimal addresses are returned without a query.
+///
+/// # Errors
+///  - SYSCALL_ERROR_TIMEOUT if the DNS server didn't respond
+///  - SYSCALL_ERROR_NOTFOUND if the name doesn't exist
+///  - SYSCALL_ERROR_NO_DATA if the name has no IPv4 address
+///
+/// Usage:
+///
+/// ```ignore
+/// let address = net::resolve("gopher.floodgap.com")?;
+/// ```
+pub fn resolve(hostname: &str) -> Result<Ipv4Addr, SyscallError> {
+    if let Some(address) = Ipv4Addr::parse(hostname) {
+        return Ok(address);
+    }
+    if let Some(address) = cache_lookup(hostname) {
+        return Ok(address);
+    }
+
+    let id = time::microseconds_monotonic() as u16; // Semi-unique
+    let query = encode_query(id, hostname)?;
+
+    let handle = syscalls::open(DNS_PATH, message::O_WRITE)?;
+    let response = match rcall(&handle, message::WRITE,
+                               (query.len() as u64).into(),
+                               MemoryHandle::from_u8_slice(&query).into(),
+                               None) {
+        Ok((message::DATA,
+            MessageData::Value(length),
+            MessageData::MemoryHandle(data))) => {
+            Vec::from(data.as_slice::<u8>(length as usize))
+        }
+        Ok((message::ERROR, MessageData::Value(code), _)) => {
+            return Err(SyscallError::new(code));
+        }
+        Ok(_) => return Err(syscalls::SYSCALL_ERROR_INVALID_DATA),
+        Err((err, _)) => return Err(err)
+    };
+
+    let (address, ttl) = parse_response(id, &response)?;
+    cache_insert(hostname, address, ttl);
+    Ok(address)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A response to encode_query(0x1234, "example.com") with a CNAME
+    /// then an A record, names compressed
+    fn example_response() -> Vec<u8> {
+        let mut response = encode_query(0x1234, "example.com").unwrap();
+        response[2] = 0x81; // QR, RD
+        response[3] = 0x80; // RA
+        response[7] = 2; // Answers
+        // CNAME: pointer to the question name
+        response.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6,
+                                     3, b'w', b'w', b'w', 0xC0, 12]);
+        // A record for the CNAME target
+        response.extend_from_slice(&[0xC0, 41, 0, 1, 0, 1, 0, 0, 0x0E, 0x10, 0, 4,
+                                     93, 184, 216, 34]);
+        response
+    }
+
+    #[test_case]
+    fn dns_query_format() {
+        let query = encode_query(0xABCD, "example.com.").unwrap();
+        assert_eq!(&query[..4], &[0xAB, 0xCD, 0x01, 0x00]);
+        assert_eq!(&query[12..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+        assert_eq!(encode_query(1, "a..b"), Err(syscalls::SYSCALL_ERROR_PARAM));
+    }
+
+    #[test_case]
+    fn dns_parse_answers() {
+        let response = example_response();
+        assert_eq!(parse_response(0x1234, &response),
+                   Ok((Ipv4Addr::new(93, 184, 216, 34), 3600)));
+        // Wrong ID
+        assert_eq!(parse_response(0x1235, &response),
+                   Err(syscalls::SYSCALL_ERROR_INVALID_DATA));
+        // Truncated
+        assert_eq!(parse_response(0x1234, &response[..response.len() - 2]),
+                   Err(syscalls::SYSCALL_ERROR_INVALID_DATA));
+
+        let mut nxdomain = encode_query(0x1234, "example.com").unwrap();
+        nxdomain[2] = 0x81;
+        nxdomain[3] = 0x83;
+        assert_eq!(parse_response(0x1234, &nxdomain),
+                   Err(syscalls::SYSCALL_ERROR_NOTFOUND));
+        nxdomain[3] = 0x80;
+        assert_eq!(parse_response(0x1234, &nxdomain),
+                   Err(syscalls::SYSCALL_ERROR_NO_DATA));
+
+        assert_eq!(Ipv4Addr::parse("10.0.2.2"), Some(Ipv4Addr::new(10, 0, 2, 2)));
+        assert_eq!(Ipv4Addr::parse("10.0.2"), None);
+        assert_eq!(Ipv4Addr::parse("example.com"), None);
+    }
+}
@@ -17,6 +17,7 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use crate::ffi::{OsStr, OsString};
 use crate::sys::path::{is_sep_byte, MAIN_SEP_STR};
+use crate::syscalls::{self, SyscallError};
 
 unsafe fn u8_slice_as_os_str(s: &[u8]) -> &OsStr {
     // SAFETY: See note at the top of this module to understand why this and
@@ -32,6 +33,24 @@ unsafe fn u8_slice_as_os_str(s: &[u8]) -> &OsStr {
     &*(s as *const [u8] as *const OsStr)
 }
 
+// Splits a file name into the parts before and after the last '.'
+// A name starting with '.' and containing no other dots, or "..",
+// is all stem and no extension
+fn rsplit_file_at_dot(file: &OsStr) -> (Option<&OsStr>, Option<&OsStr>) {
+    if file.bytes() == b".." {
+        return (Some(file), None);
+    }
+
+    let mut iter = file.bytes().rsplitn(2, |b| *b == b'.');
+    let after = iter.next();
+    let before = iter.next();
+    if before == Some(b"") {
+        (Some(file), None)
+    } else {
+        unsafe { (before.map(|s| u8_slice_as_os_str(s)), after.map(|s| u8_slice_as_os_str(s))) }
+    }
+}
+
 ////////////////////////////////////////////////////////////////////////////////
 // Basic types and traits
 ////////////////////////////////////////////////////////////////////////////////
@@ -245,6 +264,57 @@ impl PathBuf {
             None => false,
         }
     }
+
+    /// Updates [`self.extension`] to `extension`.
+    ///
+    /// Returns `false` and does nothing if [`self.file_name`] is [`None`],
+    /// returns `true` and updates the extension otherwise.
+    ///
+    /// If [`self.extension`] is [`None`], the extension is added; otherwise
+    /// it is replaced. An empty `extension` removes the extension.
+    ///
+    /// [`self.file_name`]: Path::file_name
+    /// [`self.extension`]: Path::extension
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// let mut p = PathBuf::from("/feel/the");
+    ///
+    /// p.set_extension("force");
+    /// assert_eq!(Path::new("/feel/the.force"), p.as_path());
+    ///
+    /// p.set_extension("dark_side");
+    /// assert_eq!(Path::new("/feel/the.dark_side"), p.as_path());
+    /// ```
+    pub fn set_extension<S: AsRef<OsStr>>(&mut self, extension: S) -> bool {
+        self._set_extension(extension.as_ref())
+    }
+
+    fn _set_extension(&mut self, extension: &OsStr) -> bool {
+        let file_stem = match self.file_stem() {
+            None => return false,
+            Some(f) => f.bytes(),
+        };
+
+        // truncate until right after the file stem
+        let end_file_stem = file_stem[file_stem.len()..].as_ptr() as usize;
+        let start = self.as_u8_slice().as_ptr() as usize;
+        let v = self.as_mut_vec();
+        v.truncate(end_file_stem.wrapping_sub(start));
+
+        // add the new extension, if any
+        let new = extension.bytes();
+        if !new.is_empty() {
+            v.reserve_exact(new.len() + 1);
+            v.push(b'.');
+            v.extend_from_slice(new);
+        }
+
+        true
+    }
 }
 
 impl fmt::Debug for PathBuf {
@@ -479,6 +549,121 @@ impl Path {
         })
     }
 
+    /// Extracts the stem (non-extension) portion of [`self.file_name`].
+    ///
+    /// [`self.file_name`]: Path::file_name
+    ///
+    /// The stem is:
+    ///
+    /// * [`None`], if there is no file name;
+    /// * The entire file name if there is no embedded `.`;
+    /// * The entire file name if the file name begins with `.` and has no other `.`s within;
+    /// * Otherwise, the portion of the file name before the final `.`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::Path;
+    ///
+    /// assert_eq!("foo", Path::new("foo.rs").file_stem().unwrap());
+    /// assert_eq!("foo.tar", Path::new("foo.tar.gz").file_stem().unwrap());
+    /// ```
+    #[must_use]
+    pub fn file_stem(&self) -> Option<&OsStr> {
+        self.file_name().map(rsplit_file_at_dot).and_then(|(before, after)| before.or(after))
+    }
+
+    /// Extracts the extension of [`self.file_name`], if possible.
+    ///
+    /// The extension is:
+    ///
+    /// * [`None`], if there is no file name;
+    /// * [`None`], if there is no embedded `.`;
+    /// * [`None`], if the file name begins with `.` and has no other `.`s within;
+    /// * Otherwise, the portion of the file name after the final `.`
+    ///
+    /// [`self.file_name`]: Path::file_name
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::Path;
+    ///
+    /// assert_eq!("rs", Path::new("foo.rs").extension().unwrap());
+    /// assert_eq!("gz", Path::new("foo.tar.gz").extension().unwrap());
+    /// ```
+    #[must_use]
+    pub fn extension(&self) -> Option<&OsStr> {
+        self.file_name().map(rsplit_file_at_dot).and_then(|(before, after)| before.and(after))
+    }
+
+    /// Creates an owned [`PathBuf`] like `self` but with the given extension.
+    ///
+    /// See [`PathBuf::set_extension`] for more details.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// let path = Path::new("foo.rs");
+    /// assert_eq!(path.with_extension("txt"), PathBuf::from("foo.txt"));
+    ///
+    /// let path = Path::new("foo.tar.gz");
+    /// assert_eq!(path.with_extension(""), PathBuf::from("foo.tar"));
+    /// ```
+    #[must_use]
+    pub fn with_extension<S: AsRef<OsStr>>(&self, extension: S) -> PathBuf {
+        let mut buf = self.to_path_buf();
+        buf.set_extension(extension);
+        buf
+    }
+
+    /// Normalize a path without accessing the filesystem
+    ///
+    /// Repeated separators and `.` components are removed, and each
+    /// `..` removes the component before it. Relative paths stay
+    /// relative.
+    ///
+    /// Returns SYSCALL_ERROR_PARAM if a `..` would go above the root
+    /// of an absolute path, or the start of a relative path.
+    ///
+    /// Note: `a/file/..` normalizes to `a` even if `file` isn't a
+    /// directory. Use fs::canonicalize to check with the server.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::path::{Path, PathBuf};
+    ///
+    /// assert_eq!(Path::new("/a/b/../c/./d").normalize_lexically(),
+    ///            Ok(PathBuf::from("/a/c/d")));
+    /// assert!(Path::new("/a/../..").normalize_lexically().is_err());
+    /// ```
+    pub fn normalize_lexically(&self) -> Result<PathBuf, SyscallError> {
+        let mut normalized = PathBuf::new();
+        // Number of Normal components in `normalized`
+        let mut depth = 0;
+        for component in self.components() {
+            match component {
+                Component::RootDir => normalized.push(MAIN_SEP_STR),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if depth == 0 {
+                        return Err(syscalls::SYSCALL_ERROR_PARAM);
+                    }
+                    normalized.pop();
+                    depth -= 1;
+                }
+                Component::Normal(name) => {
+                    normalized.push(name);
+                    depth += 1;
+                }
+            }
+        }
+        Ok(normalized)
+    }
+
     /// Creates an owned [`PathBuf`] with `path` adjoined to `self`.
     ///
     /// See [`PathBuf::push`] for more details on what it means to adjoin a path.
@@ -970,6 +1155,30 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
+/// Makes the path absolute without accessing the filesystem
+///
+/// Relative paths are joined to the current working directory
+/// (syscalls::getcwd). Components such as `..` are not resolved:
+/// see Path::normalize_lexically and fs::canonicalize.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::{self, Path};
+///
+/// assert_eq!(path::absolute("/etc/passwd").as_path(), Path::new("/etc/passwd"));
+/// ```
+pub fn absolute<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else if path.as_os_str().bytes().is_empty() {
+        syscalls::getcwd()
+    } else {
+        syscalls::getcwd().join(path)
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::{PathBuf, Path, Component};
@@ -1032,6 +1241,71 @@ pub mod tests {
         assert_eq!(Path::new("/etc").join("passwd"), PathBuf::from("/etc/passwd"));
     }
 
+    #[test_case]
+    fn path_join_repeated_separators() {
+        assert_eq!(Path::new("/etc/").join("passwd"), PathBuf::from("/etc/passwd"));
+        assert_eq!(Path::new("etc").join("/passwd"), PathBuf::from("/passwd"));
+        // Repeated separators and "." are skipped by components
+        let path = Path::new("/etc//./passwd");
+        assert_eq!(path.components().count(), 3);
+        assert_eq!(path.parent(), Some(Path::new("/etc")));
+        assert_eq!(Path::new("a/../b").parent(), Some(Path::new("a/..")));
+    }
+
+    #[test_case]
+    fn path_file_stem_and_extension() {
+        assert_eq!(Path::new("foo.rs").file_stem(), Some(OsStr::new("foo")));
+        assert_eq!(Path::new("/a/foo.tar.gz").file_stem(), Some(OsStr::new("foo.tar")));
+        assert_eq!(Path::new("/a/foo.tar.gz").extension(), Some(OsStr::new("gz")));
+        // Hidden files have no extension
+        assert_eq!(Path::new(".bashrc").file_stem(), Some(OsStr::new(".bashrc")));
+        assert_eq!(Path::new(".bashrc").extension(), None);
+        assert_eq!(Path::new("foo").extension(), None);
+        assert_eq!(Path::new("foo.").extension(), Some(OsStr::new("")));
+        assert_eq!(Path::new("/").file_stem(), None);
+        assert_eq!(Path::new("a/..").extension(), None);
+    }
+
+    #[test_case]
+    fn path_with_extension() {
+        assert_eq!(Path::new("foo.rs").with_extension("txt"), PathBuf::from("foo.txt"));
+        assert_eq!(Path::new("/a/foo.tar.gz").with_extension(""), PathBuf::from("/a/foo.tar"));
+        assert_eq!(Path::new("/a/foo/").with_extension("d"), PathBuf::from("/a/foo.d"));
+
+        let mut path = PathBuf::from("/");
+        assert!(!path.set_extension("txt"));
+        assert_eq!(path, PathBuf::from("/"));
+    }
+
+    #[test_case]
+    fn path_normalize_lexically() {
+        assert_eq!(Path::new("/a/b/../c/./d").normalize_lexically(),
+                   Ok(PathBuf::from("/a/c/d")));
+        assert_eq!(Path::new("//a///b/").normalize_lexically(),
+                   Ok(PathBuf::from("/a/b")));
+        assert_eq!(Path::new("/a/..").normalize_lexically(),
+                   Ok(PathBuf::from("/")));
+        assert_eq!(Path::new("./a/../b").normalize_lexically(),
+                   Ok(PathBuf::from("b")));
+        // Escaping the root, or the start of a relative path
+        assert_eq!(Path::new("/..").normalize_lexically(),
+                   Err(crate::syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(Path::new("/a/../../etc").normalize_lexically(),
+                   Err(crate::syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(Path::new("a/../..").normalize_lexically(),
+                   Err(crate::syscalls::SYSCALL_ERROR_PARAM));
+    }
+
+    #[test_case]
+    fn pathbuf_pop() {
+        let mut path = PathBuf::from("/spirited/away.rs");
+        assert!(path.pop());
+        assert_eq!(path, PathBuf::from("/spirited"));
+        assert!(path.pop());
+        assert_eq!(path, PathBuf::from("/"));
+        assert!(!path.pop());
+    }
+
     #[test_case]
     fn pathbuf_with_capacity() {
         let mut path = PathBuf::with_capacity(10);
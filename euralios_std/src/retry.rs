@@ -0,0 +1,96 @@
+//! Retry operations which fail with transient errors
+//!
+//! Usage:
+//!
+//! ```ignore
+//! let handle = euralios_std::retry(10, 100, || syscalls::open("/tcp", message::O_READ))?;
+//! ```
+
+use crate::syscalls::{ErrorKind, SyscallError};
+use crate::time;
+
+/// Error kinds which are retried by `retry`
+pub const TRANSIENT_KINDS: &[ErrorKind] = &[ErrorKind::WouldBlock];
+
+/// Call `op` until it succeeds, returns a non-transient error,
+/// or `max_attempts` have been made.
+///
+/// Between attempts sleeps for `backoff_us` microseconds, doubling
+/// the delay after each failed attempt.
+///
+/// # Returns
+///
+/// The first successful result, or the last error
+pub fn retry<T, F>(max_attempts: usize,
+                   backoff_us: u64,
+                   op: F) -> Result<T, SyscallError>
+where
+    F: FnMut() -> Result<T, SyscallError>
+{
+    retry_kinds(TRANSIENT_KINDS, max_attempts, backoff_us, op)
+}
+
+/// Like `retry` but the caller chooses which error kinds are retried
+pub fn retry_kinds<T, F>(kinds: &[ErrorKind],
+                         max_attempts: usize,
+                         backoff_us: u64,
+                         mut op: F) -> Result<T, SyscallError>
+where
+    F: FnMut() -> Result<T, SyscallError>
+{
+    let mut delay = backoff_us;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(err) if (attempt < max_attempts) &&
+                kinds.contains(&err.kind()) => {
+                    time::sleep_us(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+            result => return result
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::syscalls;
+
+    #[test_case]
+    fn retry_transient() {
+        let mut count = 0;
+        let result: Result<(), SyscallError> = retry(3, 0, || {
+            count += 1;
+            Err(syscalls::SYSCALL_ERROR_RECV_BLOCKING)
+        });
+        assert_eq!(result, Err(syscalls::SYSCALL_ERROR_RECV_BLOCKING));
+        assert_eq!(count, 3);
+    }
+
+    #[test_case]
+    fn retry_not_transient() {
+        let mut count = 0;
+        let result: Result<(), SyscallError> = retry(3, 0, || {
+            count += 1;
+            Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+        });
+        assert_eq!(result, Err(syscalls::SYSCALL_ERROR_NOTFOUND));
+        assert_eq!(count, 1);
+    }
+
+    #[test_case]
+    fn retry_kinds_override() {
+        let mut count = 0;
+        let result = retry_kinds(&[ErrorKind::NotFound], 5, 0, || {
+            count += 1;
+            if count < 2 {
+                Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+            } else {
+                Ok(count)
+            }
+        });
+        assert_eq!(result, Ok(2));
+    }
+}
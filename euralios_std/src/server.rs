@@ -4,16 +4,56 @@
 //! objects.
 
 extern crate alloc;
-use alloc::{string::String, sync::Arc};
+use alloc::{string::String, sync::Arc, vec::Vec, format};
 use spin::RwLock;
 use core::{str, cmp};
+use serde_json::Value;
 
 use crate::{path::Path,
             println,
             thread,
-            message::{self, Message, MessageData},
+            message::{self, diag, Message, MessageData},
             syscalls::{self, CommHandle, malloc}};
 
+/// Path at which a service's parent (usually init) mounts a
+/// handle to receive the startup handshake.
+pub const READY_PATH: &str = "/ready";
+
+/// Tell the process which started this service that it has
+/// finished initializing and can handle messages.
+///
+/// Replies message::READY to the parent's request on the handle
+/// mounted at READY_PATH, then removes the mount. Does nothing if
+/// there is no such mount, for example if the service was started
+/// from a shell.
+pub fn signal_ready() {
+    signal_status(Message::Short(message::READY, 0, 0));
+}
+
+/// Tell the process which started this service that it
+/// failed to initialize. Sends Short(message::ERROR, error, 0)
+pub fn signal_failed(error: syscalls::SyscallError) {
+    signal_status(Message::Short(message::ERROR, error.as_u64(), 0));
+}
+
+/// The parent sends Short(READY, 0, 0) and waits, with a timeout,
+/// for the reply. If it has stopped waiting then the handle is
+/// closed and receive fails.
+fn signal_status(msg: Message) {
+    if let Ok(handle) = syscalls::open(READY_PATH, message::O_WRITE) {
+        match syscalls::receive(&handle) {
+            Ok(Message::Short(message::READY, _, _)) => {
+                if let Err((err, _)) = syscalls::send(&handle, msg) {
+                    println!("[server] Couldn't send ready handshake: {}", err);
+                }
+            }
+            Ok(_) => println!("[server] Unexpected ready handshake request"),
+            Err(err) => println!("[server] Couldn't receive ready handshake: {}", err)
+        }
+        let _ = syscalls::umount(READY_PATH);
+    }
+}
+
 pub trait FileLike {
     /// Number of bytes in the file
     fn len(&self) -> usize;
@@ -29,6 +69,16 @@ pub trait FileLike {
     fn clear(&mut self) -> Result<(), syscalls::SyscallError> {
         Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED)
     }
+    /// Reserve space for the file to grow to `len` bytes,
+    /// without changing its length
+    fn allocate(&mut self, _len: usize) -> Result<(), syscalls::SyscallError> {
+        Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED)
+    }
+    /// Write any buffered data to storage. Files which aren't
+    /// buffered have nothing to do.
+    fn flush(&mut self) -> Result<(), syscalls::SyscallError> {
+        Ok(())
+    }
 }
 
 pub trait DirLike {
@@ -49,6 +99,9 @@ pub trait DirLike {
         Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED)
     }
     /// Lookup and remove subdirectory, returning the shared reference
+    ///
+    /// Should fail with SYSCALL_ERROR_NOT_EMPTY if the subdirectory
+    /// has contents.
     fn remove_dir(&mut self, _name: &str) -> Result<Arc<RwLock<dyn DirLike + Sync + Send>>, syscalls::SyscallError> {
         Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED)
     }
@@ -56,14 +109,194 @@ pub trait DirLike {
     fn remove_file(&mut self, _name: &str) -> Result<Arc<RwLock<dyn FileLike + Sync + Send>>, syscalls::SyscallError> {
         Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED)
     }
+    /// Add an existing file, replacing any file with the same name
+    fn add_file(&mut self, _name: &str, _file: Arc<RwLock<dyn FileLike + Sync + Send>>) -> Result<(), syscalls::SyscallError> {
+        Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED)
+    }
+    /// Run a diagnostic command, returning the driver's internal
+    /// state as a JSON object. See message::DIAG
+    fn diag(&mut self, _command: diag::Command) -> Result<String, syscalls::SyscallError> {
+        Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED)
+    }
+    /// Write buffered data in this directory and everything below it
+    /// to storage. See message::SYNC
+    fn sync(&mut self) -> Result<(), syscalls::SyscallError> {
+        Ok(())
+    }
+}
+
+/// Reply to a Short(SYNC, 0, 0) message with OK or ERROR
+fn reply_sync(comm_handle: &CommHandle,
+              result: Result<(), syscalls::SyscallError>)
+              -> Result<(), (syscalls::SyscallError, syscalls::Message)> {
+    syscalls::send(comm_handle,
+                   match result {
+                       Ok(()) => syscalls::Message::Short(message::OK, 0, 0),
+                       Err(sys_err) => syscalls::Message::Short(
+                           message::ERROR, sys_err.as_u64(), 0)
+                   })
+}
+
+/// Reply to a Short(DIAG, command, argument) message
+///
+/// Calls `f` to run the command. Used by handle_directory, and by
+/// drivers with their own message loop. Commands other than
+/// DUMP_STATE change the driver, so are denied if not `readwrite`.
+pub fn reply_diag<F>(comm_handle: &CommHandle,
+                     command: u64,
+                     argument: u64,
+                     readwrite: bool,
+                     f: F)
+where
+    F: FnOnce(diag::Command) -> Result<String, syscalls::SyscallError>,
+{
+    let result = match diag::Command::from_message(command, argument) {
+        Some(diag::Command::DumpState) => f(diag::Command::DumpState),
+        Some(_) if !readwrite => Err(syscalls::SYSCALL_ERROR_DENIED),
+        Some(command) => f(command),
+        None => Err(syscalls::SYSCALL_ERROR_PARAM)
+    };
+    let reply = match result {
+        Ok(state) => {
+            let mem_handle = syscalls::MemoryHandle::from_u8_slice(state.as_bytes());
+            syscalls::Message::Long(message::JSON,
+                                    (state.len() as u64).into(),
+                                    mem_handle.into())
+        }
+        Err(sys_err) => syscalls::Message::Short(message::ERROR, sys_err.as_u64(), 0)
+    };
+    if let Err((err, _msg)) = syscalls::send(comm_handle, reply) {
+        println!("[std:reply_diag] Reply failed: {}", err);
+    }
+}
+
+/// Find the directory containing a relative path
+///
+/// Returns the directory and the final path component
+fn parent_dir<'a>(
+    mut dir: Arc<RwLock<dyn DirLike + Sync + Send>>,
+    path: &'a str
+) -> Result<(Arc<RwLock<dyn DirLike + Sync + Send>>, &'a str), syscalls::SyscallError> {
+    let mut components = path.split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .peekable();
+    while let Some(component) = components.next() {
+        if component == ".." {
+            // Can't leave the directory
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        if components.peek().is_none() {
+            return Ok((dir, component));
+        }
+        let subdir = dir.read().get_dir(component)?;
+        dir = subdir;
+    }
+    // Empty path
+    Err(syscalls::SYSCALL_ERROR_PARAM)
+}
+
+/// Move the file at `from` to `to`, replacing any file at `to`
+///
+/// The locks on both parent directories are held while the file is
+/// moved, so other threads never see it missing from both, or in
+/// both. Fails with SYSCALL_ERROR_PARAM if `to` is a directory.
+fn rename(directory: &Arc<RwLock<dyn DirLike + Sync + Send>>,
+          from: &str, to: &str) -> Result<(), syscalls::SyscallError> {
+    let (from_dir, from_name) = parent_dir(directory.clone(), from)?;
+    let (to_dir, to_name) = parent_dir(directory.clone(), to)?;
+
+    // Compare addresses without the vtable
+    let from_ptr = Arc::as_ptr(&from_dir) as *const u8;
+    let to_ptr = Arc::as_ptr(&to_dir) as *const u8;
+
+    if from_ptr == to_ptr {
+        let mut dir = from_dir.write();
+        if dir.get_dir(to_name).is_ok() {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        let file = dir.remove_file(from_name)?;
+        if let Err(err) = dir.add_file(to_name, file.clone()) {
+            // Put the file back
+            let _ = dir.add_file(from_name, file);
+            return Err(err);
+        }
+        return Ok(());
+    }
+
+    // Lock in address order, so that two renames between the
+    // same directories can't deadlock
+    let (mut from_guard, mut to_guard) = if from_ptr < to_ptr {
+        let from_guard = from_dir.write();
+        (from_guard, to_dir.write())
+    } else {
+        let to_guard = to_dir.write();
+        (from_dir.write(), to_guard)
+    };
+    if to_guard.get_dir(to_name).is_ok() {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+    let file = from_guard.remove_file(from_name)?;
+    if let Err(err) = to_guard.add_file(to_name, file.clone()) {
+        // Put the file back
+        let _ = from_guard.add_file(from_name, file);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Apply one operation from a BATCH message
+fn apply_batch_op(directory: &Arc<RwLock<dyn DirLike + Sync + Send>>,
+                  op: &Value) -> Result<(), syscalls::SyscallError> {
+    let path = op["path"].as_str().ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+    let (dir, name) = parent_dir(directory.clone(), path)?;
+
+    match op["op"].as_str() {
+        Some("create") => {
+            dir.write().make_file(name)?;
+        }
+        Some("mkdir") => {
+            dir.write().make_dir(name)?;
+        }
+        Some("remove") => {
+            dir.write().remove_file(name)?;
+        }
+        Some("rename") => {
+            let to = op["to"].as_str().ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+            rename(directory, path, to)?;
+        }
+        _ => return Err(syscalls::SYSCALL_ERROR_PARAM)
+    }
+    Ok(())
+}
+
+/// Apply a BATCH of operations, returning a JSON array
+/// with one status code per operation
+fn apply_batch(directory: &Arc<RwLock<dyn DirLike + Sync + Send>>,
+               ops: &[Value]) -> String {
+    let codes: Vec<String> = ops.iter()
+        .map(|op| match apply_batch_op(directory, op) {
+            Ok(()) => String::from("0"),
+            Err(err) => format!("{}", err.as_u64())
+        }).collect();
+    format!("[{}]", codes.join(","))
 }
 
 /// Open a file or directory
 ///
-/// This will create files but not directories
+/// This will create files but not directories. O_CREATE and
+/// O_TRUNCATE require O_WRITE, and O_TRUNCATE can't be combined
+/// with O_APPEND, as in Rust's std::fs::OpenOptions.
 fn open(mut dir: Arc<RwLock<dyn DirLike + Sync + Send>>, path: &Path, flags: u64) -> Result<CommHandle, syscalls::SyscallError> {
     println!("[std:open] Opening {:?}", path);
 
+    if (flags & (message::O_CREATE | message::O_TRUNCATE)) != 0 &&
+        (flags & message::O_WRITE) == 0 {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+    if (flags & message::O_TRUNCATE) != 0 && (flags & message::O_APPEND) != 0 {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+
     let mut path_iter = path.iter().peekable();
     while let Some(component) = path_iter.next()  {
         // Convert to a string for indexing
@@ -73,6 +306,10 @@ fn open(mut dir: Arc<RwLock<dyn DirLike + Sync + Send>>, path: &Path, flags: u64
         if let Ok(subdir) = result_subdir {
             if path_iter.peek().is_none() {
                 // No further path components => Opening an existing directory
+                if (flags & message::O_DIRECTORY) == 0 {
+                    // Expected a file
+                    return Err(syscalls::SYSCALL_ERROR_IS_DIR);
+                }
                 let readwrite = (flags & message::O_WRITE) == message::O_WRITE;
                 println!("Starting handle_directory({}, rw:{})", key, readwrite);
 
@@ -100,6 +337,9 @@ fn open(mut dir: Arc<RwLock<dyn DirLike + Sync + Send>>, path: &Path, flags: u64
                     return Err(syscalls::SYSCALL_ERROR_NOT_DIR);
                 }
                 // No more components -> Opening an existing file
+                if (flags & message::O_DIRECTORY) == message::O_DIRECTORY {
+                    return Err(syscalls::SYSCALL_ERROR_NOT_DIR);
+                }
 
                 if (flags & message::O_TRUNCATE) == message::O_TRUNCATE {
                     // Delete contents
@@ -111,8 +351,9 @@ fn open(mut dir: Arc<RwLock<dyn DirLike + Sync + Send>>, path: &Path, flags: u64
 
                 // Start a thread
                 if (flags & message::O_WRITE) == message::O_WRITE {
+                    let append = (flags & message::O_APPEND) == message::O_APPEND;
                     thread::spawn(move || {
-                        handle_file_readwrite(file, handle);
+                        handle_file_readwrite(file, handle, append);
                     })?; // Might fail to start thread
                 } else {
                     thread::spawn(move || {
@@ -126,14 +367,16 @@ fn open(mut dir: Arc<RwLock<dyn DirLike + Sync + Send>>, path: &Path, flags: u64
                 // Missing a directory
                 println!("Error opening path {:?}: {} not found", path, key);
                 return Err(syscalls::SYSCALL_ERROR_NOTFOUND);
-            } else if (flags & message::O_CREATE) == message::O_CREATE {
-                // Create a file
+            } else if (flags & message::O_CREATE) == message::O_CREATE &&
+                (flags & message::O_DIRECTORY) == 0 {
+                // Create a file. Directories are created with MKDIR
 
                 let new_file = dir.write().make_file(key)?;
                 let (handle, client_handle) = syscalls::new_rendezvous()?;
 
+                let append = (flags & message::O_APPEND) == message::O_APPEND;
                 thread::spawn(move || {
-                    handle_file_readwrite(new_file, handle);
+                    handle_file_readwrite(new_file, handle, append);
                 })?;
 
                 return Ok(client_handle);
@@ -189,10 +432,111 @@ where
     }
 }
 
+/// Convert the 64-bit offset and length of a READ request into
+/// the (start, length) range to read from a file of `file_len` bytes.
+///
+/// An offset which can't be addressed is an error rather than being
+/// silently truncated. Lengths are clamped to the end of the file.
+fn read_range(file_len: usize, start: u64, length: u64)
+              -> Result<(usize, usize), syscalls::SyscallError> {
+    let start = usize::try_from(start)
+        .map_err(|_| syscalls::SYSCALL_ERROR_PARAM)?;
+    let length = usize::try_from(length).unwrap_or(usize::MAX);
+    Ok((start, cmp::min(file_len.saturating_sub(start), length)))
+}
+
+/// Read data from a file and send it as the reply to
+/// Short(READ, start, length)
+fn reply_read(f: &dyn FileLike,
+              comm_handle: &CommHandle,
+              start: u64,
+              length: u64) -> Result<(), (syscalls::SyscallError, Message)> {
+    let error = |sys_err: syscalls::SyscallError| {
+        syscalls::send(comm_handle,
+                       syscalls::Message::Short(
+                           message::ERROR, sys_err.as_u64(), 0))
+    };
+
+    let (start, len) = match read_range(f.len(), start, length) {
+        Ok((_, 0)) => return error(syscalls::SYSCALL_ERROR_NO_DATA),
+        // Larger reads are short, since the data is sent in one chunk
+        Ok((start, len)) => (start, len.min(syscalls::MAX_CHUNK_SIZE)),
+        Err(sys_err) => return error(sys_err)
+    };
+
+    // Allocate memory
+    let mut mem_handle = match malloc(len as u64, 0) {
+        Ok((mem_handle, _)) => mem_handle,
+        Err(sys_err) => return error(sys_err)
+    };
+    // Read data
+    match f.read(start, mem_handle.as_mut_slice(len)) {
+        Ok(nbytes) => syscalls::send(comm_handle,
+                                     syscalls::Message::Long(
+                                         message::DATA,
+                                         (nbytes as u64).into(),
+                                         mem_handle.into())),
+        Err(sys_err) => error(sys_err)
+    }
+}
+
+/// Reply to Short(QUERY, _, _) sent to a file
+///
+/// The JSON reply has the file "type" and its "size" in bytes.
+/// See fs::File::metadata
+fn reply_file_query(f: &dyn FileLike,
+                    comm_handle: &CommHandle) -> Result<(), (syscalls::SyscallError, Message)> {
+    let info = format!("{{\"type\": \"file\", \"size\": {}}}", f.len());
+    let mem_handle = syscalls::MemoryHandle::from_u8_slice(info.as_bytes());
+    syscalls::send(comm_handle,
+                   syscalls::Message::Long(
+                       message::JSON,
+                       (info.len() as u64).into(),
+                       mem_handle.into()))
+}
+
+/// Convert the offset and whence of a SEEK request into a
+/// position in a file of `file_len` bytes.
+///
+/// Positions before the start are an error. Positions past the end
+/// are clamped to the end unless `past_end` is true.
+fn seek_position(file_len: usize, offset: u64, whence: u64, past_end: bool)
+                 -> Result<u64, syscalls::SyscallError> {
+    let position = match whence {
+        message::SEEK_START => offset,
+        message::SEEK_END => u64::try_from(file_len as i128 + offset as i64 as i128)
+            .map_err(|_| syscalls::SYSCALL_ERROR_PARAM)?,
+        _ => return Err(syscalls::SYSCALL_ERROR_PARAM)
+    };
+    if past_end {
+        Ok(position)
+    } else {
+        Ok(cmp::min(position, file_len as u64))
+    }
+}
+
+/// Reply to Short(SEEK, offset, whence) with the new position
+fn reply_seek(comm_handle: &CommHandle,
+              result: Result<u64, syscalls::SyscallError>)
+              -> Result<(), (syscalls::SyscallError, Message)> {
+    syscalls::send(comm_handle,
+                   match result {
+                       Ok(position) => syscalls::Message::Short(message::OK, position, 0),
+                       Err(sys_err) => syscalls::Message::Short(
+                           message::ERROR, sys_err.as_u64(), 0)
+                   })
+}
+
 /// Serve messages received from a communication channel
 /// reading and writing data from a file
+///
+/// Writes are at a position which is moved by the end of each READ
+/// and WRITE, and by SEEK. If `append` is true then each write is
+/// instead at the end of the file (O_APPEND).
 fn handle_file_readwrite(file: Arc<RwLock<dyn FileLike + Sync + Send>>,
-                         comm_handle: CommHandle) {
+                         comm_handle: CommHandle,
+                         append: bool) {
+    let mut position: usize = 0;
     dispatch_loop(
         &comm_handle,
         |msg| {
@@ -202,13 +546,27 @@ fn handle_file_readwrite(file: Arc<RwLock<dyn FileLike + Sync + Send>>,
                     MessageData::Value(length),
                     MessageData::MemoryHandle(handle)) => {
 
-                    // Append data to file
-                    if let Err((err, _msg)) = match file.write().write(0, handle.as_slice::<u8>(length as usize)) {
+                    // Write data to file. A length which can't be
+                    // addressed, or is longer than a chunk, is an
+                    // error rather than truncated
+                    let result = usize::try_from(length).ok()
+                        .filter(|&length| length <= syscalls::MAX_CHUNK_SIZE)
+                        .ok_or(syscalls::SYSCALL_ERROR_PARAM)
+                        .and_then(|length| {
+                            let mut file = file.write();
+                            if append {
+                                position = file.len();
+                            }
+                            file.write(position, handle.as_slice::<u8>(length))
+                        });
+                    if let Err((err, _msg)) = match result {
                         Ok(written) => {
-                            // Return success
+                            // Return success, and the new position
+                            position += written;
                             syscalls::send(&comm_handle,
                                            syscalls::Message::Short(
-                                               message::OK, written as u64, 0))
+                                               message::OK, written as u64,
+                                               position as u64))
                         },
                         Err(sys_err) => {
                             // Return error
@@ -224,30 +582,60 @@ fn handle_file_readwrite(file: Arc<RwLock<dyn FileLike + Sync + Send>>,
                 syscalls::Message::Short(
                     message::READ, start, length) => {
 
-                    let f = file.read();
-                    let len = cmp::min(f.len(), length as usize);
+                    let file = file.read();
+                    if let Ok((start, len)) = read_range(file.len(), start, length) {
+                        position = start + len;
+                    }
+                    if let Err((err, _msg)) = reply_read(&*file, &comm_handle,
+                                                         start, length) {
+                        // Failed to send reply
+                        println!("[std:handle_file_rw] Reply failed: {}", err);
+                    }
+                },
+                syscalls::Message::Short(
+                    message::SEEK, offset, whence) => {
+
+                    let result = seek_position(file.read().len(), offset, whence, true)
+                        .and_then(|new_position| {
+                            position = usize::try_from(new_position)
+                                .map_err(|_| syscalls::SYSCALL_ERROR_PARAM)?;
+                            Ok(new_position)
+                        });
+                    if let Err((err, _msg)) = reply_seek(&comm_handle, result) {
+                        // Failed to send reply
+                        println!("[std:handle_file_rw] Reply failed: {}", err);
+                    }
+                },
+                syscalls::Message::Short(
+                    message::FALLOCATE, length, _) => {
 
-                    if let Err((err, _msg)) = if len == 0 {
-                        // No data
-                        syscalls::send(&comm_handle,
-                                       syscalls::Message::Short(
-                                           message::ERROR,
-                                           syscalls::SYSCALL_ERROR_NO_DATA.as_u64(), 0))
-                    } else {
-                        // Allocate memory
-                        let (mut mem_handle, _) = malloc(len as u64, 0).unwrap();
-                        // Read data
-                        match f.read(start as usize, mem_handle.as_mut_slice(len)) {
-                            Ok(nbytes) => syscalls::send(&comm_handle,
-                                                         syscalls::Message::Long(
-                                                             message::DATA,
-                                                             (nbytes as u64).into(),
-                                                             mem_handle.into())),
-                            Err(sys_err) => syscalls::send(&comm_handle,
-                                                           syscalls::Message::Short(
-                                                               message::ERROR, sys_err.as_u64(), 0))
-                        }
-                    } {
+                    let result = usize::try_from(length)
+                        .map_err(|_| syscalls::SYSCALL_ERROR_NO_SPACE)
+                        .and_then(|length| file.write().allocate(length));
+                    if let Err((err, _msg)) = syscalls::send(
+                        &comm_handle,
+                        match result {
+                            Ok(()) => syscalls::Message::Short(message::OK, 0, 0),
+                            Err(sys_err) => syscalls::Message::Short(
+                                message::ERROR, sys_err.as_u64(), 0)
+                        }) {
+                        // Failed to send reply
+                        println!("[std:handle_file_rw] Reply failed: {}", err);
+                    }
+                },
+                syscalls::Message::Short(
+                    message::QUERY, _, _) => {
+
+                    if let Err((err, _msg)) = reply_file_query(&*file.read(), &comm_handle) {
+                        // Failed to send reply
+                        println!("[std:handle_file_rw] Reply failed: {}", err);
+                    }
+                },
+                syscalls::Message::Short(
+                    message::SYNC, _, _) => {
+
+                    if let Err((err, _msg)) = reply_sync(&comm_handle,
+                                                         file.write().flush()) {
                         // Failed to send reply
                         println!("[std:handle_file_rw] Reply failed: {}", err);
                     }
@@ -270,30 +658,25 @@ fn handle_file_readonly(file: Arc<RwLock<dyn FileLike + Sync + Send>>,
                 syscalls::Message::Short(
                     message::READ, start, length) => {
 
-                    let f = file.read();
-                    let len = cmp::min(f.len(), length as usize);
+                    if let Err((err, _msg)) = reply_read(&*file.read(), &comm_handle,
+                                                         start, length) {
+                        // Failed to send reply
+                        println!("[std:handle_file_ro] Reply failed: {}", err);
+                    }
+                }
+                syscalls::Message::Short(
+                    message::SEEK, offset, whence) => {
 
-                    if let Err((err, _msg)) = if len == 0 {
-                        // No data
-                        syscalls::send(&comm_handle,
-                                       syscalls::Message::Short(
-                                           message::ERROR,
-                                           syscalls::SYSCALL_ERROR_NO_DATA.as_u64(), 0))
-                    } else {
-                        // Allocate memory
-                        let (mut mem_handle, _) = malloc(len as u64, 0).unwrap();
-                        // Read data
-                        match f.read(start as usize, mem_handle.as_mut_slice(len)) {
-                            Ok(nbytes) => syscalls::send(&comm_handle,
-                                                         syscalls::Message::Long(
-                                                             message::DATA,
-                                                             (nbytes as u64).into(),
-                                                             mem_handle.into())),
-                            Err(sys_err) => syscalls::send(&comm_handle,
-                                                           syscalls::Message::Short(
-                                                               message::ERROR, sys_err.as_u64(), 0))
-                        }
-                    } {
+                    let result = seek_position(file.read().len(), offset, whence, false);
+                    if let Err((err, _msg)) = reply_seek(&comm_handle, result) {
+                        // Failed to send reply
+                        println!("[std:handle_file_ro] Reply failed: {}", err);
+                    }
+                }
+                syscalls::Message::Short(
+                    message::QUERY, _, _) => {
+
+                    if let Err((err, _msg)) = reply_file_query(&*file.read(), &comm_handle) {
                         // Failed to send reply
                         println!("[std:handle_file_ro] Reply failed: {}", err);
                     }
@@ -365,6 +748,93 @@ where
                         println!("[std:handle_directory] Reply failed: {}", err);
                     }
                 }
+                Message::Long(
+                    message::RMDIR,
+                    MessageData::Value(length),
+                    MessageData::MemoryHandle(handle)) => {
+                    // Delete an empty directory
+
+                    if !readwrite {
+                        // Error! Read-only
+                        if let Err((err, _msg)) = syscalls::send(&comm_handle,
+                                                                 syscalls::Message::Short(
+                                                                     message::ERROR_DENIED, 0, 0)) {
+                            // Failed to send reply
+                            println!("[std:handle_directory] Reply failed: {}", err);
+                        }
+                        return;
+                    }
+
+                    // Get the path string
+                    let u8_slice = handle.as_slice::<u8>(length as usize);
+                    if let Err((err, _msg)) = if let Ok(path) = str::from_utf8(u8_slice) {
+                        match directory.write().remove_dir(path) {
+                            Ok(_) => syscalls::send(&comm_handle,
+                                           syscalls::Message::Short(
+                                               message::OK, 0, 0)),
+                            Err(sys_err) =>
+                                syscalls::send(&comm_handle,
+                                               syscalls::Message::Short(
+                                                   message::ERROR, sys_err.as_u64(), 0))
+                        }
+                    } else {
+                        // UTF-8 error
+                        syscalls::send(&comm_handle,
+                                       syscalls::Message::Short(
+                                       message::ERROR_INVALID_UTF8, 0, 0))
+                    } {
+                        // Failed to send reply
+                        println!("[std:handle_directory] Reply failed: {}", err);
+                    }
+                }
+                Message::Long(
+                    message::RENAME,
+                    MessageData::Value(length),
+                    MessageData::MemoryHandle(handle)) => {
+                    // Move a file
+
+                    if !readwrite {
+                        // Error! Read-only
+                        if let Err((err, _msg)) = syscalls::send(&comm_handle,
+                                                                 syscalls::Message::Short(
+                                                                     message::ERROR_DENIED, 0, 0)) {
+                            // Failed to send reply
+                            println!("[std:handle_directory] Reply failed: {}", err);
+                        }
+                        return;
+                    }
+
+                    let u8_slice = handle.as_slice::<u8>(length as usize);
+                    if let Err((err, _msg)) = if let Ok(s) = str::from_utf8(u8_slice) {
+                        match serde_json::from_str::<Value>(s) {
+                            Ok(value) => match (value["path"].as_str(), value["to"].as_str()) {
+                                (Some(from), Some(to)) => match rename(&directory, from, to) {
+                                    Ok(()) => syscalls::send(&comm_handle,
+                                                             syscalls::Message::Short(
+                                                                 message::OK, 0, 0)),
+                                    Err(sys_err) =>
+                                        syscalls::send(&comm_handle,
+                                                       syscalls::Message::Short(
+                                                           message::ERROR, sys_err.as_u64(), 0))
+                                },
+                                _ => syscalls::send(&comm_handle,
+                                                    syscalls::Message::Short(
+                                                        message::ERROR_INVALID_FORMAT, 0, 0))
+                            },
+                            _ => syscalls::send(&comm_handle,
+                                                syscalls::Message::Short(
+                                                    message::ERROR_INVALID_FORMAT, 0, 0))
+                        }
+                    } else {
+                        // UTF-8 error
+                        syscalls::send(&comm_handle,
+                                       syscalls::Message::Short(
+                                       message::ERROR_INVALID_UTF8, 0, 0))
+                    } {
+                        // Failed to send reply
+                        println!("[std:handle_directory] Reply failed: {}", err);
+                    }
+                }
                 Message::Long(
                     message::MKDIR,
                     MessageData::Value(length),
@@ -404,6 +874,49 @@ where
                         println!("[std:handle_directory] Reply failed: {}", err);
                     }
                 }
+                Message::Long(
+                    message::BATCH,
+                    MessageData::Value(length),
+                    MessageData::MemoryHandle(handle)) => {
+                    // Apply a list of operations
+
+                    if !readwrite {
+                        // Error! Read-only
+                        if let Err((err, _msg)) = syscalls::send(&comm_handle,
+                                                                 syscalls::Message::Short(
+                                                                     message::ERROR_DENIED, 0, 0)) {
+                            // Failed to send reply
+                            println!("[std:handle_directory] Reply failed: {}", err);
+                        }
+                        return;
+                    }
+
+                    let u8_slice = handle.as_slice::<u8>(length as usize);
+                    if let Err((err, _msg)) = if let Ok(s) = str::from_utf8(u8_slice) {
+                        match serde_json::from_str::<Value>(s) {
+                            Ok(Value::Array(ops)) => {
+                                let result = apply_batch(&directory, &ops);
+                                let mem_handle = syscalls::MemoryHandle::from_u8_slice(result.as_bytes());
+                                syscalls::send(&comm_handle,
+                                               syscalls::Message::Long(
+                                                   message::JSON,
+                                                   (result.len() as u64).into(),
+                                                   mem_handle.into()))
+                            }
+                            _ => syscalls::send(&comm_handle,
+                                                syscalls::Message::Short(
+                                                    message::ERROR_INVALID_FORMAT, 0, 0))
+                        }
+                    } else {
+                        // UTF-8 error
+                        syscalls::send(&comm_handle,
+                                       syscalls::Message::Short(
+                                       message::ERROR_INVALID_UTF8, 0, 0))
+                    } {
+                        // Failed to send reply
+                        println!("[std:handle_directory] Reply failed: {}", err);
+                    }
+                }
                 Message::Long(
                     tag,
                     MessageData::Value(length),
@@ -415,9 +928,9 @@ where
                     let u8_slice = handle.as_slice::<u8>(length as usize);
                     if let Err((err, _msg)) = if let Ok(path) = str::from_utf8(u8_slice) {
                         let path = path.trim_start_matches('/');
-                        let result = if flags == message::O_READ {
+                        let result = if (flags & !message::O_DIRECTORY) == message::O_READ {
                             // Read-only
-                            open(directory.clone(), Path::new(path), message::O_READ)
+                            open(directory.clone(), Path::new(path), flags)
                         } else if readwrite {
                             // Write, truncate or create
                             open(directory.clone(), Path::new(path), flags)
@@ -455,6 +968,20 @@ where
                         println!("[std:handle_directory] Reply failed: {}", err);
                     }
                 }
+                Message::Short(
+                    message::DIAG, command, argument) => {
+                    reply_diag(&comm_handle, command, argument, readwrite,
+                               |command| directory.write().diag(command));
+                },
+                Message::Short(
+                    message::SYNC, _, _) => {
+                    // Allowed on read-only handles: doesn't change contents
+                    if let Err((err, _msg)) = reply_sync(&comm_handle,
+                                                         directory.write().sync()) {
+                        // Couldn't send reply
+                        println!("[std:handle_directory] Reply failed: {}", err);
+                    }
+                },
                 Message::Short(
                     message::QUERY, _, _) => {
                     // Return information about this handle in JSON format
@@ -478,3 +1005,175 @@ where
             }
         });
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::{read_range, reply_read, seek_position, parent_dir, apply_batch, open,
+                FileLike, DirLike};
+    use alloc::{string::String, sync::Arc, format};
+    use spin::RwLock;
+    use serde_json::Value;
+    use crate::syscalls::{self, SyscallError};
+    use crate::{message::{self, Message, MessageData}, path::Path, thread};
+
+    const GIB: u64 = 1 << 30;
+
+    /// A sparse file whose content is a function of the offset,
+    /// so that offsets beyond 4 GiB can be checked without storage
+    struct SparseFile(usize);
+
+    impl FileLike for SparseFile {
+        fn len(&self) -> usize {
+            self.0
+        }
+        fn read(&self, start: usize, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = ((start + i) >> 32) as u8 ^ (start + i) as u8;
+            }
+            Ok(buffer.len())
+        }
+    }
+
+    #[test_case]
+    fn read_range_beyond_4gib() {
+        let file_len = (6 * GIB) as usize;
+        let start = 5 * GIB + 3;
+        assert_eq!(read_range(file_len, start, 16),
+                   Ok((start as usize, 16)));
+        // Clamped to the end of the file, not truncated to 32 bits
+        assert_eq!(read_range(file_len, start, u64::MAX),
+                   Ok((start as usize, (GIB - 3) as usize)));
+        // Past the end of the file
+        assert_eq!(read_range(file_len, 7 * GIB, 16),
+                   Ok(((7 * GIB) as usize, 0)));
+    }
+
+    #[test_case]
+    fn sparse_read_beyond_4gib() {
+        let (server, client) = syscalls::new_rendezvous().unwrap();
+        thread::spawn(move || {
+            let file = SparseFile((5 * GIB) as usize);
+            reply_read(&file, &server, 4 * GIB + 1, 4).unwrap();
+            reply_read(&file, &server, 5 * GIB, 4).unwrap();
+        }).unwrap();
+
+        match syscalls::receive(&client) {
+            Ok(Message::Long(message::DATA,
+                             MessageData::Value(4),
+                             MessageData::MemoryHandle(data))) => {
+                // High 32 bits of the offset are 4
+                assert_eq!(data.as_slice::<u8>(4), [4 ^ 1, 4 ^ 2, 4 ^ 3, 4 ^ 4]);
+            }
+            reply => panic!("Unexpected reply {:?}", reply)
+        }
+        // At the end of the file
+        match syscalls::receive(&client) {
+            Ok(Message::Short(message::ERROR, code, _)) => {
+                assert_eq!(code, syscalls::SYSCALL_ERROR_NO_DATA.as_u64());
+            }
+            reply => panic!("Unexpected reply {:?}", reply)
+        }
+    }
+
+    #[test_case]
+    fn seek_positions() {
+        assert_eq!(seek_position(100, 10, message::SEEK_START, false), Ok(10));
+        assert_eq!(seek_position(100, -10i64 as u64, message::SEEK_END, false), Ok(90));
+        // Before the start
+        assert_eq!(seek_position(100, -101i64 as u64, message::SEEK_END, true),
+                   Err(syscalls::SYSCALL_ERROR_PARAM));
+        // Past the end: Only allowed if writable
+        assert_eq!(seek_position(100, 10, message::SEEK_END, true), Ok(110));
+        assert_eq!(seek_position(100, 200, message::SEEK_START, false), Ok(100));
+        assert_eq!(seek_position(100, 0, 1, true), Err(syscalls::SYSCALL_ERROR_PARAM));
+    }
+
+    /// A directory with no contents
+    struct EmptyDir;
+
+    impl DirLike for EmptyDir {
+        fn get_dir(&self, _name: &str) -> Result<Arc<RwLock<dyn DirLike + Sync + Send>>, SyscallError> {
+            Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+        }
+        fn get_file(&self, _name: &str) -> Result<Arc<RwLock<dyn FileLike + Sync + Send>>, SyscallError> {
+            Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+        }
+        fn query(&self) -> String {
+            String::new()
+        }
+    }
+
+    /// A directory containing subdirectory "dir" and file "file"
+    struct MixedDir;
+
+    impl DirLike for MixedDir {
+        fn get_dir(&self, name: &str) -> Result<Arc<RwLock<dyn DirLike + Sync + Send>>, SyscallError> {
+            match name {
+                "dir" => Ok(Arc::new(RwLock::new(EmptyDir))),
+                _ => Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+            }
+        }
+        fn get_file(&self, name: &str) -> Result<Arc<RwLock<dyn FileLike + Sync + Send>>, SyscallError> {
+            match name {
+                "file" => Ok(Arc::new(RwLock::new(SparseFile(0)))),
+                _ => Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+            }
+        }
+        fn query(&self) -> String {
+            String::new()
+        }
+    }
+
+    #[test_case]
+    fn open_file_as_directory() {
+        let dir: Arc<RwLock<dyn DirLike + Sync + Send>> = Arc::new(RwLock::new(MixedDir));
+        assert_eq!(open(dir.clone(), Path::new("file"), message::O_DIRECTORY).err(),
+                   Some(syscalls::SYSCALL_ERROR_NOT_DIR));
+        // Not created either
+        assert_eq!(open(dir, Path::new("new"),
+                        message::O_WRITE + message::O_CREATE + message::O_DIRECTORY).err(),
+                   Some(syscalls::SYSCALL_ERROR_NOTFOUND));
+    }
+
+    #[test_case]
+    fn open_directory_as_file() {
+        let dir: Arc<RwLock<dyn DirLike + Sync + Send>> = Arc::new(RwLock::new(MixedDir));
+        assert_eq!(open(dir.clone(), Path::new("dir"), message::O_READ).err(),
+                   Some(syscalls::SYSCALL_ERROR_IS_DIR));
+        assert_eq!(open(dir, Path::new("dir"), message::O_WRITE).err(),
+                   Some(syscalls::SYSCALL_ERROR_IS_DIR));
+    }
+
+    #[test_case]
+    fn create_and_truncate_need_write() {
+        let dir: Arc<RwLock<dyn DirLike + Sync + Send>> = Arc::new(RwLock::new(MixedDir));
+        assert_eq!(open(dir.clone(), Path::new("file"), message::O_TRUNCATE).err(),
+                   Some(syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(open(dir.clone(), Path::new("new"), message::O_CREATE).err(),
+                   Some(syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(open(dir, Path::new("file"),
+                        message::O_WRITE + message::O_TRUNCATE + message::O_APPEND).err(),
+                   Some(syscalls::SYSCALL_ERROR_PARAM));
+    }
+
+    #[test_case]
+    fn batch_parent_dir() {
+        let dir: Arc<RwLock<dyn DirLike + Sync + Send>> = Arc::new(RwLock::new(EmptyDir));
+        assert_eq!(parent_dir(dir.clone(), "/./file").map(|(_, name)| name).ok(), Some("file"));
+        assert_eq!(parent_dir(dir.clone(), "sub/file").err(), Some(syscalls::SYSCALL_ERROR_NOTFOUND));
+        assert_eq!(parent_dir(dir.clone(), "../file").err(), Some(syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(parent_dir(dir, "/").err(), Some(syscalls::SYSCALL_ERROR_PARAM));
+    }
+
+    #[test_case]
+    fn batch_per_op_status() {
+        let dir: Arc<RwLock<dyn DirLike + Sync + Send>> = Arc::new(RwLock::new(EmptyDir));
+        let ops: Value = serde_json::from_str(
+            r#"[{"op": "create", "path": "a"}, {"op": "bad", "path": "b"}]"#).unwrap();
+        // Both fail, but each has its own status
+        assert_eq!(apply_batch(&dir, ops.as_array().unwrap()),
+                   format!("[{},{}]",
+                           syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED.as_u64(),
+                           syscalls::SYSCALL_ERROR_PARAM.as_u64()));
+    }
+}
@@ -0,0 +1,79 @@
+//! Signal masking
+//!
+//! Signals are numbered 0 to 31. A thread can block signals while
+//! it is in a critical section, for example while holding a lock
+//! which a signal handler also takes.
+//!
+//! Masks are per-thread. Signals raised while masked stay pending,
+//! and are delivered when the mask is lowered. While a handler runs
+//! its own signal is masked, so that the handler is not re-entered;
+//! other signals can still interrupt it unless also masked.
+//!
+//! EuraliOS only
+//!
+//! Note: The kernel keeps per-thread masks and pending signals, but
+//! doesn't yet have a way to raise signals or run handlers.
+//!
+//! # Example
+//!
+//! ```no_run
+//! {
+//!     let _guard = signal::block(signal::ALL)?;
+//!     // Critical section: no signal handlers run here
+//! } // Previous mask restored
+//! ```
+
+use crate::syscalls::{self, SyscallError};
+
+/// Mask containing all signals
+pub const ALL: u32 = 0xFFFF_FFFF;
+
+/// Mask containing a single signal
+pub const fn mask_of(signal: u32) -> u32 {
+    1 << (signal % 32)
+}
+
+/// Blocks signals for its lifetime.
+///
+/// Created by `block`. When dropped the previous mask is restored,
+/// so guards can be nested.
+#[must_use = "signals are unblocked when the guard is dropped"]
+#[derive(Debug)]
+pub struct SignalMaskGuard {
+    previous: u32
+}
+
+/// Block the signals in `mask` until the returned guard is dropped
+pub fn block(mask: u32) -> Result<SignalMaskGuard, SyscallError> {
+    let previous = syscalls::signal_block(mask)?;
+    Ok(SignalMaskGuard{previous})
+}
+
+impl SignalMaskGuard {
+    /// The mask which will be restored
+    pub fn previous(&self) -> u32 {
+        self.previous
+    }
+}
+
+impl Drop for SignalMaskGuard {
+    fn drop(&mut self) {
+        // Pending signals which are unmasked will now be delivered
+        let _ = syscalls::signal_mask(self.previous);
+    }
+}
+
+#[test_case]
+fn nested_guards_restore_mask() {
+    let original = syscalls::signal_mask(0).unwrap();
+    {
+        let outer = block(mask_of(1)).unwrap();
+        assert_eq!(outer.previous(), 0);
+        {
+            let inner = block(mask_of(2)).unwrap();
+            assert_eq!(inner.previous(), mask_of(1));
+        }
+        assert_eq!(syscalls::signal_block(0).unwrap(), mask_of(1));
+    }
+    assert_eq!(syscalls::signal_mask(original).unwrap(), 0);
+}
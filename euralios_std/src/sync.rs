@@ -0,0 +1,377 @@
+//! Synchronization between threads and processes
+//!
+//! Usage:
+//!
+//! ```ignore
+//! let counter = SharedCounter::new()?;
+//! syscalls::send(&handle, message::Message::Long(
+//!     message::DATA, 0.into(), counter.share()?.into()));
+//! counter.increment();
+//! ```
+//!
+//! The receiver wraps the memory with `SharedCounter::from_handle`.
+//!
+//! `Mutex` and `Condvar` are like those in std::sync, for threads
+//! in one process. Waiting threads are suspended with futex_wait
+//! rather than spinning, so use no CPU time.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::syscalls::{self, MemoryHandle, SyscallError};
+
+/// A 64-bit counter which can be shared between processes
+///
+/// # Lifetime
+///
+/// The counter is a memory chunk mapped by every process holding it.
+/// Each `SharedCounter` (or the `MemoryHandle` from `share`) refers
+/// to the same frame, which is freed when the last of them is
+/// dropped. Dropping one holder never affects the others.
+///
+/// # Memory ordering
+///
+/// `add` is an atomic read-modify-write, so concurrent increments
+/// from any number of threads or processes are never lost. Operations
+/// use `Ordering::Relaxed`: the counter is for statistics and
+/// accounting, and does not order any other memory accesses. Use
+/// messages to synchronize data.
+pub struct SharedCounter {
+    memory: MemoryHandle
+}
+
+impl SharedCounter {
+    /// A new counter, starting at zero
+    pub fn new() -> Result<Self, SyscallError> {
+        let (memory, _) = syscalls::malloc(
+            core::mem::size_of::<AtomicU64>() as u64, 0)?;
+        Ok(Self{memory})
+    }
+
+    /// Use memory shared by `SharedCounter::share`
+    pub fn from_handle(memory: MemoryHandle) -> Self {
+        Self{memory}
+    }
+
+    /// A handle to the counter memory, which can be sent to another process
+    pub fn share(&self) -> Result<MemoryHandle, SyscallError> {
+        self.memory.share()
+    }
+
+    fn atomic(&self) -> &AtomicU64 {
+        // Memory chunks are page aligned and live as long as self
+        unsafe {self.memory.as_ref::<AtomicU64>()}
+    }
+
+    /// Add to the counter, returning the previous value
+    pub fn add(&self, value: u64) -> u64 {
+        self.atomic().fetch_add(value, Ordering::Relaxed)
+    }
+
+    /// Add one to the counter, returning the previous value
+    pub fn increment(&self) -> u64 {
+        self.add(1)
+    }
+
+    /// The current value
+    pub fn get(&self) -> u64 {
+        self.atomic().load(Ordering::Relaxed)
+    }
+}
+
+/// Returned by a lock when another thread panicked while holding it
+///
+/// Panics in EuraliOS don't unwind, so this is never returned. It is
+/// here so that code written for std::sync, e.g. `lock().unwrap()`,
+/// also works with this module.
+pub struct PoisonError<T> {
+    guard: T
+}
+
+impl<T> PoisonError<T> {
+    /// The guard, ignoring the poisoning
+    pub fn into_inner(self) -> T {
+        self.guard
+    }
+}
+
+impl<T> fmt::Debug for PoisonError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PoisonError")
+    }
+}
+
+pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
+
+/// Mutex states. Threads only call futex_wait in state LOCKED_WAITING,
+/// so unlocking only calls futex_wake if a thread may be waiting.
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const LOCKED_WAITING: u32 = 2;
+
+/// Wait on a futex word, ignoring errors
+///
+/// SYSCALL_ERROR_WOULDBLOCK means the value changed, and callers check
+/// it again in a loop. Atomics are aligned, and have been used so are
+/// in mapped memory.
+fn futex_wait(futex: &AtomicU32, expected: u32) {
+    let _ = syscalls::futex_wait(futex as *const AtomicU32 as *const u32, expected);
+}
+
+fn futex_wake(futex: &AtomicU32, count: usize) {
+    let _ = syscalls::futex_wake(futex as *const AtomicU32 as *const u32, count);
+}
+
+/// A mutual exclusion lock protecting data of type T
+///
+/// An uncontended `lock` is a single compare_exchange. If the mutex
+/// is held then the thread waits with futex_wait until unlocked.
+///
+/// The futex is keyed by physical address, so the mutex must stay in
+/// memory mapped by this process, e.g. a static or on the heap.
+pub struct Mutex<T: ?Sized> {
+    state: AtomicU32,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// Gives access to the data in a Mutex, and unlocks it when dropped
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a Mutex<T>
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    /// A new unlocked Mutex
+    pub const fn new(data: T) -> Self {
+        Mutex{state: AtomicU32::new(UNLOCKED),
+              data: UnsafeCell::new(data)}
+    }
+
+    /// Consume the Mutex, returning the data
+    pub fn into_inner(self) -> LockResult<T> {
+        Ok(self.data.into_inner())
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Wait until the Mutex is unlocked, then lock it
+    ///
+    /// Must not be called by a thread already holding the lock,
+    /// or the thread will wait forever.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        if self.state.compare_exchange(UNLOCKED, LOCKED,
+                                       Ordering::Acquire,
+                                       Ordering::Relaxed).is_err() {
+            self.lock_contended();
+        }
+        Ok(MutexGuard{mutex: self})
+    }
+
+    fn lock_contended(&self) {
+        // Mark as having waiters before waiting. If this finds the
+        // mutex unlocked then it is locked, conservatively marked
+        // as having waiters.
+        while self.state.swap(LOCKED_WAITING, Ordering::Acquire) != UNLOCKED {
+            futex_wait(&self.state, LOCKED_WAITING);
+        }
+    }
+
+    /// Lock the Mutex if it is not already locked
+    ///
+    /// Returns None rather than waiting if it is locked.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.state.compare_exchange(UNLOCKED, LOCKED,
+                                    Ordering::Acquire,
+                                    Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard{mutex: self})
+    }
+
+    /// Mutable access to the data, without locking because
+    /// the borrow checker ensures there are no other references
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        Ok(self.data.get_mut())
+    }
+
+    fn unlock(&self) {
+        if self.state.swap(UNLOCKED, Ordering::Release) == LOCKED_WAITING {
+            futex_wake(&self.state, 1);
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("Mutex").field("data", &&*guard).finish(),
+            None => f.write_str("Mutex { <locked> }")
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // The lock is held, so there are no other references
+        unsafe {&*self.mutex.data.get()}
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe {&mut *self.mutex.data.get()}
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A condition variable, to wait for a change to data in a Mutex
+///
+/// Threads waiting with `wait` are woken by `notify_one` or
+/// `notify_all`. As with std::sync::Condvar, threads may also wake
+/// without a notification, so should check their condition in a loop
+/// or use `wait_while`.
+pub struct Condvar {
+    /// Incremented by each notification. Waiting threads wait for
+    /// it to change from the value before the mutex was unlocked,
+    /// so notifications after unlocking are not missed.
+    sequence: AtomicU32
+}
+
+impl Condvar {
+    /// A new condition variable, with no waiting threads
+    pub const fn new() -> Self {
+        Condvar{sequence: AtomicU32::new(0)}
+    }
+
+    /// Unlock the mutex and wait for a notification, then lock the
+    /// mutex again before returning
+    pub fn wait<'a, T: ?Sized>(
+        &self,
+        guard: MutexGuard<'a, T>
+    ) -> LockResult<MutexGuard<'a, T>> {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        futex_wait(&self.sequence, sequence);
+        mutex.lock()
+    }
+
+    /// Wait while `condition` returns true for the data in the mutex
+    pub fn wait_while<'a, T: ?Sized, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F
+    ) -> LockResult<MutexGuard<'a, T>>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
+
+    /// Wake one waiting thread, if any are waiting
+    pub fn notify_one(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.sequence, 1);
+    }
+
+    /// Wake all waiting threads
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.sequence, usize::MAX);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Condvar::new()
+    }
+}
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Condvar { .. }")
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::thread;
+
+    #[test_case]
+    fn shared_counter() {
+        let counter = SharedCounter::new().unwrap();
+        let view = SharedCounter::from_handle(counter.share().unwrap());
+        assert_eq!(counter.get(), 0);
+
+        assert_eq!(counter.increment(), 0);
+        assert_eq!(view.add(5), 1);
+        assert_eq!(counter.get(), 6);
+
+        // Counter remains valid after the other holder drops
+        drop(counter);
+        assert_eq!(view.get(), 6);
+    }
+
+    #[test_case]
+    fn mutex_try_lock() {
+        let mutex = Mutex::new(1);
+        {
+            let mut guard = mutex.lock().unwrap();
+            *guard += 1;
+            assert!(mutex.try_lock().is_none());
+        }
+        assert_eq!(*mutex.try_lock().unwrap(), 2);
+        assert_eq!(mutex.into_inner().unwrap(), 2);
+    }
+
+    #[test_case]
+    fn producer_consumer() {
+        const COUNT: u32 = 100;
+        /// Most items in the buffer, so the producer also waits
+        const CAPACITY: usize = 4;
+        static BUFFER: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+        static CHANGED: Condvar = Condvar::new();
+
+        thread::spawn(|| {
+            for item in 0..COUNT {
+                let mut buffer = CHANGED.wait_while(
+                    BUFFER.lock().unwrap(),
+                    |buffer| buffer.len() >= CAPACITY).unwrap();
+                buffer.push(item);
+                CHANGED.notify_all();
+            }
+        }).unwrap();
+
+        for expected in 0..COUNT {
+            let mut buffer = CHANGED.wait_while(
+                BUFFER.lock().unwrap(),
+                |buffer| buffer.is_empty()).unwrap();
+            assert!(buffer.len() <= CAPACITY);
+            assert_eq!(buffer.remove(0), expected);
+            CHANGED.notify_all();
+        }
+        assert!(BUFFER.lock().unwrap().is_empty());
+    }
+}
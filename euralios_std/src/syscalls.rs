@@ -1,13 +1,18 @@
 use core::arch::asm;
 use core::{fmt, ptr, slice, clone::Clone};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 extern crate alloc;
 use alloc::string::String;
 use alloc::string::ToString;
+use alloc::vec::Vec;
 
 pub use crate::message::{self, Message};
 use crate::debug_println;
 use crate::ffi::OsStr;
+use crate::path::{Path, PathBuf};
+use crate::gzip;
+use crate::time;
 
 /// Communication handle
 #[derive(Debug)]
@@ -45,6 +50,16 @@ impl Drop for CommHandle {
     }
 }
 
+/// Close a communication handle
+///
+/// The same as dropping it. If no other handle to the Rendezvous is
+/// left except the other end, then a thread waiting on the other end
+/// returns SYSCALL_ERROR_CLOSED, as do later sends and receives on
+/// it. Handles are also closed when a process exits.
+pub fn close(handle: CommHandle) {
+    drop(handle);
+}
+
 impl Clone for CommHandle {
     /// Makes a copy of a communication handle
     fn clone(&self) -> Self {
@@ -66,6 +81,13 @@ impl Clone for CommHandle {
     }
 }
 
+/// Largest memory chunk in bytes (1Gb)
+///
+/// A whole chunk is moved when sent in a message, so this is
+/// also the most data one message can carry. Must match
+/// MAX_CHUNK_PAGES in the kernel.
+pub const MAX_CHUNK_SIZE: usize = 512 * 512 * 4096;
+
 /// Handle to a chunk of memory that can be
 /// passed to other processes and free'd when dropped
 ///
@@ -78,6 +100,12 @@ impl MemoryHandle {
         MemoryHandle(virtaddr)
     }
 
+    /// A new memory chunk containing a copy of `values`
+    ///
+    /// Sending the handle in a message moves the chunk to the
+    /// receiver; use `share` to keep a mapping.
+    ///
+    /// Panics if `values` is empty or longer than MAX_CHUNK_SIZE.
     pub fn from_u8_slice(values: &[u8]) -> Self {
         // Allocate memory
         let (mem_handle, _) = malloc(values.len() as u64, 0).unwrap();
@@ -131,6 +159,76 @@ impl MemoryHandle {
     pub unsafe fn as_mut_ptr<T>(&mut self) -> *mut T {
         self.0 as *mut T
     }
+
+    /// A new handle to the same memory
+    ///
+    /// Unlike other memory handles, sending the new handle to another
+    /// process doesn't remove the memory from this process: both refer
+    /// to the same frames. The memory is freed when all handles to it
+    /// have been dropped.
+    ///
+    /// EuraliOS only
+    pub fn share(&self) -> Result<MemoryHandle, SyscallError> {
+        self._share(0)
+    }
+
+    /// A new read-only handle to the same memory
+    ///
+    /// As `share`, but the memory can't be written through the new
+    /// handle: writes are page faults. This handle keeps write access,
+    /// so the receiver sees any changes made through it. Useful for
+    /// sending large buffers without copying.
+    ///
+    /// Sharing a read-only handle again gives another read-only
+    /// handle; `share` on a read-only handle returns
+    /// SYSCALL_ERROR_PARAM.
+    ///
+    /// EuraliOS only
+    pub fn share_read_only(&self) -> Result<MemoryHandle, SyscallError> {
+        self._share(SHARE_READ_ONLY)
+    }
+
+    /// Number of bytes mapped in the chunk
+    ///
+    /// Memory received from another process may be smaller than
+    /// a message says, so check before accessing it.
+    ///
+    /// EuraliOS only
+    pub fn size(&self) -> Result<usize, SyscallError> {
+        let error: u64;
+        let size: usize;
+        unsafe {
+            asm!("syscall",
+                 in("rax") SYSCALL_MEMORY_SIZE,
+                 in("rdi") self.0,
+                 lateout("rax") error,
+                 lateout("rdi") size,
+                 out("rcx") _,
+                 out("r11") _);
+        }
+        if error != 0 {
+            return Err(SyscallError(error));
+        }
+        Ok(size)
+    }
+
+    fn _share(&self, flags: u64) -> Result<MemoryHandle, SyscallError> {
+        let error: u64;
+        let virtaddr: u64;
+        unsafe {
+            asm!("syscall",
+                 in("rax") SYSCALL_SHARE_MEMORY | flags,
+                 in("rdi") self.0, // First argument
+                 lateout("rax") error,
+                 lateout("rdi") virtaddr,
+                 out("rcx") _,
+                 out("r11") _);
+        }
+        if error != 0 {
+            return Err(SyscallError(error));
+        }
+        Ok(MemoryHandle(virtaddr))
+    }
 }
 
 impl Drop for MemoryHandle {
@@ -166,6 +264,63 @@ impl SyscallError {
     pub fn as_u64(&self) -> u64 {
         self.0
     }
+
+    /// Classify the error into a general category
+    pub fn kind(&self) -> ErrorKind {
+        match *self {
+            SYSCALL_ERROR_SEND_BLOCKING |
+            SYSCALL_ERROR_RECV_BLOCKING => ErrorKind::WouldBlock,
+            SYSCALL_ERROR_INVALID_HANDLE => ErrorKind::InvalidHandle,
+            SYSCALL_ERROR_MEMALLOC |
+            SYSCALL_ERROR_NOMEMSLOTS => ErrorKind::OutOfMemory,
+            SYSCALL_ERROR_PARAM |
+            SYSCALL_ERROR_UTF8 => ErrorKind::InvalidInput,
+            SYSCALL_ERROR_NOTFOUND => ErrorKind::NotFound,
+            SYSCALL_ERROR_CLOSED => ErrorKind::Closed,
+            SYSCALL_ERROR_EXISTS => ErrorKind::AlreadyExists,
+            SYSCALL_ERROR_NOT_IMPLEMENTED => ErrorKind::Unsupported,
+            SYSCALL_ERROR_NOT_DIR => ErrorKind::NotADirectory,
+            SYSCALL_ERROR_NO_DATA => ErrorKind::NoData,
+            SYSCALL_ERROR_TIMEOUT => ErrorKind::TimedOut,
+            SYSCALL_ERROR_DENIED => ErrorKind::PermissionDenied,
+            SYSCALL_ERROR_INVALID_DATA |
+            SYSCALL_ERROR_UNEXPECTED |
+            SYSCALL_ERROR_NOT_ELF |
+            SYSCALL_ERROR_ELF_SEGMENT |
+            SYSCALL_ERROR_ELF_PARSE => ErrorKind::InvalidData,
+            SYSCALL_ERROR_NO_SPACE => ErrorKind::NoSpace,
+            SYSCALL_ERROR_IS_DIR => ErrorKind::IsADirectory,
+            _ => ErrorKind::Other
+        }
+    }
+}
+
+/// General categories of SyscallError
+///
+/// Similar to std::io::ErrorKind, but only the categories
+/// which EuraliOS syscalls can currently return.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Operation would have blocked. Trying again may succeed
+    WouldBlock,
+    InvalidHandle,
+    OutOfMemory,
+    InvalidInput,
+    NotFound,
+    /// The other end of a Rendezvous was closed
+    Closed,
+    AlreadyExists,
+    Unsupported,
+    NotADirectory,
+    NoData,
+    TimedOut,
+    PermissionDenied,
+    /// Data received was malformed
+    InvalidData,
+    /// Storage is full
+    NoSpace,
+    IsADirectory,
+    Other,
 }
 
 /// Spawn a new thread with a given entry point
@@ -193,6 +348,7 @@ pub fn thread_spawn(
              "mov rdi, r9", // Function argument
              "call r8",
              "mov rax, 1", // exit_current_thread syscall
+             "mov rdi, 0", // exit code
              "syscall",
              // New thread never leaves this asm block
              "2:",
@@ -210,11 +366,57 @@ pub fn thread_spawn(
     Ok(tid)
 }
 
+/// Start a new thread in this process, running `entry`
+///
+/// The thread shares this process' memory, and has its own stack of
+/// at least `stack_size` bytes, or the default (28k) if zero. Stacks
+/// can be just under 2Mb, otherwise returns SYSCALL_ERROR_PARAM. The
+/// thread exits with code 0 when `entry` returns.
+///
+/// Returns the new thread ID, which can be passed to `wait`.
+///
+/// EuraliOS only
+pub fn spawn(entry: fn(), stack_size: usize) -> Result<u64, SyscallError> {
+    // Called by the kernel on the new thread's stack
+    extern "C" fn spawn_start(entry: usize) -> ! {
+        let entry: fn() = unsafe {core::mem::transmute(entry)};
+        entry();
+        thread_exit()
+    }
+
+    let tid: u64;
+    let error: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SPAWN_THREAD,
+             in("rdi") spawn_start as usize,
+             in("rsi") entry as usize,
+             in("rdx") stack_size,
+             lateout("rax") error,
+             lateout("rdi") tid,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(tid)
+}
+
 /// Exit the current thread. Never returns.
 pub fn thread_exit() -> ! {
+    exit(0)
+}
+
+/// Exit the current thread with an exit code. Never returns.
+///
+/// The kernel keeps the code, keyed by thread ID, until it is
+/// returned by wait.
+pub fn exit(code: u64) -> ! {
     unsafe {
         asm!("syscall",
              in("rax") SYSCALL_EXIT_THREAD,
+             in("rdi") code,
              options(noreturn));
     }
 }
@@ -223,6 +425,7 @@ pub fn thread_exit() -> ! {
 ///
 /// Usually called when a thread has nothing useful to do
 /// and can wait for an undetermined amount of time.
+/// Returns immediately if no other thread can run.
 pub fn thread_yield() {
     unsafe{
         asm!("syscall",
@@ -232,7 +435,15 @@ pub fn thread_yield() {
     }
 }
 
+/// Same as `thread_yield`, with the name used by std::thread
+pub fn yield_now() {
+    thread_yield()
+}
+
 /// Wait for a message to be received
+///
+/// If no message has been sent then the thread is suspended, and
+/// doesn't run until a message arrives.
 pub fn receive(handle: &CommHandle) -> Result<Message, SyscallError> {
     let ctrl: u64;
     let (data1, data2, data3): (u64, u64, u64);
@@ -254,6 +465,70 @@ pub fn receive(handle: &CommHandle) -> Result<Message, SyscallError> {
     Err(SyscallError(err))
 }
 
+/// Receive a message without waiting
+///
+/// Returns SYSCALL_ERROR_WOULDBLOCK immediately if no thread is
+/// waiting to send. Otherwise the same as `receive`.
+pub fn try_receive(handle: &CommHandle) -> Result<Message, SyscallError> {
+    let ctrl: u64;
+    let (data1, data2, data3): (u64, u64, u64);
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_TRY_RECEIVE,
+             in("rdi") handle.0,
+             lateout("rax") ctrl,
+             lateout("rdi") data1,
+             lateout("rsi") data2,
+             lateout("rdx") data3,
+             out("rcx") _,
+             out("r11") _);
+    }
+    let err = ctrl & 0xFF;
+    if err == 0 {
+        return Ok(Message::from_values(ctrl, data1, data2, data3));
+    }
+    Err(SyscallError(err))
+}
+
+/// Largest number of handles in one await_any call
+pub const MAX_AWAIT_HANDLES: usize = 16;
+
+/// Wait for a message on any of several handles
+///
+/// Returns the index of the handle which received, and the
+/// message. If more than one handle has a message waiting then the
+/// lowest index receives. Otherwise the thread is suspended until a
+/// message is sent to one of the handles.
+pub fn await_any(handles: &[CommHandle]) -> Result<(usize, Message), SyscallError> {
+    if handles.is_empty() || handles.len() > MAX_AWAIT_HANDLES {
+        return Err(SYSCALL_ERROR_PARAM);
+    }
+    let mut ids = [0u32; MAX_AWAIT_HANDLES];
+    for (id, handle) in ids.iter_mut().zip(handles) {
+        *id = handle.0;
+    }
+
+    let ctrl: u64;
+    let (data1, data2, data3): (u64, u64, u64);
+    let index: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_AWAIT_ANY,
+             inlateout("rdi") ids.as_ptr() as u64 => data1,
+             inlateout("rsi") handles.len() as u64 => data2,
+             lateout("rax") ctrl,
+             lateout("rdx") data3,
+             lateout("r8") index,
+             out("rcx") _,
+             out("r11") _);
+    }
+    let err = ctrl & 0xFF;
+    if err == 0 {
+        return Ok((index as usize, Message::from_values(ctrl, data1, data2, data3)));
+    }
+    Err(SyscallError(err))
+}
+
 /// Send a message and wait for it to be received
 ///
 /// If an error occurs then a message is returned.
@@ -297,6 +572,56 @@ pub fn send(
                                                  data1, data2, data3)))
 }
 
+/// Send a message and wait up to `timeout_us` microseconds for it to be received
+///
+/// If no receiver takes the message in time then SYSCALL_ERROR_TIMEOUT
+/// is returned along with the message, so that any memory handle
+/// it contains can be reused or freed. A timed out message is
+/// never partially delivered.
+///
+/// Note: The timeout is checked on timer interrupts, so the wait
+/// may be longer than requested.
+pub fn send_timeout(
+    handle: &CommHandle,
+    mut message: Message,
+    timeout_us: u64
+) -> Result<(), (SyscallError, Message)> {
+
+    let (ctrl, data1, data2, data3) = message.to_values().map_err(|e| (e, message))?;
+
+    let ret_ctrl: u64;
+    let ret_data1: u64;
+    let ret_data2: u64;
+    let ret_data3: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SEND_TIMEOUT | ctrl | ((handle.0 as u64) << 32),
+             in("rdi") data1,
+             in("rsi") data2,
+             in("rdx") data3,
+             in("r8") timeout_us,
+             lateout("rax") ret_ctrl,
+             lateout("rdi") ret_data1,
+             lateout("rsi") ret_data2,
+             lateout("rdx") ret_data3,
+             out("rcx") _,
+             out("r11") _);
+    }
+    let err = ret_ctrl & (SYSCALL_ERROR_MASK as u64);
+    if err == 0 {
+        return Ok(());
+    }
+    if ret_ctrl & (SYSCALL_ERROR_CONTAINS_MESSAGE as u64) != 0 {
+        // Error. Original message not valid, new message returned
+        return Err((SyscallError(err),
+                    Message::from_values(ret_ctrl,
+                                         ret_data1, ret_data2, ret_data3)));
+    }
+    // Error, original message still valid
+    Err((SyscallError(err), Message::from_values(ctrl,
+                                                 data1, data2, data3)))
+}
+
 /// Send a message and wait for a message back from the same thread
 ///
 ///
@@ -339,16 +664,79 @@ pub fn send_receive(
                                                  data1, data2, data3)))
 }
 
+/// As `send_receive`, but waits at most `timeout_us` microseconds
+///
+/// The timeout covers both waiting for the message to be received and
+/// waiting for the reply. On timeout SYSCALL_ERROR_TIMEOUT is returned,
+/// with the message if it wasn't received. If the receiver replies
+/// after the timeout then its reply is discarded, rather than being
+/// received by the next call on this handle.
+///
+/// Note: The timeout is checked on timer interrupts, so the wait
+/// may be longer than requested.
+pub fn send_receive_timeout(
+    handle: &CommHandle,
+    mut message: Message,
+    timeout_us: u64
+) -> Result<Message, (SyscallError, Message)> {
+
+    let (ctrl, data1, data2, data3) = message.to_values().map_err(|e| (e, message))?;
+
+    let (ret_ctrl, ret_data1, ret_data2, ret_data3): (u64, u64, u64, u64);
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SENDRECEIVE_TIMEOUT | ctrl | ((handle.0 as u64) << 32),
+             in("rdi") data1,
+             in("rsi") data2,
+             in("rdx") data3,
+             in("r8") timeout_us,
+             lateout("rax") ret_ctrl,
+             lateout("rdi") ret_data1,
+             lateout("rsi") ret_data2,
+             lateout("rdx") ret_data3,
+             out("rcx") _,
+             out("r11") _);
+    }
+    let err = ret_ctrl & (SYSCALL_ERROR_MASK as u64);
+    if err == 0 {
+        return Ok(Message::from_values(ret_ctrl,
+                                       ret_data1, ret_data2, ret_data3));
+    }
+    if ret_ctrl & (SYSCALL_ERROR_CONTAINS_MESSAGE as u64) != 0 {
+        // Error. Original message not valid, new message returned
+        return Err((SyscallError(err),
+                    Message::from_values(ret_ctrl,
+                                         ret_data1, ret_data2, ret_data3)));
+    }
+    // Error, original message still valid
+    Err((SyscallError(err), Message::from_values(ctrl,
+                                                 data1, data2, data3)))
+}
+
 /// Returns a handle on success, or an error code
 ///
 /// flags   zero (0) for readonly, or a combination (sum) of O_WRITE,
-///         O_CREATE and O_TRUNCATE
+///         O_CREATE, O_TRUNCATE, O_DIRECTORY and O_APPEND
+///
+/// Note: Opening a mount point itself doesn't check O_DIRECTORY,
+///       because the path isn't sent to the server.
 #[inline]
 pub fn open<T: AsRef<OsStr>>(path: T, flags: u64) -> Result<CommHandle, SyscallError> {
     _open(path.as_ref().to_str().unwrap(), flags)
 }
 
-fn _open(path: &str, flags: u64) -> Result<CommHandle, SyscallError> {
+/// Open the mount point which a path is in, without sending
+/// the rest of the path to it.
+///
+/// Returns the handle to the mount and the number of bytes of
+/// `path` which matched the mount path.
+///
+/// EuraliOS only
+pub fn open_mount<T: AsRef<OsStr>>(path: T) -> Result<(CommHandle, usize), SyscallError> {
+    _open_mount(path.as_ref().to_str().unwrap())
+}
+
+fn _open_mount(path: &str) -> Result<(CommHandle, usize), SyscallError> {
     let error: u64;
     let handle: u32;
     let match_len: usize;
@@ -364,45 +752,50 @@ fn _open(path: &str, flags: u64) -> Result<CommHandle, SyscallError> {
              out("r11") _);
     }
     if error == 0 {
-        // Found mount point
-        let subpath = &path[match_len..];
-
-        if subpath.len() != 0 {
-            // Send unmatched part of the path to the given handle
-            let bytes = subpath.as_bytes();
-
-            match message::rcall(
-                &CommHandle(handle),
-                message::OPEN_READONLY + flags,
-                (bytes.len() as u64).into(),
-                MemoryHandle::from_u8_slice(bytes).into(),
-                None) {
-                // Success, returning a communication handle
-                Ok((message::COMM_HANDLE,
-                    message::MessageData::CommHandle(handle), _)) => {
-                    return Ok(handle);
-                }
-                Ok(_) => {
-                    // Unexpected message type
-                    return Err(SyscallError::new(0));
-                }
-                Err((err, _msg)) => {
-                    return Err(err);
-                }
-            }
-        }
-        Ok(CommHandle(handle))
+        Ok((CommHandle(handle), match_len))
     } else {
         Err(SyscallError(error))
     }
 }
 
+fn _open(path: &str, flags: u64) -> Result<CommHandle, SyscallError> {
+    // Find mount point
+    let (handle, match_len) = _open_mount(path)?;
+    let subpath = &path[match_len..];
+
+    if subpath.len() != 0 {
+        // Send unmatched part of the path to the given handle
+        let bytes = subpath.as_bytes();
+
+        match message::rcall(
+            &handle,
+            message::OPEN_READONLY + flags,
+            (bytes.len() as u64).into(),
+            MemoryHandle::from_u8_slice(bytes).into(),
+            None) {
+            // Success, returning a communication handle
+            Ok((message::COMM_HANDLE,
+                message::MessageData::CommHandle(handle), _)) => {
+                return Ok(handle);
+            }
+            Ok(_) => {
+                // Unexpected message type
+                return Err(SyscallError::new(0));
+            }
+            Err((err, _msg)) => {
+                return Err(err);
+            }
+        }
+    }
+    Ok(handle)
+}
+
 pub fn malloc(
     num_bytes: u64,
     max_physaddr: u64
 ) -> Result<(MemoryHandle, u64), SyscallError> {
 
-    if num_bytes == 0 {
+    if num_bytes == 0 || num_bytes > MAX_CHUNK_SIZE as u64 {
         return Err(SYSCALL_ERROR_PARAM);
     }
 
@@ -510,63 +903,250 @@ impl VFS {
 pub fn exec(
     bin: &[u8],
     flags: u8,
-    mut stdin: CommHandle,
-    mut stdout: CommHandle,
+    stdin: CommHandle,
+    stdout: CommHandle,
     vfs: VFS
 ) -> Result<u64, SyscallError> {
+    _exec(bin, flags, stdin, stdout, vfs, u64::MAX, &[])
+}
 
-    let param_str = vfs.as_str();
+/// Largest encoded size of the arguments passed to exec_args
+pub const MAX_ARGS_SIZE: usize = 4096;
 
-    let error: u64;
-    let tid: u64;
-    unsafe {
-        asm!("syscall",
-             // RAX contains | bin length (32) | param length (16) | flags (8) | syscall (8)
-             in("rax") SYSCALL_EXEC | ((flags as u64) << 8) | ((param_str.len() as u64) << 16) | ((bin.len() as u64) << 32),
-             // RDI contains pointer to ELF binary data
-             in("rdi") bin.as_ptr() as usize,
-             // RSI contains STDIN & STDOUT handles
-             in("rsi") ((stdin.take() as u64) << 32) | (stdout.take() as u64),
-             // RDX will contain a pointer to a parameter string
-             in("rdx") param_str.as_ptr() as usize,
-             lateout("rax") error,
-             lateout("rdi") tid,
-             out("rcx") _,
-             out("r11") _);
-    }
-    if error == 0 {
-        // Return new thread ID
-        Ok(tid)
-    } else {
-        Err(SyscallError(error))
+/// Address of this process' arguments, set by _start. 0 if none
+static ARGS_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn set_args_address(address: usize) {
+    ARGS_ADDRESS.store(address, Ordering::Relaxed);
+}
+
+/// Encode arguments for the kernel
+///
+/// A u32 count, followed by each argument as a u32 length followed
+/// by its bytes. All integers are little-endian.
+fn encode_args(args: &[&str]) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(args.len() as u32).to_le_bytes());
+    for arg in args {
+        data.extend_from_slice(&(arg.len() as u32).to_le_bytes());
+        data.extend_from_slice(arg.as_bytes());
     }
+    data
 }
 
-pub fn mount(path: &str, mut handle: CommHandle) -> Result<(), SyscallError> {
-    let error: u64;
-    unsafe {
-        asm!("syscall",
-             // RAX contains | handle (32) | empty (24) | syscall (8)
-             in("rax") SYSCALL_MOUNT | ((handle.take() as u64) << 32),
-             // RDI contains pointer to string data
-             in("rdi") path.as_ptr() as usize,
-             // RSI contains string length
-             in("rsi") path.len(),
-             lateout("rax") error,
-             out("rcx") _,
-             out("r11") _);
+/// Decode arguments encoded by encode_args. Ignores any data
+/// after the last argument. None if the data is truncated.
+fn decode_args(data: &[u8]) -> Option<Vec<&[u8]>> {
+    fn read_u32(data: &[u8], offset: usize) -> Option<usize> {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
     }
-    if error == 0 {
-        Ok(())
-    } else {
-        Err(SyscallError(error))
+    let count = read_u32(data, 0)?;
+    let mut args = Vec::new();
+    let mut offset = 4;
+    for _ in 0..count {
+        let length = read_u32(data, offset)?;
+        args.push(data.get(offset + 4..offset + 4 + length)?);
+        offset += 4 + length;
     }
+    Some(args)
 }
 
-pub fn list_mounts() -> Result<(MemoryHandle, u64), SyscallError> {
-    let error: u64;
-    let mem_handle: u64;
-    let length: u64;
+/// The arguments this process was started with
+///
+/// Empty if started by `exec` rather than `exec_args`. The first
+/// argument is usually the program name.
+///
+/// EuraliOS only
+pub fn args() -> Vec<&'static [u8]> {
+    let address = ARGS_ADDRESS.load(Ordering::Relaxed);
+    if address == 0 {
+        return Vec::new();
+    }
+    // The kernel maps a read-only page which is never unmapped
+    let page = unsafe{slice::from_raw_parts(address as *const u8, MAX_ARGS_SIZE)};
+    decode_args(page).unwrap_or_default()
+}
+
+/// Execute a new process with arguments
+///
+/// As `exec`, with `args` copied into the new process where they are
+/// returned by `args()` (and `env::args()`). The encoded arguments
+/// must fit in MAX_ARGS_SIZE bytes, otherwise SYSCALL_ERROR_PARAM is
+/// returned.
+///
+/// EuraliOS only
+pub fn exec_args(
+    bin: &[u8],
+    flags: u8,
+    stdin: CommHandle,
+    stdout: CommHandle,
+    vfs: VFS,
+    args: &[&str]
+) -> Result<u64, SyscallError> {
+    let data = encode_args(args);
+    if data.len() > MAX_ARGS_SIZE {
+        return Err(SYSCALL_ERROR_PARAM);
+    }
+    _exec(bin, flags | EXEC_ARGS, stdin, stdout, vfs, u64::MAX, &data)
+}
+
+/// Execute a gzip-compressed program with arguments
+///
+/// As `exec_args`, but `compressed` is a gzip stream which is
+/// expanded into this process' heap first. Streams which are corrupt
+/// return SYSCALL_ERROR_INVALID_DATA. The kernel copies the binary
+/// into its heap, so a stream claiming a size larger than the
+/// largest free kernel allocation (see `memory_stats`) returns
+/// SYSCALL_ERROR_PARAM without being expanded.
+///
+/// EuraliOS only
+pub fn exec_compressed(
+    compressed: &[u8],
+    flags: u8,
+    stdin: CommHandle,
+    stdout: CommHandle,
+    vfs: VFS,
+    args: &[&str]
+) -> Result<u64, SyscallError> {
+    let limit = memory_stats()?.heap_largest_free as usize;
+    let bin = gzip::decompress(compressed, limit).map_err(|err| {
+        debug_println!("[std:exec_compressed] {}", err);
+        SyscallError::from(err)
+    })?;
+    exec_args(&bin, flags, stdin, stdout, vfs, args)
+}
+
+/// Bit in a syscall filter which allows the given syscall
+pub const fn syscall_bit(syscall: u64) -> u64 {
+    1 << syscall
+}
+
+/// Execute a new process which can only use some syscalls
+///
+/// As `exec`, with `allowed` a bitmap of syscall numbers which the
+/// new process may use: bit N allows syscall N (see `syscall_bit`).
+/// The filter applies to all threads in the process, and to any
+/// process it starts, so it can only be tightened. Exiting is always
+/// allowed.
+///
+/// A forbidden syscall returns SYSCALL_ERROR_DENIED, unless `flags`
+/// contains EXEC_FILTER_KILL in which case the process is terminated.
+///
+/// EuraliOS only
+pub fn exec_filtered(
+    bin: &[u8],
+    flags: u8,
+    stdin: CommHandle,
+    stdout: CommHandle,
+    vfs: VFS,
+    allowed: u64
+) -> Result<u64, SyscallError> {
+    _exec(bin, flags | EXEC_SYSCALL_FILTER, stdin, stdout, vfs, allowed, &[])
+}
+
+/// As `exec_filtered`, with arguments as in `exec_args`
+///
+/// EuraliOS only
+pub fn exec_filtered_args(
+    bin: &[u8],
+    flags: u8,
+    stdin: CommHandle,
+    stdout: CommHandle,
+    vfs: VFS,
+    allowed: u64,
+    args: &[&str]
+) -> Result<u64, SyscallError> {
+    let data = encode_args(args);
+    if data.len() > MAX_ARGS_SIZE {
+        return Err(SYSCALL_ERROR_PARAM);
+    }
+    _exec(bin, flags | EXEC_SYSCALL_FILTER | EXEC_ARGS, stdin, stdout, vfs, allowed, &data)
+}
+
+fn _exec(
+    bin: &[u8],
+    flags: u8,
+    mut stdin: CommHandle,
+    mut stdout: CommHandle,
+    vfs: VFS,
+    allowed: u64,
+    args: &[u8]
+) -> Result<u64, SyscallError> {
+
+    let param_str = vfs.as_str();
+
+    let error: u64;
+    let tid: u64;
+    unsafe {
+        asm!("syscall",
+             // RAX contains | bin length (32) | param length (16) | flags (8) | syscall (8)
+             in("rax") SYSCALL_EXEC | ((flags as u64) << 8) | ((param_str.len() as u64) << 16) | ((bin.len() as u64) << 32),
+             // RDI contains pointer to ELF binary data
+             in("rdi") bin.as_ptr() as usize,
+             // RSI contains STDIN & STDOUT handles
+             in("rsi") ((stdin.take() as u64) << 32) | (stdout.take() as u64),
+             // RDX will contain a pointer to a parameter string
+             in("rdx") param_str.as_ptr() as usize,
+             // R8 contains the syscall filter
+             in("r8") allowed,
+             // R9 and R10 contain the encoded arguments
+             in("r9") args.as_ptr() as usize,
+             in("r10") args.len(),
+             lateout("rax") error,
+             lateout("rdi") tid,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error == 0 {
+        // Return new thread ID
+        Ok(tid)
+    } else {
+        Err(SyscallError(error))
+    }
+}
+
+pub fn mount(path: &str, mut handle: CommHandle) -> Result<(), SyscallError> {
+    let error: u64;
+    unsafe {
+        asm!("syscall",
+             // RAX contains | handle (32) | empty (24) | syscall (8)
+             in("rax") SYSCALL_MOUNT | ((handle.take() as u64) << 32),
+             // RDI contains pointer to string data
+             in("rdi") path.as_ptr() as usize,
+             // RSI contains string length
+             in("rsi") path.len(),
+             lateout("rax") error,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error == 0 {
+        Ok(())
+    } else {
+        Err(SyscallError(error))
+    }
+}
+
+/// Path at which init mounts a writer to the system console
+pub const CONSOLE_PATH: &str = "/dev/console";
+
+/// Get a writer to the system console (the F1 screen)
+///
+/// All programs share the same console. Each WRITE message is
+/// written in one piece, and print! sends one message per call, so
+/// lines from different programs don't interleave. There is no
+/// exclusive console: programs which need their own screen should
+/// be run in a shell on another console.
+///
+/// EuraliOS only
+pub fn console() -> Result<CommHandle, SyscallError> {
+    open(CONSOLE_PATH, message::O_WRITE)
+}
+
+pub fn list_mounts() -> Result<(MemoryHandle, u64), SyscallError> {
+    let error: u64;
+    let mem_handle: u64;
+    let length: u64;
     unsafe {
         asm!("syscall",
              in("rax") SYSCALL_LISTMOUNTS,
@@ -583,6 +1163,59 @@ pub fn list_mounts() -> Result<(MemoryHandle, u64), SyscallError> {
     }
 }
 
+/// Flush buffered writes in all mounted file systems
+///
+/// Sends SYNC to the server of each of this process' mounts in turn,
+/// and waits for it to reply. All mounts share one deadline,
+/// `timeout_us` microseconds from now. A mount which doesn't reply
+/// in time is logged and skipped; see `send_receive_timeout`.
+/// Servers which reply ERROR_UNKNOWN_MESSAGE don't buffer data, so
+/// have nothing to flush.
+///
+/// None of the file servers here buffer writes yet: the ramdisk is
+/// in memory, the FAT server is read-only and the ATA driver writes
+/// through. They reply OK at once (see server::DirLike::sync), so
+/// this only waits for servers which buffer, or which have hung.
+///
+/// # Returns
+///
+/// Ok once every mount has flushed. Otherwise the first error, after
+/// trying every mount: SYSCALL_ERROR_TIMEOUT if a mount didn't reply.
+///
+/// EuraliOS only
+pub fn sync(timeout_us: u64) -> Result<(), SyscallError> {
+    let (mounts, length) = list_mounts()?;
+    let mounts = core::str::from_utf8(mounts.as_slice::<u8>(length as usize))
+        .map_err(|_| SYSCALL_ERROR_UTF8)?;
+    let deadline = time::microseconds_monotonic().saturating_add(timeout_us);
+
+    let mut result = Ok(());
+    // List of quoted paths: ["/path","/other",]
+    for path in mounts.split('"').skip(1).step_by(2) {
+        let remaining = deadline.saturating_sub(time::microseconds_monotonic());
+        if let Err(err) = sync_mount(path, remaining) {
+            debug_println!("[std:sync] Mount {} not flushed: {}", path, err);
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+fn sync_mount(path: &str, timeout_us: u64) -> Result<(), SyscallError> {
+    let (handle, _) = open_mount(path)?;
+    match send_receive_timeout(&handle,
+                               Message::Short(message::SYNC, 0, 0),
+                               timeout_us) {
+        Ok(Message::Short(message::OK, _, _)) |
+        Ok(Message::Short(message::ERROR_UNKNOWN_MESSAGE, _, _)) => Ok(()),
+        Ok(Message::Short(message::ERROR, code, _)) => Err(SyscallError(code)),
+        Ok(_) => Err(SYSCALL_ERROR_PARAM),
+        Err((err, _message)) => Err(err)
+    }
+}
+
 pub fn umount(path: &str) -> Result<(), SyscallError> {
     let error: u64;
     unsafe {
@@ -604,12 +1237,801 @@ pub fn umount(path: &str) -> Result<(), SyscallError> {
     }
 }
 
-/// Wait for a hardware interrupt to occur
-pub fn await_interrupt() {
+/// Copy a string returned by getenv or listenv out of its chunk
+fn env_string(mem_handle: u64, length: u64) -> String {
+    if mem_handle == 0 {
+        return String::new(); // Empty, so no chunk
+    }
+    // Freed when the handle is dropped
+    let handle = MemoryHandle(mem_handle);
+    String::from_utf8_lossy(handle.as_slice::<u8>(length as usize)).into_owned()
+}
+
+/// Get an environment variable from the kernel
+///
+/// Each process has its own environment, copied from its parent
+/// when started by exec. None if the variable isn't set. See also
+/// env::var, which also reads the environment file.
+///
+/// EuraliOS only
+pub fn getenv(key: &str) -> Option<String> {
+    let error: u64;
+    let mem_handle: u64;
+    let length: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_GETENV,
+             in("rdi") key.as_ptr() as usize,
+             in("rsi") key.len(),
+             lateout("rax") error,
+             lateout("rdi") mem_handle,
+             lateout("rsi") length,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return None;
+    }
+    Some(env_string(mem_handle, length))
+}
+
+/// Set an environment variable of this process
+///
+/// Requires I/O privileges, otherwise SYSCALL_ERROR_DENIED. Only
+/// this process and processes it starts afterwards see the
+/// change. Keys can't be empty or contain '=' or NUL, and values
+/// can't contain NUL (SYSCALL_ERROR_PARAM). Keys are at most 64
+/// bytes, values 1024 bytes, and all variables together 4096 bytes
+/// (SYSCALL_ERROR_NO_SPACE). CWD_VAR can't be set
+/// (SYSCALL_ERROR_DENIED): use env::set_current_dir.
+///
+/// EuraliOS only
+pub fn setenv(key: &str, value: &str) -> Result<(), SyscallError> {
+    let error: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SETENV,
+             in("rdi") key.as_ptr() as usize,
+             in("rsi") key.len(),
+             in("rdx") value.as_ptr() as usize,
+             in("r8") value.len(),
+             lateout("rax") error,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error == 0 {
+        Ok(())
+    } else {
+        Err(SyscallError(error))
+    }
+}
+
+/// Remove an environment variable of this process
+///
+/// Returns SYSCALL_ERROR_NOTFOUND if it wasn't set, or
+/// SYSCALL_ERROR_DENIED for CWD_VAR or without I/O privileges.
+///
+/// EuraliOS only
+pub fn unsetenv(key: &str) -> Result<(), SyscallError> {
+    let error: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_UNSETENV,
+             in("rdi") key.as_ptr() as usize,
+             in("rsi") key.len(),
+             lateout("rax") error,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error == 0 {
+        Ok(())
+    } else {
+        Err(SyscallError(error))
+    }
+}
+
+/// All environment variables of this process, as (key, value) pairs:
+/// CWD_VAR if set, then the others in the order they were first set
+///
+/// EuraliOS only
+pub fn listenv() -> Result<Vec<(String, String)>, SyscallError> {
+    let error: u64;
+    let mem_handle: u64;
+    let length: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_LISTENV,
+             lateout("rax") error,
+             lateout("rdi") mem_handle,
+             lateout("rsi") length,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(parse_env_list(&env_string(mem_handle, length)))
+}
+
+/// Split KEY=VALUE strings, each followed by NUL
+fn parse_env_list(list: &str) -> Vec<(String, String)> {
+    list.split_terminator('\0')
+        .filter_map(|var| var.split_once('='))
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
+
+/// Environment variable through which the current working
+/// directory is read. It can't be changed with setenv.
+///
+/// EuraliOS only
+pub const CWD_VAR: &str = "PWD";
+
+/// The current working directory of this process
+///
+/// Relative paths used with euralios_std::fs are relative to this
+/// directory. It is read as the CWD_VAR environment variable, so is
+/// inherited by processes started with exec. "/" if not set.
+pub fn getcwd() -> PathBuf {
+    match getenv(CWD_VAR) {
+        Some(cwd) => PathBuf::from(cwd.as_str()),
+        None => PathBuf::from("/")
+    }
+}
+
+/// Set the current working directory to an absolute path
+///
+/// The path must be absolute (SYSCALL_ERROR_PARAM), and an existing
+/// directory, checked with its server (SYSCALL_ERROR_NOTFOUND or
+/// SYSCALL_ERROR_NOT_DIR). It is stored in canonical form. See
+/// env::set_current_dir for relative paths.
+///
+/// EuraliOS only
+pub fn chdir(path: &str) -> Result<(), SyscallError> {
+    if !path.starts_with('/') {
+        return Err(SYSCALL_ERROR_PARAM);
+    }
+    let path = crate::fs::canonicalize_dir(Path::new(path))?;
+    let path = path.as_os_str().to_str().ok_or(SYSCALL_ERROR_UTF8)?;
+
+    let error: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_CHDIR,
+             in("rdi") path.as_ptr() as usize,
+             in("rsi") path.len(),
+             lateout("rax") error,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error == 0 {
+        Ok(())
+    } else {
+        Err(SyscallError(error))
+    }
+}
+
+/// Interrupt lines which can be waited for with `await_irq`
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_SERIAL: u8 = 4;
+pub const IRQ_MOUSE: u8 = 12;
+
+/// Wait for a device interrupt to occur
+///
+/// The kernel reads from the device in its interrupt handler: a
+/// scancode for IRQ_KEYBOARD, received bytes for IRQ_SERIAL, or one
+/// byte of a movement packet for IRQ_MOUSE. Bytes which arrive while
+/// no thread is waiting are queued, and returned by later calls.
+///
+/// # Returns
+///
+/// The byte read, or SYSCALL_ERROR_PARAM if the interrupt line
+/// can't be waited for.
+pub fn await_irq(irq: u8) -> Result<u8, SyscallError> {
+    let error: u64;
+    let byte: u64;
     unsafe {
         asm!("syscall",
-             // RAX contains syscall
              in("rax") SYSCALL_AWAIT_INTERRUPT,
+             in("rdi") irq as u64,
+             lateout("rax") error,
+             lateout("rdi") byte,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(byte as u8)
+}
+
+/// Wait for a keyboard interrupt to occur
+///
+/// The kernel reads the keyboard controller, so that it can detect
+/// the recovery key combination. Scancodes which arrive while no
+/// thread is waiting are queued, and returned by later calls.
+///
+/// # Returns
+///
+/// The keyboard scancode
+pub fn await_interrupt() -> u8 {
+    await_irq(IRQ_KEYBOARD).unwrap_or(0)
+}
+
+/// Change the scheduler priority of the calling thread
+///
+/// A positive `delta` lowers the priority, so the thread only runs
+/// when higher priority threads are waiting, or it has waited a
+/// while. A negative `delta` raises it again, but never above the
+/// priority the thread started with.
+///
+/// # Returns
+///
+/// The new priority. Larger values are lower priority.
+pub fn nice(delta: i8) -> Result<u8, SyscallError> {
+    let error: u64;
+    let priority: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_NICE,
+             in("rdi") delta as u8 as u64,
+             lateout("rax") error,
+             lateout("rdi") priority,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(priority as u8)
+}
+
+fn _signal_mask(block: bool, mask: u32) -> Result<u32, SyscallError> {
+    let error: u64;
+    let old_mask: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SIGNAL_MASK,
+             in("rdi") block as u64,
+             in("rsi") mask as u64,
+             lateout("rax") error,
+             lateout("rdi") old_mask,
+             lateout("rsi") _,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(old_mask as u32)
+}
+
+/// Set the signals which are blocked from delivery to the calling
+/// thread. Bit N of `mask` is signal N.
+///
+/// The mask is per-thread: other threads in the process are not
+/// affected. New threads inherit the mask of the thread which
+/// created them. Signals raised while masked are held pending,
+/// and delivered once unmasked.
+///
+/// # Returns
+///
+/// The previous mask
+pub fn signal_mask(mask: u32) -> Result<u32, SyscallError> {
+    _signal_mask(false, mask)
+}
+
+/// Add signals to the calling thread's mask, returning the
+/// previous mask. See `signal_mask`
+pub fn signal_block(mask: u32) -> Result<u32, SyscallError> {
+    _signal_mask(true, mask)
+}
+
+/// Register values of a thread, saved when it was last interrupted
+/// or made a syscall.
+///
+/// Layout must match the kernel's Context struct (kernel/src/interrupts.rs)
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct RegisterSet {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Read the registers of a thread, for debugging
+///
+/// The caller must have I/O privileges. For the calling thread
+/// the values are those on entry to this syscall.
+///
+/// Returns SYSCALL_ERROR_NOTFOUND if the thread has exited or is
+/// waiting on a communication handle.
+pub fn get_registers(tid: u64) -> Result<RegisterSet, SyscallError> {
+    let error: u64;
+    let mem_handle: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_GET_REGISTERS,
+             in("rdi") tid,
+             lateout("rax") error,
+             lateout("rdi") mem_handle,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    // Copy out, then free memory when handle is dropped
+    let handle = MemoryHandle(mem_handle);
+    Ok(unsafe {*handle.as_ref::<RegisterSet>()})
+}
+
+/// Largest number of bytes read by one kernel call in `read_process_memory`
+pub const MAX_READ_PROCESS_MEMORY: usize = 16 * 4096;
+
+/// Read the memory of the process containing thread `tid`, for debugging
+///
+/// Copies from `address` into `buf`. The caller must have I/O
+/// privileges. `tid` can be the calling thread.
+///
+/// # Returns
+///
+/// The number of bytes read. This is less than `buf.len()` if
+/// an unmapped page was reached. Returns SYSCALL_ERROR_NOTFOUND if
+/// the thread has exited or is a kernel thread.
+pub fn read_process_memory(tid: u64, address: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+    let mut total = 0;
+    for part in buf.chunks_mut(MAX_READ_PROCESS_MEMORY) {
+        let error: u64;
+        let mem_handle: u64;
+        let count: u64;
+        unsafe {
+            asm!("syscall",
+                 in("rax") SYSCALL_READ_PROCESS_MEMORY,
+                 in("rdi") tid,
+                 in("rsi") address.wrapping_add(total as u64),
+                 in("rdx") part.len(),
+                 lateout("rax") error,
+                 lateout("rdi") mem_handle,
+                 lateout("rsi") count,
+                 out("rcx") _,
+                 out("r11") _);
+        }
+        if error != 0 {
+            return Err(SyscallError(error));
+        }
+        // Copy out, then free memory when handle is dropped
+        let handle = MemoryHandle(mem_handle);
+        let count = count as usize;
+        unsafe {
+            ptr::copy_nonoverlapping(handle.as_ptr::<u8>(),
+                                     part.as_mut_ptr(), count);
+        }
+        total += count;
+        if count < part.len() {
+            break; // Unmapped gap
+        }
+    }
+    Ok(total)
+}
+
+/// Kernel memory usage, in bytes
+///
+/// Layout must match the kernel's MemoryStats struct (kernel/src/memory.rs)
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryStats {
+    /// Kernel heap used by other data structures
+    pub heap_other: u64,
+    /// Kernel heap used for thread stacks
+    pub heap_thread_stacks: u64,
+    /// Kernel heap used for Rendezvous and messages
+    pub heap_messages: u64,
+    /// Memory used for page tables
+    pub page_tables: u64,
+    /// Size of the kernel heap
+    pub heap_total: u64,
+    /// Kernel heap in use, including allocator overhead
+    pub heap_used: u64,
+    /// Largest kernel heap allocation which would succeed
+    pub heap_largest_free: u64,
+    /// Kernel heap fragmentation, parts per thousand.
+    /// If heap_largest_free is small but this is also small then
+    /// the heap is exhausted rather than fragmented.
+    pub heap_fragmentation: u64,
+    /// Memory mapped for kernel thread stacks, outside the heap
+    pub kernel_thread_stacks: u64,
+}
+
+/// Get kernel memory usage statistics
+pub fn memory_stats() -> Result<MemoryStats, SyscallError> {
+    let error: u64;
+    let mem_handle: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_MEMORY_STATS,
+             lateout("rax") error,
+             lateout("rdi") mem_handle,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    // Copy out, then free memory when handle is dropped
+    let handle = MemoryHandle(mem_handle);
+    Ok(unsafe {*handle.as_ref::<MemoryStats>()})
+}
+
+/// Deliver interrupt `irq` to CPU `cpu`
+///
+/// Used by drivers to run their interrupt on the same core as the
+/// thread which processes it. Requires I/O privileges. IRQs and
+/// CPUs which don't exist return SYSCALL_ERROR_PARAM.
+///
+/// Note: There is currently only one CPU, so this just records
+///       the requested affinity.
+pub fn set_irq_affinity(irq: u8, cpu: u32) -> Result<(), SyscallError> {
+    let error: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SET_IRQ_AFFINITY,
+             in("rdi") irq as u64,
+             in("rsi") cpu as u64,
+             lateout("rax") error,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(())
+}
+
+/// Map `length` bytes of device memory starting at `physaddr`
+///
+/// Used by the PCI driver to map memory BARs. The handle points to
+/// `physaddr`, which needn't be page aligned. Caching is disabled.
+/// Requires I/O privileges.
+///
+/// EuraliOS only
+pub fn map_device_memory(physaddr: u64, length: u64) -> Result<MemoryHandle, SyscallError> {
+    let offset = physaddr & 0xFFF;
+    let num_pages = (offset + length + 4095) / 4096;
+    let error: u64;
+    let virtaddr: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_MAP_DEVICE_MEMORY,
+             in("rdi") physaddr - offset,
+             in("rsi") num_pages,
+             lateout("rax") error,
+             lateout("rdi") virtaddr,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(MemoryHandle(virtaddr + offset))
+}
+
+/// ThreadInfo flag: Kernel thread
+pub const THREAD_INFO_KERNEL: u64 = 1;
+/// ThreadInfo flag: The thread which called list_threads
+pub const THREAD_INFO_CURRENT: u64 = 2;
+
+/// A thread waiting to run, returned by `list_threads`
+///
+/// Layout must match the kernel's ThreadInfo struct (kernel/src/process.rs)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ThreadInfo {
+    pub tid: u64,
+    /// Instruction pointer where the thread will continue. Zero
+    /// unless the caller has I/O privileges
+    pub rip: u64,
+    /// THREAD_INFO_KERNEL and THREAD_INFO_CURRENT
+    pub flags: u64,
+}
+
+impl ThreadInfo {
+    pub fn is_kernel(&self) -> bool {
+        self.flags & THREAD_INFO_KERNEL != 0
+    }
+
+    /// True for the thread which called list_threads
+    pub fn is_current(&self) -> bool {
+        self.flags & THREAD_INFO_CURRENT != 0
+    }
+}
+
+/// List the calling thread and the threads waiting to run
+///
+/// The list is a snapshot taken with interrupts disabled. Threads
+/// waiting for a message or interrupt are not included.
+///
+/// EuraliOS only
+pub fn list_threads() -> Result<Vec<ThreadInfo>, SyscallError> {
+    let error: u64;
+    let mem_handle: u64;
+    let count: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_LIST_THREADS,
+             lateout("rax") error,
+             lateout("rdi") mem_handle,
+             lateout("rsi") count,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    // Copy out, then free memory when handle is dropped
+    let handle = MemoryHandle(mem_handle);
+    Ok(handle.as_slice::<ThreadInfo>(count as usize).to_vec())
+}
+
+/// CPU and memory usage of a thread, returned by `sample_usage`
+///
+/// Layout must match the kernel's ThreadUsage struct (kernel/src/process.rs)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ThreadUsage {
+    pub tid: u64,
+    /// Total time the thread has spent running
+    pub cpu_time_us: u64,
+    /// User memory mapped by the thread's process
+    pub mapped_bytes: u64,
+    /// When the sample was taken. The same for all threads in a sample
+    pub timestamp_us: u64,
+}
+
+impl ThreadUsage {
+    /// CPU use between an earlier sample of the same thread and
+    /// this one, in parts per thousand of one CPU
+    pub fn cpu_permille_since(&self, earlier: &ThreadUsage) -> u64 {
+        let interval = self.timestamp_us.saturating_sub(earlier.timestamp_us);
+        if interval == 0 {
+            return 0;
+        }
+        (self.cpu_time_us.saturating_sub(earlier.cpu_time_us) * 1000) / interval
+    }
+
+    /// Change in mapped memory since an earlier sample
+    pub fn mapped_bytes_since(&self, earlier: &ThreadUsage) -> i64 {
+        self.mapped_bytes as i64 - earlier.mapped_bytes as i64
+    }
+}
+
+/// Sample the CPU and memory usage of all threads
+///
+/// All entries are sampled at the same time, so rates can be
+/// compared between threads. Threads which are blocked or sleeping
+/// are included, so a thread is in every sample until it exits.
+///
+/// # Returns
+///
+/// The number of threads sampled. If this is larger than
+/// `buf.len()` then only the first `buf.len()` are stored.
+pub fn sample_usage(buf: &mut [ThreadUsage]) -> Result<usize, SyscallError> {
+    let error: u64;
+    let mem_handle: u64;
+    let count: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SAMPLE_USAGE,
+             lateout("rax") error,
+             lateout("rdi") mem_handle,
+             lateout("rsi") count,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    // Copy out, then free memory when handle is dropped
+    let handle = MemoryHandle(mem_handle);
+    let count = count as usize;
+    let copied = count.min(buf.len());
+    unsafe {
+        ptr::copy_nonoverlapping(handle.as_ptr::<ThreadUsage>(),
+                                 buf.as_mut_ptr(), copied);
+    }
+    Ok(count)
+}
+
+/// Read the kernel's message log, starting at `cursor`
+///
+/// Reads don't remove messages, so any number of programs can read
+/// the log. Use cursor 0 to read all messages still in the kernel's
+/// buffer, then `start + data.len()` to read only newer messages.
+/// If `wait` is true and there are no newer messages then blocks
+/// until there are.
+///
+/// # Returns
+///
+/// The messages, and the cursor `start` of their first byte. This is
+/// later than `cursor` if older messages have been overwritten.
+///
+/// EuraliOS only
+pub fn read_kernel_log(cursor: u64, wait: bool) -> Result<(Vec<u8>, u64), SyscallError> {
+    let flags = if wait {KERNEL_LOG_WAIT} else {0};
+    loop {
+        let error: u64;
+        let mem_handle: u64;
+        let length: u64;
+        let start: u64;
+        unsafe {
+            asm!("syscall",
+                 in("rax") SYSCALL_READ_KERNEL_LOG,
+                 in("rdi") cursor,
+                 in("rsi") flags,
+                 lateout("rax") error,
+                 lateout("rdi") mem_handle,
+                 lateout("rsi") length,
+                 lateout("rdx") start,
+                 out("rcx") _,
+                 out("r11") _);
+        }
+        if error != 0 {
+            return Err(SyscallError(error));
+        }
+        if mem_handle != 0 {
+            // Copy out, then free memory when handle is dropped
+            let handle = MemoryHandle(mem_handle);
+            return Ok((handle.as_slice::<u8>(length as usize).to_vec(), start));
+        }
+        if !wait {
+            return Ok((Vec::new(), start));
+        }
+        // Woken because a message was logged => Read it
+    }
+}
+
+/// ID of the calling thread
+///
+/// Thread IDs are unique and don't change while the thread runs,
+/// so can be used to tell apart log lines from several threads or
+/// programs.
+///
+/// EuraliOS only
+pub fn get_tid() -> u64 {
+    let tid: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_GET_TID,
+             lateout("rax") _,
+             lateout("rdi") tid,
+             out("rcx") _,
+             out("r11") _);
+    }
+    tid
+}
+
+/// Exit code returned by wait for threads whose process was killed
+pub const EXIT_CODE_KILLED: u64 = u64::MAX;
+
+/// Wait for the thread `tid` to exit, and return its exit code
+///
+/// `tid` is typically the thread ID returned by exec. Exit codes are
+/// kept by the kernel until they are waited for, so this returns
+/// immediately if the thread has already exited.
+///
+/// Returns SYSCALL_ERROR_PARAM if there is no thread `tid`, or its
+/// exit code has already been returned by wait.
+///
+/// EuraliOS only
+pub fn wait(tid: u64) -> Result<u64, SyscallError> {
+    let error: u64;
+    let exit_code: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_WAIT,
+             in("rdi") tid,
+             lateout("rax") error,
+             lateout("rdi") exit_code,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(exit_code)
+}
+
+/// Wait until woken by futex_wake, if the u32 at `addr` is `expected`
+///
+/// The kernel checks the value and suspends the thread in one step,
+/// so a futex_wake by a thread which changed the value can't be
+/// missed: if the value is no longer `expected` returns
+/// SYSCALL_ERROR_WOULDBLOCK immediately, and the caller should read
+/// it again. May also return after a futex_wake which didn't change
+/// the value, so callers should wait in a loop.
+///
+/// `addr` must be 4-byte aligned and in mapped user memory, otherwise
+/// returns SYSCALL_ERROR_PARAM. Threads are queued by physical
+/// address, so this also works with memory shared between processes.
+///
+/// EuraliOS only
+pub fn futex_wait(addr: *const u32, expected: u32) -> Result<(), SyscallError> {
+    let error: u64;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_FUTEX_WAIT,
+             in("rdi") addr,
+             in("rsi") expected as u64,
+             lateout("rax") error,
+             lateout("rdi") _,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(())
+}
+
+/// Wake up to `count` threads waiting in futex_wait on `addr`,
+/// returning the number woken
+///
+/// `addr` must be 4-byte aligned and in mapped user memory.
+///
+/// EuraliOS only
+pub fn futex_wake(addr: *const u32, count: usize) -> Result<usize, SyscallError> {
+    let error: u64;
+    let woken: usize;
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_FUTEX_WAKE,
+             in("rdi") addr,
+             in("rsi") count,
+             lateout("rax") error,
+             lateout("rdi") woken,
+             out("rcx") _,
+             out("r11") _);
+    }
+    if error != 0 {
+        return Err(SyscallError(error));
+    }
+    Ok(woken)
+}
+
+/// read_kernel_log flag: Wait for a message after the cursor
+pub const KERNEL_LOG_WAIT: u64 = 1;
+
+/// Suspend this thread for at least `duration` microseconds
+///
+/// The kernel wakes sleeping threads on timer interrupts, so sleeps
+/// are rounded up to the timer period (about 55ms). For shorter waits
+/// use time::sleep_us. A zero duration is the same as thread_yield.
+///
+/// EuraliOS only
+pub fn sleep_us(duration: u64) {
+    unsafe {
+        asm!("syscall",
+             in("rax") SYSCALL_SLEEP,
+             in("rdi") duration,
+             lateout("rax") _,
+             lateout("rdi") _,
              out("rcx") _,
              out("r11") _);
     }
@@ -617,6 +2039,20 @@ pub fn await_interrupt() {
 
 // Exec permission flags
 pub const EXEC_PERM_IO: u8 = 1;
+/// Set by exec_filtered
+pub const EXEC_SYSCALL_FILTER: u8 = 2;
+/// Terminate a filtered process if it makes a forbidden syscall
+pub const EXEC_FILTER_KILL: u8 = 4;
+/// Set by exec_args
+pub const EXEC_ARGS: u8 = 8;
+/// Run at high scheduler priority, e.g. for drivers which should
+/// respond promptly. Ignored unless the caller has I/O privileges
+pub const EXEC_PRIORITY_HIGH: u8 = 16;
+/// Run at low scheduler priority, for background work
+pub const EXEC_PRIORITY_LOW: u8 = 32;
+
+/// share_memory flag: The new chunk is read-only
+const SHARE_READ_ONLY: u64 = 1 << 8;
 
 // Syscall numbers
 pub const SYSCALL_MASK: u64 = 0xFF;
@@ -638,6 +2074,33 @@ pub const SYSCALL_LISTMOUNTS: u64 = 14;
 pub const SYSCALL_UMOUNT: u64 = 15;
 pub const SYSCALL_CLOSE: u64 = 16;
 pub const SYSCALL_AWAIT_INTERRUPT: u64 = 17;
+pub const SYSCALL_NICE: u64 = 18;
+pub const SYSCALL_SEND_TIMEOUT: u64 = 19;
+pub const SYSCALL_GET_REGISTERS: u64 = 20;
+pub const SYSCALL_SIGNAL_MASK: u64 = 21;
+pub const SYSCALL_MEMORY_STATS: u64 = 22;
+pub const SYSCALL_SET_IRQ_AFFINITY: u64 = 23;
+pub const SYSCALL_SHARE_MEMORY: u64 = 24;
+pub const SYSCALL_SAMPLE_USAGE: u64 = 25;
+pub const SYSCALL_READ_PROCESS_MEMORY: u64 = 26;
+pub const SYSCALL_SENDRECEIVE_TIMEOUT: u64 = 27;
+pub const SYSCALL_READ_KERNEL_LOG: u64 = 28;
+pub const SYSCALL_SLEEP: u64 = 29;
+pub const SYSCALL_TRY_RECEIVE: u64 = 30;
+pub const SYSCALL_AWAIT_ANY: u64 = 31;
+pub const SYSCALL_GET_TID: u64 = 32;
+pub const SYSCALL_WAIT: u64 = 33;
+pub const SYSCALL_MAP_DEVICE_MEMORY: u64 = 34;
+pub const SYSCALL_LIST_THREADS: u64 = 35;
+pub const SYSCALL_GETENV: u64 = 36;
+pub const SYSCALL_SETENV: u64 = 37;
+pub const SYSCALL_UNSETENV: u64 = 38;
+pub const SYSCALL_LISTENV: u64 = 39;
+pub const SYSCALL_FUTEX_WAIT: u64 = 40;
+pub const SYSCALL_FUTEX_WAKE: u64 = 41;
+pub const SYSCALL_SPAWN_THREAD: u64 = 42;
+pub const SYSCALL_MEMORY_SIZE: u64 = 43;
+pub const SYSCALL_CHDIR: u64 = 44;
 
 // Syscall error codes
 pub const SYSCALL_ERROR_MASK : usize = 127; // Lower 7 bits
@@ -658,6 +2121,18 @@ pub const SYSCALL_ERROR_EXISTS: SyscallError = SyscallError(13); // Already exis
 pub const SYSCALL_ERROR_NOT_IMPLEMENTED: SyscallError = SyscallError(14);
 pub const SYSCALL_ERROR_NOT_DIR: SyscallError = SyscallError(15);
 pub const SYSCALL_ERROR_NO_DATA: SyscallError = SyscallError(16);
+pub const SYSCALL_ERROR_TIMEOUT: SyscallError = SyscallError(17);
+pub const SYSCALL_ERROR_DENIED: SyscallError = SyscallError(18);
+pub const SYSCALL_ERROR_INVALID_DATA: SyscallError = SyscallError(19); // Malformed reply
+pub const SYSCALL_ERROR_NO_SPACE: SyscallError = SyscallError(20); // Storage full
+pub const SYSCALL_ERROR_IS_DIR: SyscallError = SyscallError(21);
+pub const SYSCALL_ERROR_WOULDBLOCK: SyscallError = SyscallError(22); // No message waiting
+pub const SYSCALL_ERROR_NOT_EMPTY: SyscallError = SyscallError(23); // Directory not empty
+pub const SYSCALL_ERROR_NOT_ELF: SyscallError = SyscallError(24); // exec: Not an ELF binary
+pub const SYSCALL_ERROR_ELF_SEGMENT: SyscallError = SyscallError(25); // exec: Segment overlaps kernel memory
+pub const SYSCALL_ERROR_ELF_PARSE: SyscallError = SyscallError(26); // exec: Could not parse ELF
+pub const SYSCALL_ERROR_IO: SyscallError = SyscallError(27); // Device reported an error
+pub const SYSCALL_ERROR_UNEXPECTED: SyscallError = SyscallError(28); // Server sent an unexpected reply
 
 impl fmt::Display for SyscallError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -679,8 +2154,50 @@ impl fmt::Display for SyscallError {
                    SYSCALL_ERROR_NOT_IMPLEMENTED => "Not implemented",
                    SYSCALL_ERROR_NOT_DIR => "Not a directory",
                    SYSCALL_ERROR_NO_DATA => "No data",
+                   SYSCALL_ERROR_TIMEOUT => "Timed out",
+                   SYSCALL_ERROR_DENIED => "Permission denied",
+                   SYSCALL_ERROR_INVALID_DATA => "Invalid data",
+                   SYSCALL_ERROR_NO_SPACE => "No space left",
+                   SYSCALL_ERROR_IS_DIR => "Is a directory",
+                   SYSCALL_ERROR_WOULDBLOCK => "Would block",
+                   SYSCALL_ERROR_NOT_EMPTY => "Directory not empty",
+                   SYSCALL_ERROR_NOT_ELF => "Not an ELF binary",
+                   SYSCALL_ERROR_ELF_SEGMENT => "ELF segment overlaps kernel memory",
+                   SYSCALL_ERROR_ELF_PARSE => "Could not parse ELF",
+                   SYSCALL_ERROR_IO => "Input/output error",
+                   SYSCALL_ERROR_UNEXPECTED => "Unexpected reply",
                    _ => "Unknown error"
                })
     }
 }
 
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test_case]
+    fn env_list() {
+        assert_eq!(parse_env_list("TZ=UTC\0EMPTY=\0EQ=a=b\0"),
+                   [(String::from("TZ"), String::from("UTC")),
+                    (String::from("EMPTY"), String::new()),
+                    (String::from("EQ"), String::from("a=b"))]);
+        assert!(parse_env_list("").is_empty());
+    }
+
+    #[test_case]
+    fn args_round_trip() {
+        let data = encode_args(&["ls", "", "-l"]);
+        assert_eq!(data.len(), 4 + 3 * 4 + 4);
+        assert_eq!(decode_args(&data),
+                   Some(Vec::from([&b"ls"[..], &b""[..], &b"-l"[..]])));
+
+        // Page is zero-filled after the arguments
+        let mut page = data.clone();
+        page.resize(MAX_ARGS_SIZE, 0);
+        assert_eq!(decode_args(&page).map(|args| args.len()), Some(3));
+
+        assert_eq!(decode_args(&data[..data.len() - 1]), None);
+        assert_eq!(decode_args(&[]), None);
+    }
+}
@@ -33,6 +33,23 @@ where
     launch(Box::new(f))
 }
 
+/// Spawn a new thread, passing an argument to the closure
+///
+/// Useful for starting a pool of workers running the same code,
+/// each given e.g. its index:
+///
+/// for index in 0..4 {
+///   thread::spawn_with_arg(index, worker)?;
+/// }
+///
+pub fn spawn_with_arg<F>(arg: usize, f: F) -> Result<(), SyscallError>
+where
+    F: FnOnce(usize) -> (),
+    F: Send + 'static,
+{
+    launch(Box::new(move || f(arg)))
+}
+
 /// Launch a thread by calling the low-level syscalls
 ///
 fn launch(p: Box<dyn FnOnce()>) -> Result<(), SyscallError>
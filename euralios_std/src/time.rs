@@ -1,5 +1,7 @@
 use core::arch::asm;
 
+use crate::syscalls;
+
 pub use core::time::Duration;
 
 pub fn time_stamp_counter() -> u64 {
@@ -68,3 +70,13 @@ pub fn microseconds_monotonic() -> u64 {
     // This will overflow in about 142 years : 2**64 / 4096 microseconds
     ((((pit * SCALED_TSC_RATE + scaled_tsc) * 2011) / 4096) * 437) / (256 * SCALED_TSC_RATE)
 }
+
+/// Sleep for at least the given number of microseconds
+///
+/// Gives up the processor to other threads while waiting
+pub fn sleep_us(microseconds: u64) {
+    let end = microseconds_monotonic() + microseconds;
+    while microseconds_monotonic() < end {
+        syscalls::thread_yield();
+    }
+}
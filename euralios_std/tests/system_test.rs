@@ -4,21 +4,1142 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
 use euralios_std::{print, println};
 
+/// If this is the first argument, this program is a child started
+/// by a test, and the second argument selects what it does instead
+/// of running the tests
+const CHILD_ARG: &str = "--child";
+
+/// Passed after the mode to an "args" child, which checks it
+/// receives them unchanged
+const CHILD_EXTRA_ARGS: [&str; 3] = ["", "with space", "ünïcode"];
+
+/// Zero-initialized, so in .bss rather than the ELF file
+static mut UNINITIALIZED: [u64; 8192] = [0; 8192];
+
 #[no_mangle]
 fn main() {
+    let mut args = euralios_std::env::args().skip(1);
+    if args.next().as_deref() == Some(CHILD_ARG) {
+        child::run(&args.next().unwrap_or_default());
+        return;
+    }
     println!("EuraliOS system test");
     println!("====================");
     #[cfg(test)]
     test_main();
 }
 
+/// Behaviours of child processes, most of which are expected to be
+/// killed
+mod child {
+    use euralios_std::syscalls;
+
+    pub fn run(mode: &str) {
+        match mode {
+            // Not in the filter the parent sets
+            "forbidden_syscall" => {
+                syscalls::get_tid();
+            }
+            "stack_overflow" => {
+                overflow(0);
+            }
+            "killed_while_blocked" => {
+                // The main thread is waited for by the parent
+                let (_sender, receiver) = syscalls::new_rendezvous().unwrap();
+                euralios_std::thread::spawn(|| {
+                    syscalls::sleep_us(100_000);
+                    syscalls::get_tid();
+                }).unwrap();
+                _ = syscalls::receive(&receiver);
+            }
+            "write_code" => unsafe {
+                core::ptr::write_volatile(run as usize as *mut u8, 0);
+            }
+            "write_read_only_share" => {
+                let (memory, _) = syscalls::malloc(4096, 0).unwrap();
+                let mut view = memory.share_read_only().unwrap();
+                view.as_mut_slice::<u8>(1)[0] = 1;
+            }
+            "bss_is_zeroed" => {
+                let array = unsafe {&*core::ptr::addr_of!(super::UNINITIALIZED)};
+                syscalls::exit(array.iter().all(|&value| value == 0) as u64);
+            }
+            "setenv_denied" => {
+                // Started without I/O privileges
+                let denied = syscalls::setenv("SYSTEM_TEST_VAR", "one")
+                    == Err(syscalls::SYSCALL_ERROR_DENIED)
+                    && syscalls::unsetenv("SYSTEM_TEST_VAR") == Err(syscalls::SYSCALL_ERROR_DENIED);
+                syscalls::exit(denied as u64);
+            }
+            "list_threads" => {
+                // Started without I/O privileges, so rip is hidden
+                let tid = syscalls::get_tid();
+                let found = syscalls::list_threads().unwrap().iter()
+                    .any(|info| info.tid == tid && info.is_current() && info.rip == 0);
+                syscalls::exit(found as u64);
+            }
+            "sync_timeout" => {
+                // Started with only the parent's test mounts
+                let start = euralios_std::time::microseconds_monotonic();
+                let timed_out = syscalls::sync(100_000) == Err(syscalls::SYSCALL_ERROR_TIMEOUT);
+                let on_time = euralios_std::time::microseconds_monotonic() - start < 500_000;
+                // Wait for the late reply, which should be discarded
+                syscalls::sleep_us(500_000);
+                let flushed = syscalls::sync(1_000_000) == Ok(());
+                syscalls::exit((timed_out && on_time && flushed) as u64);
+            }
+            "args" => {
+                let args: alloc::vec::Vec<_> = euralios_std::env::args().skip(3).collect();
+                syscalls::exit((args == super::CHILD_EXTRA_ARGS) as u64);
+            }
+            _ => {}
+        }
+    }
+
+    /// Recurse until the stack can't grow any more
+    fn overflow(depth: usize) -> usize {
+        let mut frame = [0u8; 5000];
+        frame[depth % 5000] = 1;
+        if depth == usize::MAX {
+            return 0;
+        }
+        overflow(depth + 1) + frame[depth % 5000] as usize
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{CHILD_ARG, CHILD_EXTRA_ARGS, UNINITIALIZED};
+
+    /// Run a copy of this program as a child in `mode`, returning
+    /// its exit code. The filter is EXEC_FILTER_KILL with `allowed`.
+    fn run_child(mode: &str, allowed: u64) -> u64 {
+        exec_child(&child_binary(), mode, allowed)
+    }
+
+    /// The ELF binary of this program
+    fn child_binary() -> alloc::vec::Vec<u8> {
+        use euralios_std::fs::File;
+
+        let mut bin = alloc::vec::Vec::new();
+        File::open("/ramdisk/bin/system_test").unwrap().read_to_end(&mut bin).unwrap();
+        bin
+    }
+
+    /// As run_child, with the binary already read
+    fn exec_child(bin: &[u8], mode: &str, allowed: u64) -> u64 {
+        euralios_std::syscalls::wait(start_child(bin, mode, allowed)).unwrap()
+    }
+
+    /// Start a child as run_child does, returning its TID
+    /// without waiting for it
+    fn start_child(bin: &[u8], mode: &str, allowed: u64) -> u64 {
+        use euralios_std::syscalls::{self, VFS};
+
+        let (_input, child_input) = syscalls::new_rendezvous().unwrap();
+        syscalls::exec_filtered_args(
+            bin,
+            syscalls::EXEC_FILTER_KILL,
+            child_input,
+            syscalls::STDOUT.clone(),
+            VFS::shared(),
+            allowed,
+            &["system_test", CHILD_ARG, mode]).unwrap()
+    }
+
+    /// Syscalls needed to start and exit
+    const CHILD_SYSCALLS: u64 = {
+        use euralios_std::syscalls::*;
+        syscall_bit(SYSCALL_EXIT_THREAD) | syscall_bit(SYSCALL_FREE)
+    };
+
     #[test_case]
     fn empty_test() {
     }
+
+    #[test_case]
+    fn exited_threads_are_freed() {
+        use alloc::sync::Arc;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use euralios_std::{syscalls::{self, ThreadUsage}, thread};
+
+        // Each process has a limited number of thread stacks,
+        // so threads are started in batches
+        const BATCH: usize = 16;
+        const TOTAL: usize = 1024;
+        // Other processes start and stop threads while this runs, so
+        // the totals can change by a few threads' memory. Leaking
+        // TOTAL threads would be far more.
+        // Kernel stacks are 8 KiB each
+        const STACK_TOLERANCE: u64 = (BATCH * 8192) as u64;
+        // Bytes of page tables: a few tables for each process
+        const PAGE_TABLE_TOLERANCE: u64 = 16 * 4096;
+        // Frames mapped by this process: a batch of 5-page user
+        // stacks which haven't been freed yet
+        const FRAME_TOLERANCE: u64 = (BATCH * 5) as u64;
+
+        // Frames mapped by this process
+        let mapped_frames = || -> u64 {
+            let tid = syscalls::get_tid();
+            let mut buf = alloc::vec![ThreadUsage::default(); 256];
+            let count = syscalls::sample_usage(&mut buf).unwrap().min(buf.len());
+            buf[..count].iter().find(|usage| usage.tid == tid)
+                .expect("Thread missing from sample").mapped_bytes / 4096
+        };
+
+        let finished = Arc::new(AtomicUsize::new(0));
+        let run_batch = |batch: usize| {
+            for _ in 0..BATCH {
+                let finished = finished.clone();
+                thread::spawn(move || {
+                    finished.fetch_add(1, Ordering::Relaxed);
+                }).unwrap();
+            }
+            while finished.load(Ordering::Relaxed) < (batch + 1) * BATCH {
+                syscalls::thread_yield();
+            }
+            // Exited threads are freed by the scheduler
+            syscalls::sleep_us(100_000);
+        };
+        // The first batch can grow this process's heap
+        run_batch(0);
+
+        let before = syscalls::memory_stats().unwrap();
+        let frames_before = mapped_frames();
+        for batch in 1..(TOTAL / BATCH) {
+            run_batch(batch);
+        }
+        let after = syscalls::memory_stats().unwrap();
+        let frames_after = mapped_frames();
+        assert!(after.heap_thread_stacks.abs_diff(before.heap_thread_stacks) <= STACK_TOLERANCE,
+                "Thread stacks {} -> {}", before.heap_thread_stacks, after.heap_thread_stacks);
+        assert!(after.page_tables.abs_diff(before.page_tables) <= PAGE_TABLE_TOLERANCE,
+                "Page tables {} -> {}", before.page_tables, after.page_tables);
+        assert!(frames_after.abs_diff(frames_before) <= FRAME_TOLERANCE,
+                "Mapped frames {} -> {}", frames_before, frames_after);
+    }
+
+    #[test_case]
+    fn try_receive_would_block() {
+        use euralios_std::{syscalls, thread};
+        use euralios_std::message::Message;
+
+        let (sender, receiver) = syscalls::new_rendezvous().unwrap();
+        assert_eq!(syscalls::try_receive(&receiver).unwrap_err(),
+                   syscalls::SYSCALL_ERROR_WOULDBLOCK);
+
+        thread::spawn(move || {
+            syscalls::send(&sender, Message::Short(42, 1, 2)).unwrap();
+        }).unwrap();
+        let message = loop {
+            match syscalls::try_receive(&receiver) {
+                Err(syscalls::SYSCALL_ERROR_WOULDBLOCK) => syscalls::thread_yield(),
+                result => break result.unwrap()
+            }
+        };
+        assert!(matches!(message, Message::Short(42, 1, 2)));
+        // Nothing else waiting
+        assert_eq!(syscalls::try_receive(&receiver).unwrap_err(),
+                   syscalls::SYSCALL_ERROR_WOULDBLOCK);
+    }
+
+    #[test_case]
+    fn await_any_receives() {
+        use euralios_std::{syscalls, thread};
+        use euralios_std::message::Message;
+
+        let (sender0, receiver0) = syscalls::new_rendezvous().unwrap();
+        let (sender1, receiver1) = syscalls::new_rendezvous().unwrap();
+        let handles = [receiver0, receiver1];
+
+        // Wait for a send on the second handle
+        thread::spawn(move || {
+            syscalls::sleep_us(100_000);
+            syscalls::send(&sender1, Message::Short(1, 0, 0)).unwrap();
+            syscalls::send(&sender1, Message::Short(3, 0, 0)).unwrap();
+        }).unwrap();
+        let (index, message) = syscalls::await_any(&handles).unwrap();
+        assert_eq!(index, 1);
+        assert!(matches!(message, Message::Short(1, 0, 0)));
+
+        // Both ready: lowest index first
+        thread::spawn(move || {
+            syscalls::send(&sender0, Message::Short(2, 0, 0)).unwrap();
+        }).unwrap();
+        syscalls::sleep_us(100_000);
+        let (index, message) = syscalls::await_any(&handles).unwrap();
+        assert_eq!(index, 0);
+        assert!(matches!(message, Message::Short(2, 0, 0)));
+        let (index, message) = syscalls::await_any(&handles).unwrap();
+        assert_eq!(index, 1);
+        assert!(matches!(message, Message::Short(3, 0, 0)));
+
+        assert_eq!(syscalls::await_any(&[]).unwrap_err(),
+                   syscalls::SYSCALL_ERROR_PARAM);
+    }
+
+    #[test_case]
+    fn rcall_timeout_without_reply() {
+        use euralios_std::{message, syscalls, time, thread};
+        use euralios_std::message::Message;
+
+        // Nothing receives on the other handle
+        let (handle, _unused) = syscalls::new_rendezvous().unwrap();
+        let start = time::microseconds_monotonic();
+        let result = message::rcall_timeout(&handle, 42, 1.into(), 2.into(), None, 100_000);
+        assert!(matches!(result, Err((syscalls::SYSCALL_ERROR_TIMEOUT, Message::Short(42, 1, 2)))));
+        assert!(time::microseconds_monotonic() - start >= 100_000);
+
+        // Received but never answered
+        let (handle, server) = syscalls::new_rendezvous().unwrap();
+        thread::spawn(move || {
+            let _message = syscalls::receive(&server);
+            syscalls::sleep_us(1_000_000);
+        }).unwrap();
+        let result = message::rcall_timeout(&handle, 42, 1.into(), 2.into(), None, 100_000);
+        assert_eq!(result.err().map(|(err, _)| err), Some(syscalls::SYSCALL_ERROR_TIMEOUT));
+    }
+
+    #[test_case]
+    fn futex_wait_and_wake() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        use euralios_std::{syscalls, thread};
+
+        static FUTEX: AtomicU32 = AtomicU32::new(0);
+        let addr = &FUTEX as *const AtomicU32 as *const u32;
+        // Touch the page, in case it is allocated on demand
+        FUTEX.store(0, Ordering::Release);
+
+        // Value already changed
+        assert_eq!(syscalls::futex_wait(addr, 1), Err(syscalls::SYSCALL_ERROR_WOULDBLOCK));
+        // Nothing waiting
+        assert_eq!(syscalls::futex_wake(addr, 1), Ok(0));
+        // Misaligned
+        assert_eq!(syscalls::futex_wait((addr as usize + 1) as *const u32, 0),
+                   Err(syscalls::SYSCALL_ERROR_PARAM));
+
+        thread::spawn(move || {
+            syscalls::sleep_us(100_000);
+            FUTEX.store(1, Ordering::Release);
+            syscalls::futex_wake(&FUTEX as *const AtomicU32 as *const u32, 1).unwrap();
+        }).unwrap();
+        while FUTEX.load(Ordering::Acquire) == 0 {
+            match syscalls::futex_wait(addr, 0) {
+                Ok(()) | Err(syscalls::SYSCALL_ERROR_WOULDBLOCK) => {}
+                Err(err) => panic!("futex_wait: {}", err)
+            }
+        }
+        assert_eq!(FUTEX.load(Ordering::Acquire), 1);
+    }
+
+    #[test_case]
+    fn futex_on_untouched_page() {
+        use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+        use euralios_std::{syscalls, thread};
+
+        /// In .bss and never written before waiting, so mapped to
+        /// the zero page until the waker writes it
+        #[repr(align(4096))]
+        struct Page(AtomicU32);
+        static FUTEX: Page = Page(AtomicU32::new(0));
+        static WOKEN: AtomicUsize = AtomicUsize::new(0);
+        let addr = &FUTEX.0 as *const AtomicU32 as *const u32;
+
+        thread::spawn(move || {
+            syscalls::sleep_us(100_000);
+            FUTEX.0.store(1, Ordering::Release);
+            let woken = syscalls::futex_wake(&FUTEX.0 as *const AtomicU32 as *const u32, 1).unwrap();
+            WOKEN.store(woken, Ordering::Release);
+        }).unwrap();
+        assert_eq!(syscalls::futex_wait(addr, 0), Ok(()));
+        assert_eq!(FUTEX.0.load(Ordering::Acquire), 1);
+        syscalls::sleep_us(100_000);
+        assert_eq!(WOKEN.load(Ordering::Acquire), 1);
+    }
+
+    #[test_case]
+    fn spawn_with_stack_size() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use euralios_std::syscalls;
+
+        static SUM: AtomicUsize = AtomicUsize::new(0);
+
+        // More than the default stack
+        fn large_stack() {
+            let mut buffer = [0u8; 48 * 1024];
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+            let sum = buffer.iter().map(|&byte| byte as usize).sum();
+            SUM.store(sum, Ordering::Release);
+        }
+
+        let tid = syscalls::spawn(large_stack, 64 * 1024).unwrap();
+        assert_eq!(syscalls::wait(tid), Ok(0));
+        assert_eq!(SUM.load(Ordering::Acquire), 48 * 1024 / 256 * (255 * 256 / 2));
+
+        assert_eq!(syscalls::spawn(large_stack, 4 * 1024 * 1024),
+                   Err(syscalls::SYSCALL_ERROR_PARAM));
+    }
+
+    #[test_case]
+    fn user_stack_grows() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use euralios_std::syscalls;
+
+        static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+        // Each call uses over a page of stack
+        fn recurse(depth: usize) -> usize {
+            let mut frame = [0u8; 5000];
+            frame[depth % 5000] = 1;
+            if depth == 0 {
+                return frame.iter().map(|&byte| byte as usize).sum();
+            }
+            recurse(depth - 1) + frame[depth % 5000] as usize
+        }
+
+        // Default stack is 7 pages: grows to about 40
+        fn deep() {
+            DEPTH.store(recurse(32), Ordering::Release);
+        }
+
+        let tid = syscalls::spawn(deep, 0).unwrap();
+        assert_eq!(syscalls::wait(tid), Ok(0));
+        assert_eq!(DEPTH.load(Ordering::Acquire), 33);
+    }
+
+    #[test_case]
+    fn environment_variables() {
+        use euralios_std::{env, syscalls};
+
+        assert_eq!(syscalls::getenv("SYSTEM_TEST_VAR"), None);
+        assert_eq!(env::var("SYSTEM_TEST_VAR"), Err(env::VarError::NotPresent));
+        // The working directory is read like a variable
+        if let Some(cwd) = syscalls::getenv(syscalls::CWD_VAR) {
+            assert!(env::vars().iter().any(|(key, value)| key == syscalls::CWD_VAR && *value == cwd));
+        }
+
+        // Only processes with I/O privileges can change variables
+        let allowed = CHILD_SYSCALLS
+            | syscalls::syscall_bit(syscalls::SYSCALL_SETENV)
+            | syscalls::syscall_bit(syscalls::SYSCALL_UNSETENV);
+        assert_eq!(run_child("setenv_denied", allowed), 1);
+    }
+
+    #[test_case]
+    fn syscall_pointers_checked() {
+        use core::arch::asm;
+        use euralios_std::syscalls;
+
+        /// getenv with a raw key pointer, returning the error
+        fn getenv_raw(ptr: u64, length: u64) -> u64 {
+            let error: u64;
+            unsafe {
+                asm!("syscall",
+                     in("rax") syscalls::SYSCALL_GETENV,
+                     in("rdi") ptr,
+                     in("rsi") length,
+                     lateout("rax") error,
+                     lateout("rdi") _,
+                     lateout("rsi") _,
+                     out("rcx") _,
+                     out("r11") _);
+            }
+            error
+        }
+        let param = syscalls::SYSCALL_ERROR_PARAM.as_u64();
+        let key = "SYSTEM_TEST_VAR";
+        assert_eq!(getenv_raw(key.as_ptr() as u64, key.len() as u64),
+                   syscalls::SYSCALL_ERROR_NOTFOUND.as_u64());
+        // Kernel memory, mapped but not user accessible: the kernel
+        // heap, and the higher half
+        assert_eq!(getenv_raw(0x4444_4444_0000, 8), param);
+        assert_eq!(getenv_raw(0xFFFF_8000_0000_0000, 8), param);
+        // Non-canonical, and running past the top of user space
+        assert_eq!(getenv_raw(0x0000_8000_0000_0000, 8), param);
+        assert_eq!(getenv_raw(0x0000_7FFF_FFFF_FFFC, 8), param);
+        // Unmapped user memory
+        assert_eq!(getenv_raw(0x1000, 8), param);
+    }
+
+    #[test_case]
+    fn working_directory() {
+        use euralios_std::{env, fs::{self, File}, path::PathBuf, syscalls};
+
+        fs::create_dir_all("/ramdisk/cwd_test/sub").unwrap();
+        File::create("/ramdisk/cwd_test/file").unwrap().write(b"cwd").unwrap();
+
+        let old_cwd = syscalls::getcwd();
+        env::set_current_dir("/ramdisk/cwd_test/./sub").unwrap();
+        assert_eq!(syscalls::getcwd(), PathBuf::from("/ramdisk/cwd_test/sub"));
+        env::set_current_dir("..").unwrap();
+        assert_eq!(syscalls::getcwd(), PathBuf::from("/ramdisk/cwd_test"));
+
+        // Relative paths are resolved against the working directory
+        let mut data = alloc::vec::Vec::new();
+        File::open("file").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"cwd");
+        assert!(fs::read_dir("sub").is_ok());
+
+        assert_eq!(env::set_current_dir("file"), Err(syscalls::SYSCALL_ERROR_NOT_DIR));
+        assert_eq!(env::set_current_dir("missing"), Err(syscalls::SYSCALL_ERROR_NOTFOUND));
+        // Only changed through set_current_dir
+        assert_eq!(env::set_var(syscalls::CWD_VAR, "/ramdisk/cwd_test/file"),
+                   Err(syscalls::SYSCALL_ERROR_DENIED));
+        assert_eq!(syscalls::unsetenv(syscalls::CWD_VAR), Err(syscalls::SYSCALL_ERROR_DENIED));
+        assert_eq!(syscalls::chdir("relative"), Err(syscalls::SYSCALL_ERROR_PARAM));
+        // The raw wrapper also checks the directory exists
+        assert_eq!(syscalls::chdir("/ramdisk/cwd_test/missing"),
+                   Err(syscalls::SYSCALL_ERROR_NOTFOUND));
+        assert_eq!(syscalls::chdir("/ramdisk/cwd_test/file"),
+                   Err(syscalls::SYSCALL_ERROR_NOT_DIR));
+        assert_eq!(syscalls::getcwd(), PathBuf::from("/ramdisk/cwd_test"));
+        env::set_current_dir(old_cwd).unwrap();
+    }
+
+    #[test_case]
+    fn file_partial_reads() {
+        use alloc::vec::Vec;
+        use euralios_std::fs::{self, File};
+
+        const PATH: &str = "/ramdisk/read_test";
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        File::create(PATH).unwrap().write(&data).unwrap();
+
+        let mut file = File::open(PATH).unwrap();
+        let mut buf = [0u8; 300];
+        let mut read = Vec::new();
+        loop {
+            match file.read(&mut buf).unwrap() {
+                0 => break,
+                n => {
+                    assert!(n <= buf.len());
+                    read.extend_from_slice(&buf[..n]);
+                }
+            }
+        }
+        assert_eq!(read, data);
+        // Still at the end
+        assert_eq!(file.read(&mut buf), Ok(0));
+
+        let mut all = Vec::new();
+        assert_eq!(File::open(PATH).unwrap().read_to_end(&mut all), Ok(data.len()));
+        assert_eq!(all, data);
+        fs::remove_file(PATH).unwrap();
+    }
+
+    #[test_case]
+    fn write_multi_page_buffer() {
+        use alloc::vec::Vec;
+        use euralios_std::{fs::{self, File}, syscalls};
+
+        const PATH: &str = "/tmp/large_write_test";
+        const SIZE: usize = 1024 * 1024;
+        let data: Vec<u8> = (0..SIZE).map(|i| (i ^ (i >> 12)) as u8).collect();
+        // All pages in one message
+        assert_eq!(File::create(PATH).unwrap().write(&data), Ok(SIZE));
+
+        let mut read = Vec::new();
+        assert_eq!(File::open(PATH).unwrap().read_to_end(&mut read), Ok(SIZE));
+        assert!(read == data);
+        fs::remove_file(PATH).unwrap();
+
+        // Larger than a chunk
+        assert_eq!(syscalls::malloc(syscalls::MAX_CHUNK_SIZE as u64 + 1, 0).err(),
+                   Some(syscalls::SYSCALL_ERROR_PARAM));
+    }
+
+    #[test_case]
+    fn file_seek() {
+        use euralios_std::{fs::{self, File}, io::SeekFrom, syscalls};
+
+        const PATH: &str = "/ramdisk/seek_test";
+        File::create(PATH).unwrap().write(b"0123456789").unwrap();
+
+        let mut file = File::open(PATH).unwrap();
+        let mut buf = [0u8; 3];
+        assert_eq!(file.seek(SeekFrom::Start(4)), Ok(4));
+        assert_eq!(file.read(&mut buf), Ok(3));
+        assert_eq!(&buf, b"456");
+        assert_eq!(file.seek(SeekFrom::Current(-5)), Ok(2));
+        assert_eq!(file.read(&mut buf), Ok(3));
+        assert_eq!(&buf, b"234");
+        assert_eq!(file.seek(SeekFrom::End(-2)), Ok(8));
+        assert_eq!(file.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"89");
+
+        assert_eq!(file.seek(SeekFrom::Current(-20)), Err(syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(file.seek(SeekFrom::End(-20)), Err(syscalls::SYSCALL_ERROR_PARAM));
+        // Position unchanged by errors
+        assert_eq!(file.seek(SeekFrom::Current(0)), Ok(10));
+        fs::remove_file(PATH).unwrap();
+    }
+
+    #[test_case]
+    fn open_options_append() {
+        use alloc::vec::Vec;
+        use euralios_std::fs::{self, File, OpenOptions};
+
+        const PATH: &str = "/ramdisk/append_test";
+        File::create(PATH).unwrap().write(b"abc").unwrap();
+
+        let mut file = OpenOptions::new().append(true).open(PATH).unwrap();
+        file.write(b"def").unwrap();
+        // Overwrites from the start, without truncating
+        let mut file = OpenOptions::new().write(true).open(PATH).unwrap();
+        file.write(b"X").unwrap();
+
+        let mut data = Vec::new();
+        File::open(PATH).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"Xbcdef");
+        fs::remove_file(PATH).unwrap();
+    }
+
+    #[test_case]
+    fn file_metadata() {
+        use euralios_std::fs::{self, File};
+
+        const PATH: &str = "/ramdisk/metadata_test";
+        File::create(PATH).unwrap().write(b"12345").unwrap();
+
+        let metadata = File::open(PATH).unwrap().metadata().unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.len(), 5);
+        assert!(fs::metadata("/ramdisk/bin").unwrap().is_dir());
+        fs::remove_file(PATH).unwrap();
+    }
+
+    #[test_case]
+    fn walk_dir_recursive() {
+        use alloc::{string::String, vec::Vec};
+        use euralios_std::fs::{self, File};
+
+        fs::create_dir("/ramdisk/walk_test").unwrap();
+        fs::create_dir("/ramdisk/walk_test/dir").unwrap();
+        File::create("/ramdisk/walk_test/dir/file").unwrap();
+        File::create("/ramdisk/walk_test/top").unwrap();
+
+        let paths = |max_depth| -> Vec<String> {
+            fs::walk_dir("/ramdisk/walk_test").max_depth(max_depth)
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    String::from(entry.path().as_os_str().to_str().unwrap())
+                })
+                .collect()
+        };
+        // Files are listed before subdirectories
+        assert_eq!(paths(64), ["/ramdisk/walk_test/top",
+                               "/ramdisk/walk_test/dir",
+                               "/ramdisk/walk_test/dir/file"]);
+        assert_eq!(paths(1), ["/ramdisk/walk_test/top",
+                              "/ramdisk/walk_test/dir"]);
+
+        fs::remove_dir_all("/ramdisk/walk_test").unwrap();
+    }
+
+    #[test_case]
+    fn remove_directories() {
+        use euralios_std::{fs::{self, File}, syscalls};
+
+        fs::create_dir("/ramdisk/remove_test").unwrap();
+        fs::create_dir("/ramdisk/remove_test/dir").unwrap();
+        File::create("/ramdisk/remove_test/dir/file").unwrap();
+
+        assert_eq!(fs::remove_dir("/ramdisk/remove_test"),
+                   Err(syscalls::SYSCALL_ERROR_NOT_EMPTY));
+        assert_eq!(fs::remove_file("/ramdisk/remove_test/dir"),
+                   Err(syscalls::SYSCALL_ERROR_IS_DIR));
+        assert_eq!(fs::remove_file("/ramdisk/remove_test/missing"),
+                   Err(syscalls::SYSCALL_ERROR_NOTFOUND));
+
+        fs::remove_dir_all("/ramdisk/remove_test").unwrap();
+        assert!(fs::metadata("/ramdisk/remove_test").is_err());
+    }
+
+    #[test_case]
+    fn create_nested_directories() {
+        use euralios_std::{fs, syscalls};
+
+        fs::create_dir_all("/ramdisk/a/b/c").unwrap();
+        for path in ["/ramdisk/a", "/ramdisk/a/b", "/ramdisk/a/b/c"] {
+            assert!(fs::metadata(path).unwrap().is_dir());
+        }
+        // Existing directories are not an error
+        fs::create_dir_all("/ramdisk/a/b").unwrap();
+        assert_eq!(fs::create_dir("/ramdisk/a/b"),
+                   Err(syscalls::SYSCALL_ERROR_EXISTS));
+        fs::remove_dir_all("/ramdisk/a").unwrap();
+    }
+
+    #[test_case]
+    fn canonicalize_checks_components() {
+        use euralios_std::{fs::{self, File}, path::PathBuf, syscalls};
+
+        fs::create_dir_all("/ramdisk/canon_test/dir").unwrap();
+        File::create("/ramdisk/canon_test/file").unwrap();
+
+        assert_eq!(fs::canonicalize("/ramdisk/canon_test/./dir/../file"),
+                   Ok(PathBuf::from("/ramdisk/canon_test/file")));
+        assert_eq!(fs::canonicalize("/ramdisk/canon_test/dir/"),
+                   Ok(PathBuf::from("/ramdisk/canon_test/dir")));
+        assert_eq!(fs::canonicalize("/ramdisk/canon_test/missing/.."),
+                   Err(syscalls::SYSCALL_ERROR_NOTFOUND));
+        // Files can't contain other components
+        assert_eq!(fs::canonicalize("/ramdisk/canon_test/file/.."),
+                   Err(syscalls::SYSCALL_ERROR_NOT_DIR));
+        // Escaping the root
+        assert_eq!(fs::canonicalize("/ramdisk/../../ramdisk"),
+                   Err(syscalls::SYSCALL_ERROR_PARAM));
+        fs::remove_dir_all("/ramdisk/canon_test").unwrap();
+    }
+
+    #[test_case]
+    fn rename_file() {
+        use alloc::vec::Vec;
+        use euralios_std::{fs::{self, File}, syscalls};
+
+        File::create("/ramdisk/rename_from").unwrap().write(b"moved").unwrap();
+        File::create("/ramdisk/rename_to").unwrap().write(b"old").unwrap();
+        // Replaces the existing file
+        fs::rename("/ramdisk/rename_from", "/ramdisk/rename_to").unwrap();
+        assert!(File::open("/ramdisk/rename_from").is_err());
+        let mut data = Vec::new();
+        File::open("/ramdisk/rename_to").unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"moved");
+
+        // Can't replace a directory
+        fs::create_dir_all("/ramdisk/rename_dir/sub").unwrap();
+        assert_eq!(fs::rename("/ramdisk/rename_to", "/ramdisk/rename_dir"),
+                   Err(syscalls::SYSCALL_ERROR_PARAM));
+        // Into a subdirectory
+        fs::rename("/ramdisk/rename_to", "/ramdisk/rename_dir/file").unwrap();
+        assert!(fs::metadata("/ramdisk/rename_dir/file").unwrap().is_file());
+        fs::remove_dir_all("/ramdisk/rename_dir").unwrap();
+    }
+
+    #[test_case]
+    fn get_tid_is_stable() {
+        use euralios_std::syscalls;
+
+        let tid = syscalls::get_tid();
+        assert_ne!(tid, 0);
+        assert_eq!(syscalls::get_tid(), tid);
+    }
+
+    #[test_case]
+    fn wait_for_thread() {
+        use euralios_std::syscalls;
+
+        extern "C" fn exit_with_code(code: usize) {
+            syscalls::exit(code as u64);
+        }
+        let tid = syscalls::thread_spawn(exit_with_code, 42).unwrap();
+        assert_eq!(syscalls::wait(tid), Ok(42));
+        // Exit code already taken, and the thread is gone
+        assert_eq!(syscalls::wait(tid), Err(syscalls::SYSCALL_ERROR_PARAM));
+        assert!(!syscalls::list_threads().unwrap().iter().any(|info| info.tid == tid));
+    }
+
+    #[test_case]
+    fn sync_discards_late_reply() {
+        use euralios_std::{syscalls::{self, VFS}, thread};
+        use euralios_std::message::{self, Message};
+
+        // Replies to the first SYNC after the child's timeout
+        let (hung, hung_server) = syscalls::new_rendezvous().unwrap();
+        thread::spawn(move || {
+            let mut first = true;
+            while let Ok(Message::Short(message::SYNC, _, _)) = syscalls::receive(&hung_server) {
+                if first {
+                    syscalls::sleep_us(300_000);
+                    first = false;
+                }
+                _ = syscalls::send(&hung_server, Message::Short(message::OK, 0, 0));
+            }
+        }).unwrap();
+        // Always replies at once
+        let (prompt, prompt_server) = syscalls::new_rendezvous().unwrap();
+        thread::spawn(move || {
+            while let Ok(Message::Short(message::SYNC, _, _)) = syscalls::receive(&prompt_server) {
+                _ = syscalls::send(&prompt_server, Message::Short(message::OK, 0, 0));
+            }
+        }).unwrap();
+
+        let (_input, child_input) = syscalls::new_rendezvous().unwrap();
+        let tid = syscalls::exec_filtered_args(
+            &child_binary(),
+            syscalls::EXEC_FILTER_KILL,
+            child_input,
+            syscalls::STDOUT.clone(),
+            VFS::new().mount(hung, "/hung").mount(prompt, "/prompt"),
+            u64::MAX,
+            &["system_test", CHILD_ARG, "sync_timeout"]).unwrap();
+        assert_eq!(syscalls::wait(tid), Ok(1));
+    }
+
+    #[test_case]
+    fn exec_passes_args() {
+        use euralios_std::syscalls::{self, VFS};
+
+        let (_input, child_input) = syscalls::new_rendezvous().unwrap();
+        let mut args = alloc::vec!["system_test", CHILD_ARG, "args"];
+        args.extend_from_slice(&CHILD_EXTRA_ARGS);
+        let tid = syscalls::exec_filtered_args(
+            &child_binary(),
+            syscalls::EXEC_FILTER_KILL,
+            child_input,
+            syscalls::STDOUT.clone(),
+            VFS::shared(),
+            CHILD_SYSCALLS,
+            &args).unwrap();
+        assert_eq!(syscalls::wait(tid), Ok(1));
+    }
+
+    #[test_case]
+    fn vga_framebuffer_mode() {
+        use alloc::vec::Vec;
+        use euralios_std::{fs::File, syscalls::{self, MemoryHandle, VFS}};
+        use euralios_std::message::{self, Message, MessageData, video::{self, Mode, Rect}};
+
+        const WIDTH: u16 = 16;
+        const HEIGHT: u16 = 8;
+
+        let mut bin = Vec::new();
+        File::open("/ramdisk/bin/vga_driver").unwrap().read_to_end(&mut bin).unwrap();
+        let (driver, driver_stdin) = syscalls::new_rendezvous().unwrap();
+        let tid = syscalls::exec(&bin, 0, driver_stdin.clone(), driver_stdin,
+                                 VFS::shared()).unwrap();
+
+        // A memory chunk in place of the display
+        let (screen, _) = syscalls::malloc(4096, 0).unwrap();
+        let mode = Mode{width: WIDTH, height: HEIGHT,
+                        pitch: WIDTH as u32 * 4, bits_per_pixel: 32};
+        syscalls::send(&driver, Message::Long(message::VIDEO_MEMORY,
+                                              mode.to_value().into(),
+                                              screen.share().unwrap().into())).unwrap();
+        let graphics = match message::rcall(&driver, message::OPEN_READWRITE,
+                                            0.into(), 0.into(), Some(message::COMM_HANDLE)) {
+            Ok((_, MessageData::CommHandle(handle), _)) => handle,
+            _ => panic!("No graphics handle")
+        };
+        let pixel = |x: usize, y: usize| screen.as_slice::<u32>(WIDTH as usize * HEIGHT as usize)
+            [y * WIDTH as usize + x];
+
+        let rect = Rect{x: 0, y: 0, width: WIDTH, height: HEIGHT};
+        assert!(message::rcall(&graphics, video::FILL_RECT, rect.to_value().into(),
+                               0x112233.into(), Some(message::OK)).is_ok());
+        assert_eq!(pixel(WIDTH as usize - 1, HEIGHT as usize - 1), 0x112233);
+
+        // Clipped at the right edge
+        let pixels: Vec<u8> = [1u32, 2, 3, 4].iter().flat_map(|p| p.to_le_bytes()).collect();
+        let rect = Rect{x: WIDTH - 1, y: 1, width: 2, height: 2};
+        assert!(message::rcall(&graphics, video::BLIT, rect.to_value().into(),
+                               MemoryHandle::from_u8_slice(&pixels).into(),
+                               Some(message::OK)).is_ok());
+        assert_eq!(pixel(WIDTH as usize - 1, 1), 1);
+        assert_eq!(pixel(WIDTH as usize - 1, 2), 3);
+        assert_eq!(pixel(WIDTH as usize - 2, 1), 0x112233);
+
+        // More pixels than the chunk holds
+        let rect = Rect{x: 0, y: 0, width: 1024, height: 1024};
+        let result = message::rcall(&graphics, video::BLIT, rect.to_value().into(),
+                                    MemoryHandle::from_u8_slice(&[0; 16]).into(), None);
+        assert!(matches!(result, Err((syscalls::SYSCALL_ERROR_PARAM, _))));
+        assert_eq!(pixel(0, 0), 0x112233);
+
+        syscalls::close(graphics);
+        syscalls::close(driver);
+        assert_eq!(syscalls::wait(tid), Ok(0));
+    }
+
+    #[test_case]
+    fn memory_syscalls_non_canonical() {
+        use euralios_std::syscalls::{self, MemoryHandle};
+
+        // Not a valid address, rather than a kernel panic
+        let mut handle = MemoryHandle::new(0x0000_8000_0000_0000);
+        assert_eq!(handle.size(), Err(syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(handle.share().err(), Some(syscalls::SYSCALL_ERROR_PARAM));
+        assert_eq!(handle.share_read_only().err(), Some(syscalls::SYSCALL_ERROR_PARAM));
+        unsafe{handle.take()};
+    }
+
+    #[test_case]
+    fn share_memory_read_only() {
+        use euralios_std::syscalls;
+
+        let (mut memory, _) = syscalls::malloc(4096, 0).unwrap();
+        let view = memory.share_read_only().unwrap();
+
+        // Writes through the original are seen through the view
+        memory.as_mut_slice::<u8>(4)[1] = 42;
+        assert_eq!(view.as_slice::<u8>(4), [0, 42, 0, 0]);
+
+        // Can't get write access back
+        assert_eq!(view.share().err(), Some(syscalls::SYSCALL_ERROR_PARAM));
+        let second_view = view.share_read_only().unwrap();
+
+        // Frames are kept until the last mapping is freed
+        drop(memory);
+        drop(view);
+        assert_eq!(second_view.as_slice::<u8>(2), [0, 42]);
+    }
+
+    #[test_case]
+    fn memory_handle_size() {
+        use euralios_std::syscalls;
+
+        let (memory, _) = syscalls::malloc(3 * 4096 + 1, 0).unwrap();
+        assert_eq!(memory.size(), Ok(4 * 4096));
+        assert_eq!(memory.share_read_only().unwrap().size(), Ok(4 * 4096));
+    }
+
+    #[test_case]
+    fn close_wakes_receiver() {
+        use euralios_std::syscalls::{self, CommHandle};
+
+        extern "C" fn receive_until_closed(handle: usize) {
+            let handle = CommHandle::new(handle as u32);
+            let closed = syscalls::receive(&handle).err() == Some(syscalls::SYSCALL_ERROR_CLOSED);
+            syscalls::exit(closed as u64);
+        }
+        let (sender, mut receiver) = syscalls::new_rendezvous().unwrap();
+        let tid = syscalls::thread_spawn(receive_until_closed,
+                                         unsafe{receiver.take()} as usize).unwrap();
+        // Closed either while the thread waits, or before it receives
+        syscalls::close(sender);
+        assert_eq!(syscalls::wait(tid), Ok(1));
+    }
+
+    #[test_case]
+    fn forbidden_syscall_kills_process() {
+        use euralios_std::syscalls;
+
+        let tid = start_child(&child_binary(), "forbidden_syscall", CHILD_SYSCALLS);
+        assert_eq!(syscalls::wait(tid), Ok(syscalls::EXIT_CODE_KILLED));
+        // The child is reaped: its exit code is taken, and it's gone
+        assert_eq!(syscalls::wait(tid), Err(syscalls::SYSCALL_ERROR_PARAM));
+        assert!(!syscalls::list_threads().unwrap().iter().any(|info| info.tid == tid));
+        // This process carries on, and can start another
+        assert_eq!(run_child("forbidden_syscall", CHILD_SYSCALLS),
+                   syscalls::EXIT_CODE_KILLED);
+    }
+
+    #[test_case]
+    fn killed_process_wakes_exit_waiters() {
+        use euralios_std::syscalls;
+
+        let allowed = !syscalls::syscall_bit(syscalls::SYSCALL_GET_TID);
+        assert_eq!(run_child("killed_while_blocked", allowed),
+                   syscalls::EXIT_CODE_KILLED);
+    }
+
+    #[test_case]
+    fn sample_usage_includes_blocked() {
+        use euralios_std::{syscalls::{self, ThreadUsage}, time};
+
+        extern "C" fn sleeper(_: usize) {
+            syscalls::sleep_us(1_000_000);
+            syscalls::exit(0);
+        }
+        extern "C" fn busy(_: usize) {
+            let start = time::microseconds_monotonic();
+            while time::microseconds_monotonic() - start < 1_000_000 {
+                core::hint::spin_loop();
+            }
+            syscalls::exit(0);
+        }
+        let sample = |tid: u64| -> ThreadUsage {
+            let mut buf = alloc::vec![ThreadUsage::default(); 256];
+            let count = syscalls::sample_usage(&mut buf).unwrap().min(buf.len());
+            *buf[..count].iter().find(|usage| usage.tid == tid)
+                .expect("Thread missing from sample")
+        };
+
+        let sleeping = syscalls::thread_spawn(sleeper, 0).unwrap();
+        let running = syscalls::thread_spawn(busy, 0).unwrap();
+        syscalls::sleep_us(100_000);
+        let (sleep_start, busy_start) = (sample(sleeping), sample(running));
+        syscalls::sleep_us(300_000);
+        // The sleeping thread is still sampled, but uses no time
+        let (sleep_end, busy_end) = (sample(sleeping), sample(running));
+        assert!(sleep_end.cpu_time_us - sleep_start.cpu_time_us < 10_000);
+        assert!(busy_end.cpu_time_us - busy_start.cpu_time_us > 100_000);
+
+        assert_eq!(syscalls::wait(sleeping), Ok(0));
+        assert_eq!(syscalls::wait(running), Ok(0));
+    }
+
+    #[test_case]
+    fn list_threads_includes_caller() {
+        use euralios_std::syscalls;
+
+        let tid = syscalls::get_tid();
+        let threads = syscalls::list_threads().unwrap();
+        let current: alloc::vec::Vec<_> = threads.iter()
+            .filter(|info| info.is_current()).collect();
+        assert_eq!(current.len(), 1);
+        assert_eq!(current[0].tid, tid);
+        assert!(!current[0].is_kernel());
+
+        let allowed = CHILD_SYSCALLS
+            | syscalls::syscall_bit(syscalls::SYSCALL_GET_TID)
+            | syscalls::syscall_bit(syscalls::SYSCALL_LIST_THREADS);
+        assert_eq!(run_child("list_threads", allowed), 1);
+    }
+
+    #[test_case]
+    fn stack_overflow_kills_process() {
+        use euralios_std::syscalls;
+
+        assert_eq!(run_child("stack_overflow", u64::MAX),
+                   syscalls::EXIT_CODE_KILLED);
+    }
+
+    #[test_case]
+    fn read_only_writes_kill_process() {
+        use euralios_std::syscalls;
+
+        assert_eq!(run_child("write_code", u64::MAX),
+                   syscalls::EXIT_CODE_KILLED);
+        assert_eq!(run_child("write_read_only_share", u64::MAX),
+                   syscalls::EXIT_CODE_KILLED);
+    }
+
+    #[test_case]
+    fn close_wakes_await_any() {
+        use euralios_std::syscalls::{self, CommHandle};
+
+        extern "C" fn await_until_closed(handle: usize) {
+            let handle = CommHandle::new(handle as u32);
+            let closed = syscalls::await_any(&[handle]).err() == Some(syscalls::SYSCALL_ERROR_CLOSED);
+            syscalls::exit(closed as u64);
+        }
+        let (sender, mut receiver) = syscalls::new_rendezvous().unwrap();
+        let tid = syscalls::thread_spawn(await_until_closed,
+                                         unsafe{receiver.take()} as usize).unwrap();
+        syscalls::sleep_us(100_000);
+        syscalls::close(sender);
+        assert_eq!(syscalls::wait(tid), Ok(1));
+    }
+
+    #[test_case]
+    fn close_copy_keeps_await_any() {
+        use euralios_std::syscalls::{self, CommHandle};
+        use euralios_std::message::Message;
+
+        extern "C" fn await_message(handle: usize) {
+            let handle = CommHandle::new(handle as u32);
+            let received = matches!(syscalls::await_any(&[handle]),
+                                    Ok((0, Message::Short(7, 0, 0))));
+            syscalls::exit(received as u64);
+        }
+        let (sender, mut receiver) = syscalls::new_rendezvous().unwrap();
+        let receiver_copy = receiver.clone();
+        let sender_copy = sender.clone();
+        let tid = syscalls::thread_spawn(await_message,
+                                         unsafe{receiver.take()} as usize).unwrap();
+        syscalls::sleep_us(100_000);
+        // Copies of both ends are still open, so the waiter keeps waiting
+        syscalls::close(receiver_copy);
+        syscalls::close(sender_copy);
+        syscalls::sleep_us(100_000);
+        syscalls::send(&sender, Message::Short(7, 0, 0)).unwrap();
+        assert_eq!(syscalls::wait(tid), Ok(1));
+    }
+
+    #[test_case]
+    fn buffered_comm_handle_writes() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use euralios_std::{io::{self, BufWriter, Write, Writer}, syscalls, thread};
+        use euralios_std::message::{self, Message, MessageData};
+
+        static MESSAGES: AtomicUsize = AtomicUsize::new(0);
+        static BYTES: AtomicUsize = AtomicUsize::new(0);
+
+        let (client, server) = syscalls::new_rendezvous().unwrap();
+        thread::spawn(move || {
+            while let Ok(Message::Long(message::WRITE,
+                                       MessageData::Value(length),
+                                       MessageData::MemoryHandle(_))) = syscalls::receive(&server) {
+                MESSAGES.fetch_add(1, Ordering::Relaxed);
+                BYTES.fetch_add(length as usize, Ordering::Relaxed);
+                _ = syscalls::send(&server, Message::Short(message::OK, 0, 0));
+            }
+        }).unwrap();
+
+        let mut writer = BufWriter::new(Writer::new(&client));
+        for i in 0..100 {
+            write!(writer, "{}\n", i % 10).unwrap();
+        }
+        assert_eq!(MESSAGES.load(Ordering::Relaxed), 0);
+        writer.flush().unwrap();
+        assert_eq!(MESSAGES.load(Ordering::Relaxed), 1);
+        assert_eq!(BYTES.load(Ordering::Relaxed), 200);
+        drop(writer);
+        assert_eq!(MESSAGES.load(Ordering::Relaxed), 1);
+
+        let mut stdout = BufWriter::new(io::stdout());
+        write!(stdout, "buffered ").unwrap();
+        writeln!(stdout, "stdout").unwrap();
+        assert_eq!(stdout.flush(), Ok(()));
+    }
+
+    #[test_case]
+    fn bss_is_zeroed() {
+        use euralios_std::syscalls;
+
+        // Read first, so that the heap doesn't grow after the frames
+        // are dirtied
+        let bin = child_binary();
+
+        // Free frames are reused by the loader, so leave non-zero
+        // contents in more frames than the child will be given
+        const CHUNK_SIZE: usize = 1 << 20;
+        let mut chunks = alloc::vec::Vec::new();
+        while chunks.len() * CHUNK_SIZE < 2 * bin.len() + CHUNK_SIZE {
+            let (mut chunk, _) = syscalls::malloc(CHUNK_SIZE as u64, 0).unwrap();
+            chunk.as_mut_slice::<u8>(CHUNK_SIZE).fill(0xA5);
+            chunks.push(chunk);
+        }
+        drop(chunks);
+
+        assert_eq!(exec_child(&bin, "bss_is_zeroed", u64::MAX), 1);
+        // This process was loaded the same way
+        let array = unsafe {&*core::ptr::addr_of!(UNINITIALIZED)};
+        assert!(array.iter().all(|&value| value == 0));
+    }
+
+    #[test_case]
+    fn exec_compressed_child() {
+        use euralios_std::{fs::File, syscalls::{self, VFS}};
+
+        // Written by init, compressed by the makefile
+        let mut compressed = alloc::vec::Vec::new();
+        File::open("/ramdisk/bin/system_test.gz").unwrap()
+            .read_to_end(&mut compressed).unwrap();
+        let exec = |compressed: &[u8]| {
+            let (_input, child_input) = syscalls::new_rendezvous().unwrap();
+            syscalls::exec_compressed(
+                compressed,
+                0,
+                child_input,
+                syscalls::STDOUT.clone(),
+                VFS::shared(),
+                &["system_test", CHILD_ARG, "bss_is_zeroed"])
+        };
+        let tid = exec(&compressed).unwrap();
+        assert_eq!(syscalls::wait(tid), Ok(1));
+
+        // A corrupt stream fails its CRC, or doesn't inflate
+        let middle = compressed.len() / 2;
+        compressed[middle] ^= 0xFF;
+        assert_eq!(exec(&compressed), Err(syscalls::SYSCALL_ERROR_INVALID_DATA));
+    }
 }
 
 // Custom test framework
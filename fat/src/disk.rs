@@ -0,0 +1,103 @@
+//! Access to the partition containing the filesystem
+//!
+//! The block device is read through the usual file messages, so
+//! offsets and lengths must be whole device sectors.
+
+extern crate alloc;
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+
+use euralios_std::{fs::File,
+                   io::SeekFrom,
+                   println,
+                   syscalls::{self, SyscallError}};
+
+/// Sector size of the block device
+pub const SECTOR_SIZE: usize = 512;
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Offset of the signature in the MBR or a FAT boot sector
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+/// Offset of the first of four MBR partition entries
+const PARTITION_TABLE_OFFSET: usize = 446;
+const PARTITION_ENTRY_SIZE: usize = 16;
+
+/// MBR partition types which contain FAT16 or FAT32 filesystems
+const FAT_PARTITION_TYPES: [u8; 5] = [
+    0x04, // FAT16 < 32MiB
+    0x06, // FAT16
+    0x0B, // FAT32 CHS
+    0x0C, // FAT32 LBA
+    0x0E  // FAT16 LBA
+];
+
+/// True if a sector starts with a jump instruction and has the
+/// boot signature, as FAT boot sectors do
+pub fn is_boot_sector(sector: &[u8]) -> bool {
+    (sector[0] == 0xEB || sector[0] == 0xE9) &&
+        sector[BOOT_SIGNATURE_OFFSET..][..2] == BOOT_SIGNATURE
+}
+
+pub struct Partition {
+    device: Mutex<File>,
+    /// Byte offset of the partition on the device
+    start: u64
+}
+
+impl Partition {
+    /// Open a block device, and find the first FAT partition
+    ///
+    /// A device without a partition table, which starts with a
+    /// boot sector, is used as a whole.
+    pub fn open(path: &str) -> Result<Partition, SyscallError> {
+        let mut partition = Partition{device: Mutex::new(File::open(path)?),
+                                      start: 0};
+        let mut mbr = [0; SECTOR_SIZE];
+        partition.read(0, &mut mbr)?;
+
+        if is_boot_sector(&mbr) {
+            return Ok(partition);
+        }
+        if mbr[BOOT_SIGNATURE_OFFSET..][..2] != BOOT_SIGNATURE {
+            println!("[fat] No partition table on {}", path);
+            return Err(syscalls::SYSCALL_ERROR_NOTFOUND);
+        }
+
+        for (i, entry) in mbr[PARTITION_TABLE_OFFSET..BOOT_SIGNATURE_OFFSET]
+            .chunks_exact(PARTITION_ENTRY_SIZE).enumerate() {
+                let partition_type = entry[4];
+                let start_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+                if FAT_PARTITION_TYPES.contains(&partition_type) && start_lba != 0 {
+                    println!("[fat] Partition {} type {:#04x} at sector {}",
+                             i + 1, partition_type, start_lba);
+                    partition.start = start_lba as u64 * SECTOR_SIZE as u64;
+                    return Ok(partition);
+                }
+            }
+        println!("[fat] No FAT partition on {}", path);
+        Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+    }
+
+    /// Read from a byte offset in the partition
+    ///
+    /// The offset and buffer length must be multiples of SECTOR_SIZE
+    pub fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<(), SyscallError> {
+        let mut device = self.device.lock();
+        device.seek(SeekFrom::Start(self.start + offset))?;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match device.read(&mut buffer[filled..])? {
+                0 => return Err(syscalls::SYSCALL_ERROR_NO_DATA), // Past the end
+                n => filled += n
+            }
+        }
+        Ok(())
+    }
+
+    /// Read into a new buffer
+    pub fn read_vec(&self, offset: u64, length: usize) -> Result<Vec<u8>, SyscallError> {
+        let mut buffer = vec![0; length];
+        self.read(offset, &mut buffer)?;
+        Ok(buffer)
+    }
+}
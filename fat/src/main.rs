@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+//! Read-only FAT16 and FAT32 filesystem
+//!
+//! Reads the first FAT partition of the block device at DEVICE_PATH,
+//! and serves its files and directories with server::handle_directory
+//! so that euralios_std::fs can be used to read them.
+//!
+//! Names are case insensitive, and either the long name or the 8.3
+//! short name can be used.
+
+extern crate alloc;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use alloc::format;
+
+use spin::RwLock;
+
+use euralios_std::{println,
+                   server::{self, FileLike, DirLike, handle_directory},
+                   message,
+                   syscalls::{self, STDIN, SyscallError}};
+
+mod disk;
+mod volume;
+use disk::Partition;
+use volume::{Entry, Location, Volume};
+
+/// The block device containing the filesystem
+const DEVICE_PATH: &str = "/dev/sda";
+
+/// A file, with its cluster chain
+struct File {
+    volume: Arc<Volume>,
+    clusters: Vec<u32>,
+    size: usize
+}
+
+impl FileLike for File {
+    fn len(&self) -> usize {
+        self.size
+    }
+    fn read(&self, start: usize, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        if start >= self.size {
+            return Err(syscalls::SYSCALL_ERROR_NO_DATA);
+        }
+        let length = buffer.len().min(self.size - start);
+        self.volume.read(&self.clusters, start, &mut buffer[..length])
+    }
+}
+
+/// A directory, with its entries read when it is opened
+struct Directory {
+    volume: Arc<Volume>,
+    entries: Vec<Entry>
+}
+
+impl Directory {
+    fn new(volume: Arc<Volume>, location: Location) -> Result<Self, SyscallError> {
+        let entries = volume.read_dir(location)?;
+        Ok(Directory{volume, entries})
+    }
+
+    fn find(&self, name: &str) -> Result<&Entry, SyscallError> {
+        self.entries.iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(syscalls::SYSCALL_ERROR_NOTFOUND)
+    }
+}
+
+impl DirLike for Directory {
+    fn get_dir(&self, name: &str) -> Result<Arc<RwLock<dyn DirLike + Send + Sync>>, SyscallError> {
+        let entry = self.find(name)?;
+        if !entry.is_dir() {
+            return Err(syscalls::SYSCALL_ERROR_NOT_DIR);
+        }
+        Ok(Arc::new(RwLock::new(
+            Directory::new(self.volume.clone(), entry.location())?)))
+    }
+
+    fn get_file(&self, name: &str) -> Result<Arc<RwLock<dyn FileLike + Send + Sync>>, SyscallError> {
+        let entry = self.find(name)?;
+        if entry.is_dir() {
+            return Err(syscalls::SYSCALL_ERROR_IS_DIR);
+        }
+        Ok(Arc::new(RwLock::new(File{
+            volume: self.volume.clone(),
+            clusters: self.volume.cluster_chain(entry.cluster)?,
+            size: entry.size as usize
+        })))
+    }
+
+    fn query(&self) -> String {
+        // FAT names can't contain '"' or '\', so don't need escaping
+        let list = |dirs: bool| -> String {
+            self.entries.iter()
+                .filter(|entry| entry.is_dir() == dirs)
+                .map(|entry| if dirs {
+                    format!("{{\"name\":\"{}\"}}", entry.name)
+                } else {
+                    format!("{{\"name\":\"{}\", \"size\":{}}}", entry.name, entry.size)
+                })
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        format!("{{
+\"short\": \"FAT directory\",
+\"type\": \"dir\",
+\"messages\": [{{\"name\": \"open\",
+                 \"tag\": {open_tag}}},
+               {{\"name\": \"query\",
+                 \"tag\": {query_tag}}}],
+\"subdirs\": [{subdir_list}],
+\"files\": [{file_list}]}}",
+                open_tag = message::OPEN,
+                query_tag = message::QUERY,
+                file_list = list(false),
+                subdir_list = list(true))
+    }
+}
+
+#[cfg(not(test))]
+#[no_mangle]
+fn main() {
+    println!("[fat] Starting FAT filesystem on {}", DEVICE_PATH);
+
+    let volume = match Partition::open(DEVICE_PATH).and_then(Volume::open) {
+        Ok(volume) => Arc::new(volume),
+        Err(err) => {
+            println!("[fat] Couldn't open filesystem: {}", err);
+            server::signal_failed(err);
+            return;
+        }
+    };
+    println!("[fat] {:?} volume, {} MiB",
+             volume.fat_type, volume.size() >> 20);
+
+    let root = match Directory::new(volume.clone(), volume.root()) {
+        Ok(root) => root,
+        Err(err) => {
+            println!("[fat] Couldn't read root directory: {}", err);
+            server::signal_failed(err);
+            return;
+        }
+    };
+
+    server::signal_ready();
+
+    handle_directory(
+        Arc::new(RwLock::new(root)),
+        STDIN.clone(),
+        false, // Read only
+        |message| {
+            println!("[fat] Received unexpected message {:?}", message);
+        });
+}
+
+// Unit tests, built as user/fat_test
+
+#[cfg(test)]
+#[no_mangle]
+fn main() {
+    test_main();
+}
+
+#[cfg(test)]
+pub fn test_runner(tests: &[&dyn euralios_std::Testable]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+}
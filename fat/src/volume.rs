@@ -0,0 +1,583 @@
+//! FAT16 and FAT32 volumes
+//!
+//! Parses the BIOS Parameter Block (BPB) in the boot sector, follows
+//! cluster chains through the first FAT, and parses directory
+//! entries including long file names (LFN).
+//!
+//! <https://wiki.osdev.org/FAT>
+
+extern crate alloc;
+use alloc::{string::String, vec::Vec};
+use core::char;
+
+use euralios_std::{println,
+                   syscalls::{self, SyscallError}};
+
+use crate::disk::{self, Partition, SECTOR_SIZE};
+
+/// Volumes with fewer clusters are FAT12, which isn't supported
+const FAT16_MIN_CLUSTERS: u32 = 4085;
+/// Volumes with at least this many clusters are FAT32
+const FAT32_MIN_CLUSTERS: u32 = 65525;
+
+/// FAT32 entries are 28 bits. The top 4 bits are reserved
+const FAT32_ENTRY_MASK: u32 = 0x0FFF_FFFF;
+/// FAT entries from this value onwards mark the end of a chain
+const FAT16_END_OF_CHAIN: u32 = 0xFFF8;
+const FAT32_END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+/// The first cluster in the data region
+const FIRST_CLUSTER: u32 = 2;
+
+const DIR_ENTRY_SIZE: usize = 32;
+/// First name byte of the entry after the last in a directory
+const ENTRY_END: u8 = 0x00;
+/// First name byte of a deleted entry
+const ENTRY_DELETED: u8 = 0xE5;
+/// First name byte standing for 0xE5, which means deleted
+const ENTRY_E5: u8 = 0x05;
+
+pub const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_VOLUME_ID: u8 = 0x08;
+/// The attributes of a long file name entry
+const ATTR_LONG_NAME: u8 = 0x0F;
+/// Sequence number flag of the last (first stored) LFN entry
+const LFN_LAST: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1F;
+/// UTF-16 characters in each LFN entry
+const LFN_CHARS: usize = 13;
+/// Offsets of the name characters in an LFN entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Flags in byte 12 of a short entry, set by Windows NT
+/// for names which are all lower case
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXTENSION: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32
+}
+
+/// Where the entries of a directory are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// The FAT16 root directory, between the FATs and the data region
+    FixedRoot,
+    /// A chain of clusters, starting at a given cluster
+    Clusters(u32)
+}
+
+/// A file or directory in a directory
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The long file name if there is one, otherwise the short name
+    pub name: String,
+    /// 8.3 name, e.g. "README.TXT"
+    pub short_name: String,
+    pub attributes: u8,
+    /// First cluster. 0 for empty files
+    pub cluster: u32,
+    /// Size in bytes. 0 for directories
+    pub size: u32
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// FAT names are case insensitive. Either the long or short
+    /// name can be used.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) ||
+            self.short_name.eq_ignore_ascii_case(name)
+    }
+
+    pub fn location(&self) -> Location {
+        Location::Clusters(self.cluster)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..][..4].try_into().unwrap())
+}
+
+/// Checksum of an 8.3 name, stored in each of its LFN entries
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name[..11].iter().fold(0u8, |sum, &byte| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte)
+    })
+}
+
+/// Convert the 11 byte name field of a short entry to "NAME.EXT"
+///
+/// Bytes outside ASCII are treated as Latin-1 rather than the
+/// code page the volume was written with.
+fn short_name(entry: &[u8]) -> String {
+    let flags = entry[12];
+    let convert = |bytes: &[u8], lower: bool| -> String {
+        bytes.iter()
+            .map(|&byte| {
+                let c = char::from(byte);
+                if lower { c.to_ascii_lowercase() } else { c }
+            })
+            .collect::<String>()
+            .trim_end_matches(' ')
+            .into()
+    };
+
+    let mut base = [0; 8];
+    base.copy_from_slice(&entry[..8]);
+    if base[0] == ENTRY_E5 {
+        base[0] = ENTRY_DELETED;
+    }
+    let mut name = convert(&base, flags & NT_LOWER_BASE != 0);
+    let extension = convert(&entry[8..11], flags & NT_LOWER_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// Long file name being collected from LFN entries
+///
+/// LFN entries are stored in reverse order before their short
+/// entry. The long name is only used if every part is present and
+/// the checksums match the short name.
+struct LongName {
+    /// UTF-16 characters, LFN_CHARS for each entry
+    chars: Vec<u16>,
+    checksum: u8,
+    /// Sequence number of the next entry expected
+    next: u8
+}
+
+impl LongName {
+    /// Start from the last part of a name
+    fn start(entry: &[u8]) -> Option<LongName> {
+        let sequence = entry[0] & LFN_SEQUENCE_MASK;
+        if entry[0] & LFN_LAST == 0 || sequence == 0 {
+            return None;
+        }
+        let mut name = LongName{chars: Vec::new(),
+                                checksum: entry[13],
+                                next: sequence};
+        name.chars.resize(sequence as usize * LFN_CHARS, 0xFFFF);
+        name.add(entry).then(|| name)
+    }
+
+    /// Add the part stored in an entry, returning false if it is
+    /// out of sequence
+    fn add(&mut self, entry: &[u8]) -> bool {
+        if entry[0] & LFN_SEQUENCE_MASK != self.next || entry[13] != self.checksum {
+            return false;
+        }
+        self.next -= 1;
+        let start = self.next as usize * LFN_CHARS;
+        for (i, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = read_u16(entry, offset);
+        }
+        true
+    }
+
+    /// The name, if complete and belonging to the short entry
+    fn finish(self, short_entry: &[u8]) -> Option<String> {
+        if self.next != 0 || self.checksum != short_name_checksum(short_entry) {
+            return None;
+        }
+        // Names are terminated by 0 if they don't fill the last
+        // part, followed by 0xFFFF padding
+        let length = self.chars.iter()
+            .position(|&c| c == 0 || c == 0xFFFF)
+            .unwrap_or(self.chars.len());
+        Some(char::decode_utf16(self.chars[..length].iter().cloned())
+             .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+             .collect())
+    }
+}
+
+/// Parse the entries of a directory, skipping deleted entries,
+/// volume labels, and the "." and ".." entries
+pub fn parse_entries(data: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut long_name: Option<LongName> = None;
+
+    for entry in data.chunks_exact(DIR_ENTRY_SIZE) {
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long_name = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        let attributes = entry[11];
+        if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+            long_name = match long_name.take() {
+                Some(mut name) if entry[0] & LFN_LAST == 0 => {
+                    name.add(entry).then(|| name)
+                }
+                _ => LongName::start(entry)
+            };
+            continue;
+        }
+
+        let long_name = long_name.take().and_then(|name| name.finish(entry));
+        if attributes & ATTR_VOLUME_ID != 0 || entry[0] == b'.' {
+            continue;
+        }
+
+        let short_name = short_name(entry);
+        entries.push(Entry{
+            name: long_name.unwrap_or_else(|| short_name.clone()),
+            short_name,
+            attributes,
+            cluster: ((read_u16(entry, 20) as u32) << 16) | read_u16(entry, 26) as u32,
+            size: read_u32(entry, 28)
+        });
+    }
+    entries
+}
+
+/// Follow a cluster chain, with `next` reading the FAT entry of
+/// a cluster
+///
+/// Chains starting at cluster 0 are empty. Chains which run into a
+/// free, bad or out of range cluster, or which are longer than the
+/// `cluster_count` clusters in the volume (so must loop), are an
+/// error.
+fn follow_chain(start: u32, cluster_count: u32, end_of_chain: u32,
+                mut next: impl FnMut(u32) -> Result<u32, SyscallError>)
+                -> Result<Vec<u32>, SyscallError> {
+    let mut chain = Vec::new();
+    if start == 0 {
+        return Ok(chain);
+    }
+    let mut cluster = start;
+    loop {
+        if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= cluster_count ||
+            chain.len() >= cluster_count as usize {
+            println!("[fat] Invalid cluster chain from {}", start);
+            return Err(syscalls::SYSCALL_ERROR_IO);
+        }
+        chain.push(cluster);
+        match next(cluster)? {
+            entry if entry >= end_of_chain => return Ok(chain),
+            entry => cluster = entry
+        }
+    }
+}
+
+pub struct Volume {
+    partition: Partition,
+    pub fat_type: FatType,
+    bytes_per_sector: u64,
+    sectors_per_cluster: u64,
+    /// First sector of the first FAT
+    fat_start: u64,
+    /// First sector and number of sectors of the FAT16 root directory
+    root_start: u64,
+    root_sectors: u64,
+    /// First sector of cluster 2
+    data_start: u64,
+    cluster_count: u32,
+    /// First cluster of the FAT32 root directory
+    root_cluster: u32
+}
+
+impl Volume {
+    /// Read and check the BPB
+    pub fn open(partition: Partition) -> Result<Volume, SyscallError> {
+        let boot_sector = partition.read_vec(0, SECTOR_SIZE)?;
+        if !disk::is_boot_sector(&boot_sector) {
+            println!("[fat] Not a FAT boot sector");
+            return Err(syscalls::SYSCALL_ERROR_NOTFOUND);
+        }
+
+        let bytes_per_sector = read_u16(&boot_sector, 11) as u64;
+        let sectors_per_cluster = boot_sector[13] as u64;
+        let reserved_sectors = read_u16(&boot_sector, 14) as u64;
+        let num_fats = boot_sector[16] as u64;
+        let root_entries = read_u16(&boot_sector, 17) as u64;
+        let total_sectors = match read_u16(&boot_sector, 19) {
+            0 => read_u32(&boot_sector, 32) as u64,
+            sectors => sectors as u64
+        };
+        let fat_sectors = match read_u16(&boot_sector, 22) {
+            0 => read_u32(&boot_sector, 36) as u64,
+            sectors => sectors as u64
+        };
+
+        if !bytes_per_sector.is_power_of_two() ||
+            !(SECTOR_SIZE as u64..=4096).contains(&bytes_per_sector) ||
+            !sectors_per_cluster.is_power_of_two() ||
+            reserved_sectors == 0 || num_fats == 0 || fat_sectors == 0 {
+                println!("[fat] Invalid BIOS Parameter Block");
+                return Err(syscalls::SYSCALL_ERROR_PARAM);
+            }
+
+        let root_sectors = (root_entries * DIR_ENTRY_SIZE as u64
+                            + bytes_per_sector - 1) / bytes_per_sector;
+        let fat_start = reserved_sectors;
+        let root_start = fat_start + num_fats * fat_sectors;
+        let data_start = root_start + root_sectors;
+        if data_start >= total_sectors {
+            println!("[fat] Invalid BIOS Parameter Block");
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        let cluster_count = ((total_sectors - data_start) / sectors_per_cluster) as u32;
+
+        let fat_type = if cluster_count < FAT16_MIN_CLUSTERS {
+            println!("[fat] FAT12 is not supported");
+            return Err(syscalls::SYSCALL_ERROR_NOT_IMPLEMENTED);
+        } else if cluster_count < FAT32_MIN_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        Ok(Volume{
+            partition,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start,
+            root_start,
+            root_sectors,
+            data_start,
+            cluster_count,
+            root_cluster: match fat_type {
+                FatType::Fat16 => 0,
+                FatType::Fat32 => read_u32(&boot_sector, 44)
+            }
+        })
+    }
+
+    /// Total size of the data region in bytes
+    pub fn size(&self) -> u64 {
+        self.cluster_count as u64 * self.cluster_size() as u64
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        (self.bytes_per_sector * self.sectors_per_cluster) as usize
+    }
+
+    pub fn root(&self) -> Location {
+        match self.fat_type {
+            FatType::Fat16 => Location::FixedRoot,
+            FatType::Fat32 => Location::Clusters(self.root_cluster)
+        }
+    }
+
+    /// The clusters in a chain, in order. See follow_chain
+    pub fn cluster_chain(&self, start: u32) -> Result<Vec<u32>, SyscallError> {
+        let (entry_size, end_of_chain) = match self.fat_type {
+            FatType::Fat16 => (2, FAT16_END_OF_CHAIN),
+            FatType::Fat32 => (4, FAT32_END_OF_CHAIN)
+        };
+        // The FAT sector last read, to avoid reading it
+        // again for each cluster
+        let mut fat_sector: Option<(u64, Vec<u8>)> = None;
+
+        follow_chain(start, self.cluster_count, end_of_chain, |cluster| {
+            let offset = cluster as u64 * entry_size;
+            let sector = self.fat_start + offset / self.bytes_per_sector;
+            let offset = (offset % self.bytes_per_sector) as usize;
+            let data = match fat_sector {
+                Some((number, ref data)) if number == sector => data,
+                _ => {
+                    let data = self.partition.read_vec(
+                        sector * self.bytes_per_sector, self.bytes_per_sector as usize)?;
+                    &fat_sector.insert((sector, data)).1
+                }
+            };
+
+            Ok(match self.fat_type {
+                FatType::Fat16 => read_u16(data, offset) as u32,
+                FatType::Fat32 => read_u32(data, offset) & FAT32_ENTRY_MASK
+            })
+        })
+    }
+
+    /// Read part of a cluster
+    ///
+    /// `offset` and the buffer length must be multiples of the
+    /// sector size, and within the cluster.
+    pub fn read_cluster(&self, cluster: u32, offset: usize, buffer: &mut [u8]) -> Result<(), SyscallError> {
+        let sector = self.data_start +
+            (cluster - FIRST_CLUSTER) as u64 * self.sectors_per_cluster;
+        self.partition.read(sector * self.bytes_per_sector + offset as u64, buffer)
+    }
+
+    /// Read the start of a file into `buffer`
+    ///
+    /// `start` is a byte offset, and `clusters` the file's cluster
+    /// chain. Returns the number of bytes read, which is the buffer
+    /// length unless the chain ends.
+    pub fn read(&self, clusters: &[u32], start: usize, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+        let cluster_size = self.cluster_size();
+        let sector_size = self.bytes_per_sector as usize;
+        let mut sectors = Vec::new();
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = start + done;
+            let cluster = match clusters.get(position / cluster_size) {
+                Some(&cluster) => cluster,
+                None => break
+            };
+            // Read the sectors containing the rest of the buffer,
+            // up to the end of this cluster
+            let offset = position % cluster_size;
+            let first_sector = offset / sector_size;
+            let length = (buffer.len() - done).min(cluster_size - offset);
+            let end_sector = (offset + length + sector_size - 1) / sector_size;
+
+            sectors.resize((end_sector - first_sector) * sector_size, 0);
+            self.read_cluster(cluster, first_sector * sector_size, &mut sectors)?;
+            let skip = offset - first_sector * sector_size;
+            buffer[done..][..length].copy_from_slice(&sectors[skip..][..length]);
+            done += length;
+        }
+        Ok(done)
+    }
+
+    /// Read and parse the entries of a directory
+    pub fn read_dir(&self, location: Location) -> Result<Vec<Entry>, SyscallError> {
+        let data = match location {
+            Location::FixedRoot => self.partition.read_vec(
+                self.root_start * self.bytes_per_sector,
+                (self.root_sectors * self.bytes_per_sector) as usize)?,
+            Location::Clusters(start) => {
+                let clusters = self.cluster_chain(start)?;
+                let mut data = Vec::new();
+                data.resize(clusters.len() * self.cluster_size(), 0);
+                self.read(&clusters, 0, &mut data)?;
+                data
+            }
+        };
+        Ok(parse_entries(&data))
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A short entry with an 11 byte name field
+    fn short_entry(name: &[u8; 11], attributes: u8, cluster: u32, size: u32) -> [u8; 32] {
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        entry[..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+
+    /// LFN entries for `name`, in the order they are stored
+    fn lfn_entries(name: &str, checksum: u8) -> Vec<[u8; 32]> {
+        let mut chars: Vec<u16> = name.encode_utf16().collect();
+        if chars.len() % LFN_CHARS != 0 {
+            chars.push(0);
+        }
+        while chars.len() % LFN_CHARS != 0 {
+            chars.push(0xFFFF);
+        }
+        let parts = chars.len() / LFN_CHARS;
+        (0..parts).rev().map(|part| {
+            let mut entry = [0; DIR_ENTRY_SIZE];
+            entry[0] = (part + 1) as u8 | if part == parts - 1 { LFN_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (i, &offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                entry[offset..][..2].copy_from_slice(&chars[part * LFN_CHARS + i].to_le_bytes());
+            }
+            entry
+        }).collect()
+    }
+
+    fn directory(entries: &[[u8; 32]]) -> Vec<u8> {
+        entries.iter().flatten().cloned().collect()
+    }
+
+    #[test_case]
+    fn short_names() {
+        let entry = short_entry(b"README  TXT", 0, 5, 1234);
+        assert_eq!(short_name(&entry), "README.TXT");
+        // No extension, and a first byte standing for 0xE5
+        let entry = short_entry(b"\x05BC        ", ATTR_DIRECTORY, 7, 0);
+        assert_eq!(short_name(&entry), "\u{e5}BC");
+        // Lower case flags set by Windows NT
+        let mut lower = short_entry(b"NOTES   MD ", 0, 0, 0);
+        lower[12] = NT_LOWER_BASE | NT_LOWER_EXTENSION;
+        assert_eq!(short_name(&lower), "notes.md");
+    }
+
+    #[test_case]
+    fn parse_short_entry() {
+        let data = directory(&[short_entry(b".          ", ATTR_DIRECTORY, 3, 0),
+                               short_entry(b"README  TXT", 0, 0x1_0005, 1234),
+                               [0; 32],
+                               short_entry(b"AFTER   END", 0, 6, 1)]);
+        let entries = parse_entries(&data);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "README.TXT");
+        assert_eq!(entries[0].short_name, "README.TXT");
+        assert_eq!(entries[0].cluster, 0x1_0005);
+        assert_eq!(entries[0].size, 1234);
+        assert!(!entries[0].is_dir());
+    }
+
+    #[test_case]
+    fn parse_long_name() {
+        let short = short_entry(b"ALONGF~1TXT", 0, 9, 42);
+        let name = "A long file name.txt"; // Two LFN entries
+        let mut entries = lfn_entries(name, short_name_checksum(&short));
+        assert_eq!(entries.len(), 2);
+        entries.push(short);
+        let parsed = parse_entries(&directory(&entries));
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].name, name);
+        assert_eq!(parsed[0].short_name, "ALONGF~1.TXT");
+        assert!(parsed[0].matches("a LONG file name.TXT"));
+        assert!(parsed[0].matches("alongf~1.txt"));
+    }
+
+    #[test_case]
+    fn long_name_checksum_mismatch() {
+        let short = short_entry(b"ALONGF~1TXT", 0, 9, 42);
+        let checksum = short_name_checksum(&short).wrapping_add(1);
+        let mut entries = lfn_entries("A long file name.txt", checksum);
+        entries.push(short);
+        // The long name belongs to another entry, so isn't used
+        let parsed = parse_entries(&directory(&entries));
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].name, "ALONGF~1.TXT");
+    }
+
+    #[test_case]
+    fn cluster_chains() {
+        // FAT entries of clusters 0 to 5: 2 -> 3 -> 5 -> end
+        let fat = [0, 0, 3, 5, 0, FAT16_END_OF_CHAIN];
+        let next = |cluster: u32| Ok(fat[cluster as usize]);
+        assert_eq!(follow_chain(2, 4, FAT16_END_OF_CHAIN, next), Ok(alloc::vec![2, 3, 5]));
+        assert_eq!(follow_chain(0, 4, FAT16_END_OF_CHAIN, next), Ok(Vec::new()));
+        // Runs into a free cluster
+        assert_eq!(follow_chain(4, 4, FAT16_END_OF_CHAIN, next), Err(syscalls::SYSCALL_ERROR_IO));
+    }
+
+    #[test_case]
+    fn cluster_chain_loop() {
+        // 2 -> 3 -> 4 -> 2 never ends
+        let fat = [0, 0, 3, 4, 2];
+        let next = |cluster: u32| Ok(fat[cluster as usize]);
+        assert_eq!(follow_chain(2, 3, FAT16_END_OF_CHAIN, next), Err(syscalls::SYSCALL_ERROR_IO));
+    }
+}
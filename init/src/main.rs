@@ -1,13 +1,22 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
 use euralios_std::{debug_println,
                    console::sequences,
                    fprintln,
                    fs::{self, File},
+                   server,
                    syscalls::{self, STDIN, STDOUT, CommHandle, VFS},
-                   message::{self, rcall, Message, MessageData}};
+                   message::{self, rcall, rcall_timeout, Message, MessageData}};
+
+/// How long to wait for a service to signal that it is ready
+const SERVICE_READY_TIMEOUT_US: u64 = 5_000_000;
+
 
+/// Baud rate of the serial port, sent to the driver when it starts
+const SERIAL_BAUD: u64 = message::serial::DEFAULT_BAUD;
 
 /// Represents a text console with an output communication handle
 /// and optional input handle
@@ -72,6 +81,12 @@ impl<'a> Console<'a> {
 }
 
 
+/// Start a service and mount its input handle at the given path
+///
+/// The service is given a copy of the VFS with a handle mounted at
+/// `server::READY_PATH`. The path is only mounted once the service
+/// signals that it is ready, so that services started afterwards
+/// can depend on it.
 fn mount(
     path: &str,
     bin: &[u8],
@@ -83,30 +98,88 @@ fn mount(
     // Make a new Rendezvous for the process input
     let (input, input2) = syscalls::new_rendezvous().unwrap();
 
+    // Rendezvous for the startup handshake
+    let (ready, ready2) = syscalls::new_rendezvous().unwrap();
+
     // Start the process
-    syscalls::exec(
+    if let Err(err) = syscalls::exec(
         bin,
         flags,
         input,
-        stdout,
-        VFS::shared()).expect("[init] Couldn't start program");
+        stdout.clone(),
+        VFS::copy().mount(ready2, server::READY_PATH)) {
+        fprintln!(&stdout, "[init] Couldn't start program for {}: {}", path, err);
+        return;
+    }
+
+    if !wait_ready(path, ready, &stdout) {
+        // Service failed; don't mount
+        return;
+    }
 
     // Mount in filesystem
     syscalls::mount(path, input2).expect("[init] Couldn't mount path");
 }
 
+/// Configure the serial port driver mounted at /dev/serial
+fn set_serial_baud(baud: u64, stdout: &CommHandle) {
+    let handle = match syscalls::open("/dev/serial", message::O_WRITE) {
+        Ok(handle) => handle,
+        Err(err) => {
+            fprintln!(stdout, "[init] Couldn't open /dev/serial: {}", err);
+            return;
+        }
+    };
+    // Don't hang boot if the driver doesn't reply
+    match rcall_timeout(&handle, message::serial::SET_BAUD, baud.into(), 0.into(), None,
+                        SERVICE_READY_TIMEOUT_US) {
+        Ok((message::OK, _, _)) => {}
+        result => fprintln!(stdout, "[init] Couldn't set serial baud rate {}: {:?}",
+                            baud, result)
+    }
+}
+
+/// Ask a service with message::READY on the handshake handle, and
+/// wait for it to reply READY
+///
+/// Returns false if the service reported that it failed to
+/// initialize, or closed the handle. If the service doesn't respond
+/// within SERVICE_READY_TIMEOUT_US then a warning is printed and it
+/// is assumed to be ready. The handle is then closed, so the service
+/// doesn't wait for a request which will never come.
+fn wait_ready(path: &str, ready: CommHandle, stdout: &CommHandle) -> bool {
+    let error = match rcall_timeout(&ready, message::READY, 0.into(), 0.into(), None,
+                                    SERVICE_READY_TIMEOUT_US) {
+        Ok((message::READY, _, _)) => return true,
+        Ok(_) => syscalls::SYSCALL_ERROR_UNEXPECTED,
+        Err((syscalls::SYSCALL_ERROR_TIMEOUT, _)) => {
+            fprintln!(stdout, "[init] Warning: {} not ready after {} ms. Continuing",
+                      path, SERVICE_READY_TIMEOUT_US / 1000);
+            return true;
+        }
+        // Including ERROR replies from server::signal_failed
+        Err((err, _)) => err
+    };
+    fprintln!(stdout, "[init] {} failed to start: {}", path, error);
+    false
+}
+
 #[no_mangle]
 fn main() {
     debug_println!("[init] Starting");
 
     // Start the keyboard input, configuring it to send to this
-    // process' input
-    syscalls::exec(
-        include_bytes!("../../user/keyboard"),
+    // process' input. Its own input is for changing layout.
+    let (keyboard_com, keyboard_com2) = syscalls::new_rendezvous().unwrap();
+    syscalls::exec_compressed(
+        include_bytes!("../../user/keyboard.gz"),
         syscalls::EXEC_PERM_IO, // I/O permissions
+        keyboard_com2,
         STDIN.clone(),
-        STDIN.clone(),
-        VFS::shared()).expect("[init] Couldn't start keyboard program");
+        VFS::shared(),
+        &["keyboard"]).expect("[init] Couldn't start keyboard program");
+    syscalls::mount("/dev/keyboard", keyboard_com)
+        .expect("[init] Couldn't mount keyboard");
 
     // Expect a video memory buffer from the kernel
     // Note: Sent to STDOUT channel to avoid conflict with keyboard
@@ -153,6 +226,10 @@ fn main() {
     };
     let writer_sys = &consoles[0].as_ref().unwrap().output;
 
+    // Any program can open the system console
+    syscalls::mount(syscalls::CONSOLE_PATH, writer_sys.clone())
+        .expect("[init] Couldn't mount console");
+
     fprintln!(writer_sys, "[init] Starting EuraliOS...");
 
     // Mount a ramdisk to read/write files
@@ -160,6 +237,12 @@ fn main() {
           0, // No I/O privileges
           writer_sys.clone());
 
+    // Scratch space, in a separate ramdisk so that it can be
+    // cleared without touching system files in /ramdisk
+    mount("/tmp", include_bytes!("../../user/ramdisk"),
+          0,
+          writer_sys.clone());
+
     // Create a "bin" folder for system binaries
     fs::create_dir("/ramdisk/bin");
 
@@ -170,15 +253,36 @@ fn main() {
     if let Ok(mut file) = File::create("/ramdisk/bin/system_test") {
         file.write(include_bytes!("../../user/system_test"));
     }
+    if let Ok(mut file) = File::create("/ramdisk/bin/system_test.gz") {
+        file.write(include_bytes!("../../user/system_test.gz"));
+    }
     if let Ok(mut file) = File::create("/ramdisk/bin/std_test") {
         file.write(include_bytes!("../../user/std_test"));
     }
+    if let Ok(mut file) = File::create("/ramdisk/bin/fat_test") {
+        file.write(include_bytes!("../../user/fat_test"));
+    }
     if let Ok(mut file) = File::create("/ramdisk/bin/keyboard") {
         file.write(include_bytes!("../../user/keyboard"));
     }
     if let Ok(mut file) = File::create("/ramdisk/bin/shell") {
         file.write(include_bytes!("../../user/shell"));
     }
+    // Not run from here, but system_test uses it in framebuffer mode
+    if let Ok(mut file) = File::create("/ramdisk/bin/vga_driver") {
+        file.write(include_bytes!("../../user/vga_driver"));
+    }
+
+    // Default environment, read by env::var
+    fs::create_dir("/ramdisk/etc");
+    if let Ok(mut file) = File::create(euralios_std::env::ENVIRONMENT_PATH) {
+        file.write(b"# Environment variables. Lines are KEY=VALUE\n\
+                     # Console colors: black red green yellow blue magenta cyan white\n\
+                     #TERM_FG=black\n\
+                     #TERM_BG=white\n\
+                     # Shell prompt. \\w is the current directory\n\
+                     #PS1=\\w $ \n");
+    }
 
     // Create some home directories
     fs::create_dir("/ramdisk/root");
@@ -189,8 +293,25 @@ fn main() {
           writer_sys.clone());
 
     mount("/dev/nic", include_bytes!("../../user/rtl8139"),
+          syscalls::EXEC_PERM_IO | syscalls::EXEC_PRIORITY_HIGH, // Service packets promptly
+          writer_sys.clone());
+
+    mount("/dev/mouse", include_bytes!("../../user/mouse"),
+          0, // Bytes are read by the kernel
+          writer_sys.clone());
+
+    mount("/dev/sda", include_bytes!("../../user/ata"),
+          syscalls::EXEC_PERM_IO,
+          writer_sys.clone());
+
+    mount("/disk", include_bytes!("../../user/fat"),
+          0, // Reads the disk through /dev/sda
+          writer_sys.clone());
+
+    mount("/dev/serial", include_bytes!("../../user/serial"),
           syscalls::EXEC_PERM_IO,
           writer_sys.clone());
+    set_serial_baud(SERIAL_BAUD, writer_sys);
 
     mount("/tcp", include_bytes!("../../user/tcp"),
           0, // No I/O permissions
@@ -0,0 +1,183 @@
+//! Process environment variables
+//!
+//! Each process has a small set of KEY=VALUE strings, read with the
+//! getenv syscall and changed by processes with I/O privileges with
+//! setenv and unsetenv. A process started with exec gets a copy of
+//! its parent's environment, so changes only affect the process and
+//! the processes it starts.
+//!
+//! The working directory is read as the CWD_KEY variable, but is
+//! kept separately and only changed with the chdir syscall.
+
+use alloc::{string::String, vec::Vec};
+
+use crate::syscalls;
+
+/// Longest key in bytes
+pub const MAX_KEY_SIZE: usize = 64;
+
+/// Longest value in bytes
+pub const MAX_VALUE_SIZE: usize = 1024;
+
+/// Most bytes of keys and values in one environment
+pub const MAX_ENV_SIZE: usize = 4096;
+
+/// Variable through which the working directory is read
+pub const CWD_KEY: &str = "PWD";
+
+/// Environment variables of a process
+#[derive(Clone, Debug, Default)]
+pub struct Environment {
+    vars: Vec<(String, String)>,
+    /// Absolute path, if set
+    cwd: Option<String>
+}
+
+impl Environment {
+    /// An environment with no variables
+    pub fn new() -> Self {
+        Environment{vars: Vec::new(), cwd: None}
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        if key == CWD_KEY {
+            return self.cwd.as_deref();
+        }
+        self.vars.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set or replace a variable
+    ///
+    /// Returns SYSCALL_ERROR_PARAM if the key is empty, too long or
+    /// contains '=' or NUL, or the value is too long or contains
+    /// NUL. Returns SYSCALL_ERROR_NO_SPACE if the environment would
+    /// be larger than MAX_ENV_SIZE, and SYSCALL_ERROR_DENIED for
+    /// CWD_KEY.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), usize> {
+        if !valid_key(key) || value.len() > MAX_VALUE_SIZE || value.contains('\0') {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        if key == CWD_KEY {
+            return Err(syscalls::SYSCALL_ERROR_DENIED);
+        }
+        let old_size = self.get(key).map_or(0, |old| key.len() + old.len());
+        if self.size() - old_size + key.len() + value.len() > MAX_ENV_SIZE {
+            return Err(syscalls::SYSCALL_ERROR_NO_SPACE);
+        }
+        match self.vars.iter_mut().find(|(k, _)| k == key) {
+            Some((_, old)) => *old = String::from(value),
+            None => self.vars.push((String::from(key), String::from(value)))
+        }
+        Ok(())
+    }
+
+    /// Remove a variable
+    ///
+    /// Returns SYSCALL_ERROR_NOTFOUND if it wasn't set, and
+    /// SYSCALL_ERROR_DENIED for CWD_KEY.
+    pub fn remove(&mut self, key: &str) -> Result<(), usize> {
+        if key == CWD_KEY {
+            return Err(syscalls::SYSCALL_ERROR_DENIED);
+        }
+        let len = self.vars.len();
+        self.vars.retain(|(k, _)| k != key);
+        if self.vars.len() == len {
+            return Err(syscalls::SYSCALL_ERROR_NOTFOUND);
+        }
+        Ok(())
+    }
+
+    /// Change the working directory
+    ///
+    /// The path must be absolute, and not too long or contain NUL,
+    /// otherwise SYSCALL_ERROR_PARAM. Whether it is a directory is
+    /// checked by the caller, with the directory's server.
+    pub fn set_cwd(&mut self, path: &str) -> Result<(), usize> {
+        if !path.starts_with('/') || path.len() > MAX_VALUE_SIZE || path.contains('\0') {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        self.cwd = Some(String::from(path));
+        Ok(())
+    }
+
+    /// All variables as KEY=VALUE strings, each followed by NUL,
+    /// starting with CWD_KEY if set
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size() + 2 * self.vars.len());
+        let cwd = self.cwd.as_deref().map(|cwd| (CWD_KEY, cwd));
+        let vars = self.vars.iter().map(|(key, value)| (key.as_str(), value.as_str()));
+        for (key, value) in cwd.into_iter().chain(vars) {
+            bytes.extend_from_slice(key.as_bytes());
+            bytes.push(b'=');
+            bytes.extend_from_slice(value.as_bytes());
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    /// Number of bytes in keys and values, not counting the
+    /// working directory
+    fn size(&self) -> usize {
+        self.vars.iter().map(|(key, value)| key.len() + value.len()).sum()
+    }
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_SIZE && !key.contains(['=', '\0'])
+}
+
+#[test_case]
+fn set_and_replace() {
+    let mut env = Environment::new();
+    assert_eq!(env.get("HOSTNAME"), None);
+    env.set("HOSTNAME", "euralios").unwrap();
+    env.set("TZ", "UTC").unwrap();
+    env.set("HOSTNAME", "other").unwrap();
+    assert_eq!(env.get("HOSTNAME"), Some("other"));
+    assert_eq!(env.to_bytes(), b"HOSTNAME=other\0TZ=UTC\0");
+
+    assert_eq!(env.remove("TZ"), Ok(()));
+    assert_eq!(env.remove("TZ"), Err(syscalls::SYSCALL_ERROR_NOTFOUND));
+    assert_eq!(env.get("TZ"), None);
+}
+
+#[test_case]
+fn cwd_only_set_by_set_cwd() {
+    let mut env = Environment::new();
+    assert_eq!(env.get(CWD_KEY), None);
+    assert_eq!(env.set(CWD_KEY, "/ramdisk"), Err(syscalls::SYSCALL_ERROR_DENIED));
+    assert_eq!(env.set_cwd("ramdisk"), Err(syscalls::SYSCALL_ERROR_PARAM));
+    env.set_cwd("/ramdisk").unwrap();
+    env.set("TZ", "UTC").unwrap();
+    assert_eq!(env.get(CWD_KEY), Some("/ramdisk"));
+    assert_eq!(env.to_bytes(), b"PWD=/ramdisk\0TZ=UTC\0");
+    assert_eq!(env.remove(CWD_KEY), Err(syscalls::SYSCALL_ERROR_DENIED));
+    // Inherited by exec
+    assert_eq!(env.clone().get(CWD_KEY), Some("/ramdisk"));
+}
+
+#[test_case]
+fn invalid_variables() {
+    let mut env = Environment::new();
+    assert_eq!(env.set("", "value"), Err(syscalls::SYSCALL_ERROR_PARAM));
+    assert_eq!(env.set("A=B", "value"), Err(syscalls::SYSCALL_ERROR_PARAM));
+    assert_eq!(env.set("KEY", "a\0b"), Err(syscalls::SYSCALL_ERROR_PARAM));
+    let long = "x".repeat(MAX_VALUE_SIZE + 1);
+    assert_eq!(env.set("KEY", &long), Err(syscalls::SYSCALL_ERROR_PARAM));
+    assert_eq!(env.set(&long[..MAX_KEY_SIZE + 1], ""), Err(syscalls::SYSCALL_ERROR_PARAM));
+}
+
+#[test_case]
+fn total_size_is_capped() {
+    let mut env = Environment::new();
+    let value = "x".repeat(MAX_VALUE_SIZE);
+    for key in ["0", "1", "2"] {
+        env.set(key, &value).unwrap();
+    }
+    assert_eq!(env.set("3", &value), Err(syscalls::SYSCALL_ERROR_NO_SPACE));
+    // Replacing counts the new value, not both
+    env.set("2", &value[1..]).unwrap();
+    env.set("2", &value).unwrap();
+}
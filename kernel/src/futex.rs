@@ -0,0 +1,77 @@
+//! Wait queues for userspace synchronization
+//!
+//! A thread calling futex_wait is suspended if a u32 in its memory
+//! still has an expected value, until a futex_wake on the same
+//! address. Queues are keyed by physical address, so threads in
+//! different processes can wait on memory shared with share_memory.
+//!
+//! Syscalls run with interrupts disabled on a single CPU, so the
+//! value can't change between checking it and queueing the thread:
+//! a wake after the value was changed can't be missed.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use alloc::collections::{BTreeMap, VecDeque};
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use crate::process::{self, Thread};
+
+lazy_static! {
+    /// Waiting threads by physical address, in the order they waited
+    static ref WAITERS: Mutex<BTreeMap<u64, VecDeque<Box<Thread>>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Suspend a thread until woken by `wake` with the same address
+///
+/// The thread's context should be set, with the return value
+/// for when it is woken.
+pub fn wait(thread: Box<Thread>, physaddr: u64) {
+    WAITERS.lock().entry(physaddr).or_default().push_back(thread);
+}
+
+/// Remove the threads waiting in a process, e.g. when it is killed
+pub fn remove_process(page_table_physaddr: u64) -> Vec<Box<Thread>> {
+    let mut removed = Vec::new();
+    let mut waiters = WAITERS.lock();
+    for queue in waiters.values_mut() {
+        let mut i = 0;
+        while i < queue.len() {
+            if queue[i].page_table_physaddr() == page_table_physaddr {
+                removed.push(queue.remove(i).unwrap());
+            } else {
+                i += 1;
+            }
+        }
+    }
+    waiters.retain(|_, queue| !queue.is_empty());
+    removed
+}
+
+/// Schedule up to `count` threads waiting on `physaddr`, longest
+/// waiting first. Returns the number of threads woken.
+pub fn wake(physaddr: u64, count: usize) -> usize {
+    let mut woken = VecDeque::new();
+    {
+        let mut waiters = WAITERS.lock();
+        if let Some(queue) = waiters.get_mut(&physaddr) {
+            while woken.len() < count {
+                match queue.pop_front() {
+                    Some(thread) => woken.push_back(thread),
+                    None => break
+                }
+            }
+            if queue.is_empty() {
+                waiters.remove(&physaddr);
+            }
+        }
+    }
+    let num_woken = woken.len();
+    // schedule_thread puts threads at the front of the queue,
+    // so the first waiter is scheduled last to run first
+    for thread in woken.into_iter().rev() {
+        process::schedule_thread(thread);
+    }
+    num_woken
+}
@@ -8,6 +8,9 @@ use crate::gdt;
 use crate::process;
 use crate::memory;
 use crate::time;
+use crate::rendezvous;
+use crate::oom;
+use crate::kmsg;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -28,6 +31,12 @@ lazy_static! {
             idt[InterruptIndex::Keyboard.as_usize()]
                 .set_handler_fn(keyboard_interrupt_handler)
                 .set_stack_index(gdt::KEYBOARD_INTERRUPT_INDEX);
+            idt[InterruptIndex::Serial.as_usize()]
+                .set_handler_fn(serial_interrupt_handler)
+                .set_stack_index(gdt::KEYBOARD_INTERRUPT_INDEX);
+            idt[InterruptIndex::Mouse.as_usize()]
+                .set_handler_fn(mouse_interrupt_handler)
+                .set_stack_index(gdt::KEYBOARD_INTERRUPT_INDEX);
         }
         idt
     };
@@ -82,6 +91,15 @@ pub const INTERRUPT_CONTEXT_SIZE: usize = 20 * 8;
 extern "C" fn timer_handler(context_addr: usize) -> usize {
     time::pit_interrupt_notify(); // For keeping track of time
 
+    // Wake threads whose send has timed out
+    rendezvous::check_send_timeouts();
+
+    // Wake threads waiting for kernel messages
+    kmsg::check_waiting();
+
+    // Wake threads whose sleep has finished
+    time::wake_sleeping();
+
     // Process scheduler decides which process to schedule
     // Returns the stack pointer to switch to.
     let next_stack = process::schedule_next(context_addr);
@@ -253,22 +271,64 @@ extern "x86-interrupt" fn page_fault_handler(
     use x86_64::registers::control::Cr2;
     let accessed_virtaddr = Cr2::read();
 
-    if error_code == (PageFaultErrorCode::PROTECTION_VIOLATION |
-                      PageFaultErrorCode::CAUSED_BY_WRITE |
-                      PageFaultErrorCode::USER_MODE) {
-        // User code tried to access a read-only page
-        // Missing stack or heap frame
-
-        if let Err(msg) = memory::allocate_missing_ondemand_frame(accessed_virtaddr) {
-            println!("Page fault error: {}", msg);
-            hlt_loop();
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION |
+                           PageFaultErrorCode::CAUSED_BY_WRITE) &&
+        !error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        // User code (or the kernel on behalf of user code) tried to
+        // write to a read-only page. Missing stack or heap frame,
+        // or a copy-on-write zero page.
+
+        loop {
+            match memory::allocate_missing_ondemand_frame(accessed_virtaddr) {
+                Ok(()) => break,
+                Err(memory::FRAME_ALLOC_FAILED) if oom::kill_victim() => {
+                    // Memory may have been reclaimed. If the current
+                    // process was killed then this doesn't return.
+                }
+                Err(msg) => {
+                    println!("Page fault error: {}", msg);
+                    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+                        // Write to a read-only segment or share.
+                        // Doesn't return if there is a current thread
+                        process::kill_current_process();
+                    }
+                    hlt_loop();
+                }
+            }
         }
+    } else if memory::is_user_stack_guard(accessed_virtaddr) {
+        // Ran off the bottom of a user stack
+        loop {
+            match memory::grow_user_stack(accessed_virtaddr) {
+                Ok(()) => break,
+                Err(memory::FRAME_ALLOC_FAILED) if oom::kill_victim() => {}
+                Err(msg) => {
+                    println!("EXCEPTION: User stack overflow in thread TID {:?}: {}",
+                             process::current_tid(), msg);
+                    println!("Accessed Address: {:?}", accessed_virtaddr);
+                    // Doesn't return if there is a current thread
+                    process::kill_current_process();
+                    hlt_loop();
+                }
+            }
+        }
+    } else if memory::is_kernel_stack_guard(accessed_virtaddr) {
+        println!("EXCEPTION: Kernel stack overflow in thread TID {:?}",
+                 process::current_tid());
+        println!("Accessed Address: {:?}", accessed_virtaddr);
+        println!("{:#?}", stack_frame);
+
+        hlt_loop();
     } else {
         println!("EXCEPTION: PAGE FAULT");
         println!("Accessed Address: {:?}", accessed_virtaddr);
         println!("Error Code: {:?}", error_code);
         println!("{:#?}", stack_frame);
 
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            // e.g. executing a no-execute page
+            process::kill_current_process();
+        }
         hlt_loop();
     }
 }
@@ -297,6 +357,8 @@ pub static PICS: spin::Mutex<ChainedPics> =
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial = PIC_1_OFFSET + 4,
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -309,46 +371,333 @@ impl InterruptIndex {
     }
 }
 
-use alloc::sync::Arc;
 use alloc::vec::Vec;
 use alloc::boxed::Box;
 use spin::RwLock;
 use crate::process::Thread;
+use crate::message::Message;
+use crate::sysrq;
+use x86_64::instructions::port::Port;
+
+/// Device interrupt lines which threads can wait for with
+/// await_interrupt
+pub const IRQ_KEYBOARD: u64 = 1;
+pub const IRQ_SERIAL: u64 = 4;
+pub const IRQ_MOUSE: u64 = 12;
+
+/// Bytes received while no thread was waiting
+struct ByteQueue {
+    data: [u8; 64],
+    start: usize,
+    len: usize
+}
+
+impl ByteQueue {
+    const fn new() -> Self {
+        ByteQueue{data: [0; 64], start: 0, len: 0}
+    }
+
+    /// Add a byte, dropping it if the queue is full
+    fn push(&mut self, byte: u8) {
+        if self.len < self.data.len() {
+            let end = (self.start + self.len) % self.data.len();
+            self.data[end] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % self.data.len();
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// A device interrupt which threads can wait for
+struct Device {
+    /// Threads to be scheduled when the interrupt occurs
+    waiting: RwLock<Vec<Box<Thread>>>,
+    /// Only accessed with interrupts disabled
+    queue: spin::Mutex<ByteQueue>
+}
+
+impl Device {
+    fn new() -> Self {
+        Device{waiting: RwLock::new(Vec::new()),
+               queue: spin::Mutex::new(ByteQueue::new())}
+    }
+}
 
 lazy_static! {
-    static ref INTERRUPT_WAITING: Arc<RwLock<Vec<Box<Thread>>>> =
-        Arc::new(RwLock::new(Vec::new()));
+    static ref KEYBOARD: Device = Device::new();
+    static ref MOUSE: Device = Device::new();
+    static ref SERIAL: Device = Device::new();
 }
 
-/// Store a thread, to be scheduled when an interrupt occurs
-pub fn await_interrupt(thread: Box<Thread>) {
-    INTERRUPT_WAITING.write().push(thread);
+fn device(irq: u64) -> Option<&'static Device> {
+    match irq {
+        IRQ_KEYBOARD => Some(&KEYBOARD),
+        IRQ_MOUSE => Some(&MOUSE),
+        IRQ_SERIAL => Some(&SERIAL),
+        _ => None
+    }
+}
+
+/// True if threads can wait for interrupt line `irq`
+pub fn can_await_interrupt(irq: u64) -> bool {
+    device(irq).is_some()
+}
+
+/// Store a thread, to be scheduled when interrupt `irq` occurs
+///
+/// The line is unmasked in the PIC, in case the BIOS left it masked.
+/// If `irq` can't be waited for then the thread is scheduled
+/// with a parameter error.
+pub fn await_interrupt(irq: u64, thread: Box<Thread>) {
+    match device(irq) {
+        Some(device) => {
+            device.waiting.write().push(thread);
+            unmask_irq(irq as u8);
+        }
+        None => {
+            thread.return_error(syscalls::SYSCALL_ERROR_PARAM);
+            process::schedule_thread(thread);
+        }
+    }
+}
+
+/// Remove the threads in a process waiting for interrupts,
+/// e.g. when it is killed
+pub fn remove_process(page_table_physaddr: u64) -> Vec<Box<Thread>> {
+    let mut removed = Vec::new();
+    for device in [&*KEYBOARD, &*MOUSE, &*SERIAL] {
+        let mut waiting = device.waiting.write();
+        let mut i = 0;
+        while i < waiting.len() {
+            if waiting[i].page_table_physaddr() == page_table_physaddr {
+                removed.push(waiting.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+    }
+    removed
+}
+
+/// Number of threads waiting for interrupts, or None if locked
+pub fn interrupt_waiting_count() -> Option<usize> {
+    let keyboard = KEYBOARD.waiting.try_read()?.len();
+    let mouse = MOUSE.waiting.try_read()?.len();
+    let serial = SERIAL.waiting.try_read()?.len();
+    Some(keyboard + mouse + serial)
+}
+
+/// Take a byte which arrived on interrupt line `irq` while no
+/// thread was waiting
+pub fn take_interrupt_byte(irq: u64) -> Option<u8> {
+    device(irq)?.queue.lock().pop()
+}
+
+/// Give a byte read by an interrupt handler to the threads waiting
+/// for the device, or keep it until a thread waits.
+///
+/// Returns the address of the context to switch to.
+fn deliver_byte(device: &Device, byte: u8, context_addr: usize) -> usize {
+    let mut waiting = match device.waiting.try_write() {
+        Some(waiting) if !waiting.is_empty() => waiting,
+        _ => {
+            // Keep until a thread waits
+            if let Some(mut queue) = device.queue.try_lock() {
+                queue.push(byte);
+            }
+            // Return to interrupted thread
+            return context_addr;
+        }
+    };
 
+    // Schedule waiting threads
+    for thread in waiting.drain(..) {
+        thread.return_message(Message::Short(byte as u64, 0, 0));
+        // Note: This adds to the front of the queue
+        process::schedule_thread(thread);
+    }
+    drop(waiting);
+
+    // Switch to one of the scheduled threads
+    process::schedule_next(context_addr)
+}
+
+/// Allow interrupt line `irq` through the PIC
+///
+/// Lines on the secondary PIC also need the cascade line (IRQ 2).
+pub fn unmask_irq(irq: u8) {
+    let mut primary = Port::<u8>::new(0x21);
+    let mut secondary = Port::<u8>::new(0xA1);
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        if irq < 8 {
+            let mask = primary.read();
+            primary.write(mask & !(1 << irq));
+        } else {
+            let mask = primary.read();
+            primary.write(mask & !(1 << 2));
+            let mask = secondary.read();
+            secondary.write(mask & !(1 << (irq - 8)));
+        }
     }
 }
 
 interrupt_wrap!(keyboard_handler_inner => keyboard_interrupt_handler);
 
+/// Keyboard interrupt
+///
+/// The kernel reads the scancode, so that the recovery key
+/// combination (see sysrq) works even if the keyboard driver is
+/// hung. Scancodes are returned to the driver by await_interrupt.
 extern "C" fn keyboard_handler_inner(
     context_addr: usize
 )-> usize {
+    let scancode: u8 = unsafe { Port::new(0x60).read() };
 
-    // Change to a new thread if there is one waiting
-    if !INTERRUPT_WAITING.read().is_empty() {
+    sysrq::key_event(scancode);
 
-        // Schedule waiting threads
-        for thread in INTERRUPT_WAITING.write().drain(..) {
-            // Note: This adds to the front of the queue
-            process::schedule_thread(thread);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    }
+
+    deliver_byte(&KEYBOARD, scancode, context_addr)
+}
+
+interrupt_wrap!(mouse_handler_inner => mouse_interrupt_handler);
+
+/// PS/2 mouse interrupt
+///
+/// Each interrupt is one byte of a movement packet, returned to the
+/// mouse driver by await_interrupt. The driver assembles and
+/// decodes the packets.
+extern "C" fn mouse_handler_inner(
+    context_addr: usize
+)-> usize {
+    let data: u8 = unsafe { Port::new(0x60).read() };
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+
+    deliver_byte(&MOUSE, data, context_addr)
+}
+
+/// COM1 receive buffer
+const SERIAL_DATA_PORT: u16 = 0x3F8;
+/// COM1 line status register
+const SERIAL_LINE_STATUS_PORT: u16 = 0x3FD;
+/// Line status: Data waiting in the receive buffer
+const SERIAL_DATA_READY: u8 = 1;
+
+interrupt_wrap!(serial_handler_inner => serial_interrupt_handler);
+
+/// COM1 receive interrupt
+///
+/// Reads the bytes waiting in the UART FIFO, which also acknowledges
+/// the interrupt. The serial driver configures the UART and gets the
+/// bytes from await_interrupt.
+extern "C" fn serial_handler_inner(
+    context_addr: usize
+)-> usize {
+    let mut bytes = [0u8; 16]; // FIFO size
+    let mut count = 0;
+    unsafe {
+        let mut status = Port::<u8>::new(SERIAL_LINE_STATUS_PORT);
+        let mut data = Port::<u8>::new(SERIAL_DATA_PORT);
+        while count < bytes.len() && status.read() & SERIAL_DATA_READY != 0 {
+            bytes[count] = data.read();
+            count += 1;
         }
 
-        // Switch to one of the scheduled threads
-        process::schedule_next(context_addr)
-    } else {
-        // Return to interrupted thread
-        context_addr
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+    }
+
+    if count == 0 {
+        return context_addr;
+    }
+    // Waiting threads get the first byte, and the rest are queued
+    let next_context = deliver_byte(&SERIAL, bytes[0], context_addr);
+    if let Some(mut queue) = SERIAL.queue.try_lock() {
+        for &byte in &bytes[1..count] {
+            queue.push(byte);
+        }
+    }
+    next_context
+}
+
+// Interrupt affinity
+//
+// Records which CPU each device interrupt should be delivered to.
+// Interrupts currently come through the 8259 PIC, which always
+// delivers to the boot CPU, and there is only one CPU. When the I/O
+// APIC and SMP are supported, set_irq_affinity should also program
+// the IRQ's redirection entry destination field.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::syscalls;
+
+/// Number of device interrupt lines (two chained 8259 PICs)
+pub const NUM_IRQS: usize = 16;
+
+/// Number of CPUs which can receive interrupts
+pub const NUM_CPUS: u32 = 1;
+
+/// Target CPU for each IRQ, initially the boot CPU
+static IRQ_AFFINITY: [AtomicU32; NUM_IRQS] = {
+    const BOOT_CPU: AtomicU32 = AtomicU32::new(0);
+    [BOOT_CPU; NUM_IRQS]
+};
+
+/// Steer interrupt `irq` to CPU `cpu`
+///
+/// Returns SYSCALL_ERROR_PARAM if the IRQ or CPU doesn't exist.
+/// On a single CPU this only records the request.
+pub fn set_irq_affinity(irq: u64, cpu: u64) -> Result<(), usize> {
+    if irq >= NUM_IRQS as u64 || cpu >= NUM_CPUS as u64 {
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+    IRQ_AFFINITY[irq as usize].store(cpu as u32, Ordering::Relaxed);
+    Ok(())
+}
+
+/// The CPU which interrupt `irq` has been steered to
+pub fn irq_affinity(irq: usize) -> Option<u32> {
+    IRQ_AFFINITY.get(irq).map(|cpu| cpu.load(Ordering::Relaxed))
+}
+
+#[test_case]
+fn irq_affinity_validates() {
+    assert_eq!(set_irq_affinity(NUM_IRQS as u64, 0),
+               Err(syscalls::SYSCALL_ERROR_PARAM));
+    assert_eq!(set_irq_affinity(0, NUM_CPUS as u64),
+               Err(syscalls::SYSCALL_ERROR_PARAM));
+    assert_eq!(set_irq_affinity(11, 0), Ok(()));
+    assert_eq!(irq_affinity(11), Some(0));
+    assert_eq!(irq_affinity(NUM_IRQS), None);
+}
+
+#[test_case]
+fn byte_queue_order() {
+    let mut queue = ByteQueue::new();
+    for byte in 0..70 {
+        queue.push(byte);
+    }
+    // Bytes after the first 64 are dropped
+    for byte in 0..64 {
+        assert_eq!(queue.pop(), Some(byte));
     }
+    assert_eq!(queue.pop(), None);
+    assert!(can_await_interrupt(IRQ_MOUSE));
+    assert!(!can_await_interrupt(0)); // Timer
 }
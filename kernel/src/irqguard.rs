@@ -0,0 +1,170 @@
+//! Interrupt-disabled regions
+//!
+//! Kernel code should disable interrupts with `without_interrupts`
+//! (or an `IrqGuard`) from this module, rather than the x86_64 crate
+//! functions, so that long regions can be found.
+//!
+//! In debug builds each region is timed with the TSC, from when
+//! interrupts are disabled until they are enabled again. Nested
+//! regions are counted as part of the outermost one. The longest
+//! region and where it started are kept, and a warning is printed
+//! for any region longer than WARN_MICROSECONDS.
+//!
+//! In release builds the guard only disables and restores
+//! interrupts, and report() prints nothing.
+//!
+//! Code which runs with interrupts disabled by the CPU (interrupt
+//! handlers and syscalls) is not measured.
+
+use core::panic::Location;
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(debug_assertions)]
+use spin::Mutex;
+#[cfg(debug_assertions)]
+use crate::time;
+
+/// Regions longer than this print a warning.
+/// The timer interrupt is every 55ms, so this is well before
+/// ticks are lost.
+pub const WARN_MICROSECONDS: u64 = 1000;
+
+/// Disables interrupts until dropped
+///
+/// Interrupts are only enabled on drop if they were enabled when
+/// the guard was created, so guards can be nested.
+pub struct IrqGuard {
+    was_enabled: bool,
+    #[cfg(debug_assertions)]
+    start_tsc: u64,
+    #[cfg(debug_assertions)]
+    location: &'static Location<'static>
+}
+
+impl IrqGuard {
+    /// Disable interrupts. The caller's location is reported if
+    /// this is the longest region.
+    #[track_caller]
+    pub fn new() -> Self {
+        let was_enabled = interrupts::are_enabled();
+        if was_enabled {
+            interrupts::disable();
+        }
+        IrqGuard {
+            was_enabled,
+            #[cfg(debug_assertions)]
+            start_tsc: time::time_stamp_counter(),
+            #[cfg(debug_assertions)]
+            location: Location::caller()
+        }
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        if !self.was_enabled {
+            return; // Part of an outer region
+        }
+        #[cfg(debug_assertions)]
+        let duration_tsc = time::time_stamp_counter() - self.start_tsc;
+        #[cfg(debug_assertions)]
+        record(duration_tsc, self.location);
+
+        interrupts::enable();
+
+        #[cfg(debug_assertions)]
+        warn_if_long(duration_tsc, self.location);
+    }
+}
+
+/// Run a closure with interrupts disabled
+///
+/// Replacement for x86_64::instructions::interrupts::without_interrupts
+#[track_caller]
+pub fn without_interrupts<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = IrqGuard::new();
+    f()
+}
+
+/// Longest region, in TSC ticks
+#[cfg(debug_assertions)]
+static MAX_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Where the longest region started
+#[cfg(debug_assertions)]
+static MAX_LOCATION: Mutex<Option<&'static Location<'static>>> = Mutex::new(None);
+
+/// Set while printing a warning, to avoid warning about the print
+#[cfg(debug_assertions)]
+static WARNING: AtomicBool = AtomicBool::new(false);
+
+/// Called with interrupts still disabled
+#[cfg(debug_assertions)]
+fn record(duration_tsc: u64, location: &'static Location<'static>) {
+    if duration_tsc <= MAX_TSC.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(mut max_location) = MAX_LOCATION.try_lock() {
+        MAX_TSC.store(duration_tsc, Ordering::Relaxed);
+        *max_location = Some(location);
+    }
+}
+
+#[cfg(debug_assertions)]
+fn warn_if_long(duration_tsc: u64, location: &'static Location<'static>) {
+    let microseconds = match time::tsc_to_microseconds(duration_tsc) {
+        Some(us) => us,
+        None => return // TSC not yet calibrated
+    };
+    if microseconds > WARN_MICROSECONDS &&
+        !WARNING.swap(true, Ordering::Relaxed) {
+            crate::println!("[kernel] Interrupts disabled for {}us at {}",
+                            microseconds, location);
+            WARNING.store(false, Ordering::Relaxed);
+        }
+}
+
+/// The longest interrupt-disabled region so far, in microseconds,
+/// and where it started. None in release builds, or if there haven't
+/// been any regions since the TSC was calibrated.
+pub fn longest() -> Option<(u64, &'static Location<'static>)> {
+    #[cfg(debug_assertions)]
+    {
+        let location = (*MAX_LOCATION.try_lock()?)?;
+        let microseconds = time::tsc_to_microseconds(MAX_TSC.load(Ordering::Relaxed))?;
+        Some((microseconds, location))
+    }
+    #[cfg(not(debug_assertions))]
+    None
+}
+
+/// Write the longest region. Used in the sysrq diagnostic dump.
+pub fn report(out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+    match longest() {
+        Some((microseconds, location)) =>
+            writeln!(out, "[irqguard] Longest interrupts disabled {}us at {}",
+                     microseconds, location),
+        None => Ok(())
+    }
+}
+
+#[test_case]
+fn guards_nest() {
+    assert!(interrupts::are_enabled());
+    {
+        let _outer = IrqGuard::new();
+        {
+            let _inner = IrqGuard::new();
+            assert!(!interrupts::are_enabled());
+        }
+        // Inner guard doesn't enable interrupts
+        assert!(!interrupts::are_enabled());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(without_interrupts(|| interrupts::are_enabled()), false);
+}
@@ -0,0 +1,131 @@
+//! Kernel message log
+//!
+//! Everything printed to the screen with print! and println! is also
+//! kept in a ring buffer, so that user programs (e.g. the shell's
+//! `dmesg`) can read messages printed during boot.
+//!
+//! Reads are not destructive: each reader keeps a cursor, the number
+//! of bytes logged before the next byte it wants. Readers which fall
+//! more than LOG_SIZE bytes behind miss the oldest messages, and
+//! continue from the oldest byte still in the buffer.
+//!
+//! The buffer is a static array, so messages are kept from before
+//! the kernel heap is initialised.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+use crate::process::{self, Thread};
+
+/// Size of the ring buffer in bytes
+pub const LOG_SIZE: usize = 16384;
+
+struct LogBuffer {
+    data: [u8; LOG_SIZE],
+    /// Total number of bytes written
+    end: u64
+}
+
+impl LogBuffer {
+    /// Cursor of the oldest byte still in the buffer
+    fn start(&self) -> u64 {
+        self.end.saturating_sub(LOG_SIZE as u64)
+    }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[(self.end % LOG_SIZE as u64) as usize] = byte;
+            self.end += 1;
+        }
+        Ok(())
+    }
+}
+
+static LOG: Mutex<LogBuffer> = Mutex::new(LogBuffer{data: [0; LOG_SIZE], end: 0});
+
+/// Threads waiting for messages after a cursor
+static WAITING: Mutex<Vec<(Box<Thread>, u64)>> = Mutex::new(Vec::new());
+
+/// Append to the log. Called by vga_buffer::_print
+///
+/// Messages are dropped rather than waiting if the log is locked,
+/// which can happen if an interrupt handler prints during a read.
+pub fn log_fmt(args: fmt::Arguments) {
+    use fmt::Write;
+    if let Some(mut log) = LOG.try_lock() {
+        let _ = log.write_fmt(args);
+    }
+}
+
+/// Cursor after the last byte written
+pub fn end() -> u64 {
+    LOG.lock().end
+}
+
+/// Copy messages starting at `cursor` into `buf`
+///
+/// Returns the number of bytes copied, and the cursor of the first.
+/// This is later than `cursor` if messages have been overwritten.
+pub fn read(cursor: u64, buf: &mut [u8]) -> (usize, u64) {
+    let log = LOG.lock();
+    let start = cursor.clamp(log.start(), log.end);
+    let count = core::cmp::min(buf.len() as u64, log.end - start) as usize;
+    for (i, byte) in buf[..count].iter_mut().enumerate() {
+        *byte = log.data[((start + i as u64) % LOG_SIZE as u64) as usize];
+    }
+    (count, start)
+}
+
+/// Suspend a thread until a message is logged after `cursor`
+///
+/// The thread's return values should already be set.
+pub fn wait(thread: Box<Thread>, cursor: u64) {
+    WAITING.lock().push((thread, cursor));
+}
+
+/// Schedule threads waiting for messages which have been logged
+///
+/// Called from the timer interrupt, like rendezvous::check_send_timeouts,
+/// so uses try_lock to avoid deadlocks.
+pub fn check_waiting() {
+    let mut waiting = match WAITING.try_lock() {
+        Some(waiting) => waiting,
+        None => return
+    };
+    if waiting.is_empty() {
+        return;
+    }
+    let end = match LOG.try_lock() {
+        Some(log) => log.end,
+        None => return
+    };
+    let mut i = 0;
+    while i < waiting.len() {
+        if waiting[i].1 < end {
+            let (thread, _) = waiting.swap_remove(i);
+            process::schedule_thread(thread);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+#[test_case]
+fn log_ring_buffer() {
+    let cursor = end();
+    crate::println!("kmsg test");
+    let mut buf = [0; 10];
+    assert_eq!(read(cursor, &mut buf), (10, cursor));
+    assert_eq!(&buf, b"kmsg test\n");
+
+    // Nothing after the end
+    assert_eq!(read(end(), &mut buf).0, 0);
+    // Cursors before the start are moved forward
+    if end() > LOG_SIZE as u64 {
+        assert_eq!(read(0, &mut buf).1, end() - LOG_SIZE as u64);
+    }
+}
@@ -19,7 +19,17 @@ pub mod process;
 pub mod rendezvous;
 pub mod message;
 pub mod vfs;
+pub mod env;
+pub mod futex;
 pub mod time;
+pub mod sched_test;
+pub mod oom;
+pub mod tls;
+pub mod sysrq;
+pub mod kmsg;
+pub mod irqguard;
+pub mod rtc;
+pub mod ps2;
 
 extern crate alloc; // Memory allocation in stdlib
 
@@ -30,6 +40,9 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() }; // Configure hardware interrupt controller
+    time::init(); // Calibrate the TSC before timer interrupts
+    rtc::init(); // Wall-clock time at boot
+    ps2::init(); // Mouse, before interrupts are enabled
     x86_64::instructions::interrupts::enable(); // CPU starts listening for hardware interrupts
 }
 
@@ -82,8 +95,10 @@ use bootloader::{entry_point, BootInfo};
 entry_point!(test_kernel_main);
 
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    // Tests may allocate, or create page tables
+    memory::init(boot_info);
     test_main();
     hlt_loop();
 }
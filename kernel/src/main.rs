@@ -14,8 +14,12 @@ use spin::RwLock;
 use kernel::memory;
 use kernel::syscalls;
 use kernel::process;
+use kernel::sched_test;
+use kernel::oom;
+use kernel::sysrq;
 use kernel::rendezvous::Rendezvous;
 use kernel::vfs;
+use kernel::env;
 use kernel::message::{self, Message};
 
 entry_point!(kernel_entry);
@@ -38,7 +42,11 @@ fn kernel_thread_main() {
                 init_screen.clone()
             ]),
             io_privileges: true,
-            mounts: vfs::VFS::new() // Create a Virtual File System
+            mounts: vfs::VFS::new(), // Create a Virtual File System
+            env: env::Environment::new(),
+            syscall_filter: process::SyscallFilter::ALL,
+            args: Vec::new(),
+            priority: process::PRIORITY_NORMAL
         }).unwrap();
 
     // Allocate a memory chunk mapping video memory
@@ -78,12 +86,22 @@ fn kernel_entry(boot_info: &'static BootInfo) -> ! {
     // Set up system calls
     syscalls::init();
 
+    // Scheduler test mode, if enabled at build time
+    sched_test::init();
+
+    // Choose how to recover when physical memory runs out
+    oom::init();
+
+    // Key combination for diagnostics when the system hangs
+    sysrq::init();
+
     #[cfg(test)]
     test_main();
 
     // Launch the main kernel thread
     // which will be scheduled and take over from here
-    process::new_kernel_thread(kernel_thread_main, Vec::new());
+    process::new_kernel_thread(kernel_thread_main, Vec::new(),
+                               process::PRIORITY_NORMAL);
 
     kernel::hlt_loop();
 }
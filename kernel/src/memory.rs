@@ -2,25 +2,105 @@
 mod frame_allocator; // In memory/frame_allocator.rs
 use frame_allocator::MultilevelBitmapFrameAllocator;
 mod allocator;
+pub use allocator::{Category, with_category, HeapStats, heap_stats, try_heap_stats};
 pub mod kernel_info;
 
 use x86_64::{
     structures::paging::{Page, PageTable, PhysFrame,
                          Size4KiB, FrameAllocator, OffsetPageTable,
-                         mapper::MapToError, PageTableFlags, Mapper
+                         mapper::MapToError, mapper::FlagUpdateError,
+                         PageTableFlags, Mapper,
+                         Translate, mapper::TranslateResult
     },
     PhysAddr, VirtAddr
 };
+use x86_64::registers::model_specific::{Efer, EferFlags};
 
 /// The level 4, 3 and 2 page table index
 /// to access the level 1 page where stacks are stored
 const THREAD_STACK_PAGE_INDEX: [u8; 3] = [5, 0, 0];
 
+/// Pages in each user stack slot. A stack uses one or more
+/// consecutive slots, and the lowest page is a guard page.
+const USER_STACK_SLOT_PAGES: usize = 8;
+
+/// Number of user stack slots, filling one level 1 page table
+const USER_STACK_SLOTS: usize = 512 / USER_STACK_SLOT_PAGES;
+
+/// Pages in a user stack unless another size is requested
+pub const USER_STACK_PAGES: usize = USER_STACK_SLOT_PAGES - 1;
+
+/// Most pages in one user stack
+pub const MAX_USER_STACK_PAGES: usize = USER_STACK_SLOTS * USER_STACK_SLOT_PAGES - 1;
+
+/// User stacks grow down on faults below them until they have
+/// this many pages (1Mb). Larger stacks don't grow.
+const USER_STACK_GROWTH_LIMIT: usize = 256;
+
+/// Faults this many pages below a user stack grow it. Further
+/// below is treated as an invalid access rather than the stack.
+const USER_STACK_FAULT_GAP: usize = 16;
+
+/// Level 4 page table index of the region containing kernel thread
+/// stacks. Kernel threads run with whichever page table is active,
+/// so the level 3 table is shared by all page tables rather than
+/// copied, and stacks mapped later are visible in every one.
+const KERNEL_STACK_L4_INDEX: usize = 6;
+
+/// Pages in each kernel thread stack slot. The lowest page is never
+/// mapped, so is a guard page for the stack above it.
+const KERNEL_STACK_SLOT_PAGES: u64 = 8;
+
+/// Marks a read-only user page which maps the shared zero frame.
+/// On the first write a private zeroed frame is allocated.
+///
+/// Uses one of the bits in the page table entry which is
+/// available for use by the OS.
+const ZERO_PAGE_COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Marks a user page whose frame may be mapped in more than one
+/// page table. The number of mappings is counted in SHARED_FRAMES,
+/// and the frame is freed when the last one is removed.
+const SHARED_FRAME: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Marks a user page which is read-only because of its ELF segment
+/// permissions, rather than because it is copy-on-write. Writes are
+/// faults, and the frame is owned by the page table.
+pub const READ_ONLY_PAGE: PageTableFlags = PageTableFlags::BIT_11;
+
+/// Marks a user page which maps device memory, for example a PCI
+/// BAR. The frame isn't RAM, so is never freed, though its
+/// SHARED_FRAMES count is released if the chunk was shared.
+const DEVICE_FRAME: PageTableFlags = PageTableFlags::BIT_52;
+
+/// True if a page table entry maps a user frame which should be
+/// freed with the page table: writable pages, and read-only
+/// pages loaded from an ELF file. Excludes the shared zero frame
+/// and device memory.
+fn is_owned_user_frame(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) &&
+        flags.intersects(PageTableFlags::WRITABLE | READ_ONLY_PAGE) &&
+        !flags.contains(DEVICE_FRAME)
+}
+
 use crate::println;
 use crate::syscalls;
 use bootloader::BootInfo;
 
 use core::arch::asm;
+use core::cmp;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::collections::btree_map::BTreeMap;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use crate::irqguard;
+
+/// Number of frames currently used for user page tables
+static PAGE_TABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of frames mapped for GuardedStacks
+static KERNEL_STACK_FRAMES: AtomicUsize = AtomicUsize::new(0);
 
 struct MemoryInfo {
     boot_info: &'static BootInfo,
@@ -31,7 +111,11 @@ struct MemoryInfo {
     frame_allocator: MultilevelBitmapFrameAllocator,
 
     /// Kernel page table physical address
-    kernel_l4_table: &'static mut PageTable
+    kernel_l4_table: &'static mut PageTable,
+
+    /// A frame filled with zeros, shared by all copy-on-write
+    /// zero pages. Only ever mapped read-only, and never freed.
+    zero_frame: PhysFrame
 }
 
 /// Store BootInfo struct and other useful things for later use
@@ -44,9 +128,8 @@ static mut MEMORY_INFO: Option<MemoryInfo> = None;
 /// This function must be only called once to avoid aliasing `&mut`
 /// references (which is undefined behavior).
 pub fn init(boot_info: &'static BootInfo) {
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
+    irqguard::without_interrupts(|| {
         let mut memory_size = 0;
         for region in boot_info.memory_map.iter() {
             let start_addr = region.range.start_addr();
@@ -62,6 +145,12 @@ pub fn init(boot_info: &'static BootInfo) {
 
         let level_4_table = unsafe {active_level_4_table(physical_memory_offset)};
 
+        // Allow pages to be marked NO_EXECUTE. Without this the
+        // bit is reserved, and using it causes page faults.
+        unsafe {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+
         // Initialise the memory mapper
         let mut mapper = unsafe {OffsetPageTable::new(level_4_table, physical_memory_offset)};
         let mut frame_allocator = unsafe {
@@ -75,12 +164,27 @@ pub fn init(boot_info: &'static BootInfo) {
         kernel_info::init(&mut frame_allocator, physical_memory_offset)
             .expect("KernelInfo initialization failed");
 
+        // Pinned frame shared by all zero pages
+        let zero_frame = frame_allocator.allocate_frame()
+            .expect("Zero frame allocation failed");
+        zero_fill_frame(physical_memory_offset, zero_frame);
+
+        // Level 3 table for kernel thread stacks, shared by all page tables
+        let stack_table_frame = frame_allocator.allocate_frame()
+            .expect("Kernel stack table allocation failed");
+        zero_fill_frame(physical_memory_offset, stack_table_frame);
+        let entry = &mut level_4_table[KERNEL_STACK_L4_INDEX];
+        assert!(entry.is_unused(), "Kernel stack region already mapped");
+        entry.set_frame(stack_table_frame,
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
         // Store boot_info for later calls
         unsafe { MEMORY_INFO = Some(MemoryInfo {
             boot_info,
             physical_memory_offset,
             frame_allocator,
-            kernel_l4_table: level_4_table
+            kernel_l4_table: level_4_table,
+            zero_frame
         }) };
     });
 }
@@ -123,6 +227,7 @@ fn create_empty_pagetable() -> (*mut PageTable, u64) {
     unsafe {
         (*page_table_ptr).zero();
     }
+    PAGE_TABLE_FRAMES.fetch_add(1, Ordering::Relaxed);
 
     (page_table_ptr, phys.as_u64())
 }
@@ -137,7 +242,10 @@ fn copy_pagetables(level_4_table: &PageTable) -> (*mut PageTable, u64) {
                       from_table: &PageTable, to_table: &mut PageTable,
                       level: u16) {
         for (i, entry) in from_table.iter().enumerate() {
-            if !entry.is_unused() {
+            if level == 4 && i == KERNEL_STACK_L4_INDEX {
+                // Shared by all page tables
+                to_table[i].set_addr(entry.addr(), entry.flags());
+            } else if !entry.is_unused() {
                 if (level == 1) || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
                     // Maps a frame, not a page table
                     to_table[i].set_addr(entry.addr(), entry.flags());
@@ -232,6 +340,65 @@ pub fn active_pagetable_ptr() -> *mut PageTable {
     virt.as_mut_ptr()
 }
 
+/// Kernel memory usage, in bytes
+///
+/// Copied to user programs by the memory_stats syscall, so the
+/// layout must match euralios_std::syscalls::MemoryStats
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryStats {
+    pub heap_other: usize,
+    pub heap_thread_stacks: usize,
+    pub heap_messages: usize,
+    /// Frames used for page tables
+    pub page_tables: usize,
+    /// Size of the kernel heap
+    pub heap_total: usize,
+    /// Bytes of kernel heap in use, including allocator overhead
+    pub heap_used: usize,
+    /// Largest kernel heap allocation which would succeed
+    pub heap_largest_free: usize,
+    /// Kernel heap fragmentation, parts per thousand
+    pub heap_fragmentation: usize,
+    /// Frames mapped for kernel thread stacks. These are not in the
+    /// heap, so not included in heap_thread_stacks.
+    pub kernel_thread_stacks: usize,
+}
+
+/// Report how much kernel memory is used in each category
+pub fn memory_stats() -> MemoryStats {
+    memory_stats_with(heap_stats())
+}
+
+/// As memory_stats, but returns None rather than wait for the heap lock
+pub fn try_memory_stats() -> Option<MemoryStats> {
+    try_heap_stats().map(memory_stats_with)
+}
+
+fn memory_stats_with(heap: HeapStats) -> MemoryStats {
+    MemoryStats {
+        heap_other: allocator::heap_usage(Category::Other),
+        heap_thread_stacks: allocator::heap_usage(Category::ThreadStack),
+        heap_messages: allocator::heap_usage(Category::Message),
+        page_tables: PAGE_TABLE_FRAMES.load(Ordering::Relaxed) * 4096,
+        heap_total: heap.total,
+        heap_used: heap.used,
+        heap_largest_free: heap.largest_free,
+        heap_fragmentation: heap.fragmentation_permille(),
+        kernel_thread_stacks: KERNEL_STACK_FRAMES.load(Ordering::Relaxed) * 4096,
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Heap: stacks {} messages {} other {}; page tables {}; kernel thread stacks {}; heap {}/{} used, largest free {}, fragmentation {}.{}%",
+               self.heap_thread_stacks, self.heap_messages, self.heap_other, self.page_tables,
+               self.kernel_thread_stacks,
+               self.heap_used, self.heap_total, self.heap_largest_free,
+               self.heap_fragmentation / 10, self.heap_fragmentation % 10)
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////
 // Routines to allocate memory in a single page table
 
@@ -288,6 +455,31 @@ pub fn allocate_pages(level_4_table: *mut PageTable,
         start_addr, size, flags)
 }
 
+/// Change the flags of pages which are already mapped
+///
+/// Used to make ELF segments read-only after loading them.
+pub fn update_page_flags(level_4_table: *mut PageTable,
+                         start_addr: VirtAddr,
+                         size: u64,
+                         flags: PageTableFlags)
+                         -> Result<(), FlagUpdateError> {
+
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+
+    let mut mapper = unsafe {
+        OffsetPageTable::new(&mut *level_4_table,
+                             memory_info.physical_memory_offset)};
+
+    let end_addr = start_addr + size - 1u64;
+    for page in Page::<Size4KiB>::range_inclusive(Page::containing_address(start_addr),
+                                                  Page::containing_address(end_addr)) {
+        unsafe {
+            mapper.update_flags(page, flags)?.flush();
+        }
+    }
+    Ok(())
+}
+
 /// Allocate pages in the active page table
 ///
 /// Inputs
@@ -312,9 +504,9 @@ pub fn allocate_active_pages(
 /// Create user-accessible pages, which are allocated on demand
 /// ie when written to.
 ///
-/// One frame is allocated, and made writable through the first page in the range.
-/// The other pages point to the same frame but are read-only. Writes to those
-/// frames trigger a page fault, and the handler allocates a frame.
+/// All pages initially point to a single shared frame filled with
+/// zeros, and are read-only. Writes to those pages trigger a page
+/// fault, and the handler allocates a private zeroed frame.
 ///
 /// This allows large user heaps to be created without using a lot of memory.
 ///
@@ -346,18 +538,15 @@ pub fn create_user_ondemand_pages(
         Page::range_inclusive(start_page, end_page)
     };
 
-    // Only allocating one frame
-    let frame = frame_allocator
-        .allocate_frame()
-        .ok_or(MapToError::FrameAllocationFailed)?;
-
+    // No frames allocated: all pages share the zero frame
     for page in page_range {
         unsafe {
             mapper.map_to_with_table_flags(page,
-                                           frame,
+                                           memory_info.zero_frame,
                                            // Page not writable
                                            PageTableFlags::PRESENT |
-                                           PageTableFlags::USER_ACCESSIBLE,
+                                           PageTableFlags::USER_ACCESSIBLE |
+                                           ZERO_PAGE_COW,
                                            // Parent table flags include writable
                                            PageTableFlags::PRESENT |
                                            PageTableFlags::WRITABLE |
@@ -366,16 +555,6 @@ pub fn create_user_ondemand_pages(
         };
     }
 
-    // Make one page writable, so this 'owns' the frame
-    unsafe {
-        mapper.update_flags(page_range.start,
-                            PageTableFlags::PRESENT |
-                            PageTableFlags::WRITABLE |
-                            PageTableFlags::USER_ACCESSIBLE)
-            .map_err(|_| MapToError::FrameAllocationFailed)?
-            .flush(); // Update page table
-    }
-
     Ok(())
 }
 
@@ -389,7 +568,8 @@ fn map_consecutive_pages(
     level_4_physaddr: u64,
     start_page: Page,
     start_frame: PhysFrame,
-    num_frames: u64)
+    num_frames: u64,
+    extra_flags: PageTableFlags)
     -> Result<PhysAddr, MapToError<Size4KiB>> {
 
     let frame_range = PhysFrame::range(start_frame, start_frame + num_frames);
@@ -414,7 +594,8 @@ fn map_consecutive_pages(
                                            // Writeable by user
                                            PageTableFlags::PRESENT |
                                            PageTableFlags::WRITABLE |
-                                           PageTableFlags::USER_ACCESSIBLE,
+                                           PageTableFlags::USER_ACCESSIBLE |
+                                           extra_flags,
                                            // Parent table flags include writable
                                            PageTableFlags::PRESENT |
                                            PageTableFlags::WRITABLE |
@@ -453,7 +634,8 @@ pub fn create_consecutive_pages(
     map_consecutive_pages(level_4_physaddr,
                           start_page,
                           start_frame,
-                          num_frames)
+                          num_frames,
+                          PageTableFlags::empty())
 }
 
 /// Create a mapping to a specific range of physical memory
@@ -476,7 +658,33 @@ pub fn create_physical_range_pages(
     map_consecutive_pages(level_4_physaddr,
                           start_page,
                           start_frame,
-                          num_frames)
+                          num_frames,
+                          PageTableFlags::empty())
+}
+
+/// Map device memory, such as a PCI memory BAR
+///
+/// As create_physical_range_pages, but caching is disabled and
+/// the frames aren't freed with the page table.
+pub fn create_device_pages(
+    level_4_physaddr: u64,
+    start_virtaddr: VirtAddr,
+    num_frames: u64,
+    start_physaddr: PhysAddr)
+    -> Result<PhysAddr, MapToError<Size4KiB>> {
+
+    let start_page = Page::from_start_address(start_virtaddr)
+        .map_err(|_| MapToError::FrameAllocationFailed)?;
+    let start_frame = PhysFrame::from_start_address(start_physaddr)
+        .map_err(|_| MapToError::FrameAllocationFailed)?;
+
+    map_consecutive_pages(level_4_physaddr,
+                          start_page,
+                          start_frame,
+                          num_frames,
+                          PageTableFlags::NO_CACHE |
+                          PageTableFlags::WRITE_THROUGH |
+                          DEVICE_FRAME)
 }
 
 ///////////////////////////////////////////////////////////////////////
@@ -598,6 +806,38 @@ pub fn get_page_chunk(
     Ok((physaddr, 2))
 }
 
+/// Number of bytes mapped from the start of the memory chunk
+/// containing `address`, up to the first page which isn't mapped
+pub fn page_chunk_size(
+    level_4_physaddr: u64,
+    address: VirtAddr
+) -> Result<u64, usize> {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+
+    let (l2_physaddr, _) = get_page_chunk(level_4_physaddr, address, false)?;
+    let l2_table: &PageTable = unsafe {
+        & *(memory_info.physical_memory_offset
+            + l2_physaddr.as_u64()).as_ptr()};
+
+    let mut num_pages = 0;
+    for l2_entry in l2_table.iter() {
+        if l2_entry.is_unused() || l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        let l1_table: &PageTable = unsafe {
+            & *(memory_info.physical_memory_offset
+                + l2_entry.addr().as_u64()).as_ptr()};
+        for entry in l1_table.iter() {
+            if !entry.flags().contains(PageTableFlags::PRESENT |
+                                       PageTableFlags::USER_ACCESSIBLE) {
+                return Ok(num_pages * 4096);
+            }
+            num_pages += 1;
+        }
+    }
+    Ok(num_pages * 4096)
+}
+
 /// Finds an available page chunk entry, stores the physical address
 /// in the page table and returns the virtual address.
 pub fn put_page_chunk(
@@ -642,6 +882,181 @@ pub fn put_page_chunk(
     Err(syscalls::SYSCALL_ERROR_NOMEMSLOTS)
 }
 
+/// Map the pages of a memory chunk into a second chunk
+///
+/// Both chunks then refer to the same frames, so writes through one
+/// are seen through the other. The new chunk can be sent to another
+/// process by message, like any other chunk. Frames stay allocated
+/// until every chunk mapping them has been freed.
+///
+/// On-demand pages which haven't been written yet are allocated
+/// first, so that both chunks share them.
+///
+/// If `writable` is false then the new chunk is mapped read-only
+/// (marked READ_ONLY_PAGE), and writes through it are page faults.
+/// The original chunk keeps write access, so the new chunk aliases
+/// memory which can change while it is being read. Read-only chunks
+/// can be shared again, but only read-only.
+///
+/// Returns the virtual address of the new chunk.
+pub fn share_page_chunk(
+    level_4_physaddr: u64,
+    address: VirtAddr,
+    writable: bool
+) -> Result<VirtAddr, usize> {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+
+    let (l2_physaddr, _) = get_page_chunk(level_4_physaddr, address, false)?;
+    let l2_table: &mut PageTable = unsafe {
+        &mut *(memory_info.physical_memory_offset
+               + l2_physaddr.as_u64()).as_mut_ptr()};
+
+    let (new_l2_ptr, new_l2_physaddr) = create_empty_pagetable();
+    let new_l2_table = unsafe {&mut *new_l2_ptr};
+
+    let result = irqguard::without_interrupts(|| {
+        let mut shared_frames = SHARED_FRAMES.lock();
+
+        for (l2_entry, new_l2_entry) in l2_table.iter().zip(new_l2_table.iter_mut()) {
+            if l2_entry.is_unused() {
+                continue;
+            }
+            if l2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                // Not used for memory chunks
+                return Err(syscalls::SYSCALL_ERROR_PARAM);
+            }
+            let (new_l1_ptr, new_l1_physaddr) = create_empty_pagetable();
+            new_l2_entry.set_addr(PhysAddr::new(new_l1_physaddr), l2_entry.flags());
+
+            let l1_table: &mut PageTable = unsafe {
+                &mut *(memory_info.physical_memory_offset
+                       + l2_entry.addr().as_u64()).as_mut_ptr()};
+            let new_l1_table = unsafe {&mut *new_l1_ptr};
+
+            for (entry, new_entry) in l1_table.iter_mut().zip(new_l1_table.iter_mut()) {
+                if entry.is_unused() {
+                    continue;
+                }
+                if entry.flags().contains(ZERO_PAGE_COW) {
+                    // Not yet written => Allocate a frame now
+                    let frame = memory_info.frame_allocator.allocate_frame()
+                        .ok_or(syscalls::SYSCALL_ERROR_MEMORY)?;
+                    zero_fill_frame(memory_info.physical_memory_offset, frame);
+                    let no_execute = entry.flags() & PageTableFlags::NO_EXECUTE;
+                    entry.set_addr(frame.start_address(),
+                                   PageTableFlags::PRESENT |
+                                   PageTableFlags::WRITABLE |
+                                   PageTableFlags::USER_ACCESSIBLE |
+                                   no_execute);
+                }
+                if !entry.flags().contains(PageTableFlags::PRESENT |
+                                           PageTableFlags::USER_ACCESSIBLE) ||
+                    !entry.flags().intersects(PageTableFlags::WRITABLE | READ_ONLY_PAGE) ||
+                    (writable && !entry.flags().contains(PageTableFlags::WRITABLE)) {
+                    // Not an owned frame, or would make read-only memory writable
+                    return Err(syscalls::SYSCALL_ERROR_PARAM);
+                }
+                let flags = entry.flags() | SHARED_FRAME;
+                entry.set_flags(flags);
+                new_entry.set_addr(entry.addr(), if writable {
+                    flags
+                } else {
+                    (flags - PageTableFlags::WRITABLE) | READ_ONLY_PAGE
+                });
+
+                count_shared_mapping(&mut shared_frames, entry.addr().as_u64());
+            }
+        }
+        Ok(())
+    });
+    // Zero pages may have been replaced
+    x86_64::instructions::tlb::flush_all();
+
+    if let Err(code) = result {
+        // Undo, releasing any frames already shared
+        free_pages_rec(memory_info.physical_memory_offset,
+                       &mut memory_info.frame_allocator,
+                       PhysAddr::new(new_l2_physaddr),
+                       2);
+        return Err(code);
+    }
+
+    put_page_chunk(level_4_physaddr, PhysAddr::new(new_l2_physaddr))
+        .map_err(|code| {
+            free_pages_rec(memory_info.physical_memory_offset,
+                           &mut memory_info.frame_allocator,
+                           PhysAddr::new(new_l2_physaddr),
+                           2);
+            code
+        })
+}
+
+/// Free a memory chunk which is not in any page table
+///
+/// Used for chunks taken from the sender of a message which can't
+/// be delivered. `physaddr` is the level 2 page table returned by
+/// get_page_chunk. Shared frames are only freed if this was their
+/// last mapping.
+pub fn free_unmapped_chunk(physaddr: PhysAddr) {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+    free_pages_rec(memory_info.physical_memory_offset,
+                   &mut memory_info.frame_allocator,
+                   physaddr,
+                   2);
+}
+
+lazy_static! {
+    /// Number of page table entries mapping each shared frame,
+    /// indexed by physical address
+    ///
+    /// Frames which have never been shared aren't in the map. A chunk
+    /// sent in a message is moved from one page table to another, so
+    /// its counts don't change; only share_page_chunk adds mappings,
+    /// and free_pages_rec removes them.
+    static ref SHARED_FRAMES: spin::Mutex<BTreeMap<u64, u64>> =
+        spin::Mutex::new(BTreeMap::new());
+}
+
+/// Count a new mapping of a frame. The first mapping is counted
+/// too if the frame wasn't already shared.
+fn count_shared_mapping(shared_frames: &mut BTreeMap<u64, u64>, physaddr: u64) {
+    *shared_frames.entry(physaddr).or_insert(1) += 1;
+}
+
+/// Remove one counted mapping of a frame
+///
+/// Returns true if this was the last mapping, so the frame
+/// should be deallocated.
+fn uncount_shared_mapping(shared_frames: &mut BTreeMap<u64, u64>, physaddr: u64) -> bool {
+    match shared_frames.get_mut(&physaddr) {
+        Some(count) if *count > 1 => {
+            *count -= 1;
+            false
+        }
+        Some(_) => {
+            // Last mapping
+            shared_frames.remove(&physaddr);
+            true
+        }
+        None => {
+            // More mappings freed than were made. In release
+            // builds leak the frame rather than free it twice
+            debug_assert!(false, "Shared frame {:#x} freed with a count of zero", physaddr);
+            false
+        }
+    }
+}
+
+/// Remove one mapping of a shared frame
+///
+/// Returns true if this was the last mapping, so the frame
+/// should be deallocated.
+fn release_shared_frame(physaddr: u64) -> bool {
+    irqguard::without_interrupts(|| {
+        uncount_shared_mapping(&mut SHARED_FRAMES.lock(), physaddr)
+    })
+}
+
 ///////////////////////////////////////////////////////////////////////
 
 /// Recursively free all pages and page tables, including the page
@@ -661,14 +1076,18 @@ fn free_pages_rec(physical_memory_offset: VirtAddr,
     let table = unsafe{&mut *(physical_memory_offset
                               + physaddr.as_u64())
                        .as_mut_ptr() as &mut PageTable};
-    for entry in table.iter() {
+    for (i, entry) in table.iter().enumerate() {
+        if level == 4 && i == KERNEL_STACK_L4_INDEX {
+            continue; // Shared with the kernel page table
+        }
         if !entry.is_unused() {
             if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-                // Maps a frame, not a page table
-                if entry.flags().contains(PageTableFlags::PRESENT |
-                                          PageTableFlags::WRITABLE |
-                                          PageTableFlags::USER_ACCESSIBLE)  {
-                    // A user frame => deallocate
+                // Maps a frame, not a page table. Shared device
+                // frames are counted too, but never deallocated
+                let last_mapping = !entry.flags().contains(SHARED_FRAME) ||
+                    release_shared_frame(entry.addr().as_u64());
+                if last_mapping && is_owned_user_frame(entry.flags()) {
+                    // A user frame, not mapped elsewhere => deallocate
                     frame_allocator.deallocate_frame(
                         entry.frame().unwrap());
                 }
@@ -684,6 +1103,7 @@ fn free_pages_rec(physical_memory_offset: VirtAddr,
     // Free page table
     frame_allocator.deallocate_frame(
         PhysFrame::from_start_address(physaddr).unwrap());
+    PAGE_TABLE_FRAMES.fetch_sub(1, Ordering::Relaxed);
 }
 
 /// Free all user-accessible pages and the page table frames
@@ -699,19 +1119,221 @@ pub fn free_user_pagetables(level_4_physaddr: u64) {
                    4);
 }
 
+fn count_frames_rec(physical_memory_offset: VirtAddr,
+                    physaddr: PhysAddr,
+                    level: u16) -> usize {
+    let table = unsafe{&*(physical_memory_offset
+                          + physaddr.as_u64())
+                       .as_ptr() as &PageTable};
+    table.iter()
+        .filter(|entry| !entry.is_unused())
+        .map(|entry| {
+            if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                // Same test as free_pages_rec
+                if is_owned_user_frame(entry.flags()) {1} else {0}
+            } else {
+                count_frames_rec(physical_memory_offset, entry.addr(), level - 1)
+            }
+        }).sum()
+}
+
+/// Copy user memory from a page table which may not be active
+///
+/// Copies from `address` into `buf`, crossing page boundaries, and
+/// stops at the first page which is not mapped or not user accessible.
+///
+/// Returns the number of bytes copied.
+pub fn read_user_memory(level_4_physaddr: u64, address: u64, buf: &mut [u8]) -> usize {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+    let l4_table: &mut PageTable = unsafe {
+        &mut *(memory_info.physical_memory_offset
+               + level_4_physaddr).as_mut_ptr()};
+    let mapper = unsafe {
+        OffsetPageTable::new(l4_table, memory_info.physical_memory_offset)};
+
+    let mut copied = 0;
+    while copied < buf.len() {
+        let virtaddr = match address.checked_add(copied as u64)
+            .and_then(|addr| VirtAddr::try_new(addr).ok()) {
+                Some(virtaddr) => virtaddr,
+                None => break
+            };
+        let (frame, offset) = match mapper.translate(virtaddr) {
+            TranslateResult::Mapped{frame, offset, flags}
+            if flags.contains(PageTableFlags::PRESENT |
+                              PageTableFlags::USER_ACCESSIBLE) => (frame, offset),
+            _ => break // Unmapped gap
+        };
+        // Copy up to the end of this page
+        let len = cmp::min((frame.size() - offset) as usize, buf.len() - copied);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (memory_info.physical_memory_offset
+                 + frame.start_address().as_u64() + offset).as_ptr::<u8>(),
+                buf[copied..].as_mut_ptr(),
+                len);
+        }
+        copied += len;
+    }
+    copied
+}
+
+/// Physical address of a user virtual address
+///
+/// Returns None if the page is not mapped and user accessible.
+/// Pages allocated on demand are only mapped once touched.
+pub fn translate_user_address(level_4_physaddr: u64, address: u64) -> Option<PhysAddr> {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+    let l4_table: &mut PageTable = unsafe {
+        &mut *(memory_info.physical_memory_offset
+               + level_4_physaddr).as_mut_ptr()};
+    let mapper = unsafe {
+        OffsetPageTable::new(l4_table, memory_info.physical_memory_offset)};
+
+    match mapper.translate(VirtAddr::try_new(address).ok()?) {
+        TranslateResult::Mapped{frame, offset, flags}
+        if flags.contains(PageTableFlags::PRESENT |
+                          PageTableFlags::USER_ACCESSIBLE) =>
+            Some(frame.start_address() + offset),
+        _ => None
+    }
+}
+
+/// Count the user frames which would be freed with a page table,
+/// i.e. the physical memory used by a process.
+///
+/// Shared (zero or read-only) frames are not counted.
+pub fn count_user_frames(level_4_physaddr: u64) -> usize {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+
+    count_frames_rec(memory_info.physical_memory_offset,
+                     PhysAddr::new(level_4_physaddr),
+                     4)
+}
+
 ///////////////////////////////////////////////////////////////////////
 
+/// Slots in the kernel thread stack region which have been freed
+static FREE_KERNEL_STACK_SLOTS: spin::Mutex<Vec<u64>> = spin::Mutex::new(Vec::new());
+
+/// Slots at and above this have never been used
+static NEXT_KERNEL_STACK_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+/// A stack for kernel threads, with an unmapped guard page below
+///
+/// An overflow causes a page fault (see is_kernel_stack_guard)
+/// rather than overwriting other kernel memory. Frames are
+/// allocated when the stack is created, and freed when dropped.
+#[derive(Debug)]
+pub struct GuardedStack {
+    slot: u64,
+    pages: u64
+}
+
+impl GuardedStack {
+    /// Map a new stack of at least `size` bytes
+    ///
+    /// Each stack uses a slot of KERNEL_STACK_SLOT_PAGES pages
+    /// including the guard, so can be up to 28KiB.
+    pub fn new(size: usize) -> Result<GuardedStack, &'static str> {
+        let pages = ((size + 4095) / 4096) as u64;
+        if pages == 0 || pages >= KERNEL_STACK_SLOT_PAGES {
+            return Err("Kernel stack size not supported");
+        }
+        let slot = irqguard::without_interrupts(|| FREE_KERNEL_STACK_SLOTS.lock().pop())
+            .unwrap_or_else(|| NEXT_KERNEL_STACK_SLOT.fetch_add(1, Ordering::Relaxed) as u64);
+        // Dropped if mapping fails, to unmap any pages already mapped
+        let stack = GuardedStack{slot, pages};
+
+        let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+        let mut mapper = unsafe {
+            OffsetPageTable::new(&mut *memory_info.kernel_l4_table,
+                                 memory_info.physical_memory_offset)};
+        for page in stack.page_range() {
+            let frame = memory_info.frame_allocator.allocate_frame()
+                .ok_or(FRAME_ALLOC_FAILED)?;
+            unsafe {
+                mapper.map_to(page, frame,
+                              PageTableFlags::PRESENT |
+                              PageTableFlags::WRITABLE |
+                              PageTableFlags::NO_EXECUTE,
+                              &mut memory_info.frame_allocator)
+                    .map_err(|_| "Could not map kernel stack")?
+                    .flush();
+            }
+            KERNEL_STACK_FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(stack)
+    }
+
+    /// Address of the guard page
+    fn slot_address(&self) -> u64 {
+        ((KERNEL_STACK_L4_INDEX as u64) << 39) +
+            self.slot * KERNEL_STACK_SLOT_PAGES * 4096
+    }
+
+    /// Lowest address of the stack, above the guard page
+    pub fn start(&self) -> u64 {
+        self.slot_address() + 4096
+    }
+
+    /// Address of the end of the stack. Stacks grow down,
+    /// so this is the initial stack pointer.
+    pub fn end(&self) -> u64 {
+        self.start() + self.pages * 4096
+    }
+
+    fn page_range(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(Page::containing_address(VirtAddr::new(self.start())),
+                    Page::containing_address(VirtAddr::new(self.end())))
+    }
+}
+
+impl Drop for GuardedStack {
+    fn drop(&mut self) {
+        let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+        let mut mapper = unsafe {
+            OffsetPageTable::new(&mut *memory_info.kernel_l4_table,
+                                 memory_info.physical_memory_offset)};
+        for page in self.page_range() {
+            // Pages may not be mapped if GuardedStack::new failed
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                memory_info.frame_allocator.deallocate_frame(frame);
+                KERNEL_STACK_FRAMES.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        let slot = self.slot;
+        irqguard::without_interrupts(|| FREE_KERNEL_STACK_SLOTS.lock().push(slot));
+    }
+}
+
+/// True if `addr` is in the guard page of a kernel thread stack,
+/// so a page fault there is a stack overflow
+pub fn is_kernel_stack_guard(addr: VirtAddr) -> bool {
+    usize::from(addr.p4_index()) == KERNEL_STACK_L4_INDEX &&
+        ((addr.as_u64() >> 12) % KERNEL_STACK_SLOT_PAGES) == 0
+}
+
 /// Allocate memory for a thread's user stack
 ///
-/// Uses 8 pages per thread: 7 for user stack, one guard page
+/// Uses consecutive slots of 8 pages, each one guard page followed
+/// by the user stack. `num_pages` is rounded up to fill the slots, so
+/// USER_STACK_PAGES (7) use one slot, and 8 to 15 pages use two.
+/// `num_pages` must be between 1 and MAX_USER_STACK_PAGES.
 ///
 /// # Returns
 ///
 /// (user_stack_start, user_stack_end)
 ///
 pub fn allocate_user_stack(
-    level_4_table: *mut PageTable
+    level_4_table: *mut PageTable,
+    num_pages: usize
 ) -> Result<(u64, u64), &'static str> {
+    if num_pages == 0 || num_pages > MAX_USER_STACK_PAGES {
+        return Err("Invalid user stack size");
+    }
+    let num_slots = (num_pages + USER_STACK_SLOT_PAGES) / USER_STACK_SLOT_PAGES;
 
     let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
 
@@ -732,59 +1354,163 @@ pub fn allocate_user_stack(
 
     // Table should now be the level 1 page table
     //
-    // Find an unused set of 8 pages. The lowest page is always unused
-    // (guard), but the first should be used so look in pages
-    // (1 + 8*n) where n=0..64
+    // Find a run of unused slots of 8 pages. The lowest page of
+    // every slot in a stack is used except the first (guard),
+    // so look in pages (1 + 8*n) where n=0..64
     //
     // Choose a random n to start looking, and check entries
     // sequentially from there. For now just use process::unique_id
     use crate::process;
-    let n_start = process::unique_id(); // Modulo 64 soon
-    for i in 0..64 {
-        let n = ((n_start + i) % 64) as usize;
+    let n_start = process::unique_id() as usize;
+    for i in 0..USER_STACK_SLOTS {
+        let n = (n_start + i) % USER_STACK_SLOTS;
+        if n + num_slots > USER_STACK_SLOTS {
+            continue; // Would run off the end of the table
+        }
 
-        if table[n * 8 + 1].is_unused() {
-            // Found an empty slot:
+        // Stacks may have grown into the lower pages of a slot,
+        // so check every page rather than only the first
+        let start = n * USER_STACK_SLOT_PAGES;
+        let end = (n + num_slots) * USER_STACK_SLOT_PAGES;
+        if (start..end).all(|index| table[index].is_unused()) {
+            // Found empty slots:
             //  [n * 8] -> Empty (guard)
             //  [n * 8 + 1] -> User stack (read-only)
             //      ...
-            //  [n * 8 + 7] -> User stack (writable)
+            //  [end - 1] -> User stack (writable)
+            // where end = (n + num_slots) * 8
 
             // Note: Only one frame is going to be allocated, and the rest
-            //       are going to be read-only references to the same frame.
+            //       are going to be read-only references to the zero frame.
             //       When a thread tries to write to them a page fault will
             //       be triggered and the frame allocated.
             let frame = memory_info.frame_allocator.allocate_frame()
                     .ok_or("Failed to allocate frame")?;
+            zero_fill_frame(memory_info.physical_memory_offset, frame);
 
-            for j in 1..7 {
+            // Stacks are never executable
+            for j in (n * USER_STACK_SLOT_PAGES + 1)..(end - 1) {
                 // These pages are read-only
-                let entry = &mut table[n * 8 + j];
-                entry.set_addr(frame.start_address(),
+                let entry = &mut table[j];
+                entry.set_addr(memory_info.zero_frame.start_address(),
                                PageTableFlags::PRESENT |
-                               PageTableFlags::USER_ACCESSIBLE);
+                               PageTableFlags::USER_ACCESSIBLE |
+                               PageTableFlags::NO_EXECUTE |
+                               ZERO_PAGE_COW);
             }
-            let entry = &mut table[n * 8 + 7];
+            let entry = &mut table[end - 1];
             entry.set_addr(frame.start_address(),
                            PageTableFlags::PRESENT |
                            PageTableFlags::WRITABLE | // Note!
-                           PageTableFlags::USER_ACCESSIBLE);
+                           PageTableFlags::USER_ACCESSIBLE |
+                           PageTableFlags::NO_EXECUTE);
 
             // Return the virtual addresses of the top of the kernel and user stacks
             let slot_address: u64 =
                 ((THREAD_STACK_PAGE_INDEX[0] as u64) << 39) +
                 ((THREAD_STACK_PAGE_INDEX[1] as u64) << 30) +
                 ((THREAD_STACK_PAGE_INDEX[2] as u64) << 21) +
-                (((n * 8) as u64) << 12);
+                (((n * USER_STACK_SLOT_PAGES) as u64) << 12);
 
             return Ok((slot_address + 4096,
-                       slot_address + 8 * 4096)); // User stack
+                       slot_address + ((end - n * USER_STACK_SLOT_PAGES) as u64) * 4096)); // User stack
         }
     }
 
     Err("All thread stack slots full")
 }
 
+/// The level 1 page table of user stacks in the active page table,
+/// if any stacks have been allocated
+fn active_user_stack_table() -> Option<&'static mut PageTable> {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+    let mut table = unsafe {&mut (*active_pagetable_ptr())};
+    for index in THREAD_STACK_PAGE_INDEX {
+        let entry = &mut table[index as usize];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        table = unsafe {&mut *(memory_info.physical_memory_offset
+                               + entry.addr().as_u64()).as_mut_ptr()};
+    }
+    Some(table)
+}
+
+/// Level 1 index of `addr` if it is in the user stack region
+fn user_stack_index(addr: VirtAddr) -> Option<usize> {
+    let [l4, l3, l2] = THREAD_STACK_PAGE_INDEX;
+    if usize::from(addr.p4_index()) == l4 as usize &&
+        usize::from(addr.p3_index()) == l3 as usize &&
+        usize::from(addr.p2_index()) == l2 as usize {
+        Some(usize::from(addr.p1_index()))
+    } else {
+        None
+    }
+}
+
+/// Lowest page of the user stack above level 1 `index`, if `index`
+/// is not present and at most USER_STACK_FAULT_GAP pages below it
+fn user_stack_above(table: &PageTable, index: usize) -> Option<usize> {
+    let present = |index: usize| table[index].flags().contains(PageTableFlags::PRESENT);
+    if present(index) {
+        return None;
+    }
+    ((index + 1)..cmp::min(index + 1 + USER_STACK_FAULT_GAP, 512))
+        .find(|&i| present(i))
+}
+
+/// True if `addr` is just below a user stack in the active page
+/// table, so a fault there is the stack running out
+///
+/// Functions with large stack frames may skip over the guard page,
+/// so faults up to USER_STACK_FAULT_GAP pages below are included.
+pub fn is_user_stack_guard(addr: VirtAddr) -> bool {
+    match (user_stack_index(addr), active_user_stack_table()) {
+        (Some(index), Some(table)) => user_stack_above(table, index).is_some(),
+        _ => false
+    }
+}
+
+/// Grow the user stack above `addr` down to include it
+///
+/// New frames are mapped from the page containing `addr` up to the
+/// stack, and the page below becomes the guard. Fails if that page is
+/// in use by another stack, so no guard would remain, or the stack
+/// would have more than USER_STACK_GROWTH_LIMIT pages: the stack has
+/// overflowed.
+pub fn grow_user_stack(addr: VirtAddr) -> Result<(), &'static str> {
+    let index = user_stack_index(addr).ok_or("Not a user stack address")?;
+    let table = active_user_stack_table().ok_or("No user stacks")?;
+    let bottom = user_stack_above(table, index).ok_or("Not below a user stack")?;
+    let present = |index: usize| table[index].flags().contains(PageTableFlags::PRESENT);
+
+    if index == 0 || present(index - 1) {
+        return Err("No space below stack");
+    }
+    let stack_pages = (bottom..512).take_while(|&i| present(i)).count();
+    if stack_pages + (bottom - index) > USER_STACK_GROWTH_LIMIT {
+        return Err("Stack size limit reached");
+    }
+
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+    // Map from the top down, so pages mapped before running out of
+    // frames are still contiguous with the stack
+    for i in (index..bottom).rev() {
+        let frame = memory_info.frame_allocator.allocate_frame()
+            .ok_or(FRAME_ALLOC_FAILED)?;
+        zero_fill_frame(memory_info.physical_memory_offset, frame);
+        table[i].set_addr(frame.start_address(),
+                          PageTableFlags::PRESENT |
+                          PageTableFlags::WRITABLE |
+                          PageTableFlags::USER_ACCESSIBLE |
+                          PageTableFlags::NO_EXECUTE);
+        // May have been mapped by a stack which was freed
+        x86_64::instructions::tlb::flush(
+            addr.align_down(4096u64) + ((i - index) as u64) * 4096);
+    }
+    Ok(())
+}
+
 fn active_level_1_table_containing(
     addr: VirtAddr
 ) -> &'static mut PageTable {
@@ -802,9 +1528,27 @@ fn active_level_1_table_containing(
     table
 }
 
+/// Fill a frame with zeros, through the kernel's
+/// mapping of physical memory
+fn zero_fill_frame(physical_memory_offset: VirtAddr, frame: PhysFrame) {
+    let ptr: *mut u8 = (physical_memory_offset
+                        + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe {
+        core::ptr::write_bytes(ptr, 0, frame.size() as usize);
+    }
+}
+
+/// Error returned by allocate_missing_ondemand_frame when
+/// physical memory is exhausted
+pub const FRAME_ALLOC_FAILED: &str = "Could not allocate frame";
+
 /// Allocate a read-only page which user code
 /// has attempted to write to.
 /// This is called by the page fault handler
+///
+/// Pages marked ZERO_PAGE_COW get a new zeroed frame; other
+/// read-only user pages get a private copy of the frame they
+/// point to. The shared zero frame is never written.
 pub fn allocate_missing_ondemand_frame(
     addr: VirtAddr
 ) -> Result<(), &'static str> {
@@ -812,26 +1556,81 @@ pub fn allocate_missing_ondemand_frame(
     let table = active_level_1_table_containing(addr);
     let entry = &mut table[addr.p1_index()];
 
-    if entry.flags() != (PageTableFlags::PRESENT |
-                         PageTableFlags::USER_ACCESSIBLE) {
-        println!("Unexpected flags: {:?} addr: {:?}", entry.flags(), addr);
+    // Ignore bits set by the CPU when the page is read
+    let flags = entry.flags() - (PageTableFlags::ACCESSED |
+                                 PageTableFlags::DIRTY);
+    if !is_ondemand(flags) {
+        println!("Unexpected flags: {:?} addr: {:?}", flags, addr);
         return Err("Error: Unexpected table flags");
     }
+    let no_execute = flags & PageTableFlags::NO_EXECUTE;
 
     // Get a new frame and update page table
     let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
     let frame = memory_info.frame_allocator.allocate_frame()
-        .ok_or("Could not allocate frame")?;
+        .ok_or(FRAME_ALLOC_FAILED)?;
+
+    let old_frame = match entry.frame() {
+        Ok(old_frame) => old_frame,
+        Err(_) => {
+            memory_info.frame_allocator.deallocate_frame(frame);
+            return Err("Could not get frame");
+        }
+    };
+    if flags.contains(ZERO_PAGE_COW) || (old_frame == memory_info.zero_frame) {
+        zero_fill_frame(memory_info.physical_memory_offset, frame);
+    } else {
+        // Copy the contents of the shared frame
+        unsafe {
+            let from: *const u8 = (memory_info.physical_memory_offset
+                                   + old_frame.start_address().as_u64()).as_ptr();
+            let to: *mut u8 = (memory_info.physical_memory_offset
+                               + frame.start_address().as_u64()).as_mut_ptr();
+            core::ptr::copy_nonoverlapping(from, to, frame.size() as usize);
+        }
+    }
 
     entry.set_addr(frame.start_address(),
                    PageTableFlags::PRESENT |
                    PageTableFlags::WRITABLE |
-                   PageTableFlags::USER_ACCESSIBLE);
+                   PageTableFlags::USER_ACCESSIBLE |
+                   no_execute);
+    x86_64::instructions::tlb::flush(addr);
     Ok(())
 }
 
+/// Is a page with these flags given its own frame when written?
+///
+/// Pages marked READ_ONLY_PAGE don't match, so writes are errors
+fn is_ondemand(flags: PageTableFlags) -> bool {
+    let flags = flags - (PageTableFlags::ACCESSED |
+                         PageTableFlags::DIRTY |
+                         PageTableFlags::NO_EXECUTE |
+                         ZERO_PAGE_COW);
+    flags == (PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE)
+}
+
+/// Give a mapped on-demand page in the active page table its own
+/// frame now, rather than on the first write
+///
+/// Its physical address then doesn't change when written.
+/// Writable and read-only pages are left as they are.
+pub fn resolve_ondemand_frame(
+    addr: VirtAddr
+) -> Result<(), &'static str> {
+    let table = active_level_1_table_containing(addr);
+    if is_ondemand(table[addr.p1_index()].flags()) {
+        allocate_missing_ondemand_frame(addr)
+    } else {
+        Ok(())
+    }
+}
+
 /// Free frames used for a thread stack
 /// given virtual address
+///
+/// Pages are freed down to the first which is not present,
+/// so stacks of any size allocated by allocate_user_stack.
 pub fn free_user_stack(
     stack_end: VirtAddr
 ) -> Result<(), &'static str> {
@@ -841,8 +1640,11 @@ pub fn free_user_stack(
     let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
 
     let iend = usize::from(addr.p1_index());
-    for index in ((iend - 6)..=iend).rev() {
+    for index in (0..=iend).rev() {
         let entry = &mut table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            break; // Guard page, or below a shorter stack
+        }
         if entry.flags().contains(PageTableFlags::WRITABLE) {
             // Free this frame
             memory_info.frame_allocator.deallocate_frame(
@@ -853,3 +1655,106 @@ pub fn free_user_stack(
 
     Ok(())
 }
+
+#[test_case]
+fn kernel_stack_guard_pages() {
+    // Not allocated: only the addresses are checked
+    let stack = core::mem::ManuallyDrop::new(GuardedStack{slot: 3, pages: 2});
+    assert_eq!(stack.end() - stack.start(), 2 * 4096);
+    assert!(is_kernel_stack_guard(VirtAddr::new(stack.start() - 8)));
+    assert!(!is_kernel_stack_guard(VirtAddr::new(stack.start())));
+    assert!(!is_kernel_stack_guard(VirtAddr::new(stack.end() - 8)));
+    // Outside the kernel stack region
+    assert!(!is_kernel_stack_guard(VirtAddr::new(0x20_0000)));
+}
+
+#[test_case]
+fn shared_frame_counts() {
+    let mut shared_frames = BTreeMap::new();
+    // Shared twice: three mappings
+    count_shared_mapping(&mut shared_frames, 0x1000);
+    count_shared_mapping(&mut shared_frames, 0x1000);
+    assert_eq!(shared_frames.get(&0x1000), Some(&3));
+
+    // Only the last mapping frees the frame
+    assert!(!uncount_shared_mapping(&mut shared_frames, 0x1000));
+    assert!(!uncount_shared_mapping(&mut shared_frames, 0x1000));
+    assert!(uncount_shared_mapping(&mut shared_frames, 0x1000));
+    assert!(shared_frames.is_empty());
+}
+
+#[test_case]
+fn user_stack_fault_range() {
+    let mut table = alloc::boxed::Box::new(PageTable::new());
+    // A stack in pages 20 to 27
+    for index in 20..28 {
+        table[index].set_addr(PhysAddr::new(0x1000), PageTableFlags::PRESENT);
+    }
+    assert_eq!(user_stack_above(&table, 19), Some(20));
+    assert_eq!(user_stack_above(&table, 20 - USER_STACK_FAULT_GAP), Some(20));
+    assert_eq!(user_stack_above(&table, 19 - USER_STACK_FAULT_GAP), None);
+    // In the stack, or above it
+    assert_eq!(user_stack_above(&table, 21), None);
+    assert_eq!(user_stack_above(&table, 511), None);
+
+    let stack_region = (THREAD_STACK_PAGE_INDEX[0] as u64) << 39;
+    assert_eq!(user_stack_index(VirtAddr::new(stack_region + 20 * 4096 + 8)), Some(20));
+    assert_eq!(user_stack_index(VirtAddr::new(0x20_0000)), None);
+}
+
+#[test_case]
+fn read_user_memory_boundaries() {
+    let (table_ptr, table_physaddr) = create_new_user_pagetable();
+    // One user page, with nothing mapped after it
+    let page = 0x20_0000;
+    allocate_pages(table_ptr, VirtAddr::new(page), 4096,
+                   PageTableFlags::PRESENT |
+                   PageTableFlags::WRITABLE |
+                   PageTableFlags::USER_ACCESSIBLE).unwrap();
+    let physaddr = translate_user_address(table_physaddr, page).unwrap();
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+    unsafe {
+        core::ptr::write_bytes((memory_info.physical_memory_offset + physaddr.as_u64())
+                               .as_mut_ptr::<u8>(), 0x5A, 4096);
+    }
+
+    let mut buf = [0u8; 64];
+    // Crossing into the unmapped page: only the mapped part is read
+    assert_eq!(read_user_memory(table_physaddr, page + 4096 - 16, &mut buf), 16);
+    assert!(buf[..16].iter().all(|&byte| byte == 0x5A));
+    assert_eq!(read_user_memory(table_physaddr, page + 4096, &mut buf), 0);
+    // Kernel addresses are mapped, but not user accessible
+    assert_eq!(read_user_memory(table_physaddr, allocator::HEAP_START as u64, &mut buf), 0);
+    assert_eq!(read_user_memory(table_physaddr,
+                                memory_info.physical_memory_offset.as_u64(), &mut buf), 0);
+    // Past the end of the address space
+    assert_eq!(read_user_memory(table_physaddr, u64::MAX - 8, &mut buf), 0);
+
+    free_user_pagetables(table_physaddr);
+}
+
+#[test_case]
+fn shared_device_chunk_freed() {
+    let memory_info = unsafe {MEMORY_INFO.as_mut().unwrap()};
+    let (_, table_physaddr) = create_new_user_pagetable();
+    // Any frame will do as the device: it is mapped but not owned
+    let device = memory_info.frame_allocator.allocate_frame().unwrap();
+    let device_physaddr = device.start_address().as_u64();
+    let mappings = || irqguard::without_interrupts(|| {
+        SHARED_FRAMES.lock().get(&device_physaddr).copied()
+    });
+
+    let address = find_available_page_chunk(table_physaddr).unwrap();
+    create_device_pages(table_physaddr, address, 1, device.start_address()).unwrap();
+    let shared = share_page_chunk(table_physaddr, address, true).unwrap();
+    assert_eq!(mappings(), Some(2));
+
+    free_page_chunk(table_physaddr, shared).unwrap();
+    assert_eq!(mappings(), Some(1));
+    // The last mapping removes the count
+    free_page_chunk(table_physaddr, address).unwrap();
+    assert_eq!(mappings(), None);
+
+    free_user_pagetables(table_physaddr);
+    memory_info.frame_allocator.deallocate_frame(device);
+}
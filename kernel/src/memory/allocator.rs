@@ -4,6 +4,14 @@
 //! Used to store kernel data structures, including:
 //! - Thread objects (in Box<Thread>)
 //! - Stacks for kernel threads
+//!
+//! Each allocation is tagged with a Category so that the memory
+//! used for different purposes can be tracked. The tag is stored
+//! in an extra byte after the allocation.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
 use x86_64::{
     structures::paging::{
@@ -31,7 +39,7 @@ pub fn init_heap(
         PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?;
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
+        ALLOCATOR.heap.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
     }
 
     Ok(())
@@ -39,5 +47,159 @@ pub fn init_heap(
 
 use linked_list_allocator::LockedHeap;
 
+/// Coarse categories of kernel heap allocations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Category {
+    Other = 0,
+    /// Kernel stacks (and user stacks of kernel threads)
+    ThreadStack = 1,
+    /// Rendezvous objects, which hold messages
+    Message = 2,
+}
+
+pub const NUM_CATEGORIES: usize = 3;
+
+/// Category used to tag new allocations
+static CURRENT_CATEGORY: AtomicU8 = AtomicU8::new(Category::Other as u8);
+
+/// Bytes currently allocated in each Category
+static HEAP_USAGE: [AtomicUsize; NUM_CATEGORIES] = {
+    const ZERO: AtomicUsize = AtomicUsize::new(0);
+    [ZERO; NUM_CATEGORIES]
+};
+
+/// Run a function, tagging all allocations it makes with the given category
+///
+/// Note: The category is global rather than per-thread, so if another
+///       thread is scheduled inside `f` its allocations will
+///       also be tagged.
+pub fn with_category<T>(category: Category, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT_CATEGORY.swap(category as u8, Ordering::Relaxed);
+    let result = f();
+    CURRENT_CATEGORY.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// Number of bytes currently allocated in the given category
+pub fn heap_usage(category: Category) -> usize {
+    HEAP_USAGE[category as usize].load(Ordering::Relaxed)
+}
+
+/// Maximum number of trial allocations used to find the largest free block
+const MAX_FREE_BLOCK_PROBES: usize = 24;
+
+/// Size and free space of the kernel heap
+///
+/// The heap is a fixed region (HEAP_START, HEAP_SIZE) which is
+/// mapped at boot and never grows, so allocation failures are due
+/// either to exhaustion or to fragmentation.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Size of the heap in bytes
+    pub total: usize,
+    /// Bytes allocated, including allocator overhead
+    pub used: usize,
+    /// Size of the largest allocation which would succeed
+    /// (accurate to within 8 bytes, or 1 part in 2^24)
+    pub largest_free: usize,
+}
+
+impl HeapStats {
+    /// Fraction of the free space which is not in the largest free
+    /// block, in parts per thousand. 0 means unfragmented.
+    pub fn fragmentation_permille(&self) -> usize {
+        let free = self.total - self.used;
+        if free == 0 {
+            return 0;
+        }
+        1000 - (self.largest_free.min(free) * 1000) / free
+    }
+}
+
+/// Measure the kernel heap
+///
+/// The free list isn't exposed by the allocator, so the largest free
+/// block is found by binary search with trial allocations. This makes
+/// at most MAX_FREE_BLOCK_PROBES allocations, each of which walks the
+/// free list once.
+pub fn heap_stats() -> HeapStats {
+    crate::irqguard::without_interrupts(|| {
+        measure_heap(&mut ALLOCATOR.heap.lock())
+    })
+}
+
+/// Measure the kernel heap, unless it is locked
+///
+/// For use in interrupt handlers, which may have interrupted
+/// an allocation.
+pub fn try_heap_stats() -> Option<HeapStats> {
+    crate::irqguard::without_interrupts(|| {
+        ALLOCATOR.heap.try_lock().map(|mut heap| measure_heap(&mut heap))
+    })
+}
+
+fn measure_heap(heap: &mut linked_list_allocator::Heap) -> HeapStats {
+    let total = heap.size();
+    let used = heap.used();
+
+    // Invariant: lo bytes can be allocated, hi bytes can't
+    let mut lo = 0;
+    let mut hi = total - used + 1;
+    for _ in 0..MAX_FREE_BLOCK_PROBES {
+        if hi - lo <= 8 {
+            break;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let layout = Layout::from_size_align(mid, 8).unwrap();
+        match heap.allocate_first_fit(layout) {
+            Ok(ptr) => {
+                unsafe {heap.deallocate(ptr, layout);}
+                lo = mid;
+            }
+            Err(_) => hi = mid
+        }
+    }
+    HeapStats{total, used, largest_free: lo}
+}
+
+/// Wraps the heap allocator, counting the bytes allocated in each Category
+struct CountingHeap {
+    heap: LockedHeap
+}
+
+/// Add a byte to the end of the layout to store the Category
+///
+/// Returns the extended layout and the offset of the tag byte
+fn tagged_layout(layout: Layout) -> Option<(Layout, usize)> {
+    layout.extend(Layout::new::<u8>()).ok()
+}
+
+unsafe impl GlobalAlloc for CountingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (tagged, offset) = match tagged_layout(layout) {
+            Some(value) => value,
+            None => return ptr::null_mut()
+        };
+        let ptr = self.heap.alloc(tagged);
+        if !ptr.is_null() {
+            let category = CURRENT_CATEGORY.load(Ordering::Relaxed);
+            *ptr.add(offset) = category;
+            HEAP_USAGE[category as usize].fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Layout was valid when allocated
+        let (tagged, offset) = tagged_layout(layout).unwrap();
+        let category = *ptr.add(offset);
+        HEAP_USAGE[category as usize].fetch_sub(layout.size(), Ordering::Relaxed);
+        self.heap.dealloc(ptr, tagged)
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: CountingHeap = CountingHeap {
+    heap: LockedHeap::empty()
+};
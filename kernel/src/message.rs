@@ -4,6 +4,7 @@ use alloc::sync::Arc;
 use spin::RwLock;
 use x86_64::{VirtAddr, PhysAddr};
 
+use crate::memory;
 use crate::process::Thread;
 use crate::rendezvous::Rendezvous;
 use crate::syscalls;
@@ -104,6 +105,8 @@ impl Message {
                                 virtaddr.as_u64()
                             }
                             Err(error_code) => {
+                                // The chunk is in no page table, so would leak
+                                memory::free_unmapped_chunk(*physaddr);
                                 ctrl |= MESSAGE_DATA2_ERR | MESSAGE_LONG;
                                 error_code as u64
                             }
@@ -124,6 +127,8 @@ impl Message {
                                 virtaddr.as_u64()
                             }
                             Err(error_code) => {
+                                // The chunk is in no page table, so would leak
+                                memory::free_unmapped_chunk(*physaddr);
                                 ctrl |= MESSAGE_DATA3_ERR | MESSAGE_LONG;
                                 error_code as u64
                             }
@@ -135,6 +140,20 @@ impl Message {
         }
     }
 
+    /// Drop a message which will never be delivered
+    ///
+    /// Memory chunks have been removed from the sender's page table,
+    /// so are freed here. Rendezvous are dropped as usual.
+    pub fn discard(self) {
+        if let Message::Long(_, data2, data3) = self {
+            for data in [data2, data3] {
+                if let MessageData::Memory(physaddr) = data {
+                    memory::free_unmapped_chunk(physaddr);
+                }
+            }
+        }
+    }
+
     /// Take data passed via syscall and convert to
     /// a kernel Message object.
     ///
@@ -194,6 +213,13 @@ impl Message {
                     }
                     _ => MessageData::Value(data3)
                 });
+            if let Message::Long(_, MessageData::Memory(physaddr2),
+                                 MessageData::Memory(physaddr3)) = &message {
+                if physaddr2 == physaddr3 {
+                    // One chunk can't be moved twice
+                    return Err(syscalls::SYSCALL_ERROR_PARAM);
+                }
+            }
             // Message is valid => Remove handles being moved
             match syscall_id & MESSAGE_DATA2_TYPE {
                 MESSAGE_DATA2_RDV => {
@@ -0,0 +1,143 @@
+//! Out-of-memory (OOM) killer
+//!
+//! When a user process writes to an on-demand page and no physical
+//! frame is available, the page fault handler calls kill_victim()
+//! rather than halting. A victim process is selected and terminated
+//! to reclaim its memory, and the allocation is retried.
+//!
+//! The victim selection policy can be set when the kernel is built:
+//!
+//!     EURALIOS_OOM_POLICY=priority cargo run
+//!
+//! where the policy is one of
+//!  - "memory"   : Largest memory user (the default)
+//!  - "priority" : Lowest scheduler priority, then largest memory user
+//!  - "none"     : Don't kill processes; halt as before
+//!
+//! It can also be changed with set_policy().
+//!
+//! Processes with I/O privileges (init and drivers) are critical,
+//! and are only chosen as a last resort if no other process is running.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::println;
+use crate::process;
+
+/// How to choose a process to terminate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Process using the most physical memory
+    LargestMemory = 0,
+    /// Process with the lowest priority thread
+    LowestPriority = 1,
+    /// Don't terminate processes
+    Disabled = 2
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(Policy::LargestMemory as u8);
+
+/// Set the policy from the build environment, if given
+pub fn init() {
+    if let Some(name) = option_env!("EURALIOS_OOM_POLICY") {
+        let policy = match name {
+            "memory" => Policy::LargestMemory,
+            "priority" => Policy::LowestPriority,
+            "none" => Policy::Disabled,
+            _ => {
+                println!("[kernel] Invalid EURALIOS_OOM_POLICY '{}'", name);
+                return;
+            }
+        };
+        println!("[kernel] OOM killer policy {:?}", policy);
+        set_policy(policy);
+    }
+}
+
+/// Change the victim selection policy
+pub fn set_policy(policy: Policy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// The current victim selection policy
+pub fn policy() -> Policy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => Policy::LargestMemory,
+        1 => Policy::LowestPriority,
+        _ => Policy::Disabled
+    }
+}
+
+/// A user process which could be terminated
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    /// Identifies the process: Physical address of its page table
+    pub id: u64,
+    /// One of the threads in the process, for logging
+    pub tid: u64,
+    /// Number of physical frames which would be freed
+    pub frames: usize,
+    /// Lowest priority (largest number) of its threads
+    pub priority: u8,
+    /// Critical process, only chosen as a last resort
+    pub exempt: bool
+}
+
+/// Choose a process to terminate, or None if none should be.
+pub fn select_victim(candidates: &[Candidate], policy: Policy) -> Option<&Candidate> {
+    match policy {
+        Policy::LargestMemory => candidates.iter()
+            .max_by_key(|c| (!c.exempt, c.frames, c.priority)),
+        Policy::LowestPriority => candidates.iter()
+            .max_by_key(|c| (!c.exempt, c.priority, c.frames)),
+        Policy::Disabled => None
+    }
+}
+
+/// Terminate a process to reclaim memory
+///
+/// Returns true if a process was terminated, false if there
+/// was nothing to terminate. If the current process is chosen
+/// then this function does not return.
+pub fn kill_victim() -> bool {
+    let candidates = process::oom_candidates();
+    let victim = match select_victim(&candidates, policy()).cloned() {
+        Some(victim) => victim,
+        None => {
+            println!("[kernel] Out of memory: nothing to terminate");
+            return false;
+        }
+    };
+    println!("[kernel] Out of memory: terminating process of thread {} ({} frames, priority {}{})",
+             victim.tid, victim.frames, victim.priority,
+             if victim.exempt {", critical"} else {""});
+    // May not return, so free the list first
+    drop(candidates);
+    process::kill_process(victim.id);
+    true
+}
+
+#[cfg(test)]
+fn candidate(id: u64, frames: usize, priority: u8, exempt: bool) -> Candidate {
+    Candidate {id, tid: id, frames, priority, exempt}
+}
+
+#[test_case]
+fn select_largest_memory() {
+    let candidates = [candidate(1, 10, 2, false),
+                      candidate(2, 50, 2, false),
+                      candidate(3, 20, 7, false)];
+    assert_eq!(select_victim(&candidates, Policy::LargestMemory).unwrap().id, 2);
+    assert_eq!(select_victim(&candidates, Policy::LowestPriority).unwrap().id, 3);
+    assert!(select_victim(&candidates, Policy::Disabled).is_none());
+}
+
+#[test_case]
+fn exempt_last_resort() {
+    let candidates = [candidate(1, 500, 7, true),
+                      candidate(2, 10, 2, false)];
+    assert_eq!(select_victim(&candidates, Policy::LargestMemory).unwrap().id, 2);
+    assert_eq!(select_victim(&candidates, Policy::LowestPriority).unwrap().id, 2);
+    // Only critical processes left
+    assert_eq!(select_victim(&candidates[..1], Policy::LargestMemory).unwrap().id, 1);
+}
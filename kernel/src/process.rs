@@ -4,29 +4,44 @@
 //!
 
 use x86_64::{VirtAddr, PhysAddr};
-use x86_64::instructions::interrupts;
+use crate::irqguard;
 use x86_64::structures::paging::PageTableFlags;
 
 use spin::RwLock;
 use lazy_static::lazy_static;
 extern crate alloc;
-use alloc::{boxed::Box, collections::vec_deque::VecDeque, vec::Vec, sync::Arc};
+use alloc::{boxed::Box, collections::vec_deque::VecDeque, vec::Vec, sync::{Arc, Weak}};
+use alloc::collections::btree_map::BTreeMap;
 
 use core::arch::asm;
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use crate::println;
-use crate::interrupts::{Context, INTERRUPT_CONTEXT_SIZE};
+use crate::interrupts::{self, Context, INTERRUPT_CONTEXT_SIZE};
 
 use crate::gdt;
 use crate::memory;
 use crate::syscalls;
-use crate::rendezvous::Rendezvous;
+use crate::rendezvous::{self, Rendezvous};
 use crate::message::Message;
 use crate::vfs;
+use crate::env;
+use crate::sched_test;
+use crate::oom;
+use crate::tls;
+use crate::futex;
+use crate::time;
 
-use object::{Object, ObjectSegment};
+use object::{Object, ObjectSegment, SegmentFlags};
 
 /// Size of the kernel stack for each process, in bytes
+///
+/// User threads' kernel stacks are allocated on the kernel heap
+/// (MemoryStats::heap_thread_stacks). Kernel threads have a
+/// GuardedStack of this size, and another of USER_STACK_SIZE to run
+/// on; each uses one more page as a guard, which isn't mapped so
+/// doesn't use a frame (MemoryStats::kernel_thread_stacks).
 const KERNEL_STACK_SIZE: usize = 4096 * 2;
 
 /// Size of the user stack for each user process, in bytes
@@ -37,9 +52,37 @@ pub const USER_CODE_START: u64 = 0x20_0000;
 /// Exclusive upper limit for user code or data
 pub const USER_CODE_END: u64 = 0x5000_0000;
 
+// Scheduler priority levels. Larger numbers are lower priority.
+//
+// The scheduler runs the highest priority thread which is ready,
+// taking turns with others of the same priority. Lower priority
+// threads only run when no higher priority thread is ready, or
+// after they have waited long enough (see AGING_SKIPS).
+
+/// For drivers which should respond promptly e.g. the NIC
+pub const PRIORITY_HIGH: u8 = 0;
+/// Priority of new threads unless another is requested
+pub const PRIORITY_NORMAL: u8 = 2;
+/// For background work
+pub const PRIORITY_LOW: u8 = 4;
+/// Lowest priority a thread can be set to
+pub const PRIORITY_LOWEST: u8 = 7;
+
+/// A ready thread passed over this many times by the scheduler is
+/// treated as one level higher priority, so that low priority
+/// threads aren't starved by busy higher priority threads.
+const AGING_SKIPS: u8 = 8;
+
 const USER_HEAP_START: u64 = 0x280_0060_0000;
 const USER_HEAP_SIZE: u64 = 4 * 1024 * 1024; //0x28002e00000 - 0x28000600000;
 
+/// Read-only page holding a new process' arguments. Its address is
+/// passed in RDX. Just above the range of ELF segments, so that a
+/// large binary can't overlap it.
+pub const USER_ARGS_VIRTADDR: u64 = USER_CODE_END;
+/// Encoded arguments must fit in one page
+pub const MAX_ARGS_SIZE: usize = 4096;
+
 lazy_static! {
     /// Queue of processes which can run
     ///
@@ -52,16 +95,85 @@ lazy_static! {
     /// The process which is currently running
     static ref CURRENT_THREAD: RwLock<Option<Box<Thread>>> = RwLock::new(None);
 
-    /// Unique ID counter
-    static ref UNIQUE_COUNTER: RwLock<u64> = RwLock::new(0);
+    /// Threads which have exited, but may still be running on their
+    /// kernel stack. Dropped by schedule_next.
+    static ref EXITED_THREADS: RwLock<Vec<Box<Thread>>> = RwLock::new(Vec::new());
+
+    /// Exit codes of threads which have exited, by TID
+    static ref EXIT_CODES: RwLock<BTreeMap<u64, u64>> = RwLock::new(BTreeMap::new());
+
+    /// Threads which have been created and not yet exited, by TID
+    static ref LIVE_THREADS: RwLock<BTreeMap<u64, LiveThread>> = RwLock::new(BTreeMap::new());
+
+    /// Threads waiting for another thread to exit, with its TID
+    static ref EXIT_WAITERS: RwLock<Vec<(u64, Box<Thread>)>> = RwLock::new(Vec::new());
 }
 
+/// Exit code of threads whose process was killed
+pub const EXIT_CODE_KILLED: u64 = u64::MAX;
+
+/// Maximum number of exit codes kept. When full, the oldest
+/// (lowest TID) is discarded.
+const MAX_EXIT_CODES: usize = 256;
+
+/// Set by kill_current_process if it couldn't reach the current
+/// thread. schedule_next then kills the thread's process.
+static KILL_CURRENT: AtomicBool = AtomicBool::new(false);
+
+/// Unique ID counter
+static UNIQUE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 /// Generate a unique number
+///
+/// Used for thread IDs, so is never reused. The increment is a single
+/// atomic operation, so can be called from any context without
+/// disabling interrupts.
 pub fn unique_id() -> u64 {
-    interrupts::without_interrupts(|| {
-        let mut counter = UNIQUE_COUNTER.write();
-        *counter += 1;
-        *counter
+    UNIQUE_COUNTER.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// What can be found out about a live thread without owning it
+///
+/// A Thread is held by whatever it is waiting for: the run queue, a
+/// Rendezvous, a futex, the sleep queue etc. This lets threads be
+/// found by TID wherever they are.
+struct LiveThread {
+    /// Doesn't keep the process alive after its threads exit
+    process: Weak<RwLock<Process>>,
+    /// Shared with Thread::cpu_time_us
+    cpu_time_us: Arc<AtomicU64>,
+}
+
+/// Record that a new thread is live
+///
+/// Removed by thread_exited, or when the Thread is dropped
+fn register_thread(thread: &Thread) {
+    irqguard::without_interrupts(|| {
+        LIVE_THREADS.write().insert(thread.tid, LiveThread{
+            process: Arc::downgrade(&thread.process),
+            cpu_time_us: thread.cpu_time_us.clone()
+        });
+    });
+}
+
+/// The process of a live thread, found wherever the thread is held
+fn live_thread_process(tid: u64) -> Option<Arc<RwLock<Process>>> {
+    irqguard::without_interrupts(|| {
+        LIVE_THREADS.read().get(&tid)?.process.upgrade()
+    })
+}
+
+/// A process with a live thread, found by its page table
+///
+/// Finds processes whose threads are all blocked. May be called
+/// from a fault handler, so locks are taken with try_read, and
+/// None is returned if one is held.
+fn live_process(page_table_physaddr: u64) -> Option<Arc<RwLock<Process>>> {
+    irqguard::without_interrupts(|| {
+        LIVE_THREADS.try_read()?.values()
+            .filter_map(|live| live.process.upgrade())
+            .find(|process| process.try_read()
+                  .map_or(false, |process| process.page_table_physaddr == page_table_physaddr))
     })
 }
 
@@ -74,11 +186,66 @@ struct Process {
     handles: Vec<Option<Arc<RwLock<Rendezvous>>>>,
 
     /// Paths to handlers which can be open'ed
-    mounts: vfs::VFS
+    mounts: vfs::VFS,
+
+    /// Environment variables, copied by exec
+    env: env::Environment,
+
+    /// Only chosen by the OOM killer as a last resort
+    oom_exempt: bool,
+
+    /// Set when the process is terminated. Its threads are
+    /// removed when they are next scheduled.
+    killed: bool,
+
+    /// Syscalls this process may make. See SyscallFilter
+    syscall_filter: SyscallFilter,
+
+    /// Initial thread-local storage, copied for each new thread
+    tls: Option<tls::Template>
+}
+
+/// Allowlist of syscalls, set when a process is started by exec
+///
+/// Threads started by fork share their process' filter, and a
+/// process started by exec gets at most its parent's permissions,
+/// so a filter can only be tightened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallFilter {
+    /// Bit N allows syscall N. Syscalls above 63 are never allowed
+    /// unless all bits are set.
+    pub allowed: u64,
+    /// If true a forbidden syscall terminates the process,
+    /// otherwise it returns SYSCALL_ERROR_DENIED
+    pub kill: bool
+}
+
+impl SyscallFilter {
+    /// Allows all syscalls
+    pub const ALL: SyscallFilter = SyscallFilter{allowed: u64::MAX, kill: false};
+
+    /// Is the given syscall number allowed?
+    ///
+    /// Exiting a thread is always allowed.
+    pub fn allows(&self, syscall: u64) -> bool {
+        self.allowed == u64::MAX ||
+            syscall == syscalls::SYSCALL_EXIT_THREAD ||
+            (syscall < 64 && (self.allowed & (1 << syscall)) != 0)
+    }
+
+    /// Combine with a child's requested filter. The result
+    /// allows no more than either filter.
+    pub fn tighten(&self, child: SyscallFilter) -> SyscallFilter {
+        SyscallFilter{allowed: self.allowed & child.allowed,
+                      kill: self.kill || child.kill}
+    }
 }
 
 impl Drop for Process {
     fn drop(&mut self) {
+        if self.page_table_physaddr == 0 {
+            return; // Kernel threads use the kernel page table
+        }
         // Check if the page table is currently active
         if self.page_table_physaddr == memory::active_pagetable_physaddr() {
             memory::switch_to_kernel_pagetable();
@@ -136,6 +303,10 @@ pub struct Thread {
     /// save/restore process state in context switch
     kernel_stack: Vec<u8>,
 
+    /// Kernel threads' stacks, mapped with guard pages. Empty
+    /// for user threads, which use kernel_stack and a user stack.
+    guarded_stacks: Vec<memory::GuardedStack>,
+
     /// Address of the end of the stack.
     /// This value is put in the Interrupt Stack Table
     kernel_stack_end: u64,
@@ -146,6 +317,36 @@ pub struct Thread {
     /// Address within the kernel_stack which stores
     /// the Context structure containing thread state.
     context: u64,
+
+    /// Scheduler priority, from PRIORITY_HIGH to PRIORITY_LOWEST
+    priority: u8,
+
+    /// Highest priority (smallest number) this thread can raise
+    /// its priority to.
+    base_priority: u8,
+
+    /// Number of times passed over by the scheduler
+    /// since it last ran. Used to age priorities.
+    skipped: u8,
+
+    /// Signals blocked from delivery to this thread. Bit N is signal N
+    signal_mask: u32,
+
+    /// Signals raised but not yet delivered
+    signals_pending: u32,
+
+    /// FS base register, pointing to the thread's TLS block.
+    /// Zero if the process has no thread-local storage.
+    fs_base: u64,
+
+    /// Total time spent running, in microseconds. Doesn't include
+    /// the time since run_start_us if this is the current thread.
+    /// Shared with LIVE_THREADS, so it can be read while the thread
+    /// is blocked.
+    cpu_time_us: Arc<AtomicU64>,
+
+    /// When this thread last started running (time::microseconds_monotonic)
+    run_start_us: u64,
 }
 
 impl Thread {
@@ -154,6 +355,24 @@ impl Thread {
         self.tid
     }
 
+    /// Record that the thread is now running
+    fn start_running(&mut self) {
+        self.run_start_us = time::microseconds_monotonic();
+    }
+
+    /// Record that the thread has stopped running, adding to its CPU time
+    ///
+    /// Does nothing if the thread never started running, because
+    /// then there's no start time to measure from.
+    fn stop_running(&mut self) {
+        if self.run_start_us == 0 {
+            return;
+        }
+        self.cpu_time_us.fetch_add(time::microseconds_monotonic()
+                                   .saturating_sub(self.run_start_us),
+                                   Ordering::Relaxed);
+    }
+
     /// Get a reference to the thread Context
     fn context(&self) -> &Context {
         unsafe {& *(self.context as *const Context)}
@@ -209,6 +428,12 @@ impl Thread {
         self.return_error_message(0, message)
     }
 
+    /// Modify a thread context, setting R8 to the index of the
+    /// handle which received a message in await_any
+    pub fn return_index(&self, index: usize) {
+        self.context_mut().r8 = index;
+    }
+
     /// Get a clone of a rendezvous handle if it exists
     pub fn rendezvous(&self, id: u64)
                       -> Option<Arc<RwLock<Rendezvous>>> {
@@ -282,6 +507,55 @@ impl Thread {
     pub fn vfs(&self) -> vfs::VFS {
         self.process.read().mounts.clone()
     }
+
+    /// Mark a signal as pending. It is delivered when not masked
+    pub fn raise_signal(&mut self, signal: u32) {
+        self.signals_pending |= 1 << (signal % 32);
+    }
+
+    /// Remove and return the pending signals which can be delivered
+    ///
+    /// A signal delivery path should call this before returning to
+    /// user code, and add each handler's signal to the mask while
+    /// the handler runs, so that handlers are not re-entered.
+    pub fn take_deliverable_signals(&mut self) -> u32 {
+        let signals = self.signals_pending & !self.signal_mask;
+        self.signals_pending &= !signals;
+        signals
+    }
+
+    /// Physical address of the page table, identifying the process
+    pub fn page_table_physaddr(&self) -> u64 {
+        self.process.read().page_table_physaddr
+    }
+
+    /// The syscall filter of this thread's process
+    pub fn syscall_filter(&self) -> SyscallFilter {
+        self.process.read().syscall_filter
+    }
+
+    /// Get a copy of the process environment variables
+    pub fn env(&self) -> env::Environment {
+        self.process.read().env.clone()
+    }
+
+    /// Set an environment variable of this thread's process.
+    /// See env::Environment::set
+    pub fn set_env(&self, key: &str, value: &str) -> Result<(), usize> {
+        self.process.write().env.set(key, value)
+    }
+
+    /// Remove an environment variable.
+    /// See env::Environment::remove
+    pub fn remove_env(&self, key: &str) -> Result<(), usize> {
+        self.process.write().env.remove(key)
+    }
+
+    /// Change the working directory of this thread's process.
+    /// See env::Environment::set_cwd
+    pub fn set_cwd(&self, path: &str) -> Result<(), usize> {
+        self.process.write().env.set_cwd(path)
+    }
 }
 
 use core::fmt;
@@ -309,6 +583,15 @@ TID: {}, rip: {:#016X}
 
 impl Drop for Thread {
     fn drop(&mut self) {
+        // Already removed if the thread exited. May be dropped in
+        // schedule_next, so the timer can't interrupt the lock
+        irqguard::without_interrupts(|| {
+            LIVE_THREADS.write().remove(&self.tid);
+        });
+
+        if !self.guarded_stacks.is_empty() {
+            return; // Kernel thread: Stacks freed when guarded_stacks is dropped
+        }
         if let Err(e) = memory::free_user_stack(
             VirtAddr::new(self.user_stack_end)) {
             println!("Error in Thread::drop : {:?}", e);
@@ -320,20 +603,74 @@ impl Drop for Thread {
 /// so it will be scheduled next
 pub fn schedule_thread(thread: Box<Thread>) {
     // Turn off interrupts while modifying process table
-    interrupts::without_interrupts(|| {
+    irqguard::without_interrupts(|| {
         RUNNING_QUEUE.write().push_front(thread);
     });
 }
 
 
+/// Get the Thread ID of the currently running thread
+pub fn current_tid() -> Option<u64> {
+    CURRENT_THREAD.read().as_ref().map(|thread| thread.tid)
+}
+
+/// Get a copy of the saved Context of a thread which is
+/// waiting to run.
+///
+/// Returns None if the thread is not in the running queue: it may
+/// have exited, or be waiting on a Rendezvous or interrupt.
+///
+/// Note: Only a single CPU is used, so threads in the queue are
+/// not running and their saved Context is up to date.
+pub fn thread_context(tid: u64) -> Option<Context> {
+    irqguard::without_interrupts(|| {
+        RUNNING_QUEUE.read().iter()
+            .find(|thread| thread.tid == tid)
+            .map(|thread| thread.context().clone())
+    })
+}
+
+/// Copy user memory from the process containing a thread
+///
+/// The thread may be running, waiting to run, or blocked: it is
+/// found through LIVE_THREADS. Stops at the first unmapped page (see
+/// memory::read_user_memory).
+///
+/// Returns the number of bytes copied, or None if the thread is
+/// not found or is a kernel thread.
+pub fn read_thread_memory(tid: u64, address: u64, buf: &mut [u8]) -> Option<usize> {
+    // Keeps page tables alive
+    let process = live_thread_process(tid)?;
+
+    let page_table_physaddr = process.read().page_table_physaddr;
+    if page_table_physaddr == 0 {
+        return None;
+    }
+    Some(memory::read_user_memory(page_table_physaddr, address, buf))
+}
+
+/// True if there are threads waiting to run, other than the
+/// current thread
+pub fn other_threads_runnable() -> bool {
+    irqguard::without_interrupts(|| {
+        !RUNNING_QUEUE.read().is_empty()
+    })
+}
+
 /// Takes ownership of the current Thread
 pub fn take_current_thread() -> Option<Box<Thread>> {
-    CURRENT_THREAD.write().take()
+    CURRENT_THREAD.write().take().map(|mut thread| {
+        thread.stop_running();
+        thread
+    })
 }
 
 /// Makes the given thread the current thread
 /// If another thread was running schedule it
-pub fn set_current_thread(thread: Box<Thread>) {
+pub fn set_current_thread(mut thread: Box<Thread>) {
+    tls::set_thread_pointer(thread.fs_base);
+    thread.start_running();
+
     // Replace the current thread
     let old_current = CURRENT_THREAD.write().replace(thread);
     if let Some(t) = old_current {
@@ -350,6 +687,8 @@ pub fn set_current_thread(thread: Box<Thread>) {
 ///
 /// function : fn() -> ()
 ///    The new thread entry point
+/// priority : u8
+///    Scheduler priority e.g. PRIORITY_NORMAL
 ///
 /// Returns
 /// -------
@@ -361,7 +700,49 @@ pub fn set_current_thread(thread: Box<Thread>) {
 ///
 pub fn new_kernel_thread(
     function: fn()->(),
-    mut handles: Vec<Arc<RwLock<Rendezvous>>>
+    handles: Vec<Arc<RwLock<Rendezvous>>>,
+    priority: u8
+) -> u64 {
+    start_kernel_thread(function as usize, 0, handles, priority)
+}
+
+/// Start a new kernel thread which is passed an argument
+///
+/// The argument is put in RDI, where the System V calling convention
+/// expects the first argument, so each thread running the same
+/// function can be given e.g. a different work queue. The function
+/// must be extern "C" because the Rust ABI isn't guaranteed to use
+/// RDI.
+///
+/// Inputs
+/// ------
+///
+/// function : extern "C" fn(usize) -> ()
+///    The new thread entry point
+/// arg : usize
+///    Passed to function when the thread first runs
+/// priority : u8
+///    Scheduler priority e.g. PRIORITY_NORMAL
+///
+/// Returns
+/// -------
+/// The TID of the new thread
+pub fn new_kernel_thread_with_arg(
+    function: extern "C" fn(usize)->(),
+    arg: usize,
+    handles: Vec<Arc<RwLock<Rendezvous>>>,
+    priority: u8
+) -> u64 {
+    start_kernel_thread(function as usize, arg, handles, priority)
+}
+
+/// Create and schedule a kernel thread starting at address `entry`
+/// with `arg` in RDI
+fn start_kernel_thread(
+    entry: usize,
+    arg: usize,
+    mut handles: Vec<Arc<RwLock<Rendezvous>>>,
+    priority: u8
 ) -> u64 {
 
     // Create a new process table entry
@@ -369,11 +750,16 @@ pub fn new_kernel_thread(
     // Note this is first created on the stack, then moved into a Box
     // on the heap.
     let new_thread = {
-        // Allocate both "user" and kernel stacks in kernel memory
-        let kernel_stack = Vec::with_capacity(KERNEL_STACK_SIZE + USER_STACK_SIZE);
-        let kernel_stack_start = VirtAddr::from_ptr(kernel_stack.as_ptr());
-        let kernel_stack_end = (kernel_stack_start + KERNEL_STACK_SIZE).as_u64();
-        let user_stack_end = kernel_stack_end + (USER_STACK_SIZE as u64);
+        // Allocate both "user" and kernel stacks in kernel memory,
+        // each with a guard page below so that an overflow is a
+        // page fault. These are mapped frames, not heap: they are
+        // counted in MemoryStats::kernel_thread_stacks.
+        let guarded_stacks = [KERNEL_STACK_SIZE, USER_STACK_SIZE].iter()
+            .map(|&size| memory::GuardedStack::new(size))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Could not allocate kernel thread stack");
+        let kernel_stack_end = guarded_stacks[0].end();
+        let user_stack_end = guarded_stacks[1].end();
 
         Box::new(Thread {
             tid: unique_id(),
@@ -383,23 +769,43 @@ pub fn new_kernel_thread(
                 handles:handles.drain(..)
                     .map(|h| Some(h)).collect(),
                 // Empty set of mount paths
-                mounts: vfs::VFS::new()
+                mounts: vfs::VFS::new(),
+                env: env::Environment::new(),
+                oom_exempt: true,
+                killed: false,
+                syscall_filter: SyscallFilter::ALL,
+                tls: None
             })),
             page_table_physaddr: 0, // Don't need to switch PT
-            kernel_stack,
+            kernel_stack: Vec::new(),
+            guarded_stacks,
             // Note that stacks move backwards, so SP points to the end
             kernel_stack_end,
             user_stack_end,
             // Push a Context struct on the kernel stack
             context: kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64,
+            priority,
+            base_priority: priority,
+            skipped: 0,
+            signal_mask: 0,
+            signals_pending: 0,
+            fs_base: 0,
+            cpu_time_us: Arc::new(AtomicU64::new(0)),
+            run_start_us: 0,
         })
     };
 
+    register_thread(&new_thread);
+
     // Cast context address to Context struct
     let context = new_thread.context_mut();
 
     // Set the instruction pointer
-    context.rip = function as usize;
+    context.rip = entry;
+
+    // First function argument. The context is restored into
+    // registers when the thread is first scheduled.
+    context.rdi = arg;
 
     // Set flags
     context.rflags = 0x200;
@@ -444,10 +850,127 @@ fn with_pagetable<F, R>(page_table_physaddr: u64, func: F) -> R where
     result
 }
 
+/// Check that an ELF segment `[address, address + size)` is inside
+/// the user code region
+///
+/// Kernel pages are mapped in every user page table, so segments
+/// outside [USER_CODE_START, USER_CODE_END) could replace kernel
+/// mappings. This includes ranges which wrap around the top of the
+/// address space.
+fn check_segment_range(address: u64, size: u64) -> Result<(), ExecError> {
+    match address.checked_add(size) {
+        Some(end) if address >= USER_CODE_START && end <= USER_CODE_END => Ok(()),
+        _ => Err(ExecError::SegmentOverlap)
+    }
+}
+
+/// Error returned by new_user_thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    /// The binary doesn't start with the ELF magic number
+    NotElf,
+    /// A segment is outside user code memory
+    SegmentOverlap,
+    /// The ELF file couldn't be parsed
+    ParseFailed,
+    /// Any other failure, e.g. out of memory. The message is only
+    /// logged
+    Other(&'static str)
+}
+
+impl ExecError {
+    /// Syscall error code returned by exec. Errors without their
+    /// own code are SYSCALL_ERROR_THREAD
+    pub fn code(&self) -> usize {
+        match self {
+            ExecError::NotElf => syscalls::SYSCALL_ERROR_NOT_ELF,
+            ExecError::SegmentOverlap => syscalls::SYSCALL_ERROR_ELF_SEGMENT,
+            ExecError::ParseFailed => syscalls::SYSCALL_ERROR_ELF_PARSE,
+            ExecError::Other(_) => syscalls::SYSCALL_ERROR_THREAD
+        }
+    }
+}
+
+/// Errors from memory and tls functions
+impl From<&'static str> for ExecError {
+    fn from(msg: &'static str) -> Self {
+        ExecError::Other(msg)
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::NotElf => write!(f, "Expected ELF binary"),
+            ExecError::SegmentOverlap => write!(f, "Segment overlaps kernel memory"),
+            ExecError::ParseFailed => write!(f, "Could not parse ELF"),
+            ExecError::Other(msg) => write!(f, "{}", msg)
+        }
+    }
+}
+
+// ELF program header permission flags
+const ELF_PF_X: u32 = 1;
+const ELF_PF_W: u32 = 2;
+
+/// Page table flags for a user ELF segment with permissions `p_flags`
+///
+/// Segments are only writable or executable if the flags allow it.
+/// Read-only segments are marked so that writes fault, rather than
+/// being treated as copy-on-write.
+fn elf_page_flags(p_flags: u32) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if p_flags & ELF_PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    } else {
+        flags |= memory::READ_ONLY_PAGE;
+    }
+    if p_flags & ELF_PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
 pub struct Params {
     pub handles: Vec<Arc<RwLock<Rendezvous>>>,
     pub io_privileges: bool,
-    pub mounts: vfs::VFS
+    pub mounts: vfs::VFS,
+    /// Environment variables, usually a copy of the parent's
+    pub env: env::Environment,
+    pub syscall_filter: SyscallFilter,
+    /// Encoded arguments (see `valid_args`). Empty for none
+    pub args: Vec<u8>,
+    /// Scheduler priority e.g. PRIORITY_NORMAL
+    pub priority: u8
+}
+
+/// Check the layout of encoded program arguments
+///
+/// The argument page contains a u32 count, followed by that many
+/// strings. Each string is a u32 length followed by the bytes.
+/// All integers are little-endian, and there is no padding.
+pub fn valid_args(args: &[u8]) -> bool {
+    fn read_u32(data: &[u8], offset: usize) -> Option<usize> {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+    if args.len() > MAX_ARGS_SIZE {
+        return false;
+    }
+    let count = match read_u32(args, 0) {
+        Some(count) => count,
+        None => return false
+    };
+    let mut offset = 4;
+    for _ in 0..count {
+        match read_u32(args, offset) {
+            Some(length) if offset + 4 + length <= args.len() => {
+                offset += 4 + length;
+            }
+            _ => return false
+        }
+    }
+    offset == args.len()
 }
 
 /// Create a new user thread
@@ -461,17 +984,22 @@ pub struct Params {
 pub fn new_user_thread(
     bin: &[u8],
     params: Params
-) -> Result<Box<Thread>, &'static str> {
+) -> Result<Box<Thread>, ExecError> {
     // Check the header
     const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
 
-    if bin[0..4] != ELF_MAGIC {
-        return Err("Expected ELF binary");
+    if bin.len() < ELF_MAGIC.len() || bin[0..4] != ELF_MAGIC {
+        return Err(ExecError::NotElf);
     }
     // Use the object crate to parse the ELF file
     // <https://crates.io/crates/object>
     if let Ok(obj) = object::File::parse(bin) {
 
+        // Check all segments before allocating anything
+        for segment in obj.segments() {
+            check_segment_range(segment.address(), segment.size())?;
+        }
+
         // Create a user pagetable with only kernel pages
         let (user_page_table_ptr, user_page_table_physaddr) =
             memory::create_new_user_pagetable();
@@ -481,7 +1009,7 @@ pub fn new_user_thread(
             user_page_table_physaddr,
             VirtAddr::new(USER_HEAP_START),
             USER_HEAP_SIZE).is_err() {
-            return Err("Couldn't allocate on-demand pages");
+            return Err(ExecError::Other("Couldn't allocate on-demand pages"));
         }
 
         return with_pagetable(user_page_table_physaddr, || {
@@ -491,14 +1019,8 @@ pub fn new_user_thread(
             for segment in obj.segments() {
                 let segment_address = segment.address() as u64;
 
+                // Range already checked by check_segment_range
                 let start_address = VirtAddr::new(segment_address);
-                let end_address = start_address + segment.size() as u64;
-
-                // Check if data is in allowed range
-                if (start_address < VirtAddr::new(USER_CODE_START))
-                    || (end_address >= VirtAddr::new(USER_CODE_END)) {
-                        return Err("ELF segment outside allowed range");
-                    }
 
                 // Allocate memory in the pagetable
                 if memory::allocate_pages(user_page_table_ptr,
@@ -507,42 +1029,113 @@ pub fn new_user_thread(
                                           PageTableFlags::PRESENT |
                                           PageTableFlags::WRITABLE |
                                           PageTableFlags::USER_ACCESSIBLE).is_err() {
-                    return Err("Could not allocate memory");
+                    return Err(ExecError::Other("Could not allocate memory"));
                 }
                 memory::switch_to_pagetable(user_page_table_physaddr);
 
                 if let Ok(data) = segment.data() {
                     if data.len() > segment.size() as usize {
-                        return Err("ELF data length > segment size");
-                    } else if data.len() > 0 {
-                        // Copy data
-                        let dest_ptr = segment_address as *mut u8;
-                        for (i, value) in data.iter().enumerate() {
-                            unsafe {
-                                let ptr = dest_ptr.add(i);
-                                core::ptr::write(ptr, *value);
-                            }
+                        return Err(ExecError::Other("ELF data length > segment size"));
+                    }
+                    // Copy data
+                    let dest_ptr = segment_address as *mut u8;
+                    for (i, value) in data.iter().enumerate() {
+                        unsafe {
+                            let ptr = dest_ptr.add(i);
+                            core::ptr::write(ptr, *value);
+                        }
+                    }
+                    // The rest of the segment (e.g. .bss) is not in the
+                    // file, and must be zeroed because new frames are not
+                    for i in data.len()..(segment.size() as usize) {
+                        unsafe {
+                            core::ptr::write(dest_ptr.add(i), 0);
                         }
                     }
                 } else {
-                    return Err("Could not get segment data");
+                    return Err(ExecError::Other("Could not get segment data"));
+                }
+
+                // Now the data is loaded, apply the segment permissions
+                let flags = match segment.flags() {
+                    SegmentFlags::Elf { p_flags } => elf_page_flags(p_flags),
+                    _ => return Err(ExecError::Other("Expected ELF segment flags"))
+                };
+                if flags & (PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+                    == PageTableFlags::WRITABLE {
+                        println!("[kernel] Warning: ELF segment at {:#x} is writable and executable",
+                                 segment_address);
+                    }
+                if memory::update_page_flags(user_page_table_ptr,
+                                             start_address,
+                                             segment.size() as u64,
+                                             flags).is_err() {
+                    return Err(ExecError::Other("Could not set segment permissions"));
                 }
             }
 
+            // Arguments are copied into their own page, which is
+            // then made read-only
+            let args_address = if params.args.is_empty() {
+                0 // No arguments
+            } else {
+                let start_address = VirtAddr::new(USER_ARGS_VIRTADDR);
+                if memory::allocate_pages(user_page_table_ptr,
+                                          start_address,
+                                          MAX_ARGS_SIZE as u64,
+                                          PageTableFlags::PRESENT |
+                                          PageTableFlags::WRITABLE |
+                                          PageTableFlags::USER_ACCESSIBLE).is_err() {
+                    return Err(ExecError::Other("Could not allocate argument page"));
+                }
+                memory::switch_to_pagetable(user_page_table_physaddr);
+
+                let dest_ptr = USER_ARGS_VIRTADDR as *mut u8;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(params.args.as_ptr(), dest_ptr,
+                                                   params.args.len());
+                    // Don't leak the previous contents of the frame
+                    core::ptr::write_bytes(dest_ptr.add(params.args.len()), 0,
+                                           MAX_ARGS_SIZE - params.args.len());
+                }
+                if memory::update_page_flags(user_page_table_ptr,
+                                             start_address,
+                                             MAX_ARGS_SIZE as u64,
+                                             PageTableFlags::PRESENT |
+                                             PageTableFlags::USER_ACCESSIBLE |
+                                             PageTableFlags::NO_EXECUTE |
+                                             memory::READ_ONLY_PAGE).is_err() {
+                    return Err(ExecError::Other("Could not set argument page permissions"));
+                }
+                USER_ARGS_VIRTADDR
+            };
+
             // Create the new Thread struct
-            let new_thread = {
+            let (new_thread, user_stack_pointer) = {
                 // Note: Kernel stack needs to be mapped in all pages
                 //       because the page table will be changed during
                 //       context switch
-                let kernel_stack = Vec::with_capacity(KERNEL_STACK_SIZE);
+                let kernel_stack = memory::with_category(memory::Category::ThreadStack,
+                                                     || Vec::with_capacity(KERNEL_STACK_SIZE));
                 let kernel_stack_start = VirtAddr::from_ptr(kernel_stack.as_ptr());
                 let kernel_stack_end = (kernel_stack_start + KERNEL_STACK_SIZE).as_u64();
 
                 // Allocate user stack
-                let (user_stack_start, user_stack_end) = memory::allocate_user_stack(user_page_table_ptr)?;
+                let (user_stack_start, user_stack_end) = memory::allocate_user_stack(
+                    user_page_table_ptr, memory::USER_STACK_PAGES)?;
+
+                // Thread-local storage at the top of the stack
+                let tls_template = tls::find_template(bin, USER_CODE_START, USER_CODE_END)?;
+                let (user_stack_pointer, fs_base) = match &tls_template {
+                    Some(template) => {
+                        let layout = tls::create_block(template, user_stack_end)?;
+                        (layout.stack_end, layout.thread_pointer)
+                    }
+                    None => (user_stack_end, 0)
+                };
 
                 let mut handles = params.handles;
-                Box::new(Thread {
+                (Box::new(Thread {
                     tid: unique_id(),
                     // Create a new process
                     process: Arc::new(RwLock::new(Process {
@@ -550,16 +1143,32 @@ pub fn new_user_thread(
                         handles:handles.drain(..)
                             .map(|h| Some(h)).collect(),
                         mounts: params.mounts,
+                        env: params.env,
+                        // Privileged processes (init, drivers) are critical
+                        oom_exempt: params.io_privileges,
+                        killed: false,
+                        syscall_filter: params.syscall_filter,
+                        tls: tls_template
                     })),
                     page_table_physaddr: user_page_table_physaddr,
                     kernel_stack: kernel_stack,
+                    guarded_stacks: Vec::new(),
                     // Note that stacks move backwards, so SP points to the end
                     kernel_stack_end,
                     user_stack_end,
                     // Push a Context struct on the kernel stack
-                    context: kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64
-                })
+                    context: kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64,
+                    priority: params.priority,
+                    base_priority: params.priority,
+                    skipped: 0,
+                    signal_mask: 0,
+                    signals_pending: 0,
+                    fs_base,
+                    cpu_time_us: Arc::new(AtomicU64::new(0)),
+                    run_start_us: 0,
+                }), user_stack_pointer)
             };
+            register_thread(&new_thread);
 
             // Cast context address to Context struct
             let context = new_thread.context_mut();
@@ -578,51 +1187,28 @@ pub fn new_user_thread(
             context.ss = data_selector.0 as usize; // Without this we get a GPF
 
             // Note: Need to point to the end of the allocated region
-            //       because the stack moves down in memory
-            context.rsp = new_thread.user_stack_end as usize;
+            //       because the stack moves down in memory.
+            //       The end is below the TLS block, if any.
+            context.rsp = user_stack_pointer as usize;
 
             // Modify the context to pass information to the new thread
             context.rax = USER_HEAP_START as usize;
             context.rcx = USER_HEAP_SIZE as usize;
+            context.rdx = args_address as usize;
 
             Ok(new_thread)
         });
     }
-    Err("Could not parse ELF")
+    Err(ExecError::ParseFailed)
 }
 
 /// Fork the current user thread
 ///
 ///
 pub fn fork_current_thread(current_context: &mut Context) {
-
-    if let Some(current_thread) = CURRENT_THREAD.read().as_ref() {
-
-        // Allocate user stack
-        let page_table_ptr = memory::active_pagetable_ptr();
-        if let Ok((user_stack_start, user_stack_end)) = memory::allocate_user_stack(page_table_ptr) {
-            let new_thread = {
-                // Create a new kernel stack
-                let kernel_stack = Vec::with_capacity(KERNEL_STACK_SIZE);
-                let kernel_stack_start = VirtAddr::from_ptr(kernel_stack.as_ptr());
-                let kernel_stack_end = (kernel_stack_start + KERNEL_STACK_SIZE).as_u64();
-
-                Box::new(Thread {
-                    tid: unique_id(),
-                    process: current_thread.process.clone(), // Shared state
-                    page_table_physaddr: current_thread.page_table_physaddr, // Shared page table
-                    kernel_stack,
-                    kernel_stack_end,
-                    user_stack_end,
-                    context: kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64,
-                })
-            };
-
+    match new_thread_in_current_process(current_context, memory::USER_STACK_PAGES) {
+        Ok(new_thread) => {
             let new_context = unsafe {&mut *(new_thread.context as *mut Context)};
-            *new_context = current_context.clone();
-
-            // Set new stack pointer
-            new_context.rsp = new_thread.user_stack_end as usize;
 
             // Set return values in rax
             new_context.rax = 0; // No error
@@ -631,36 +1217,700 @@ pub fn fork_current_thread(current_context: &mut Context) {
             current_context.rdi = new_thread.tid as usize;
 
             RUNNING_QUEUE.write().push_back(new_thread);
-        } else {
-            // Failed to allocate user stack
-            current_context.rax = syscalls::SYSCALL_ERROR_MEMALLOC; // Error code
         }
-    } else {
-        // Somehow no current thread
-        current_context.rax = 2; // Error code
+        Err(error_code) => {
+            current_context.rax = error_code;
+        }
+    }
+}
+
+/// Start a new thread in the current process at `entry`
+///
+/// The thread shares the caller's page table, with a new user stack
+/// of at least `stack_size` bytes (USER_STACK_PAGES if zero). It
+/// starts with `arg` in RDI, and must not return from `entry`.
+///
+/// Returns the new thread's TID in RDI
+pub fn spawn_thread(current_context: &mut Context, entry: u64, arg: u64, stack_size: u64) {
+    let max_size = (memory::MAX_USER_STACK_PAGES * 4096) as u64;
+    let stack_pages = match stack_size {
+        0 => memory::USER_STACK_PAGES,
+        size if size <= max_size => ((size + 4095) / 4096) as usize,
+        _ => {
+            current_context.rax = syscalls::SYSCALL_ERROR_PARAM;
+            return;
+        }
+    };
+    if !(USER_CODE_START..USER_CODE_END).contains(&entry) {
+        current_context.rax = syscalls::SYSCALL_ERROR_PARAM;
+        return;
+    }
+    match new_thread_in_current_process(current_context, stack_pages) {
+        Ok(new_thread) => {
+            let new_context = unsafe {&mut *(new_thread.context as *mut Context)};
+            new_context.rip = entry as usize;
+            new_context.rdi = arg as usize;
+            // As after a call: the stack is aligned to 16 bytes
+            // below an (invalid) return address
+            new_context.rsp -= 8;
+            current_context.rax = 0; // No error
+            current_context.rdi = new_thread.tid as usize;
+
+            RUNNING_QUEUE.write().push_back(new_thread);
+        }
+        Err(error_code) => {
+            current_context.rax = error_code;
+        }
     }
 }
 
+/// Create a thread in the current process, sharing its page table
+///
+/// The new thread has a copy of `current_context`, with the stack
+/// pointer at the top of a new user stack of `stack_pages` pages.
+/// It is not scheduled.
+fn new_thread_in_current_process(
+    current_context: &Context,
+    stack_pages: usize
+) -> Result<Box<Thread>, usize> {
+    let current_thread = CURRENT_THREAD.read();
+    let current_thread = match current_thread.as_ref() {
+        Some(thread) => thread,
+        None => return Err(2) // Somehow no current thread
+    };
+
+    // Allocate user stack
+    let page_table_ptr = memory::active_pagetable_ptr();
+    let (_user_stack_start, user_stack_end) = memory::allocate_user_stack(
+        page_table_ptr, stack_pages)
+        .map_err(|_| syscalls::SYSCALL_ERROR_MEMALLOC)?;
+
+    // New TLS block, copied from the process' template
+    let tls_template = current_thread.process.read().tls;
+    let (user_stack_pointer, fs_base) = match tls_template.map(
+        |template| tls::create_block(&template, user_stack_end)) {
+        Some(Ok(layout)) => (layout.stack_end, layout.thread_pointer),
+        Some(Err(_)) => {
+            // Checked when the process was created
+            let _ = memory::free_user_stack(VirtAddr::new(user_stack_end));
+            return Err(syscalls::SYSCALL_ERROR_MEMALLOC);
+        }
+        None => (user_stack_end, 0)
+    };
+
+    let new_thread = {
+        // Create a new kernel stack
+        let kernel_stack = memory::with_category(memory::Category::ThreadStack,
+                                             || Vec::with_capacity(KERNEL_STACK_SIZE));
+        let kernel_stack_start = VirtAddr::from_ptr(kernel_stack.as_ptr());
+        let kernel_stack_end = (kernel_stack_start + KERNEL_STACK_SIZE).as_u64();
+
+        Box::new(Thread {
+            tid: unique_id(),
+            process: current_thread.process.clone(), // Shared state
+            page_table_physaddr: current_thread.page_table_physaddr, // Shared page table
+            kernel_stack,
+            guarded_stacks: Vec::new(),
+            kernel_stack_end,
+            user_stack_end,
+            context: kernel_stack_end - INTERRUPT_CONTEXT_SIZE as u64,
+            // Inherit priority
+            priority: current_thread.priority,
+            base_priority: current_thread.base_priority,
+            skipped: 0,
+            // Inherit signal mask, but not pending signals
+            signal_mask: current_thread.signal_mask,
+            signals_pending: 0,
+            fs_base,
+            cpu_time_us: Arc::new(AtomicU64::new(0)),
+            run_start_us: 0,
+        })
+    };
+
+    register_thread(&new_thread);
+
+    let new_context = unsafe {&mut *(new_thread.context as *mut Context)};
+    *new_context = current_context.clone();
+
+    // Set new stack pointer, below the TLS block
+    new_context.rsp = user_stack_pointer as usize;
+
+    Ok(new_thread)
+}
+
 /// This function is called via syscall (and maybe other mechanism)
 /// to remove the current thread.
-pub fn exit_current_thread(_current_context: &mut Context) {
-    {
-        let mut current_thread = CURRENT_THREAD.write();
-
-        if let Some(_thread) = current_thread.take() {
-            // Drop thread, freeing stacks. If this is the last thread
-            // in this process, memory and page tables will be freed
-            // in the Process drop() function
+///
+/// The exit code in RDI is kept for take_exit_code. The thread is
+/// still running on its kernel stack, so it can't be dropped here;
+/// it is moved to EXITED_THREADS and dropped by schedule_next once
+/// another thread is running.
+pub fn exit_current_thread(current_context: &mut Context) {
+    if let Some(thread) = take_current_thread() {
+        for waiter in thread_exited(&thread, current_context.rdi as u64) {
+            schedule_thread(waiter);
         }
+        EXITED_THREADS.write().push(thread);
     }
     // Can't return from this syscall, so this thread now waits for a
     // timer interrupt to switch context.
+    wait_for_switch();
+}
+
+/// Record that `thread` has finished with `exit_code`
+///
+/// Threads waiting for it are given the exit code and returned, to
+/// be scheduled by the caller. If there are none then the exit code
+/// is kept for take_exit_code. If this is the last thread in its
+/// process then the process' handles are closed, and any threads
+/// woken by that are also returned.
+fn thread_exited(thread: &Thread, exit_code: u64) -> Vec<Box<Thread>> {
+    let tid = thread.tid;
+    LIVE_THREADS.write().remove(&tid);
+
+    let mut woken = if Arc::strong_count(&thread.process) == 1 {
+        close_process_handles(&thread.process)
+    } else {
+        Vec::new()
+    };
+    let mut exit_waiters = 0;
+    {
+        let mut waiters = EXIT_WAITERS.write();
+        let mut i = 0;
+        while i < waiters.len() {
+            if waiters[i].0 == tid {
+                let (_, waiter) = waiters.swap_remove(i);
+                let context = waiter.context_mut();
+                context.rax = 0; // No error
+                context.rdi = exit_code as usize;
+                woken.push(waiter);
+                exit_waiters += 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+    if exit_waiters == 0 {
+        let mut exit_codes = EXIT_CODES.write();
+        if exit_codes.len() >= MAX_EXIT_CODES {
+            if let Some(&oldest) = exit_codes.keys().next() {
+                exit_codes.remove(&oldest);
+            }
+        }
+        exit_codes.insert(tid, exit_code);
+    }
+    woken
+}
+
+/// Close the handles of a process whose last thread is exiting
+///
+/// As the close syscall, a Rendezvous is only closed if the other end
+/// is its only other reference, so clones held elsewhere keep working.
+/// A thread waiting on the other end gets SYSCALL_ERROR_CLOSED rather
+/// than waiting forever, and is returned to be scheduled.
+fn close_process_handles(process: &RwLock<Process>) -> Vec<Box<Thread>> {
+    let handles: Vec<_> = process.write().handles.drain(..).flatten().collect();
+    let mut woken = Vec::new();
+    let mut closed = Vec::new();
+    for rdv in handles {
+        let weak = Arc::downgrade(&rdv);
+        if Arc::strong_count(&rdv) != 2 {
+            // Still referenced elsewhere, unless dropped below
+            closed.push((weak, false));
+            continue;
+        }
+        // This may run in schedule_next, so don't wait for a
+        // Rendezvous locked by an interrupted thread
+        if let Some(mut rendezvous) = rdv.try_write() {
+            if let Some(thread) = rendezvous.close() {
+                woken.push(thread);
+            }
+            closed.push((weak, true));
+        }
+    }
+    // Handles are dropped, so wake await_any on Rendezvous which were
+    // closed or freed, but not on copies which other threads still use
+    for (rdv, was_closed) in closed {
+        if was_closed || rdv.strong_count() == 0 {
+            woken.extend(rendezvous::close_any_waiters(&rdv));
+        }
+    }
+    woken
+}
+
+/// True if thread `tid` has been created and not yet exited
+pub fn is_live(tid: u64) -> bool {
+    LIVE_THREADS.read().contains_key(&tid)
+}
+
+/// Suspend a thread until thread `tid` exits
+///
+/// The thread's context should be set. When woken RDI
+/// contains the exit code.
+pub fn wait_for_exit(thread: Box<Thread>, tid: u64) {
+    EXIT_WAITERS.write().push((tid, thread));
+}
+
+/// Remove and return the exit code of a thread which has exited
+///
+/// Returns None if the thread is still running, or its exit code has
+/// been taken or discarded.
+pub fn take_exit_code(tid: u64) -> Option<u64> {
+    irqguard::without_interrupts(|| {
+        EXIT_CODES.write().remove(&tid)
+    })
+}
+
+/// Drop exited threads, except any whose kernel stack contains
+/// `stack_address` because it is still in use.
+///
+/// Called from the timer interrupt, so skips if the list is locked.
+fn drop_exited_threads(stack_address: u64) {
+    let exited = match EXITED_THREADS.try_write() {
+        Some(mut exited) if !exited.is_empty() => {
+            let mut i = 0;
+            let mut to_drop = Vec::new();
+            while i < exited.len() {
+                let stack_end = exited[i].kernel_stack_end;
+                let stack_start = stack_end - (KERNEL_STACK_SIZE as u64);
+                if (stack_start..stack_end).contains(&stack_address) {
+                    i += 1;
+                } else {
+                    to_drop.push(exited.swap_remove(i));
+                }
+            }
+            to_drop
+        }
+        _ => return
+    };
+    for thread in exited {
+        // If this is the last thread in this process, memory and
+        // page tables will be freed in the Process drop() function
+        free_thread(thread);
+    }
+}
+
+/// Enable interrupts and wait for the timer interrupt to
+/// switch to another thread. Used when the current thread has
+/// been removed.
+fn wait_for_switch() -> ! {
     unsafe {
         asm!("sti",
              "2:",
              "hlt",
-             "jmp 2b");
+             "jmp 2b",
+             options(noreturn));
+    }
+}
+
+/// Drop a thread, freeing its stacks
+///
+/// The kernel stack is freed when the Thread is dropped. The user
+/// stack is freed using the active page table, so switch to the
+/// thread's page table while dropping it. If this is the last thread
+/// in its process then the Process is dropped, which frees all user
+/// frames and the page tables (see Process::drop).
+///
+/// Must not be called while running on the thread's kernel stack.
+fn free_thread(thread: Box<Thread>) {
+    let active = memory::active_pagetable_physaddr();
+    if thread.page_table_physaddr != 0 {
+        memory::switch_to_pagetable(thread.page_table_physaddr);
     }
+    let process_table = thread.process.read().page_table_physaddr;
+    drop(thread);
+    // If this was the last thread in the process then its page
+    // table has been freed, and the kernel page table is active
+    if active != process_table {
+        memory::switch_to_pagetable(active);
+    }
+}
+
+/// Summarise the user processes which the OOM killer could terminate
+///
+/// Called from the page fault handler with interrupts disabled.
+/// If the thread tables are locked then returns no candidates.
+pub fn oom_candidates() -> Vec<oom::Candidate> {
+    let mut candidates: Vec<oom::Candidate> = Vec::new();
+
+    let (current_thread, running_queue) = match (CURRENT_THREAD.try_read(),
+                                                RUNNING_QUEUE.try_read()) {
+        (Some(current), Some(queue)) => (current, queue),
+        _ => return candidates
+    };
+
+    for thread in current_thread.iter().chain(running_queue.iter()) {
+        let process = match thread.process.try_read() {
+            Some(process) => process,
+            None => continue
+        };
+        if process.page_table_physaddr == 0 || process.killed {
+            // Kernel thread, or already terminated
+            continue;
+        }
+        if let Some(candidate) = candidates.iter_mut().find(
+            |c| c.id == process.page_table_physaddr) {
+            // Another thread in the same process
+            candidate.priority = cmp::max(candidate.priority, thread.priority);
+            continue;
+        }
+        candidates.push(oom::Candidate {
+            id: process.page_table_physaddr,
+            tid: thread.tid,
+            frames: memory::count_user_frames(process.page_table_physaddr),
+            priority: thread.priority,
+            exempt: process.oom_exempt
+        });
+    }
+    candidates
+}
+
+/// Write the current thread and run queue, in scheduling order
+///
+/// Called from interrupt handlers, so doesn't allocate or wait for
+/// locks. Threads which are blocked on a Rendezvous are not listed.
+pub fn dump_threads(out: &mut dyn fmt::Write) -> fmt::Result {
+    let (current_thread, running_queue) = match (CURRENT_THREAD.try_read(),
+                                                RUNNING_QUEUE.try_read()) {
+        (Some(current), Some(queue)) => (current, queue),
+        _ => return writeln!(out, "Thread tables locked")
+    };
+
+    for (i, thread) in current_thread.iter().chain(running_queue.iter()).enumerate() {
+        write!(out, "{} tid {} priority {} skipped {}",
+               if (i == 0) && current_thread.is_some() {"*"} else {" "},
+               thread.tid, thread.priority, thread.skipped)?;
+        match thread.process.try_read() {
+            Some(process) => writeln!(out, " process {:#x}{}",
+                                      process.page_table_physaddr,
+                                      if process.killed {" (killed)"} else {""})?,
+            None => writeln!(out, " process locked")?
+        }
+    }
+    Ok(())
+}
+
+/// CPU and memory usage of a thread at a point in time
+///
+/// Layout must match euralios_std::syscalls::ThreadUsage
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ThreadUsage {
+    pub tid: u64,
+    /// Total time the thread has spent running
+    pub cpu_time_us: u64,
+    /// User memory mapped by the thread's process
+    pub mapped_bytes: u64,
+    /// When cpu_time_us was sampled. The same for all threads
+    pub timestamp_us: u64,
+}
+
+/// Sample the usage of all live threads
+///
+/// Includes threads which are blocked or sleeping, through
+/// LIVE_THREADS. CPU times are read with interrupts disabled, so no
+/// thread runs during the sample and all times are at timestamp_us.
+/// Memory is counted afterwards, once per process, because that
+/// walks the page tables.
+pub fn sample_usage() -> Vec<ThreadUsage> {
+    let mut samples: Vec<(ThreadUsage, Arc<RwLock<Process>>)> =
+        irqguard::without_interrupts(|| {
+            let now = time::microseconds_monotonic();
+            // Only the current thread's time since it started
+            // running isn't in its cpu_time_us
+            let running = CURRENT_THREAD.read().as_ref().map(|thread| {
+                (thread.tid, now.saturating_sub(thread.run_start_us))
+            });
+
+            LIVE_THREADS.read().iter().filter_map(|(&tid, live)| {
+                let running_us = match running {
+                    Some((running_tid, running_us)) if running_tid == tid => running_us,
+                    _ => 0
+                };
+                Some((ThreadUsage {
+                    tid,
+                    cpu_time_us: live.cpu_time_us.load(Ordering::Relaxed) + running_us,
+                    mapped_bytes: 0,
+                    timestamp_us: now
+                }, live.process.upgrade()?)) // Keeps page tables alive
+            }).collect()
+        });
+
+    // Memory mapped by each process, by page table address
+    let mut mapped: Vec<(u64, u64)> = Vec::new();
+    for (usage, process) in samples.iter_mut() {
+        let page_table_physaddr = process.read().page_table_physaddr;
+        if page_table_physaddr == 0 {
+            continue; // Kernel thread
+        }
+        usage.mapped_bytes = match mapped.iter().find(|(pt, _)| *pt == page_table_physaddr) {
+            Some((_, bytes)) => *bytes,
+            None => {
+                let bytes = memory::count_user_frames(page_table_physaddr) as u64 * 4096;
+                mapped.push((page_table_physaddr, bytes));
+                bytes
+            }
+        };
+    }
+    samples.into_iter().map(|(usage, _)| usage).collect()
+}
+
+/// ThreadInfo flag: Kernel thread, with no user page table
+pub const THREAD_INFO_KERNEL: u64 = 1;
+/// ThreadInfo flag: The thread which was running
+pub const THREAD_INFO_CURRENT: u64 = 2;
+
+/// Summary of a thread, returned by list_threads
+///
+/// Layout must match euralios_std::syscalls::ThreadInfo
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ThreadInfo {
+    pub tid: u64,
+    /// Instruction pointer in the saved Context. Out of date for
+    /// the current thread, whose Context is saved on the next switch
+    pub rip: u64,
+    /// THREAD_INFO_KERNEL and THREAD_INFO_CURRENT
+    pub flags: u64,
+}
+
+/// Snapshot of the current thread and the run queue
+///
+/// Taken with interrupts disabled, so the threads don't change
+/// during the snapshot. Unlike sample_usage, threads waiting on a
+/// Rendezvous or interrupt are not included, because their context
+/// is only known to what holds them.
+pub fn list_threads() -> Vec<ThreadInfo> {
+    irqguard::without_interrupts(|| {
+        let current_thread = CURRENT_THREAD.read();
+        let running_queue = RUNNING_QUEUE.read();
+
+        let current = current_thread.iter().map(|thread| (thread, THREAD_INFO_CURRENT));
+        let waiting = running_queue.iter().map(|thread| (thread, 0));
+
+        current.chain(waiting).map(|(thread, flags)| {
+            ThreadInfo {
+                tid: thread.tid,
+                rip: thread.context().rip as u64,
+                flags: flags | if thread.page_table_physaddr == 0 {
+                    THREAD_INFO_KERNEL
+                } else {
+                    0
+                }
+            }
+        }).collect()
+    })
+}
+
+/// Terminate the process with the given page table, freeing
+/// the threads which are waiting to run.
+///
+/// Threads blocked on the process' Rendezvous, a futex, await_any,
+/// an interrupt or another thread's exit are also freed, so threads
+/// waiting for any of them to exit are woken. Sleeping threads are
+/// removed when next scheduled. If the current thread is in the process then
+/// this function does not return: as in exit_current_thread, the
+/// thread is moved to EXITED_THREADS and freed by schedule_next
+/// once it is no longer running on its kernel stack.
+///
+/// May be called from a fault handler, so the thread tables are
+/// taken with try_write. If the current thread can't be taken then
+/// schedule_next removes it, as its process is marked killed.
+pub fn kill_process(page_table_physaddr: u64) {
+    let in_process = |thread: &Box<Thread>| {
+        thread.process.read().page_table_physaddr == page_table_physaddr
+    };
+
+    let mut queued = Vec::new();
+    if let Some(mut running_queue) = RUNNING_QUEUE.try_write() {
+        let mut i = 0;
+        while i < running_queue.len() {
+            if in_process(&running_queue[i]) {
+                queued.push(running_queue.remove(i).unwrap());
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    let current = match CURRENT_THREAD.try_write() {
+        Some(mut current_thread) => match current_thread.as_ref() {
+            Some(thread) if in_process(thread) => {
+                thread.process.write().killed = true;
+                current_thread.take()
+            }
+            _ => None
+        },
+        None => None
+    };
+
+    // Blocked threads may never be scheduled again
+    let process = current.as_ref().or(queued.first())
+        .map(|t| t.process.clone())
+        .or_else(|| live_process(page_table_physaddr)); // All threads blocked
+    if let Some(process) = process {
+        if let Some(mut process) = process.try_write() {
+            // Threads which can't be removed now exit when scheduled
+            process.killed = true;
+        }
+        queued.extend(remove_rendezvous_waiters(&process, page_table_physaddr));
+    }
+    queued.extend(futex::remove_process(page_table_physaddr));
+    queued.extend(rendezvous::remove_any_waiters(page_table_physaddr));
+    queued.extend(interrupts::remove_process(page_table_physaddr));
+    queued.extend(remove_exit_waiters(page_table_physaddr));
+
+    for thread in queued {
+        thread.process.write().killed = true;
+        for waiter in thread_exited(&thread, EXIT_CODE_KILLED) {
+            schedule_thread(waiter);
+        }
+        free_thread(thread);
+    }
+
+    if let Some(thread) = current {
+        for waiter in thread_exited(&thread, EXIT_CODE_KILLED) {
+            schedule_thread(waiter);
+        }
+        // Still running on its kernel stack
+        EXITED_THREADS.write().push(thread);
+        wait_for_switch();
+    }
+}
+
+/// Remove threads in a process which are waiting on its Rendezvous
+///
+/// A message being sent by one of them is discarded.
+fn remove_rendezvous_waiters(process: &RwLock<Process>,
+                             page_table_physaddr: u64) -> Vec<Box<Thread>> {
+    let handles: Vec<_> = match process.try_read() {
+        Some(process) => process.handles.iter().flatten().cloned().collect(),
+        None => return Vec::new()
+    };
+    handles.iter()
+        .filter_map(|rdv| rdv.try_write()?.remove_process(page_table_physaddr))
+        .collect()
+}
+
+/// Remove threads in a process which are waiting for a thread to exit
+///
+/// If EXIT_WAITERS is held by an interrupted thread then none are
+/// removed. They are dropped by schedule_next when woken, as their
+/// process is marked killed.
+fn remove_exit_waiters(page_table_physaddr: u64) -> Vec<Box<Thread>> {
+    let mut waiters = match EXIT_WAITERS.try_write() {
+        Some(waiters) => waiters,
+        None => return Vec::new()
+    };
+    let mut removed = Vec::new();
+    let mut i = 0;
+    while i < waiters.len() {
+        if waiters[i].1.page_table_physaddr() == page_table_physaddr {
+            removed.push(waiters.swap_remove(i).1);
+        } else {
+            i += 1;
+        }
+    }
+    removed
+}
+
+/// Terminate the process of the current thread, after a fault in
+/// user code which it can't recover from
+///
+/// Does not return, unless there is no current thread.
+pub fn kill_current_process() {
+    let process = match CURRENT_THREAD.try_read() {
+        Some(current_thread) => match current_thread.as_ref() {
+            Some(thread) => thread.process.clone(),
+            None => return
+        },
+        None => {
+            // Interrupted while the scheduler held the thread, so
+            // leave it to schedule_next on the next timer interrupt
+            KILL_CURRENT.store(true, Ordering::Release);
+            wait_for_switch();
+        }
+    };
+    // Marked first, in case kill_process can't take the thread
+    process.write().killed = true;
+    let page_table_physaddr = process.read().page_table_physaddr;
+    drop(process);
+    kill_process(page_table_physaddr);
+    // The current thread couldn't be taken. It is removed by
+    // schedule_next on the next timer interrupt
+    wait_for_switch();
+}
+
+/// Adjust the scheduler priority of the current thread
+///
+/// A positive delta lowers the priority. The priority can't be
+/// raised above the base priority the thread was created with.
+///
+/// Returns the new priority
+pub fn nice_current_thread(delta: i8) -> Result<u8, usize> {
+    if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+        let priority = (thread.priority as i16 + delta as i16)
+            .clamp(thread.base_priority as i16,
+                   PRIORITY_LOWEST as i16) as u8;
+        thread.priority = priority;
+        return Ok(priority);
+    }
+    Err(syscalls::SYSCALL_ERROR_THREAD)
+}
+
+/// Check whether the current thread may make a syscall
+///
+/// If the process' filter forbids the syscall and is set to kill,
+/// the process is terminated and this function doesn't return.
+pub fn check_syscall_filter(syscall: u64) -> bool {
+    let (filter, tid, page_table_physaddr) = match CURRENT_THREAD.read().as_ref() {
+        Some(thread) => (thread.syscall_filter(), thread.tid,
+                         thread.process.read().page_table_physaddr),
+        None => return true
+    };
+    if filter.allows(syscall) {
+        return true;
+    }
+    if filter.kill {
+        println!("[kernel] Thread {} made forbidden syscall {}; terminating process",
+                 tid, syscall);
+        // Frees the calling thread after switching away from it
+        kill_process(page_table_physaddr);
+    }
+    false
+}
+
+/// Change the signal mask of the current thread
+///
+/// If `block` is true then the signals in `mask` are added to the
+/// mask, otherwise the mask is replaced.
+///
+/// Returns the previous mask, and the pending signals which are
+/// no longer masked.
+pub fn signal_mask_current_thread(block: bool, mask: u32) -> Result<(u32, u32), usize> {
+    if let Some(thread) = CURRENT_THREAD.write().as_mut() {
+        let old_mask = thread.signal_mask;
+        thread.signal_mask = if block {old_mask | mask} else {mask};
+        return Ok((old_mask, thread.signals_pending & !thread.signal_mask));
+    }
+    Err(syscalls::SYSCALL_ERROR_THREAD)
+}
+
+/// Priority of a thread which has been passed over `skipped` times
+fn aged_priority(priority: u8, skipped: u8) -> u8 {
+    priority.saturating_sub(skipped / AGING_SKIPS)
+}
+
+/// Index of the next thread to run, given the (aged) priorities of
+/// the threads in the running queue
+///
+/// The highest priority thread nearest the front of the queue is
+/// chosen. Threads go to the back of the queue when they stop
+/// running, so threads of the same priority take turns.
+fn choose_thread(priorities: impl Iterator<Item = u8>) -> Option<usize> {
+    priorities.enumerate()
+        .min_by_key(|&(_, priority)| priority)
+        .map(|(index, _)| index)
 }
 
 /// This is called by the timer interrupt handler
@@ -671,6 +1921,15 @@ pub fn schedule_next(context_addr: usize) -> usize {
     let mut running_queue = RUNNING_QUEUE.write();
     let mut current_thread = CURRENT_THREAD.write();
 
+    // In test mode, choices are made by a seeded random number generator
+    let test_random = sched_test::next_random();
+    if let Some(random) = test_random {
+        if (random & 1 == 0) && current_thread.is_some() {
+            // Keep running the current thread
+            return context_addr;
+        }
+    }
+
     if let Some(mut thread) = current_thread.take() {
         // Put the current thread to the back of the queue
 
@@ -685,12 +1944,62 @@ pub fn schedule_next(context_addr: usize) -> usize {
         // for example new_user_thread
         thread.page_table_physaddr = memory::active_pagetable_physaddr();
 
-        running_queue.push_back(thread);
+        thread.stop_running();
+        if KILL_CURRENT.swap(false, Ordering::Acquire) {
+            // Faulted while CURRENT_THREAD was held
+            thread.process.write().killed = true;
+        }
+        if thread.process.read().killed {
+            // Killed while running, e.g. by a fault handler which
+            // couldn't take it. Its kernel stack is in use until
+            // this returns, so it is freed by a later call.
+            for waiter in thread_exited(&thread, EXIT_CODE_KILLED) {
+                running_queue.push_back(waiter);
+            }
+            EXITED_THREADS.write().push(thread);
+        } else {
+            running_queue.push_back(thread);
+        }
     }
-    *current_thread = running_queue.pop_front();
 
-    match current_thread.as_ref() {
+    if let Some(random) = test_random {
+        // Test mode: Choose a random thread, ignoring priority
+        let len = running_queue.len();
+        if len > 0 {
+            *current_thread = running_queue.remove(((random >> 1) as usize) % len);
+        }
+    } else if let Some(index) = choose_thread(
+        running_queue.iter().map(|thread| aged_priority(thread.priority, thread.skipped))) {
+        // The others have been passed over
+        for thread in running_queue.iter_mut() {
+            thread.skipped = thread.skipped.saturating_add(1);
+        }
+        let mut thread = running_queue.remove(index).unwrap();
+        thread.skipped = 0;
+        *current_thread = Some(thread);
+    }
+    if current_thread.is_none() {
+        *current_thread = running_queue.pop_front();
+    }
+
+    // Free threads which have exited. The interrupted thread may
+    // have exited, and its kernel stack is used until this returns.
+    drop_exited_threads(context_addr as u64);
+
+    // Remove threads of processes terminated by the OOM killer
+    while current_thread.as_ref().map_or(false, |thread| thread.process.read().killed) {
+        let thread = current_thread.take().unwrap();
+        for waiter in thread_exited(&thread, EXIT_CODE_KILLED) {
+            running_queue.push_back(waiter);
+        }
+        free_thread(thread);
+        *current_thread = running_queue.pop_front();
+    }
+
+    match current_thread.as_mut() {
         Some(thread) => {
+            thread.start_running();
+
             // Set the kernel stack for the next interrupt
             gdt::set_interrupt_stack_table(
                 gdt::TIMER_INTERRUPT_INDEX as usize,
@@ -703,6 +2012,9 @@ pub fn schedule_next(context_addr: usize) -> usize {
                 memory::switch_to_pagetable(thread.page_table_physaddr);
             }
 
+            // Thread-local storage
+            tls::set_thread_pointer(thread.fs_base);
+
             // Point the stack to the new context
             // (which is usually stored on the kernel stack)
             thread.context as usize
@@ -823,6 +2135,29 @@ pub fn special_memory_chunk(
     }
 }
 
+/// A memory chunk in the current thread which maps device memory
+///
+/// `start_physaddr` must be page aligned. See memory::create_device_pages
+pub fn device_memory_chunk(
+    num_pages: u64,
+    start_physaddr: u64
+) -> Result<VirtAddr, usize> {
+    if let Some(thread) = CURRENT_THREAD.read().as_ref() {
+        let start_virtaddr = memory::find_available_page_chunk(
+            thread.page_table_physaddr)
+            .ok_or(syscalls::SYSCALL_ERROR_MEMORY)?;
+
+        return memory::create_device_pages(
+            thread.page_table_physaddr,
+            start_virtaddr,
+            num_pages,
+            PhysAddr::new(start_physaddr))
+            .map(|_| start_virtaddr)
+            .map_err(|_| syscalls::SYSCALL_ERROR_MEMORY);
+    }
+    Err(syscalls::SYSCALL_ERROR_THREAD)
+}
+
 /// Free a memory chunk previously allocated with new_memory_chunk
 pub fn free_memory_chunk(
     address: VirtAddr
@@ -834,9 +2169,37 @@ pub fn free_memory_chunk(
     Err(syscalls::SYSCALL_ERROR_THREAD)
 }
 
+/// Size in bytes of a memory chunk of the current thread.
+/// See memory::page_chunk_size
+pub fn memory_chunk_size(
+    address: VirtAddr
+) -> Result<u64, usize> {
+    if let Some(thread) = CURRENT_THREAD.read().as_ref() {
+        return memory::page_chunk_size(thread.page_table_physaddr,
+                                       address);
+    }
+    Err(syscalls::SYSCALL_ERROR_THREAD)
+}
+
+/// Map a memory chunk into a second chunk of the current thread
+///
+/// Returns the address of the new chunk. See memory::share_page_chunk
+pub fn share_memory_chunk(
+    address: VirtAddr,
+    writable: bool
+) -> Result<VirtAddr, usize> {
+    if let Some(thread) = CURRENT_THREAD.read().as_ref() {
+        return memory::share_page_chunk(thread.page_table_physaddr,
+                                        address,
+                                        writable);
+    }
+    Err(syscalls::SYSCALL_ERROR_THREAD)
+}
+
 pub fn new_rendezvous() -> Result<(usize, usize), usize> {
     if let Some(thread) = CURRENT_THREAD.read().as_ref() {
-        let rv = Arc::new(RwLock::new(Rendezvous::Empty));
+        let rv = memory::with_category(memory::Category::Message,
+                                       || Arc::new(RwLock::new(Rendezvous::Empty)));
 
         let handle1 = thread.give_rendezvous(rv.clone());
         let handle2 = thread.give_rendezvous(rv);
@@ -844,3 +2207,181 @@ pub fn new_rendezvous() -> Result<(usize, usize), usize> {
     }
     Err(syscalls::SYSCALL_ERROR_THREAD)
 }
+
+#[test_case]
+fn syscall_filter_tightens() {
+    let parent = SyscallFilter{allowed: 0b1110, kill: false};
+    let child = parent.tighten(SyscallFilter{allowed: 0b0111, kill: true});
+    assert_eq!(child, SyscallFilter{allowed: 0b0110, kill: true});
+    assert!(child.allows(2));
+    assert!(!child.allows(3));
+    assert!(!child.allows(100));
+    // Exit is always allowed
+    assert!(child.allows(syscalls::SYSCALL_EXIT_THREAD));
+    assert!(SyscallFilter::ALL.allows(100));
+}
+
+#[test_case]
+fn elf_segment_permissions() {
+    const ELF_PF_R: u32 = 4;
+    // Code: read + execute
+    let code = elf_page_flags(ELF_PF_R | ELF_PF_X);
+    assert!(!code.contains(PageTableFlags::WRITABLE));
+    assert!(!code.contains(PageTableFlags::NO_EXECUTE));
+    assert!(code.contains(memory::READ_ONLY_PAGE));
+    // Data: read + write
+    let data = elf_page_flags(ELF_PF_R | ELF_PF_W);
+    assert!(data.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    // Read-only data
+    let rodata = elf_page_flags(ELF_PF_R);
+    assert!(rodata.contains(memory::READ_ONLY_PAGE | PageTableFlags::NO_EXECUTE));
+    assert!(!rodata.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn elf_segment_in_kernel_memory() {
+    assert_eq!(check_segment_range(USER_CODE_START, 4096), Ok(()));
+    assert!(check_segment_range(USER_CODE_END - 4096, 8192).is_err());
+    assert!(check_segment_range(u64::MAX - 4095, 8192).is_err()); // Wraps
+
+    // ELF header and one loadable segment at a kernel address
+    fn push(bin: &mut Vec<u8>, value: u64, bytes: usize) {
+        bin.extend_from_slice(&value.to_le_bytes()[..bytes]);
+    }
+    let mut bin = Vec::new();
+    bin.extend_from_slice(&[0x7f, b'E', b'L', b'F',
+                            2, 1, 1, 0, // 64-bit, little endian, version 1
+                            0, 0, 0, 0, 0, 0, 0, 0]);
+    push(&mut bin, 2, 2); // Executable
+    push(&mut bin, 0x3E, 2); // x86-64
+    push(&mut bin, 1, 4); // Version
+    push(&mut bin, 0xFFFF_8000_0000_0000, 8); // Entry point
+    push(&mut bin, 64, 8); // Program header offset
+    push(&mut bin, 0, 8); // No section headers
+    push(&mut bin, 0, 4); // Flags
+    push(&mut bin, 64, 2); // ELF header size
+    push(&mut bin, 56, 2); // Program header size
+    push(&mut bin, 1, 2); // Number of program headers
+    push(&mut bin, 64, 2); // Section header size
+    push(&mut bin, 0, 2); // Number of section headers
+    push(&mut bin, 0, 2); // Section name index
+    push(&mut bin, 1, 4); // PT_LOAD
+    push(&mut bin, (ELF_PF_X | 4) as u64, 4);
+    push(&mut bin, 0, 8); // Offset
+    push(&mut bin, 0xFFFF_8000_0000_0000, 8); // Virtual address
+    push(&mut bin, 0xFFFF_8000_0000_0000, 8); // Physical address
+    push(&mut bin, 0, 8); // File size
+    push(&mut bin, 4096, 8); // Memory size
+    push(&mut bin, 4096, 8); // Alignment
+
+    let result = new_user_thread(&bin, Params{
+        handles: Vec::new(),
+        io_privileges: false,
+        mounts: vfs::VFS::new(),
+        env: env::Environment::new(),
+        syscall_filter: SyscallFilter::ALL,
+        args: Vec::new(),
+        priority: PRIORITY_NORMAL
+    });
+    assert_eq!(result.err(), Some(ExecError::SegmentOverlap));
+}
+
+#[test_case]
+fn exec_error_codes() {
+    let params = || Params{
+        handles: Vec::new(),
+        io_privileges: false,
+        mounts: vfs::VFS::new(),
+        env: env::Environment::new(),
+        syscall_filter: SyscallFilter::ALL,
+        args: Vec::new(),
+        priority: PRIORITY_NORMAL
+    };
+    assert_eq!(new_user_thread(b"EL", params()).err(), Some(ExecError::NotElf));
+    assert_eq!(new_user_thread(&[0x7f, b'E', b'L', b'F', 0, 0], params()).err(),
+               Some(ExecError::ParseFailed));
+
+    assert_eq!(ExecError::NotElf.code(), syscalls::SYSCALL_ERROR_NOT_ELF);
+    assert_eq!(ExecError::SegmentOverlap.code(), syscalls::SYSCALL_ERROR_ELF_SEGMENT);
+    assert_eq!(ExecError::ParseFailed.code(), syscalls::SYSCALL_ERROR_ELF_PARSE);
+    assert_eq!(ExecError::Other("Could not allocate memory").code(),
+               syscalls::SYSCALL_ERROR_THREAD);
+}
+
+#[test_case]
+fn argument_layout() {
+    // No arguments
+    assert!(valid_args(&[0, 0, 0, 0]));
+    // "ls", "-l"
+    assert!(valid_args(&[2, 0, 0, 0,
+                         2, 0, 0, 0, b'l', b's',
+                         2, 0, 0, 0, b'-', b'l']));
+    // Empty string
+    assert!(valid_args(&[1, 0, 0, 0, 0, 0, 0, 0]));
+
+    assert!(!valid_args(&[]));
+    // String runs past the end
+    assert!(!valid_args(&[1, 0, 0, 0, 3, 0, 0, 0, b'l', b's']));
+    // Trailing data
+    assert!(!valid_args(&[0, 0, 0, 0, 1]));
+    // Large count
+    assert!(!valid_args(&[0xFF, 0xFF, 0xFF, 0xFF]));
+}
+
+#[test_case]
+fn scheduler_priorities() {
+    // Highest priority first, then the front of the queue
+    assert_eq!(choose_thread([PRIORITY_NORMAL, PRIORITY_HIGH, PRIORITY_HIGH].into_iter()),
+               Some(1));
+    assert_eq!(choose_thread([PRIORITY_LOW, PRIORITY_NORMAL, PRIORITY_NORMAL].into_iter()),
+               Some(1));
+    assert_eq!(choose_thread(core::iter::empty()), None);
+
+    // Waiting threads are eventually treated as high priority
+    assert_eq!(aged_priority(PRIORITY_LOW, 0), PRIORITY_LOW);
+    assert_eq!(aged_priority(PRIORITY_LOW, AGING_SKIPS), PRIORITY_LOW - 1);
+    assert_eq!(aged_priority(PRIORITY_LOWEST, u8::MAX), PRIORITY_HIGH);
+}
+
+#[test_case]
+fn kernel_thread_argument() {
+    extern "C" fn entry(_arg: usize) {}
+
+    irqguard::without_interrupts(|| {
+        let tid = new_kernel_thread_with_arg(entry, 0x1234_5678, Vec::new(),
+                                             PRIORITY_NORMAL);
+        // Take the thread back before it can be scheduled
+        let thread = {
+            let mut running_queue = RUNNING_QUEUE.write();
+            let index = running_queue.iter().position(|t| t.tid == tid).unwrap();
+            running_queue.remove(index).unwrap()
+        };
+        let context = thread.context();
+        assert_eq!(context.rip, entry as usize);
+        assert_eq!(context.rdi, 0x1234_5678);
+        free_thread(thread);
+    });
+}
+
+#[test_case]
+fn live_thread_found_while_blocked() {
+    extern "C" fn entry(_arg: usize) {}
+
+    irqguard::without_interrupts(|| {
+        let tid = new_kernel_thread_with_arg(entry, 0, Vec::new(), PRIORITY_NORMAL);
+        // Held outside the scheduler, as by a Rendezvous or futex
+        let thread = {
+            let mut running_queue = RUNNING_QUEUE.write();
+            let index = running_queue.iter().position(|t| t.tid == tid).unwrap();
+            running_queue.remove(index).unwrap()
+        };
+        assert!(is_live(tid));
+        assert!(live_thread_process(tid).is_some());
+        // Kernel threads have no user memory to read
+        assert_eq!(read_thread_memory(tid, USER_CODE_START, &mut [0; 8]), None);
+
+        free_thread(thread);
+        assert!(!is_live(tid));
+        assert!(live_thread_process(tid).is_none());
+    });
+}
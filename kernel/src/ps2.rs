@@ -0,0 +1,106 @@
+//! PS/2 controller setup for the mouse
+//!
+//! The keyboard and mouse share the controller's data port, and
+//! replies to controller commands would be read by the keyboard
+//! interrupt handler. The kernel therefore enables the mouse at boot,
+//! before interrupts are enabled. After that the mouse interrupt
+//! handler reads each byte and the mouse driver decodes the packets.
+//!
+//! <https://wiki.osdev.org/PS/2_Mouse>
+
+use x86_64::instructions::port::Port;
+
+use crate::interrupts;
+use crate::println;
+
+const DATA_PORT: u16 = 0x60;
+/// Status when read, command when written
+const COMMAND_PORT: u16 = 0x64;
+
+/// Status: Data waiting to be read from DATA_PORT
+const STATUS_OUTPUT_FULL: u8 = 1;
+/// Status: Controller hasn't read the last byte written
+const STATUS_INPUT_FULL: u8 = 2;
+
+const ENABLE_AUX: u8 = 0xA8;
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+/// The next byte written to DATA_PORT goes to the mouse
+const WRITE_AUX: u8 = 0xD4;
+
+/// Config: Interrupt on IRQ12 when mouse data arrives
+const CONFIG_AUX_INTERRUPT: u8 = 0x02;
+/// Config: Mouse clock disabled
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_ACK: u8 = 0xFA;
+
+/// Number of status polls before giving up, in case there is
+/// no controller or mouse
+const TIMEOUT_POLLS: usize = 100_000;
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(COMMAND_PORT).read() }
+}
+
+fn wait_for(ready: impl Fn(u8) -> bool) -> Result<(), ()> {
+    for _ in 0..TIMEOUT_POLLS {
+        if ready(status()) {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(())
+}
+
+fn command(byte: u8) -> Result<(), ()> {
+    wait_for(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(byte) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), ()> {
+    wait_for(|status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, ()> {
+    wait_for(|status| status & STATUS_OUTPUT_FULL != 0)?;
+    Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+/// Send a command byte to the mouse, and check that it acknowledged
+fn mouse_command(byte: u8) -> Result<(), ()> {
+    command(WRITE_AUX)?;
+    write_data(byte)?;
+    if read_data()? == MOUSE_ACK { Ok(()) } else { Err(()) }
+}
+
+fn enable_mouse() -> Result<(), ()> {
+    command(ENABLE_AUX)?;
+
+    command(READ_CONFIG)?;
+    let config = (read_data()? | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED;
+    command(WRITE_CONFIG)?;
+    write_data(config)?;
+
+    mouse_command(MOUSE_SET_DEFAULTS)?;
+    mouse_command(MOUSE_ENABLE_REPORTING)
+}
+
+/// Enable the PS/2 mouse and its interrupt
+///
+/// Must be called with interrupts disabled. Prints a message and
+/// continues if there is no mouse.
+pub fn init() {
+    match enable_mouse() {
+        Ok(()) => {
+            interrupts::unmask_irq(interrupts::IRQ_MOUSE as u8);
+            println!("[kernel] PS/2 mouse enabled");
+        }
+        Err(()) => println!("[kernel] No PS/2 mouse")
+    }
+}
@@ -1,25 +1,40 @@
 //! Non-buffering communication mechanism
 
 use alloc::boxed::Box;
-use crate::process::Thread;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::RwLock;
+use lazy_static::lazy_static;
+use crate::process::{self, Thread};
+use crate::time;
 use crate::syscalls;
 use crate::message::Message;
 use core::mem;
 
 /// Represents a blocking communication channel
 ///
-/// A Rendezvous is in one of three states:
+/// A Rendezvous is in one of these states:
 ///  1. Empty
 ///  2. Receiving (or Reading). Optionally from a specific thread
 ///  3. Sending (or Writing)
 ///  4. Sending, expecting a reply
+///  5. Discarding a late reply, after a send_receive timed out
 /// In states 2 and 4 there is a Thread waiting
 /// for a matching call, and in state 3 the sender may wait.
+/// State 5 is otherwise the same as Empty: any other call ends it.
+///
+/// Waiting threads are owned by the Rendezvous rather than the
+/// running queue, so use no CPU time. Whichever of send or receive
+/// arrives second completes the transfer: the message is written to
+/// the receiving thread's saved context registers, and the threads
+/// are returned to be scheduled. Threads in await_any instead wait
+/// in a separate list, and are woken by wake_any_waiter after a send.
 pub enum Rendezvous {
     Empty,
     Sending(Option<Box<Thread>>, Message),
     Receiving(Box<Thread>, Option<u64>),
     SendReceiving(Box<Thread>, Message),
+    Discarding(u64),
 }
 
 impl Rendezvous {
@@ -37,8 +52,17 @@ impl Rendezvous {
     ///    Error returned to thread
     pub fn send(&mut self, thread: Option<Box<Thread>>, message: Message)
                 -> (Option<Box<Thread>>, Option<Box<Thread>>) {
+        if let Rendezvous::Discarding(tid) = *self {
+            *self = Rendezvous::Empty;
+            if let Some(t) = thread.as_ref().filter(|t| t.tid() == tid) {
+                // Reply to a send_receive which timed out. Return the
+                // message so that any handles are not lost
+                t.return_error_message(syscalls::SYSCALL_ERROR_TIMEOUT, message);
+                return (thread, None);
+            }
+        }
         match &*self {
-            Rendezvous::Empty => {
+            Rendezvous::Empty | Rendezvous::Discarding(_) => {
                 *self = Rendezvous::Sending(thread, message);
                 (None, None)
             }
@@ -105,7 +129,7 @@ impl Rendezvous {
     pub fn receive(&mut self, thread: Box<Thread>)
                    -> (Option<Box<Thread>>, Option<Box<Thread>>) {
         match &*self {
-            Rendezvous::Empty => {
+            Rendezvous::Empty | Rendezvous::Discarding(_) => {
                 // Can receive from any thread
                 *self = Rendezvous::Receiving(thread, None);
                 (None, None)
@@ -139,6 +163,31 @@ impl Rendezvous {
         }
     }
 
+    /// Non-blocking receive a message
+    ///
+    /// As receive, if a thread is waiting to send. Otherwise the
+    /// state is not changed, and SYSCALL_ERROR_WOULDBLOCK is
+    /// returned to the thread.
+    ///
+    /// Always returns (receiving thread, Option<sending thread>)
+    pub fn try_receive(&mut self, thread: Box<Thread>)
+                       -> (Option<Box<Thread>>, Option<Box<Thread>>) {
+        match &*self {
+            Rendezvous::Sending(_, _) | Rendezvous::SendReceiving(_, _) => {
+                self.receive(thread)
+            }
+            _ => {
+                thread.return_error(syscalls::SYSCALL_ERROR_WOULDBLOCK);
+                (Some(thread), None)
+            }
+        }
+    }
+
+    /// True if a thread is waiting to send
+    fn has_sender(&self) -> bool {
+        matches!(self, Rendezvous::Sending(_, _) | Rendezvous::SendReceiving(_, _))
+    }
+
     /// Send a message and block on receive from the same thread
     ///
     /// When a Rendezvous is shared between multiple threads, for example
@@ -153,7 +202,7 @@ impl Rendezvous {
     pub fn send_receive(&mut self, thread: Box<Thread>, message: Message)
                         -> (Option<Box<Thread>>, Option<Box<Thread>>) {
         match &*self {
-            Rendezvous::Empty => {
+            Rendezvous::Empty | Rendezvous::Discarding(_) => {
                 *self = Rendezvous::SendReceiving(thread, message);
                 (None, None)
             }
@@ -191,20 +240,63 @@ impl Rendezvous {
         }
     }
 
+    /// Cancel a blocking send or send_receive from the given thread
+    ///
+    /// If the thread is still waiting for its message to be received then
+    /// the rendezvous becomes Empty and the thread is returned with a
+    /// SYSCALL_ERROR_TIMEOUT error and the message. If it is waiting for
+    /// a reply then the thread is returned with SYSCALL_ERROR_TIMEOUT, and
+    /// the rendezvous discards the reply. Otherwise nothing changes and
+    /// None is returned.
+    pub fn cancel_send(&mut self, tid: u64) -> Option<Box<Thread>> {
+        match &*self {
+            Rendezvous::Sending(Some(thread), _) |
+            Rendezvous::SendReceiving(thread, _) if thread.tid() == tid => {
+                match mem::replace(self, Rendezvous::Empty) {
+                    Rendezvous::Sending(Some(snd_thread), message) |
+                    Rendezvous::SendReceiving(snd_thread, message) => {
+                        snd_thread.return_error_message(syscalls::SYSCALL_ERROR_TIMEOUT, message);
+                        Some(snd_thread)
+                    }
+                    _ => None
+                }
+            }
+            Rendezvous::Receiving(thread, Some(_)) if thread.tid() == tid => {
+                // Waiting for a reply to send_receive. Discard the
+                // reply if it is sent later.
+                if let Rendezvous::Receiving(rec_thread, Some(replying_tid)) =
+                    mem::replace(self, Rendezvous::Empty) {
+                    *self = Rendezvous::Discarding(replying_tid);
+                    rec_thread.return_error(syscalls::SYSCALL_ERROR_TIMEOUT);
+                    Some(rec_thread)
+                } else {
+                    None
+                }
+            }
+            _ => None
+        }
+    }
+
     /// Close a Rendezvous.
     ///
     /// If a thread was waiting then it is returned and should be scheduled.
     /// An error SYSCALL_ERROR_CLOSED will be returned to the waiting thread.
     pub fn close(&mut self) -> Option<Box<Thread>> {
         match &*self {
-            Rendezvous::Empty => None,
+            Rendezvous::Empty | Rendezvous::Discarding(_) => None,
             Rendezvous::Sending(_, _) => {
                 // Cannot complete the message transfer
-                if let Rendezvous::Sending(Some(snd_thread), message) = mem::replace(self, Rendezvous::Empty) {
-                    snd_thread.return_error_message(syscalls::SYSCALL_ERROR_CLOSED, message);
-                    Some(snd_thread)
-                } else {
-                    None
+                match mem::replace(self, Rendezvous::Empty) {
+                    Rendezvous::Sending(Some(snd_thread), message) => {
+                        snd_thread.return_error_message(syscalls::SYSCALL_ERROR_CLOSED, message);
+                        Some(snd_thread)
+                    }
+                    Rendezvous::Sending(None, message) => {
+                        // No sender waiting to take the message back
+                        message.discard();
+                        None
+                    }
+                    _ => None
                 }
             }
             Rendezvous::Receiving(_, _) => {
@@ -228,3 +320,230 @@ impl Rendezvous {
         }
     }
 }
+
+impl Rendezvous {
+    /// Remove a waiting thread if it is in the process with this page
+    /// table, e.g. because the process was killed. A message it was
+    /// sending is discarded.
+    pub fn remove_process(&mut self, page_table_physaddr: u64) -> Option<Box<Thread>> {
+        let in_process = match &*self {
+            Rendezvous::Sending(Some(thread), _) |
+            Rendezvous::Receiving(thread, _) |
+            Rendezvous::SendReceiving(thread, _) =>
+                thread.page_table_physaddr() == page_table_physaddr,
+            _ => false
+        };
+        if !in_process {
+            return None;
+        }
+        match mem::replace(self, Rendezvous::Empty) {
+            Rendezvous::Sending(thread, message) => {
+                message.discard();
+                thread
+            }
+            Rendezvous::SendReceiving(thread, message) => {
+                message.discard();
+                Some(thread)
+            }
+            Rendezvous::Receiving(thread, _) => Some(thread),
+            _ => None
+        }
+    }
+}
+
+/// A send which should be cancelled if not received by a deadline
+struct SendTimeout {
+    /// Time in microseconds after which the send is cancelled
+    deadline: u64,
+    rendezvous: Weak<RwLock<Rendezvous>>,
+    /// The sending thread
+    tid: u64,
+}
+
+lazy_static! {
+    static ref SEND_TIMEOUTS: RwLock<Vec<SendTimeout>> = RwLock::new(Vec::new());
+}
+
+/// Cancel the send from thread `tid` if it hasn't been received by `deadline`
+///
+/// Any previous timeout for the same thread is removed: A thread
+/// can only be waiting on one send at a time.
+pub fn add_send_timeout(rendezvous: &Arc<RwLock<Rendezvous>>,
+                        tid: u64,
+                        deadline: u64) {
+    let mut timeouts = SEND_TIMEOUTS.write();
+    timeouts.retain(|t| t.tid != tid);
+    timeouts.push(SendTimeout{
+        deadline,
+        rendezvous: Arc::downgrade(rendezvous),
+        tid
+    });
+}
+
+/// Remove any send timeout for the thread
+///
+/// Called when a thread starts a new send, so that a timeout from
+/// an earlier (completed) send can't cancel it.
+pub fn clear_send_timeout(tid: u64) {
+    let mut timeouts = SEND_TIMEOUTS.write();
+    if !timeouts.is_empty() {
+        timeouts.retain(|t| t.tid != tid);
+    }
+}
+
+/// Cancel sends whose deadline has passed, scheduling the sending threads
+///
+/// Called from the timer interrupt, so uses try_write to avoid
+/// deadlocks if the interrupted code holds a lock. Sends which can't
+/// be checked now will be checked on the next timer interrupt.
+pub fn check_send_timeouts() {
+    let mut timeouts = match SEND_TIMEOUTS.try_write() {
+        Some(timeouts) => timeouts,
+        None => return
+    };
+    if timeouts.is_empty() {
+        return;
+    }
+    let now = time::microseconds_monotonic();
+
+    timeouts.retain(|timeout| {
+        if now <= timeout.deadline {
+            // Not expired. A receiver arriving at the deadline
+            // still completes the send
+            return true;
+        }
+        if let Some(rdv) = timeout.rendezvous.upgrade() {
+            if let Some(mut rdv) = rdv.try_write() {
+                if let Some(thread) = rdv.cancel_send(timeout.tid) {
+                    process::schedule_thread(thread);
+                }
+            } else {
+                return true; // Try again later
+            }
+        }
+        false // Expired: remove
+    });
+}
+
+/// A thread in await_any, waiting for a message on any of
+/// several Rendezvous
+struct AnyWaiter {
+    thread: Box<Thread>,
+    rendezvous: Vec<Weak<RwLock<Rendezvous>>>,
+}
+
+lazy_static! {
+    static ref ANY_WAITERS: RwLock<Vec<AnyWaiter>> = RwLock::new(Vec::new());
+}
+
+/// Receive from the first Rendezvous with a waiting sender
+///
+/// The index of the Rendezvous is returned to the thread in R8, and
+/// (thread1, thread2) are as for receive. If no Rendezvous has a
+/// sender then the thread is returned as the error.
+fn receive_first(thread: Box<Thread>, rendezvous: &[Weak<RwLock<Rendezvous>>])
+                 -> Result<(Option<Box<Thread>>, Option<Box<Thread>>), Box<Thread>> {
+    for (index, rdv) in rendezvous.iter().enumerate() {
+        if let Some(rdv) = rdv.upgrade() {
+            let mut rdv = rdv.write();
+            if rdv.has_sender() {
+                thread.return_index(index);
+                return Ok(rdv.receive(thread));
+            }
+        }
+    }
+    Err(thread)
+}
+
+/// Receive a message from any of several Rendezvous
+///
+/// If more than one has a waiting sender then the lowest index
+/// receives. If none do then the thread waits until a send calls
+/// wake_any_waiter.
+///
+/// Returns zero, one or two threads, as Rendezvous::receive
+pub fn await_any(thread: Box<Thread>, rendezvous: &[Arc<RwLock<Rendezvous>>])
+                 -> (Option<Box<Thread>>, Option<Box<Thread>>) {
+    let weak: Vec<_> = rendezvous.iter().map(Arc::downgrade).collect();
+    match receive_first(thread, &weak) {
+        Ok(threads) => threads,
+        Err(thread) => {
+            // Only the caller's handle and this reference: the other
+            // end was closed, so nothing will be sent
+            if rendezvous.iter().any(|rdv| Arc::strong_count(rdv) == 2 &&
+                                     matches!(*rdv.read(), Rendezvous::Empty)) {
+                thread.return_error(syscalls::SYSCALL_ERROR_CLOSED);
+                return (Some(thread), None);
+            }
+            ANY_WAITERS.write().push(AnyWaiter{thread, rendezvous: weak});
+            (None, None)
+        }
+    }
+}
+
+/// Wake await_any waiters on a Rendezvous which has been closed,
+/// and those on any Rendezvous which has been dropped
+///
+/// The waiters get SYSCALL_ERROR_CLOSED, as from receive.
+/// Returns the threads to schedule
+pub fn close_any_waiters(rendezvous: &Weak<RwLock<Rendezvous>>) -> Vec<Box<Thread>> {
+    let mut waiters = ANY_WAITERS.write();
+    let mut woken = Vec::new();
+    let mut i = 0;
+    while i < waiters.len() {
+        if waiters[i].rendezvous.iter().any(
+            |r| Weak::ptr_eq(r, rendezvous) || r.strong_count() == 0) {
+            let AnyWaiter{thread, ..} = waiters.remove(i);
+            thread.return_error(syscalls::SYSCALL_ERROR_CLOSED);
+            woken.push(thread);
+        } else {
+            i += 1;
+        }
+    }
+    woken
+}
+
+/// Remove the await_any waiters in a process, e.g. when it is killed
+pub fn remove_any_waiters(page_table_physaddr: u64) -> Vec<Box<Thread>> {
+    let mut waiters = ANY_WAITERS.write();
+    let mut removed = Vec::new();
+    let mut i = 0;
+    while i < waiters.len() {
+        if waiters[i].thread.page_table_physaddr() == page_table_physaddr {
+            removed.push(waiters.remove(i).thread);
+        } else {
+            i += 1;
+        }
+    }
+    removed
+}
+
+/// Complete an await_any waiting on `rendezvous`, if a sender is waiting
+///
+/// Called after a send. The thread which has been waiting longest
+/// receives the message, and stops waiting on all its Rendezvous.
+///
+/// Returns zero, one or two threads to schedule
+pub fn wake_any_waiter(rendezvous: &Arc<RwLock<Rendezvous>>)
+                       -> (Option<Box<Thread>>, Option<Box<Thread>>) {
+    if !rendezvous.read().has_sender() {
+        return (None, None);
+    }
+    let mut waiters = ANY_WAITERS.write();
+    if waiters.is_empty() {
+        return (None, None);
+    }
+    let weak = Arc::downgrade(rendezvous);
+    if let Some(i) = waiters.iter().position(
+        |w| w.rendezvous.iter().any(|r| Weak::ptr_eq(r, &weak))) {
+        let AnyWaiter{thread, rendezvous: rdvs} = waiters.remove(i);
+        match receive_first(thread, &rdvs) {
+            Ok(threads) => return threads,
+            Err(thread) => {
+                // Should not happen: Keep waiting
+                waiters.insert(i, AnyWaiter{thread, rendezvous: rdvs});
+            }
+        }
+    }
+    (None, None)
+}
@@ -0,0 +1,265 @@
+//! Wall-clock time from the CMOS Real Time Clock
+//!
+//! The RTC is slow to read, so it is only read once by init(). After
+//! that now() adds the time since boot from microseconds_monotonic.
+//!
+//! The RTC is assumed to be set to UTC.
+//!
+//! <https://wiki.osdev.org/CMOS>
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::irqguard;
+use crate::println;
+use crate::time;
+
+/// CMOS register select port
+const CMOS_ADDRESS: u16 = 0x70;
+/// CMOS data port
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+/// Not standard, but where most BIOSes keep it
+const REG_CENTURY: u8 = 0x32;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Status A: Set while the RTC is updating
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: Set if 24 hour, clear if 12 hour
+const STATUS_B_24_HOUR: u8 = 0x02;
+/// Status B: Set if binary, clear if BCD
+const STATUS_B_BINARY: u8 = 0x04;
+/// Hours register in 12 hour mode: Set for PM
+const HOUR_PM: u8 = 0x80;
+
+/// Unix time in seconds when the RTC was read
+static BOOT_UNIX_SECONDS: AtomicU64 = AtomicU64::new(0);
+/// microseconds_monotonic when the RTC was read
+static BOOT_MICROSECONDS: AtomicU64 = AtomicU64::new(0);
+
+/// A date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12
+    pub month: u8,
+    /// 1 to 31
+    pub day: u8,
+    /// 0 to 23
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+///
+/// <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+        + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    ///
+    /// Dates before 1970 return 0
+    pub fn to_unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        let seconds = days * 86400
+            + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        if seconds < 0 { 0 } else { seconds as u64 }
+    }
+
+    /// Convert seconds since 1970-01-01 00:00:00 UTC
+    ///
+    /// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64 + 719468;
+        let seconds = timestamp % 86400;
+
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+                           - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: ((seconds / 60) % 60) as u8,
+            second: (seconds % 60) as u8
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// ISO 8601 format e.g. 2022-07-01T12:30:00Z
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+               self.year, self.month, self.day,
+               self.hour, self.minute, self.second)
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address = Port::<u8>::new(CMOS_ADDRESS);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Raw register values, before BCD and 12 hour conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RtcRegisters {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8
+}
+
+fn read_registers() -> RtcRegisters {
+    // Wait until an update isn't in progress
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+    RtcRegisters {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: read_register(REG_CENTURY)
+    }
+}
+
+/// Convert register values to a DateTime, using the format
+/// flags in status register B
+fn decode(registers: RtcRegisters, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    let pm = registers.hour & HOUR_PM != 0;
+    let mut hour = convert(registers.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 hour clock: 12am is 0, 12pm is 12
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // The century register reads 0 or 0xFF if not present
+    let century = convert(registers.century);
+    let year = convert(registers.year) as u16 + if (19..=99).contains(&century) {
+        century as u16 * 100
+    } else {
+        2000
+    };
+
+    DateTime {
+        year,
+        month: convert(registers.month),
+        day: convert(registers.day),
+        hour,
+        minute: convert(registers.minute),
+        second: convert(registers.second)
+    }
+}
+
+/// Read the date and time from the RTC
+///
+/// Slow: waits for any update to finish, and reads until two
+/// reads agree.
+pub fn read_rtc() -> DateTime {
+    irqguard::without_interrupts(|| {
+        let mut registers = read_registers();
+        loop {
+            // An update could have happened during the read
+            let again = read_registers();
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        decode(registers, read_register(REG_STATUS_B))
+    })
+}
+
+/// Read the RTC, to be used as the start time for now()
+pub fn init() {
+    let datetime = read_rtc();
+    BOOT_UNIX_SECONDS.store(datetime.to_unix_timestamp(), Ordering::Relaxed);
+    BOOT_MICROSECONDS.store(time::microseconds_monotonic(), Ordering::Relaxed);
+    println!("[kernel] RTC time {}", datetime);
+}
+
+/// Seconds since 1970-01-01 00:00:00 UTC
+pub fn unix_timestamp() -> u64 {
+    let elapsed = time::microseconds_monotonic()
+        .saturating_sub(BOOT_MICROSECONDS.load(Ordering::Relaxed));
+    BOOT_UNIX_SECONDS.load(Ordering::Relaxed) + elapsed / 1_000_000
+}
+
+/// The current date and time in UTC
+///
+/// Doesn't read the RTC: the time when init() read it, plus the
+/// time since then.
+pub fn now() -> DateTime {
+    DateTime::from_unix_timestamp(unix_timestamp())
+}
+
+#[test_case]
+fn unix_timestamps() {
+    let datetime = DateTime{year: 2000, month: 1, day: 1,
+                            hour: 0, minute: 0, second: 0};
+    assert_eq!(datetime.to_unix_timestamp(), 946684800);
+
+    // Leap day
+    let datetime = DateTime{year: 2024, month: 2, day: 29,
+                            hour: 13, minute: 45, second: 30};
+    assert_eq!(DateTime::from_unix_timestamp(datetime.to_unix_timestamp()), datetime);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
+
+#[test_case]
+fn decode_registers() {
+    // 9:05:30pm on 2022-07-14, BCD 12 hour with century register
+    let registers = RtcRegisters{second: 0x30, minute: 0x05, hour: HOUR_PM | 0x09,
+                                 day: 0x14, month: 0x07, year: 0x22, century: 0x20};
+    assert_eq!(decode(registers, 0),
+               DateTime{year: 2022, month: 7, day: 14,
+                        hour: 21, minute: 5, second: 30});
+
+    // Binary 24 hour, no century register
+    let registers = RtcRegisters{second: 59, minute: 59, hour: 0,
+                                 day: 31, month: 12, year: 99, century: 0xFF};
+    assert_eq!(decode(registers, STATUS_B_BINARY | STATUS_B_24_HOUR).year, 2099);
+}
@@ -0,0 +1,81 @@
+//! Deterministic scheduler test mode
+//!
+//! To help reproduce concurrency bugs, the scheduler can make its
+//! decisions using a pseudo-random number generator with a fixed
+//! seed. At each scheduling point it randomly chooses whether to keep
+//! running the current thread, and which waiting thread to run next.
+//! Running with the same seed makes the same sequence of choices,
+//! so a failing interleaving can be replayed.
+//!
+//! Enabled by setting the seed when the kernel is built:
+//!
+//!     EURALIOS_SCHED_SEED=12345 cargo run
+//!
+//! (The bootloader doesn't pass a kernel command line.)
+//!
+//! Notes:
+//!  - This is for testing only, not for production use: scheduling is
+//!    unfair and threads may be starved for long periods.
+//!  - Scheduling points are timer interrupts and syscalls. The choices
+//!    are deterministic, but the timing of timer interrupts depends on
+//!    the host, so keep test inputs fixed (e.g. avoid network traffic).
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Generator state. Zero if test mode is disabled
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Enable test mode if a seed was set at build time
+pub fn init() {
+    if let Some(seed) = option_env!("EURALIOS_SCHED_SEED") {
+        match seed.parse::<u64>() {
+            Ok(0) | Err(_) => {
+                crate::println!("[kernel] Invalid EURALIOS_SCHED_SEED '{}'", seed);
+            }
+            Ok(seed) => {
+                crate::println!("[kernel] Deterministic scheduler test mode, seed {}", seed);
+                STATE.store(seed, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Is the deterministic test mode enabled?
+pub fn enabled() -> bool {
+    STATE.load(Ordering::Relaxed) != 0
+}
+
+/// Xorshift64 pseudo-random number generator step.
+/// Non-zero state always gives non-zero output
+fn xorshift(mut x: u64) -> u64 {
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+/// Get the next pseudo-random number, or None if test mode disabled
+///
+/// Note: Only called from the scheduler with interrupts disabled,
+///       so the load and store don't race.
+pub fn next_random() -> Option<u64> {
+    let state = STATE.load(Ordering::Relaxed);
+    if state == 0 {
+        return None;
+    }
+    let next = xorshift(state);
+    STATE.store(next, Ordering::Relaxed);
+    Some(next)
+}
+
+#[test_case]
+fn xorshift_deterministic() {
+    let mut a = 42;
+    let mut b = 42;
+    for _ in 0..100 {
+        a = xorshift(a);
+        b = xorshift(b);
+        assert_eq!(a, b);
+        assert!(a != 0);
+    }
+}
@@ -13,9 +13,9 @@ lazy_static! {
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    use crate::irqguard;
 
-    interrupts::without_interrupts(|| {
+    irqguard::without_interrupts(|| {
         SERIAL1
             .lock()
             .write_fmt(args)
@@ -8,7 +8,7 @@
 //! syscall function selector in AL (first 8 bits of RAX)
 //!
 //!  0   fork_current_thread() -> (RAX: errcode, RDI: thread_id)
-//!  1   exit_current_thread() -> !   (does not return)
+//!  1   exit_current_thread(RDI: exit code) -> !   (does not return)
 //!  2   debug_write(RDI: *const u8, RSI: usize) -> ()
 //!         Used to print to console for development/debugging
 //!  3   receive
@@ -18,16 +18,50 @@
 //!  6   open(RDI: *const u8, RSI: usize) -> RAX: errcode, RDI: handle
 //!         Opens a VFS handle for read/write
 //!  7   malloc(num_pages, max_physaddr)
+//!         At most MAX_CHUNK_PAGES pages
 //!  8   free(mem_handle)
 //!  9   yield()
 //! 10   new_rendezvous() -> (handle, handle)
 //! 11   copy_rendezvous(handle) -> handle
-//! 12   exec(RAX: flags, RDI: ELF, RSI: stdin/stdout, RDX: vfs)
+//! 12   exec(RAX: flags, RDI: ELF, RSI: stdin/stdout, RDX: vfs, R8: syscall filter)
 //! 13   mount(RAX: handle, RDI: *const u8, RSI: length)
 //! 14   listmounts() -> memory_handle
 //! 15   umount(RDI: *const u8, RSI: length)
 //! 16   close(RDI: handle)  Drop a Rendezvous
-//! 17   await_interrupt(RDI: number)  Wait for an interrupt
+//! 17   await_interrupt(RDI: irq) -> RDI: byte  Wait for a keyboard (1), serial (4) or mouse (12) interrupt
+//! 18   nice(RDI: delta) -> RDI: priority  Lower or restore thread priority
+//! 19   send_timeout  As send, with R8: timeout in microseconds
+//! 20   get_registers(RDI: tid) -> RDI: memory_handle  Copy of thread Context
+//! 21   signal_mask(RDI: block, RSI: mask) -> RDI: old mask, RSI: unmasked pending
+//! 22   memory_stats() -> RDI: memory_handle  Kernel memory usage
+//! 23   set_irq_affinity(RDI: irq, RSI: cpu)  Steer a device interrupt
+//! 24   share_memory(RDI: mem_handle) -> RDI: mem_handle  Second mapping of a chunk,
+//!        read-only if SHARE_READ_ONLY is set
+//! 25   sample_usage() -> RDI: mem_handle, RSI: count  CPU and memory usage of
+//!        all threads, including blocked and sleeping threads
+//! 26   read_process_memory(RDI: tid, RSI: address, RDX: length) -> RDI: mem_handle, RSI: count
+//! 27   send_receive_timeout  As sendreceive, with R8: timeout in microseconds for the reply
+//! 28   read_kernel_log(RDI: cursor, RSI: flags) -> RDI: mem_handle, RSI: length, RDX: cursor
+//! 29   sleep(RDI: microseconds)  Suspend the thread
+//! 30   try_receive  As receive, but returns WOULDBLOCK if no message is waiting
+//! 31   await_any(RDI: *const u32, RSI: count) -> R8: index  Receive from any of several handles
+//! 32   get_tid() -> RDI: thread_id  ID of the calling thread
+//! 33   wait(RDI: thread_id) -> RDI: exit code  Wait for a thread to exit
+//! 34   map_device_memory(RDI: physaddr, RSI: num_pages) -> RDI: mem_handle  Map device
+//!        registers. Requires I/O privileges
+//! 35   list_threads() -> RDI: mem_handle, RSI: count  TIDs and instruction pointers
+//! 36   getenv(RDI: *const u8, RSI: length) -> RDI: mem_handle, RSI: length  Environment variable
+//! 37   setenv(RDI: *const u8, RSI: length, RDX: *const u8, R8: length)  Set key to value.
+//!        Requires I/O privileges
+//! 38   unsetenv(RDI: *const u8, RSI: length)  Remove an environment variable.
+//!        Requires I/O privileges
+//! 39   listenv() -> RDI: mem_handle, RSI: length  KEY=VALUE strings, each ending in NUL
+//! 40   futex_wait(RDI: *const u32, RSI: expected)  Wait if the value is expected
+//! 41   futex_wake(RDI: *const u32, RSI: count) -> RDI: number woken
+//! 42   spawn_thread(RDI: entry, RSI: argument, RDX: stack size) -> RDI: thread_id
+//!         New thread in the same process, starting at entry with the argument in RDI
+//! 43   memory_size(RDI: address) -> RDI: bytes  Mapped size of a memory chunk
+//! 44   chdir(RDI: *const u8, RSI: length)  Set the working directory, an absolute path
 //!
 //! Potential future syscalls
 //! -------------------------
@@ -58,6 +92,49 @@ pub const SYSCALL_LISTMOUNTS: u64 = 14;
 pub const SYSCALL_UMOUNT: u64 = 15;
 pub const SYSCALL_CLOSE: u64 = 16;
 pub const SYSCALL_AWAIT_INTERRUPT: u64 = 17;
+pub const SYSCALL_NICE: u64 = 18;
+pub const SYSCALL_SEND_TIMEOUT: u64 = 19;
+pub const SYSCALL_GET_REGISTERS: u64 = 20;
+pub const SYSCALL_SIGNAL_MASK: u64 = 21;
+pub const SYSCALL_MEMORY_STATS: u64 = 22;
+pub const SYSCALL_SET_IRQ_AFFINITY: u64 = 23;
+pub const SYSCALL_SHARE_MEMORY: u64 = 24;
+pub const SYSCALL_SAMPLE_USAGE: u64 = 25;
+pub const SYSCALL_READ_PROCESS_MEMORY: u64 = 26;
+pub const SYSCALL_SENDRECEIVE_TIMEOUT: u64 = 27;
+pub const SYSCALL_READ_KERNEL_LOG: u64 = 28;
+pub const SYSCALL_SLEEP: u64 = 29;
+pub const SYSCALL_TRY_RECEIVE: u64 = 30;
+pub const SYSCALL_AWAIT_ANY: u64 = 31;
+pub const SYSCALL_GET_TID: u64 = 32;
+pub const SYSCALL_WAIT: u64 = 33;
+pub const SYSCALL_MAP_DEVICE_MEMORY: u64 = 34;
+pub const SYSCALL_LIST_THREADS: u64 = 35;
+pub const SYSCALL_GETENV: u64 = 36;
+pub const SYSCALL_SETENV: u64 = 37;
+pub const SYSCALL_UNSETENV: u64 = 38;
+pub const SYSCALL_LISTENV: u64 = 39;
+pub const SYSCALL_FUTEX_WAIT: u64 = 40;
+pub const SYSCALL_FUTEX_WAKE: u64 = 41;
+pub const SYSCALL_SPAWN_THREAD: u64 = 42;
+pub const SYSCALL_MEMORY_SIZE: u64 = 43;
+pub const SYSCALL_CHDIR: u64 = 44;
+
+/// read_kernel_log flag: Wait until there are messages after the cursor
+pub const KERNEL_LOG_WAIT: u64 = 1;
+
+/// Largest number of handles in one await_any call
+pub const MAX_AWAIT_HANDLES: u64 = 16;
+
+/// Largest number of bytes copied by one read_process_memory call
+pub const MAX_READ_PROCESS_MEMORY: u64 = 16 * 4096;
+
+/// Most pages in one memory chunk (1Gb)
+///
+/// A chunk is one level 3 page table entry, and all of it is
+/// moved when sent in a message, so this is also the most data
+/// which can be sent in one message.
+pub const MAX_CHUNK_PAGES: u64 = 512 * 512;
 
 // Syscall error codes
 pub const SYSCALL_ERROR_MASK : usize = 127; // Lower 7 bits
@@ -74,13 +151,32 @@ pub const SYSCALL_ERROR_MEMORY: usize = 9;
 pub const SYSCALL_ERROR_DOUBLEFREE: usize = 10;
 pub const SYSCALL_ERROR_NOMEMSLOTS: usize = 11; // No memory chunk slots
 pub const SYSCALL_ERROR_CLOSED: usize = 12; // Rendezvous closed
+pub const SYSCALL_ERROR_TIMEOUT: usize = 17; // Timed out waiting
+pub const SYSCALL_ERROR_DENIED: usize = 18; // Permission denied
+pub const SYSCALL_ERROR_WOULDBLOCK: usize = 22; // No message waiting
+pub const SYSCALL_ERROR_NOT_ELF: usize = 24; // exec: Not an ELF binary
+pub const SYSCALL_ERROR_ELF_SEGMENT: usize = 25; // exec: Segment overlaps kernel memory
+pub const SYSCALL_ERROR_ELF_PARSE: usize = 26; // exec: Could not parse ELF
 
 // Exec permission flags
 pub const EXEC_PERM_IO: u64 = 1;
+/// R8 contains a bitmap of syscalls the new process can use
+pub const EXEC_SYSCALL_FILTER: u64 = 2;
+/// Terminate the new process if it makes a forbidden syscall
+pub const EXEC_FILTER_KILL: u64 = 4;
+/// R9 points to encoded arguments, R10 contains their length
+pub const EXEC_ARGS: u64 = 8;
+/// Run the new process at PRIORITY_HIGH. Requires I/O privileges
+pub const EXEC_PRIORITY_HIGH: u64 = 16;
+/// Run the new process at PRIORITY_LOW
+pub const EXEC_PRIORITY_LOW: u64 = 32;
+
+/// share_memory flag in syscall_id: The new chunk is read-only
+pub const SHARE_READ_ONLY: u64 = 1 << 8;
 
 use crate::{print, println};
 use core::arch::asm;
-use core::{slice, str, ptr};
+use core::{slice, str, ptr, mem, cmp};
 use core::mem::drop;
 extern crate alloc;
 use alloc::vec::Vec;
@@ -89,10 +185,16 @@ use alloc::sync::Arc;
 use x86_64::VirtAddr;
 
 use crate::process;
+use crate::memory;
 use crate::gdt;
 use crate::vfs;
+use crate::env;
 use crate::interrupts::{self, Context};
 use crate::message::Message;
+use crate::rendezvous;
+use crate::kmsg;
+use crate::futex;
+use crate::time;
 
 // register for address of syscall handler
 const MSR_STAR: usize = 0xc0000081;
@@ -288,13 +390,23 @@ extern "C" fn dispatch_syscall(context_ptr: *mut Context, syscall_id: u64,
     context.cs = code_selector.0 as usize;
     context.ss = data_selector.0 as usize;
 
+    // Check the process' syscall allowlist
+    if !process::check_syscall_filter(syscall_id & SYSCALL_MASK) {
+        context.rax = SYSCALL_ERROR_DENIED;
+        return;
+    }
+
     match syscall_id & SYSCALL_MASK {
         SYSCALL_FORK_THREAD => process::fork_current_thread(context),
         SYSCALL_EXIT_THREAD => process::exit_current_thread(context),
         SYSCALL_DEBUG_WRITE => sys_debug_write(arg1 as *const u8, arg2 as usize),
-        SYSCALL_RECEIVE => sys_receive(context_ptr, arg1),
+        SYSCALL_RECEIVE => sys_receive(context_ptr, arg1, true),
+        SYSCALL_TRY_RECEIVE => sys_receive(context_ptr, arg1, false),
+        SYSCALL_AWAIT_ANY => sys_await_any(context_ptr, arg1 as *const u32, arg2),
         SYSCALL_SEND => sys_send(context_ptr, syscall_id, arg1, arg2, arg3),
         SYSCALL_SENDRECEIVE => sys_send(context_ptr, syscall_id, arg1, arg2, arg3), // sys_sendreceive
+        SYSCALL_SEND_TIMEOUT => sys_send(context_ptr, syscall_id, arg1, arg2, arg3),
+        SYSCALL_SENDRECEIVE_TIMEOUT => sys_send(context_ptr, syscall_id, arg1, arg2, arg3),
         SYSCALL_OPEN => sys_open(context_ptr, arg1 as *const u8, arg2 as usize),
         SYSCALL_MALLOC => sys_malloc(context_ptr, arg1, arg2),
         SYSCALL_FREE => sys_free(context_ptr, arg1),
@@ -307,6 +419,29 @@ extern "C" fn dispatch_syscall(context_ptr: *mut Context, syscall_id: u64,
         SYSCALL_UMOUNT => sys_umount(context_ptr, arg1 as *const u8, arg2),
         SYSCALL_CLOSE => sys_close(context_ptr, arg1),
         SYSCALL_AWAIT_INTERRUPT => sys_await_interrupt(context_ptr, arg1),
+        SYSCALL_NICE => sys_nice(context_ptr, arg1),
+        SYSCALL_GET_REGISTERS => sys_get_registers(context_ptr, arg1),
+        SYSCALL_SIGNAL_MASK => sys_signal_mask(context_ptr, arg1, arg2),
+        SYSCALL_MEMORY_STATS => sys_memory_stats(context_ptr),
+        SYSCALL_SET_IRQ_AFFINITY => sys_set_irq_affinity(context_ptr, arg1, arg2),
+        SYSCALL_SHARE_MEMORY => sys_share_memory(context_ptr, syscall_id, arg1),
+        SYSCALL_SAMPLE_USAGE => sys_sample_usage(context_ptr),
+        SYSCALL_READ_KERNEL_LOG => sys_read_kernel_log(context_ptr, arg1, arg2),
+        SYSCALL_READ_PROCESS_MEMORY => sys_read_process_memory(context_ptr, arg1, arg2, arg3),
+        SYSCALL_SLEEP => sys_sleep(context_ptr, arg1),
+        SYSCALL_GET_TID => sys_get_tid(context_ptr),
+        SYSCALL_WAIT => sys_wait(context_ptr, arg1),
+        SYSCALL_MAP_DEVICE_MEMORY => sys_map_device_memory(context_ptr, arg1, arg2),
+        SYSCALL_LIST_THREADS => sys_list_threads(context_ptr),
+        SYSCALL_GETENV => sys_getenv(context_ptr, arg1 as *const u8, arg2),
+        SYSCALL_SETENV => sys_setenv(context_ptr, arg1 as *const u8, arg2, arg3 as *const u8),
+        SYSCALL_UNSETENV => sys_unsetenv(context_ptr, arg1 as *const u8, arg2),
+        SYSCALL_LISTENV => sys_listenv(context_ptr),
+        SYSCALL_FUTEX_WAIT => sys_futex_wait(context_ptr, arg1, arg2 as u32),
+        SYSCALL_FUTEX_WAKE => sys_futex_wake(context_ptr, arg1, arg2),
+        SYSCALL_SPAWN_THREAD => process::spawn_thread(context, arg1, arg2, arg3),
+        SYSCALL_MEMORY_SIZE => sys_memory_size(context_ptr, arg1),
+        SYSCALL_CHDIR => sys_chdir(context_ptr, arg1 as *const u8, arg2),
         _ => println!("Unknown syscall {:?} {} {} {}",
                       context_ptr, syscall_id, arg1, arg2)
     }
@@ -326,7 +461,11 @@ fn sys_debug_write(ptr: *const u8, len:usize) {
 }
 
 
-fn sys_receive(context_ptr: *mut Context, handle: u64) {
+/// This handles syscall_receive and syscall_try_receive
+///
+/// If `blocking` is false then the thread always returns, with
+/// SYSCALL_ERROR_WOULDBLOCK if no thread is waiting to send.
+fn sys_receive(context_ptr: *mut Context, handle: u64, blocking: bool) {
     // Extract the current thread
     if let Some(mut thread) = process::take_current_thread() {
         let current_tid = thread.tid();
@@ -334,7 +473,18 @@ fn sys_receive(context_ptr: *mut Context, handle: u64) {
 
         // Get the Rendezvous and call
         if let Some(rdv) = thread.rendezvous(handle) {
-            let (thread1, thread2) = rdv.write().receive(thread);
+            if Arc::strong_count(&rdv) == 2 &&
+                matches!(*rdv.read(), rendezvous::Rendezvous::Empty) {
+                    // Other end closed, and no message waiting
+                    thread.return_error(SYSCALL_ERROR_CLOSED);
+                    process::set_current_thread(thread);
+                    return;
+                }
+            let (thread1, thread2) = if blocking {
+                rdv.write().receive(thread)
+            } else {
+                rdv.write().try_receive(thread)
+            };
             // thread1 should be started asap
             // thread2 should be scheduled
 
@@ -366,7 +516,62 @@ fn sys_receive(context_ptr: *mut Context, handle: u64) {
     }
 }
 
-/// This handles both syscall_send and syscall_sendreceive
+/// Receive a message from any of RSI handles, in an array at RDI
+///
+/// The message is returned as for receive, with the index of the
+/// handle in R8.
+fn sys_await_any(context_ptr: *mut Context, handles_ptr: *const u32, count: u64) {
+    if count == 0 || count > MAX_AWAIT_HANDLES {
+        unsafe {(*context_ptr).rax = SYSCALL_ERROR_PARAM;}
+        return;
+    }
+    let handles = unsafe {slice::from_raw_parts(handles_ptr, count as usize)};
+
+    if let Some(mut thread) = process::take_current_thread() {
+        let current_tid = thread.tid();
+        thread.set_context(context_ptr);
+
+        let mut rdvs = Vec::with_capacity(handles.len());
+        for &handle in handles {
+            match thread.rendezvous(handle as u64) {
+                Some(rdv) => rdvs.push(rdv),
+                None => {
+                    // Missing handle
+                    thread.return_error(SYSCALL_ERROR_INVALID_HANDLE);
+                    process::set_current_thread(thread);
+                    return;
+                }
+            }
+        }
+
+        let (thread1, thread2) = rendezvous::await_any(thread, &rdvs);
+
+        let mut returning = false;
+        for maybe_thread in [thread2, thread1] {
+            if let Some(t) = maybe_thread {
+                if t.tid() == current_tid {
+                    // Same thread -> return
+                    process::set_current_thread(t);
+                    returning = true;
+                } else {
+                    process::schedule_thread(t);
+                }
+            }
+        }
+
+        if !returning {
+            // Waiting for a message
+            drop(rdvs); // Not returning from launch_thread
+            let new_context_addr = process::schedule_next(context_ptr as usize);
+            interrupts::launch_thread(new_context_addr);
+        }
+    }
+}
+
+/// This handles syscall_send, syscall_send_timeout, syscall_sendreceive
+/// and syscall_sendreceive_timeout
+///
+/// For the timeout variants the timeout in microseconds is in R8
 fn sys_send(
     context_ptr: *mut Context,
     syscall_id: u64,
@@ -392,20 +597,50 @@ fn sys_send(
 
             match Message::from_values(&mut thread, syscall_id, data1, data2, data3) {
                 Ok(message) => {
+                    // Remove timeouts from any previous send, so they
+                    // can't cancel this one or its reply
+                    rendezvous::clear_send_timeout(current_tid);
                     let (thread1, thread2) = match syscall_id & SYSCALL_MASK {
                         SYSCALL_SEND => rdv.write().send(
                             Some(thread),
                             message),
+                        SYSCALL_SEND_TIMEOUT => {
+                            let timeout = unsafe {(*context_ptr).r8} as u64;
+                            let result = rdv.write().send(Some(thread), message);
+                            if result.0.is_none() {
+                                // Thread is waiting in the rendezvous
+                                rendezvous::add_send_timeout(
+                                    &rdv, current_tid,
+                                    time::microseconds_monotonic().saturating_add(timeout));
+                            }
+                            result
+                        }
                         SYSCALL_SENDRECEIVE => rdv.write().send_receive(
                             thread,
                             message),
+                        SYSCALL_SENDRECEIVE_TIMEOUT => {
+                            let timeout = unsafe {(*context_ptr).r8} as u64;
+                            let result = rdv.write().send_receive(thread, message);
+                            let returned = [&result.0, &result.1].iter().any(
+                                |t| t.as_ref().map_or(false, |t| t.tid() == current_tid));
+                            if !returned {
+                                // Waiting to send, or for the reply
+                                rendezvous::add_send_timeout(
+                                    &rdv, current_tid,
+                                    time::microseconds_monotonic().saturating_add(timeout));
+                            }
+                            result
+                        }
                         _ => panic!("Internal error")
                     };
+                    // A thread in await_any may now receive the message
+                    let (thread3, thread4) = rendezvous::wake_any_waiter(&rdv);
+
                     // thread1 should be started asap
                     // thread2 should be scheduled
 
                     let mut returning = false;
-                    for maybe_thread in [thread2, thread1] {
+                    for maybe_thread in [thread2, thread1, thread4, thread3] {
                         if let Some(t) = maybe_thread {
                             if t.tid() == current_tid {
                                 // Same thread -> return
@@ -477,6 +712,9 @@ fn sys_open(
 ///  - handle in RDI
 ///  - starting virtual address in RSI
 ///  - starting physical address in RDX
+///
+/// More than MAX_CHUNK_PAGES pages would overflow into the next
+/// chunk, so is SYSCALL_ERROR_PARAM.
 fn sys_malloc(
     context_ptr: *mut Context,
     num_pages: u64,
@@ -484,6 +722,14 @@ fn sys_malloc(
 ) {
     let context = unsafe {&mut (*context_ptr)};
 
+    if num_pages == 0 || num_pages > MAX_CHUNK_PAGES {
+        context.rax = SYSCALL_ERROR_PARAM;
+        context.rdi = 0;
+        context.rsi = 0;
+        context.rdx = 0;
+        return;
+    }
+
     match process::new_memory_chunk(
         num_pages,
         max_physaddr) {
@@ -501,6 +747,72 @@ fn sys_malloc(
     }
 }
 
+/// Create a memory chunk which maps the same memory as the chunk
+/// containing the given virtual address.
+///
+/// Returns the new chunk in RDI. It can be sent to another process,
+/// which then shares the memory. If syscall_id contains
+/// SHARE_READ_ONLY then the new chunk can't be written.
+fn sys_share_memory(
+    context_ptr: *mut Context,
+    syscall_id: u64,
+    virtaddr: u64
+) {
+    let context = unsafe {&mut (*context_ptr)};
+    let writable = syscall_id & SHARE_READ_ONLY == 0;
+
+    // Non-canonical addresses from user space would panic VirtAddr::new
+    let virtaddr = match VirtAddr::try_new(virtaddr) {
+        Ok(virtaddr) => virtaddr,
+        Err(_) => {
+            context.rax = SYSCALL_ERROR_PARAM;
+            context.rdi = 0;
+            return;
+        }
+    };
+    match process::share_memory_chunk(virtaddr, writable) {
+        Ok(new_virtaddr) => {
+            context.rax = 0; // No error
+            context.rdi = new_virtaddr.as_u64() as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Size of the memory chunk containing the given virtual address
+///
+/// Returns the number of bytes mapped in RDI, so a process can check
+/// that a chunk it received is large enough before using it.
+fn sys_memory_size(
+    context_ptr: *mut Context,
+    virtaddr: u64
+) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    // Non-canonical addresses from user space would panic VirtAddr::new
+    let virtaddr = match VirtAddr::try_new(virtaddr) {
+        Ok(virtaddr) => virtaddr,
+        Err(_) => {
+            context.rax = SYSCALL_ERROR_PARAM;
+            context.rdi = 0;
+            return;
+        }
+    };
+    match process::memory_chunk_size(virtaddr) {
+        Ok(size) => {
+            context.rax = 0; // No error
+            context.rdi = size as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
 /// Free a memory chunk containing the given virtual address
 fn sys_free(
     context_ptr: *mut Context,
@@ -508,7 +820,15 @@ fn sys_free(
 ) {
     let context = unsafe {&mut (*context_ptr)};
 
-    match process::free_memory_chunk(VirtAddr::new(virtaddr)) {
+    // Non-canonical addresses from user space would panic VirtAddr::new
+    let virtaddr = match VirtAddr::try_new(virtaddr) {
+        Ok(virtaddr) => virtaddr,
+        Err(_) => {
+            context.rax = SYSCALL_ERROR_PARAM;
+            return;
+        }
+    };
+    match process::free_memory_chunk(virtaddr) {
         Ok(()) => {
             context.rax = 0; // No error
         }
@@ -518,8 +838,15 @@ fn sys_free(
     }
 }
 
-/// Yield to another process
+/// Give up the rest of the time slice
+///
+/// The current context is saved at syscall entry, and the thread
+/// moved to the back of the running queue. Returns immediately if
+/// no other threads can run.
 fn sys_yield(context_ptr: *mut Context) {
+    if !process::other_threads_runnable() {
+        return;
+    }
     let next_stack = process::schedule_next(context_ptr as usize);
     interrupts::launch_thread(next_stack);
 }
@@ -575,10 +902,18 @@ fn sys_copy_rendezvous(context_ptr: *mut Context, handle: u64) {
 ///  - Length of parameter string (16 bits of syscall_id)
 ///  - Flags controlling permissions (8 bits of syscall_id)
 ///    - I/O privileges: EXEC_PERM_IO
+///    - Syscall filter in R8: EXEC_SYSCALL_FILTER
+///    - Arguments in R9 (pointer) and R10 (length): EXEC_ARGS.
+///      The layout is checked by process::valid_args
+///    - Scheduler priority: EXEC_PRIORITY_HIGH or EXEC_PRIORITY_LOW,
+///      otherwise PRIORITY_NORMAL. High priority is ignored unless
+///      the caller has I/O privileges.
 ///    - Thread fork?
 ///    - Malloc?
 ///    - Exec?
 ///    - Interrupts
+///
+/// The new process gets a copy of the caller's environment variables.
 fn sys_exec(
     context_ptr: *mut Context,
     syscall_id: u64,
@@ -610,6 +945,32 @@ fn sys_exec(
             return;
         }
 
+        // Copy arguments before the page table changes
+        let args = if flags & EXEC_ARGS != 0 {
+            let args_length = context.r10;
+            if args_length > process::MAX_ARGS_SIZE {
+                thread.return_error(SYSCALL_ERROR_PARAM);
+                process::set_current_thread(thread);
+                return;
+            }
+            let args_slice = match user_slice(context.r9 as *const u8, args_length as u64) {
+                Ok(args_slice) => args_slice,
+                Err(code) => {
+                    thread.return_error(code);
+                    process::set_current_thread(thread);
+                    return;
+                }
+            };
+            if !process::valid_args(args_slice) {
+                thread.return_error(SYSCALL_ERROR_PARAM);
+                process::set_current_thread(thread);
+                return;
+            }
+            Vec::from(args_slice)
+        } else {
+            Vec::new()
+        };
+
         // Get the Rendezvous handles for stdin & stdout
         let stdin = if let Some(rdv) = thread.take_rendezvous(stdin_handle) {
             rdv
@@ -633,6 +994,26 @@ fn sys_exec(
         let io_privileges = (flags & EXEC_PERM_IO == EXEC_PERM_IO) &&
             ((context.rflags & 0x3000) == 0x3000);
 
+        let priority = if (flags & EXEC_PRIORITY_HIGH != 0) &&
+            ((context.rflags & 0x3000) == 0x3000) {
+            process::PRIORITY_HIGH
+        } else if flags & EXEC_PRIORITY_LOW != 0 {
+            process::PRIORITY_LOW
+        } else {
+            process::PRIORITY_NORMAL
+        };
+
+        // Child can't have more syscalls than the parent
+        let syscall_filter = thread.syscall_filter().tighten(
+            process::SyscallFilter {
+                allowed: if flags & EXEC_SYSCALL_FILTER != 0 {
+                    context.r8 as u64
+                } else {
+                    u64::MAX
+                },
+                kill: flags & EXEC_FILTER_KILL != 0
+            });
+
         // Get the VFS for this process
         // This will be set from the param string
         // For now the same as parent
@@ -767,7 +1148,11 @@ fn sys_exec(
                     stdin, stdout
                 ]),
                 io_privileges,
-                mounts
+                mounts,
+                env: thread.env(),
+                syscall_filter,
+                args,
+                priority
             }) {
             Ok(new_thread) => {
                 let tid = new_thread.tid() as usize;
@@ -775,9 +1160,10 @@ fn sys_exec(
                 context.rax = 0; // Success!
                 context.rdi = tid; // Thread ID in rdi
             }
-            Err(msg) => {
-                println!("sys_exec error: {}", msg);
-                thread.return_error(SYSCALL_ERROR_THREAD);
+            Err(err) => {
+                // The message is only logged. The caller gets an error code
+                println!("sys_exec error: {}", err);
+                thread.return_error(err.code());
             }
         }
         process::set_current_thread(thread);
@@ -905,27 +1291,48 @@ fn sys_close(context_ptr: *mut Context, handle: u64) {
 
         // Take the Rendezvous from the thread
         if let Some(rdv) = thread.take_rendezvous(handle) {
-            if Arc::strong_count(&rdv) == 2 {
+            let weak = Arc::downgrade(&rdv);
+            let mut woken = Vec::new();
+            let closed = Arc::strong_count(&rdv) == 2;
+            if closed {
                 // Only this handle (rdv) and one other
                 // All other handles have been dropped
-                let option_thread = rdv.write().close();
-                if let Some(waiting_thread) = option_thread {
-                    // A thread was waiting -> Switch to it
-                    process::schedule_thread(thread);
+                woken.extend(rdv.write().close());
+            }
+            drop(rdv);
+            if closed || weak.strong_count() == 0 {
+                // Threads in await_any on it. Other copies of the
+                // handle still work, so leave their waiters alone
+                woken.extend(rendezvous::close_any_waiters(&weak));
+            }
+            if !woken.is_empty() {
+                // A thread was waiting -> Switch to it
+                process::schedule_thread(thread);
+                for waiting_thread in woken {
                     process::schedule_thread(waiting_thread);
-
-                    drop(rdv); // Not returning from launch_thread
-                    let new_context_addr = process::schedule_next(context_ptr as usize);
-                    interrupts::launch_thread(new_context_addr);
                 }
+                let new_context_addr = process::schedule_next(context_ptr as usize);
+                interrupts::launch_thread(new_context_addr);
             }
-            // Drop rendezvous
         }
         process::set_current_thread(thread);
     }
 }
 
-fn sys_await_interrupt(context_ptr: *mut Context, _interrupt_number: u64) {
+fn sys_await_interrupt(context_ptr: *mut Context, irq: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+    if !interrupts::can_await_interrupt(irq) {
+        context.rax = SYSCALL_ERROR_PARAM;
+        return;
+    }
+
+    // A byte which arrived while no thread was waiting
+    if let Some(byte) = interrupts::take_interrupt_byte(irq) {
+        context.rax = 0;
+        context.rdi = byte as usize;
+        return;
+    }
+
     // Extract the current thread
     if let Some(mut thread) = process::take_current_thread() {
         thread.set_context(context_ptr);
@@ -933,10 +1340,700 @@ fn sys_await_interrupt(context_ptr: *mut Context, _interrupt_number: u64) {
         // Check if this thread has permission to wait for interrupts
 
         // Pass thread to interrupt handler
-        interrupts::await_interrupt(thread);
+        interrupts::await_interrupt(irq, thread);
 
         // Schedule another thread
         let new_context_addr = process::schedule_next(context_ptr as usize);
         interrupts::launch_thread(new_context_addr);
     }
 }
+
+/// Change the priority of the current thread
+///
+/// Takes the change in priority as first argument (syscall RDI),
+/// the low 8 bits interpreted as a signed integer.
+/// Returns the new priority in RDI
+fn sys_nice(context_ptr: *mut Context, delta: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    match process::nice_current_thread(delta as u8 as i8) {
+        Ok(priority) => {
+            context.rax = 0; // No error
+            context.rdi = priority as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+        }
+    }
+}
+
+/// Change the signal mask of the calling thread
+///
+/// If the first argument (syscall RDI) is non-zero then the signals
+/// in the second argument (RSI) are blocked, otherwise the mask is
+/// replaced. Returns the old mask in RDI, and the pending signals
+/// which are no longer masked in RSI.
+///
+/// Masks are per-thread, not per-process.
+fn sys_signal_mask(context_ptr: *mut Context, block: u64, mask: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    match process::signal_mask_current_thread(block != 0, mask as u32) {
+        Ok((old_mask, pending)) => {
+            context.rax = 0; // No error
+            context.rdi = old_mask as usize;
+            context.rsi = pending as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+        }
+    }
+}
+
+/// Copy the saved registers of a thread into a new memory chunk
+///
+/// Takes the thread ID as first argument (syscall RDI).
+/// Returns the memory chunk address in RDI.
+///
+/// Only threads with I/O privileges can read registers. The target
+/// thread must be the caller, or waiting in the running queue;
+/// threads which have exited or are blocked in a Rendezvous
+/// return SYSCALL_ERROR_NOTFOUND.
+///
+/// Note: When multiple CPUs are supported, a thread running on
+///       another CPU will need to be stopped before its registers
+///       can be read.
+fn sys_get_registers(context_ptr: *mut Context, tid: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    if (context.rflags & 0x3000) != 0x3000 {
+        // Caller doesn't have I/O privileges
+        context.rax = SYSCALL_ERROR_DENIED;
+        return;
+    }
+
+    let registers = if process::current_tid() == Some(tid) {
+        // Registers saved on syscall entry
+        context.clone()
+    } else {
+        match process::thread_context(tid) {
+            Some(registers) => registers,
+            None => {
+                context.rax = SYSCALL_ERROR_NOTFOUND;
+                return;
+            }
+        }
+    };
+
+    match process::new_memory_chunk(
+        1, // One page
+        0xFFFF_FFFF_FFFF_FFFF) {
+        Ok((virtaddr, _physaddr)) => {
+            unsafe {
+                ptr::write(virtaddr.as_u64() as *mut Context, registers);
+            }
+            context.rax = 0; // No error
+            context.rdi = virtaddr.as_u64() as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Copy kernel memory usage statistics into a new memory chunk
+///
+/// Returns the memory chunk address in RDI, containing a
+/// memory::MemoryStats struct.
+fn sys_memory_stats(context_ptr: *mut Context) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    // Measure before allocating the chunk
+    let stats = memory::memory_stats();
+
+    match process::new_memory_chunk(
+        1, // One page
+        0xFFFF_FFFF_FFFF_FFFF) {
+        Ok((virtaddr, _physaddr)) => {
+            unsafe {
+                ptr::write(virtaddr.as_u64() as *mut memory::MemoryStats, stats);
+            }
+            context.rax = 0; // No error
+            context.rdi = virtaddr.as_u64() as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Copy a sample of thread usage into a new memory chunk
+///
+/// Returns the memory chunk in RDI and the number of
+/// process::ThreadUsage entries in RSI. All live threads are
+/// sampled: see process::sample_usage.
+fn sys_sample_usage(context_ptr: *mut Context) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    // Sample before allocating the chunk
+    let samples = process::sample_usage();
+    let num_pages = (samples.len() * mem::size_of::<process::ThreadUsage>())
+        .div_ceil(4096).max(1);
+
+    match process::new_memory_chunk(
+        num_pages as u64,
+        0xFFFF_FFFF_FFFF_FFFF) {
+        Ok((virtaddr, _physaddr)) => {
+            unsafe {
+                ptr::copy_nonoverlapping(samples.as_ptr(),
+                                         virtaddr.as_u64() as *mut process::ThreadUsage,
+                                         samples.len());
+            }
+            context.rax = 0; // No error
+            context.rdi = virtaddr.as_u64() as usize;
+            context.rsi = samples.len();
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Copy a list of threads into a new memory chunk
+///
+/// Returns the memory chunk in RDI and the number of
+/// process::ThreadInfo entries in RSI. Instruction pointers are
+/// only included if the caller has I/O privileges, and are
+/// otherwise zero.
+fn sys_list_threads(context_ptr: *mut Context) {
+    let context = unsafe {&mut (*context_ptr)};
+    let privileged = (context.rflags & 0x3000) == 0x3000;
+
+    // Snapshot before allocating the chunk
+    let mut threads = process::list_threads();
+    for thread in threads.iter_mut() {
+        thread.rip = if !privileged {
+            0
+        } else if thread.flags & process::THREAD_INFO_CURRENT != 0 {
+            // Where the caller entered the kernel
+            context.rip as u64
+        } else {
+            thread.rip
+        };
+    }
+    let num_pages = (threads.len() * mem::size_of::<process::ThreadInfo>())
+        .div_ceil(4096).max(1);
+
+    match process::new_memory_chunk(
+        num_pages as u64,
+        0xFFFF_FFFF_FFFF_FFFF) {
+        Ok((virtaddr, _physaddr)) => {
+            unsafe {
+                ptr::copy_nonoverlapping(threads.as_ptr(),
+                                         virtaddr.as_u64() as *mut process::ThreadInfo,
+                                         threads.len());
+            }
+            context.rax = 0; // No error
+            context.rdi = virtaddr.as_u64() as usize;
+            context.rsi = threads.len();
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Exclusive upper limit of user addresses: the top of the lower
+/// half of the canonical address space
+const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// A slice of the calling process' memory
+///
+/// Every page of [ptr, ptr + length) must be below USER_SPACE_END
+/// and mapped user accessible, otherwise SYSCALL_ERROR_PARAM. This
+/// stops a process reading kernel memory through a syscall, or
+/// faulting the kernel with an unmapped pointer. Pages allocated on
+/// demand which haven't been touched are not mapped yet, so are
+/// also rejected.
+fn user_slice<'a>(ptr: *const u8, length: u64) -> Result<&'a [u8], usize> {
+    if length == 0 {
+        return Ok(&[]);
+    }
+    let start = ptr as u64;
+    let end = match start.checked_add(length) {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return Err(SYSCALL_ERROR_PARAM)
+    };
+    let page_table_physaddr = memory::active_pagetable_physaddr();
+    let mut page = start & !0xFFF;
+    while page < end {
+        if memory::translate_user_address(page_table_physaddr, page).is_none() {
+            return Err(SYSCALL_ERROR_PARAM);
+        }
+        page += 4096;
+    }
+    Ok(unsafe {slice::from_raw_parts(ptr, length as usize)})
+}
+
+/// Read a UTF-8 string from user memory. See user_slice
+fn user_str<'a>(ptr: *const u8, length: u64) -> Result<&'a str, usize> {
+    if length > env::MAX_VALUE_SIZE as u64 {
+        return Err(SYSCALL_ERROR_PARAM);
+    }
+    str::from_utf8(user_slice(ptr, length)?).map_err(|_| SYSCALL_ERROR_UTF8)
+}
+
+/// Copy bytes into a new memory chunk, returning the chunk in RDI
+/// and the length in RSI. Nothing is allocated for no bytes, and
+/// the chunk in RDI is zero.
+fn return_bytes(context: &mut Context, bytes: &[u8]) {
+    if bytes.is_empty() {
+        context.rax = 0;
+        context.rdi = 0;
+        context.rsi = 0;
+        return;
+    }
+    match process::new_memory_chunk(
+        bytes.len().div_ceil(4096) as u64,
+        0xFFFF_FFFF_FFFF_FFFF) {
+        Ok((virtaddr, _physaddr)) => {
+            unsafe {
+                ptr::copy_nonoverlapping(bytes.as_ptr(),
+                                         virtaddr.as_u64() as *mut u8,
+                                         bytes.len());
+            }
+            context.rax = 0; // No error
+            context.rdi = virtaddr.as_u64() as usize;
+            context.rsi = bytes.len();
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+            context.rsi = 0;
+        }
+    }
+}
+
+/// Get the value of an environment variable
+///
+/// Returns the value in a memory chunk (RDI) and its length (RSI),
+/// or SYSCALL_ERROR_NOTFOUND if the variable isn't set. An empty
+/// value has no memory chunk.
+fn sys_getenv(context_ptr: *mut Context, key_ptr: *const u8, key_length: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+    let key = match user_str(key_ptr, key_length) {
+        Ok(key) => key,
+        Err(code) => {
+            context.rax = code;
+            return;
+        }
+    };
+    // Copied from the process before allocating the chunk
+    let env = match process::take_current_thread() {
+        Some(mut thread) => {
+            thread.set_context(context_ptr);
+            let env = thread.env();
+            process::set_current_thread(thread);
+            env
+        }
+        None => return
+    };
+    match env.get(key) {
+        Some(value) => return_bytes(context, value.as_bytes()),
+        None => context.rax = SYSCALL_ERROR_NOTFOUND
+    }
+}
+
+/// Set an environment variable of the calling process
+///
+/// The key is in RDI (pointer) and RSI (length), the value in RDX
+/// (pointer) and R8 (length). See env::Environment::set for limits.
+///
+/// Requires I/O privileges, otherwise SYSCALL_ERROR_DENIED: the
+/// environment is configuration set up by privileged processes such
+/// as init, which other programs can read and pass on to their
+/// children but not change.
+fn sys_setenv(
+    context_ptr: *mut Context,
+    key_ptr: *const u8,
+    key_length: u64,
+    value_ptr: *const u8
+) {
+    let context = unsafe {&mut (*context_ptr)};
+    if (context.rflags & 0x3000) != 0x3000 {
+        context.rax = SYSCALL_ERROR_DENIED;
+        return;
+    }
+    let value_length = context.r8 as u64;
+    let result = user_str(key_ptr, key_length)
+        .and_then(|key| Ok((key, user_str(value_ptr, value_length)?)));
+    let (key, value) = match result {
+        Ok(key_value) => key_value,
+        Err(code) => {
+            context.rax = code;
+            return;
+        }
+    };
+    if let Some(mut thread) = process::take_current_thread() {
+        thread.set_context(context_ptr);
+        context.rax = match thread.set_env(key, value) {
+            Ok(()) => 0,
+            Err(code) => code
+        };
+        process::set_current_thread(thread);
+    }
+}
+
+/// Remove an environment variable of the calling process
+///
+/// Returns SYSCALL_ERROR_NOTFOUND if it wasn't set, or
+/// SYSCALL_ERROR_DENIED for the working directory or if the caller
+/// doesn't have I/O privileges (see sys_setenv).
+fn sys_unsetenv(context_ptr: *mut Context, key_ptr: *const u8, key_length: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+    if (context.rflags & 0x3000) != 0x3000 {
+        context.rax = SYSCALL_ERROR_DENIED;
+        return;
+    }
+    let key = match user_str(key_ptr, key_length) {
+        Ok(key) => key,
+        Err(code) => {
+            context.rax = code;
+            return;
+        }
+    };
+    if let Some(mut thread) = process::take_current_thread() {
+        thread.set_context(context_ptr);
+        context.rax = match thread.remove_env(key) {
+            Ok(()) => 0,
+            Err(code) => code
+        };
+        process::set_current_thread(thread);
+    }
+}
+
+/// Set the working directory of the calling process
+///
+/// The path in RDI (pointer) and RSI (length) must be absolute. It
+/// is read with getenv as env::CWD_KEY, which setenv can't change.
+fn sys_chdir(context_ptr: *mut Context, path_ptr: *const u8, path_length: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+    let path = match user_str(path_ptr, path_length) {
+        Ok(path) => path,
+        Err(code) => {
+            context.rax = code;
+            return;
+        }
+    };
+    if let Some(mut thread) = process::take_current_thread() {
+        thread.set_context(context_ptr);
+        context.rax = match thread.set_cwd(path) {
+            Ok(()) => 0,
+            Err(code) => code
+        };
+        process::set_current_thread(thread);
+    }
+}
+
+/// Copy all environment variables into a memory chunk
+///
+/// Each is a KEY=VALUE string followed by NUL. Returns the chunk in
+/// RDI and the length in RSI, with no chunk if there are none.
+fn sys_listenv(context_ptr: *mut Context) {
+    let context = unsafe {&mut (*context_ptr)};
+    let env = match process::take_current_thread() {
+        Some(mut thread) => {
+            thread.set_context(context_ptr);
+            let env = thread.env();
+            process::set_current_thread(thread);
+            env
+        }
+        None => return
+    };
+    return_bytes(context, &env.to_bytes());
+}
+
+/// Physical address of a futex word in the current address space
+///
+/// The address must be 4-byte aligned, so the word is in one page,
+/// and mapped user memory.
+fn futex_physaddr(address: u64) -> Result<u64, usize> {
+    if address % 4 != 0 {
+        return Err(SYSCALL_ERROR_PARAM);
+    }
+    let page_table_physaddr = memory::active_pagetable_physaddr();
+    if memory::translate_user_address(page_table_physaddr, address).is_none() {
+        return Err(SYSCALL_ERROR_PARAM);
+    }
+    // A zero or copy-on-write page moves to a new frame when first
+    // written, so waiters and wakers would use different keys
+    memory::resolve_ondemand_frame(VirtAddr::new(address))
+        .map_err(|_| SYSCALL_ERROR_MEMALLOC)?;
+    memory::translate_user_address(page_table_physaddr, address)
+        .map(|physaddr| physaddr.as_u64())
+        .ok_or(SYSCALL_ERROR_PARAM)
+}
+
+/// Suspend the thread if the u32 at `address` equals `expected`
+///
+/// Returns SYSCALL_ERROR_WOULDBLOCK without waiting if the value is
+/// different, e.g. because another thread changed it after the caller
+/// read it. Interrupts are disabled during syscalls, so no wake can
+/// happen between the check and the wait.
+fn sys_futex_wait(context_ptr: *mut Context, address: u64, expected: u32) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    let physaddr = match futex_physaddr(address) {
+        Ok(physaddr) => physaddr,
+        Err(err) => {
+            context.rax = err;
+            return;
+        }
+    };
+    // Mapped, aligned and in user memory
+    let value = unsafe {ptr::read_volatile(address as *const u32)};
+    if value != expected {
+        context.rax = SYSCALL_ERROR_WOULDBLOCK;
+        return;
+    }
+
+    if let Some(mut thread) = process::take_current_thread() {
+        context.rax = 0; // No error when woken
+        thread.set_context(context_ptr);
+        futex::wait(thread, physaddr);
+
+        let new_context_addr = process::schedule_next(context_ptr as usize);
+        interrupts::launch_thread(new_context_addr);
+    }
+}
+
+/// Wake up to `count` threads waiting on the u32 at `address`
+///
+/// Returns the number of threads woken in RDI.
+fn sys_futex_wake(context_ptr: *mut Context, address: u64, count: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    match futex_physaddr(address) {
+        Ok(physaddr) => {
+            context.rax = 0; // No error
+            context.rdi = futex::wake(physaddr, count as usize);
+        }
+        Err(err) => {
+            context.rax = err;
+        }
+    }
+}
+
+/// Copy kernel messages after a cursor into a new memory chunk
+///
+/// Takes the cursor in RDI and flags in RSI. Returns the memory chunk
+/// in RDI, the number of bytes in RSI, and the cursor of the first
+/// byte in RDX. If there are no messages then RDI and RSI are zero.
+///
+/// With KERNEL_LOG_WAIT, if there are no messages after the cursor
+/// then waits until there are, and returns no messages. The caller
+/// should then read again.
+fn sys_read_kernel_log(context_ptr: *mut Context, cursor: u64, flags: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    let available = kmsg::end().saturating_sub(cursor) as usize;
+    if available == 0 {
+        context.rax = 0; // No error
+        context.rdi = 0;
+        context.rsi = 0;
+        context.rdx = cursor as usize;
+
+        if flags & KERNEL_LOG_WAIT != 0 {
+            if let Some(mut thread) = process::take_current_thread() {
+                thread.set_context(context_ptr);
+                kmsg::wait(thread, cursor);
+
+                let new_context_addr = process::schedule_next(context_ptr as usize);
+                interrupts::launch_thread(new_context_addr);
+            }
+        }
+        return;
+    }
+
+    let length = cmp::min(available, kmsg::LOG_SIZE);
+    match process::new_memory_chunk(
+        length.div_ceil(4096) as u64,
+        0xFFFF_FFFF_FFFF_FFFF) {
+        Ok((virtaddr, _physaddr)) => {
+            let buf = unsafe {
+                slice::from_raw_parts_mut(virtaddr.as_u64() as *mut u8, length)};
+            let (count, start) = kmsg::read(cursor, buf);
+            context.rax = 0; // No error
+            context.rdi = virtaddr.as_u64() as usize;
+            context.rsi = count;
+            context.rdx = start as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Copy memory from the process containing thread `tid`
+///
+/// Takes the thread ID in RDI, the address in RSI and the number of
+/// bytes in RDX, at most MAX_READ_PROCESS_MEMORY. Returns a memory
+/// chunk in RDI containing the bytes, and the number copied in RSI.
+/// This is less than requested if an unmapped page was reached.
+///
+/// Only threads with I/O privileges can read other processes.
+fn sys_read_process_memory(context_ptr: *mut Context, tid: u64, address: u64, length: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    if (context.rflags & 0x3000) != 0x3000 {
+        // Caller doesn't have I/O privileges
+        context.rax = SYSCALL_ERROR_DENIED;
+        return;
+    }
+
+    if length == 0 || length > MAX_READ_PROCESS_MEMORY {
+        context.rax = SYSCALL_ERROR_PARAM;
+        return;
+    }
+
+    match process::new_memory_chunk(
+        length.div_ceil(4096),
+        0xFFFF_FFFF_FFFF_FFFF) {
+        Ok((virtaddr, _physaddr)) => {
+            let buf = unsafe {
+                slice::from_raw_parts_mut(virtaddr.as_u64() as *mut u8,
+                                          length as usize)};
+            match process::read_thread_memory(tid, address, buf) {
+                Some(count) => {
+                    context.rax = 0; // No error
+                    context.rdi = virtaddr.as_u64() as usize;
+                    context.rsi = count;
+                }
+                None => {
+                    let _ = process::free_memory_chunk(virtaddr);
+                    context.rax = SYSCALL_ERROR_NOTFOUND;
+                    context.rdi = 0;
+                }
+            }
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Deliver a device interrupt to a chosen CPU
+///
+/// Takes the IRQ line in RDI and the target CPU in RSI. Only threads
+/// with I/O privileges can change interrupt routing.
+fn sys_set_irq_affinity(context_ptr: *mut Context, irq: u64, cpu: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    if (context.rflags & 0x3000) != 0x3000 {
+        // Caller doesn't have I/O privileges
+        context.rax = SYSCALL_ERROR_DENIED;
+        return;
+    }
+
+    context.rax = match interrupts::set_irq_affinity(irq, cpu) {
+        Ok(()) => 0,
+        Err(code) => code
+    };
+}
+
+/// Map a range of physical memory used by a device
+///
+/// Takes the page aligned physical address in RDI and the number of
+/// pages in RSI, and returns a memory chunk in RDI. Only threads with
+/// I/O privileges can map device memory, since the range isn't
+/// checked against the RAM used by the kernel and other processes.
+fn sys_map_device_memory(context_ptr: *mut Context, physaddr: u64, num_pages: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    if (context.rflags & 0x3000) != 0x3000 {
+        // Caller doesn't have I/O privileges
+        context.rax = SYSCALL_ERROR_DENIED;
+        return;
+    }
+    if physaddr & 0xFFF != 0 || num_pages == 0 || num_pages > MAX_CHUNK_PAGES ||
+        physaddr.checked_add(num_pages * 4096).map_or(true, |end| end > (1 << 52)) {
+            context.rax = SYSCALL_ERROR_PARAM;
+            return;
+        }
+
+    match process::device_memory_chunk(num_pages, physaddr) {
+        Ok(virtaddr) => {
+            context.rax = 0; // No error
+            context.rdi = virtaddr.as_u64() as usize;
+        }
+        Err(code) => {
+            context.rax = code;
+            context.rdi = 0;
+        }
+    }
+}
+
+/// Suspend the current thread for at least `microseconds`
+///
+/// Zero behaves like yield.
+fn sys_sleep(context_ptr: *mut Context, microseconds: u64) {
+    if microseconds == 0 {
+        return sys_yield(context_ptr);
+    }
+    let deadline = time::microseconds_monotonic().saturating_add(microseconds);
+    if let Some(mut thread) = process::take_current_thread() {
+        thread.set_context(context_ptr);
+        time::sleep(thread, deadline);
+
+        let new_context_addr = process::schedule_next(context_ptr as usize);
+        interrupts::launch_thread(new_context_addr);
+    }
+}
+
+/// Return the ID of the calling thread in RDI
+fn sys_get_tid(context_ptr: *mut Context) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    match process::current_tid() {
+        Some(tid) => {
+            context.rax = 0; // No error
+            context.rdi = tid as usize;
+        }
+        None => {
+            context.rax = SYSCALL_ERROR_THREAD;
+        }
+    }
+}
+
+/// Wait for thread `tid` to exit, and return its exit code in RDI
+///
+/// Returns immediately if the thread has already exited and its
+/// exit code hasn't been taken. The exit code is removed, so only
+/// one wait returns it. Returns SYSCALL_ERROR_PARAM if the thread
+/// doesn't exist, is the caller, or has been waited for.
+fn sys_wait(context_ptr: *mut Context, tid: u64) {
+    let context = unsafe {&mut (*context_ptr)};
+
+    if let Some(exit_code) = process::take_exit_code(tid) {
+        context.rax = 0; // No error
+        context.rdi = exit_code as usize;
+        return;
+    }
+    if !process::is_live(tid) || process::current_tid() == Some(tid) {
+        context.rax = SYSCALL_ERROR_PARAM;
+        return;
+    }
+    if let Some(mut thread) = process::take_current_thread() {
+        thread.set_context(context_ptr);
+        process::wait_for_exit(thread, tid);
+
+        let new_context_addr = process::schedule_next(context_ptr as usize);
+        interrupts::launch_thread(new_context_addr);
+    }
+}
@@ -0,0 +1,264 @@
+//! Recovery key combination
+//!
+//! The kernel watches keyboard scancodes for a key combination which
+//! prints a diagnostic dump (threads, run queue and kernel memory) to
+//! the console, and optionally reboots. This works even if user
+//! programs, including the keyboard driver, are hung.
+//!
+//! The combination and action can be set when the kernel is built:
+//!
+//!     EURALIOS_SYSRQ=alt-sysrq EURALIOS_SYSRQ_ACTION=dump-reboot cargo run
+//!
+//! where the combination is one of
+//!  - "ctrl-alt-del" : Ctrl+Alt+Delete (the default)
+//!  - "alt-sysrq"    : Alt+SysRq (Alt+PrintScreen)
+//!  - "none"         : Disabled
+//!
+//! and the action is one of
+//!  - "dump"        : Print diagnostics (the default)
+//!  - "reboot"      : Reset the machine
+//!  - "dump-reboot" : Print diagnostics and then reset
+//!
+//! They can also be changed with set_combo() and set_action().
+//!
+//! Running in interrupt context
+//! ----------------------------
+//!
+//! key_event() is called from the keyboard interrupt handler, with
+//! interrupts disabled. The interrupted code may hold any kernel
+//! lock, and can't run until the handler returns, so the dump:
+//!  - Doesn't allocate, because the heap may be locked
+//!  - Only uses try_read / try_lock on the thread tables and heap,
+//!    and skips anything which is locked
+//!  - Takes the console locks even if they are held, so output may
+//!    be interleaved with whatever was being printed.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use x86_64::instructions::port::Port;
+
+use crate::println;
+use crate::process;
+use crate::memory;
+use crate::interrupts;
+use crate::irqguard;
+use crate::time;
+use crate::vga_buffer;
+use crate::serial;
+
+/// Key combination which triggers the action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combo {
+    /// Ctrl+Alt+Delete
+    CtrlAltDel = 0,
+    /// Alt+SysRq
+    AltSysRq = 1,
+    /// Not recognised
+    Disabled = 2
+}
+
+/// What to do when the combination is pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Print diagnostics to the console
+    Dump = 0,
+    /// Reset the machine
+    Reboot = 1,
+    /// Print diagnostics, then reset
+    DumpReboot = 2
+}
+
+static COMBO: AtomicU8 = AtomicU8::new(Combo::CtrlAltDel as u8);
+static ACTION: AtomicU8 = AtomicU8::new(Action::Dump as u8);
+
+// Modifier key state, tracked from scancodes
+static CTRL: AtomicBool = AtomicBool::new(false);
+static ALT: AtomicBool = AtomicBool::new(false);
+
+/// Set the combination and action from the build environment, if given
+pub fn init() {
+    if let Some(name) = option_env!("EURALIOS_SYSRQ") {
+        let combo = match name {
+            "ctrl-alt-del" => Combo::CtrlAltDel,
+            "alt-sysrq" => Combo::AltSysRq,
+            "none" => Combo::Disabled,
+            _ => {
+                println!("[kernel] Invalid EURALIOS_SYSRQ '{}'", name);
+                return;
+            }
+        };
+        set_combo(combo);
+    }
+    if let Some(name) = option_env!("EURALIOS_SYSRQ_ACTION") {
+        let action = match name {
+            "dump" => Action::Dump,
+            "reboot" => Action::Reboot,
+            "dump-reboot" => Action::DumpReboot,
+            _ => {
+                println!("[kernel] Invalid EURALIOS_SYSRQ_ACTION '{}'", name);
+                return;
+            }
+        };
+        set_action(action);
+    }
+    if combo() != Combo::Disabled {
+        println!("[kernel] Recovery key {:?} action {:?}", combo(), action());
+    }
+}
+
+/// Change the key combination
+pub fn set_combo(combo: Combo) {
+    COMBO.store(combo as u8, Ordering::Relaxed);
+}
+
+/// The current key combination
+pub fn combo() -> Combo {
+    match COMBO.load(Ordering::Relaxed) {
+        0 => Combo::CtrlAltDel,
+        1 => Combo::AltSysRq,
+        _ => Combo::Disabled
+    }
+}
+
+/// Change the action
+pub fn set_action(action: Action) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+/// The current action
+pub fn action() -> Action {
+    match ACTION.load(Ordering::Relaxed) {
+        0 => Action::Dump,
+        1 => Action::Reboot,
+        _ => Action::DumpReboot
+    }
+}
+
+// Scan code set 1. Released keys have the top bit set.
+const SCANCODE_RELEASED: u8 = 0x80;
+const SCANCODE_CTRL: u8 = 0x1D;
+const SCANCODE_ALT: u8 = 0x38;
+const SCANCODE_DELETE: u8 = 0x53;
+const SCANCODE_SYSRQ: u8 = 0x54;
+
+/// Update the modifier state with a scancode
+///
+/// Returns true if the key combination was pressed.
+/// Extended (0xE0) prefixes are ignored, so left and right
+/// modifiers, and keypad Delete, are treated the same.
+fn track(scancode: u8) -> bool {
+    let pressed = (scancode & SCANCODE_RELEASED) == 0;
+    match scancode & !SCANCODE_RELEASED {
+        SCANCODE_CTRL => CTRL.store(pressed, Ordering::Relaxed),
+        SCANCODE_ALT => ALT.store(pressed, Ordering::Relaxed),
+        SCANCODE_DELETE if pressed => {
+            return (combo() == Combo::CtrlAltDel) &&
+                CTRL.load(Ordering::Relaxed) && ALT.load(Ordering::Relaxed);
+        }
+        SCANCODE_SYSRQ if pressed => {
+            return (combo() == Combo::AltSysRq) && ALT.load(Ordering::Relaxed);
+        }
+        _ => {}
+    }
+    false
+}
+
+/// Handle a scancode. Called from the keyboard interrupt handler.
+pub fn key_event(scancode: u8) {
+    if !track(scancode) {
+        return;
+    }
+    match action() {
+        Action::Dump => dump(),
+        Action::Reboot => reboot(),
+        Action::DumpReboot => {
+            dump();
+            reboot();
+        }
+    }
+}
+
+/// Writes to both VGA and serial consoles, even if they're locked
+struct EmergencyConsole;
+
+impl Write for EmergencyConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        lock_anyway(&vga_buffer::WRITER).write_str(s)?;
+        lock_anyway(&serial::SERIAL1).write_str(s)
+    }
+}
+
+/// Lock a mutex, breaking the lock if it is held. Only safe because
+/// the holder was interrupted, and can't run until we return.
+fn lock_anyway<T>(mutex: &spin::Mutex<T>) -> spin::MutexGuard<T> {
+    mutex.try_lock().unwrap_or_else(|| unsafe {
+        mutex.force_unlock();
+        mutex.lock()
+    })
+}
+
+/// Print the diagnostic dump
+pub fn dump() {
+    let _ = write_dump(&mut EmergencyConsole);
+}
+
+fn write_dump(out: &mut dyn Write) -> fmt::Result {
+    writeln!(out, "\n[sysrq] ---- Diagnostic dump ----")?;
+    writeln!(out, "[sysrq] Threads (* running, then run queue order):")?;
+    process::dump_threads(out)?;
+    match interrupts::interrupt_waiting_count() {
+        Some(count) => writeln!(out, "[sysrq] {} thread(s) waiting for interrupts", count)?,
+        None => writeln!(out, "[sysrq] Interrupt wait queue locked")?
+    }
+    match memory::try_memory_stats() {
+        Some(stats) => writeln!(out, "[sysrq] {}", stats)?,
+        None => writeln!(out, "[sysrq] Kernel heap locked")?
+    }
+    irqguard::report(out)?;
+    time::report(out)?;
+    writeln!(out, "[sysrq] ---- End of dump ----")
+}
+
+/// Reset the machine
+///
+/// Pulses the CPU reset line through the keyboard controller.
+/// If that fails, causes a triple fault.
+pub fn reboot() -> ! {
+    let _ = write!(EmergencyConsole, "[sysrq] Rebooting\n");
+    unsafe {
+        Port::<u8>::new(0x64).write(0xFE);
+    }
+    // Fault with no IDT
+    unsafe {
+        x86_64::instructions::tables::lidt(
+            &x86_64::structures::DescriptorTablePointer {
+                limit: 0,
+                base: x86_64::VirtAddr::new(0)});
+        core::arch::asm!("int3");
+    }
+    crate::hlt_loop();
+}
+
+#[test_case]
+fn recognises_ctrl_alt_del() {
+    set_combo(Combo::CtrlAltDel);
+    assert!(!track(SCANCODE_DELETE)); // No modifiers
+    assert!(!track(SCANCODE_DELETE | SCANCODE_RELEASED));
+
+    track(SCANCODE_CTRL);
+    track(SCANCODE_ALT);
+    assert!(track(SCANCODE_DELETE));
+    assert!(!track(SCANCODE_SYSRQ)); // Not the selected combination
+
+    track(SCANCODE_CTRL | SCANCODE_RELEASED);
+    assert!(!track(SCANCODE_DELETE));
+    track(SCANCODE_ALT | SCANCODE_RELEASED);
+
+    set_combo(Combo::Disabled);
+    track(SCANCODE_CTRL);
+    track(SCANCODE_ALT);
+    assert!(!track(SCANCODE_DELETE));
+    track(SCANCODE_CTRL | SCANCODE_RELEASED);
+    track(SCANCODE_ALT | SCANCODE_RELEASED);
+    set_combo(Combo::CtrlAltDel);
+}
@@ -1,8 +1,14 @@
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::arch::asm;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::cmp;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
 
 use crate::memory;
+use crate::println;
+use crate::process::{self, Thread};
 
 /// The Programmable Interrupt Timer frequency divider
 const PIT_TICKS_PER_INTERRUPT: u64 = 65536;
@@ -15,10 +21,90 @@ static LAST_TSC: AtomicU64 = AtomicU64::new(0);
 
 static TSC_PER_PIT: AtomicU64 = AtomicU64::new(0);
 
+/// PIT input frequency in Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Set if TSC_PER_PIT was calculated from the CPUID frequency,
+/// so isn't updated by PIT interrupts
+static CPUID_CALIBRATED: AtomicBool = AtomicBool::new(false);
+
+/// Set if the TSC runs at a constant rate
+///
+/// If the TSC rate changes with power states (older or some virtual
+/// CPUs) then the TSC is still used to interpolate between PIT
+/// interrupts, but times between interrupts are less accurate.
+/// Interpolation is limited to one interrupt period, so times stay
+/// monotonic and accurate over long periods either way.
+static INVARIANT_TSC: AtomicBool = AtomicBool::new(false);
+
+/// True if the TSC rate is constant, so times between timer
+/// interrupts are accurate
+pub fn precise_timing() -> bool {
+    INVARIANT_TSC.load(Ordering::Relaxed)
+}
+
+/// Check the InvariantTSC bit, CPUID leaf 0x80000007 EDX bit 8
+fn cpuid_invariant_tsc() -> bool {
+    use core::arch::x86_64::{__cpuid, __get_cpuid_max};
+
+    let (max_leaf, _) = unsafe { __get_cpuid_max(0x8000_0000) };
+    max_leaf >= 0x8000_0007 &&
+        (unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8)) != 0
+}
+
+/// TSC frequency in Hz, from CPUID leaf 0x15 or 0x16
+///
+/// <https://www.felixcloutier.com/x86/cpuid>
+fn cpuid_tsc_frequency() -> Option<u64> {
+    use core::arch::x86_64::{__cpuid, __get_cpuid_max};
+
+    let (max_leaf, _) = unsafe { __get_cpuid_max(0) };
+    if max_leaf >= 0x15 {
+        // Core crystal clock in ECX (maybe zero), and the
+        // ratio of TSC to crystal frequency as EBX / EAX
+        let leaf = unsafe { __cpuid(0x15) };
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64);
+        }
+    }
+    if max_leaf >= 0x16 {
+        // Processor base frequency in MHz
+        let mhz = unsafe { __cpuid(0x16) }.eax & 0xffff;
+        if mhz != 0 {
+            return Some(mhz as u64 * 1_000_000);
+        }
+    }
+    None
+}
+
+/// Calibrate the TSC
+///
+/// If CPUID reports the TSC frequency then that is used, so that
+/// microseconds_monotonic is accurate from boot. Otherwise the rate
+/// is found from a moving average over PIT interrupts.
+pub fn init() {
+    LAST_TSC.store(time_stamp_counter(), Ordering::Relaxed);
+
+    if cpuid_invariant_tsc() {
+        INVARIANT_TSC.store(true, Ordering::Relaxed);
+    } else {
+        println!("[kernel] TSC not invariant: Times between PIT interrupts are approximate");
+    }
+
+    match cpuid_tsc_frequency().map(|frequency| (frequency, frequency / PIT_FREQUENCY)) {
+        Some((frequency, tsc_per_pit)) if tsc_per_pit != 0 => {
+            TSC_PER_PIT.store(tsc_per_pit, Ordering::Relaxed);
+            CPUID_CALIBRATED.store(true, Ordering::Relaxed);
+            println!("[kernel] TSC {} MHz from CPUID", frequency / 1_000_000);
+        }
+        _ => println!("[kernel] TSC calibrated by PIT interrupts")
+    }
+}
+
 /// Read the processor's Time Stamp Counter
 /// uses RDTSC
 /// <https://www.felixcloutier.com/x86/rdtsc>
-fn time_stamp_counter() -> u64 {
+pub fn time_stamp_counter() -> u64 {
     let counter: u64;
     unsafe{
         asm!("rdtsc",
@@ -41,9 +127,14 @@ pub fn pit_interrupt_notify() {
     // TSC ticks per PIT tick.
     let new_tsc = time_stamp_counter();
     let last_tsc = LAST_TSC.swap(new_tsc, Ordering::Relaxed);
-    let new_tsc_per_pit = (new_tsc - last_tsc) / PIT_TICKS_PER_INTERRUPT;
-    let ma_tsc_per_pit = (new_tsc_per_pit + TSC_PER_PIT.load(Ordering::Relaxed)) / 2;
-    TSC_PER_PIT.store(ma_tsc_per_pit, Ordering::Relaxed);
+    let ma_tsc_per_pit = if CPUID_CALIBRATED.load(Ordering::Relaxed) {
+        TSC_PER_PIT.load(Ordering::Relaxed)
+    } else {
+        let new_tsc_per_pit = (new_tsc - last_tsc) / PIT_TICKS_PER_INTERRUPT;
+        let ma_tsc_per_pit = (new_tsc_per_pit + TSC_PER_PIT.load(Ordering::Relaxed)) / 2;
+        TSC_PER_PIT.store(ma_tsc_per_pit, Ordering::Relaxed);
+        ma_tsc_per_pit
+    };
 
     // Store in user-accessible KernelInfo page
     let info = memory::kernel_info::get_mut();
@@ -52,6 +143,18 @@ pub fn pit_interrupt_notify() {
     info.tsc_per_pit = ma_tsc_per_pit;
 }
 
+/// Convert a number of TSC ticks to microseconds
+///
+/// Returns None until the TSC has been calibrated by PIT interrupts
+pub fn tsc_to_microseconds(tsc: u64) -> Option<u64> {
+    let tsc_per_pit = TSC_PER_PIT.load(Ordering::Relaxed);
+    if tsc_per_pit == 0 {
+        return None;
+    }
+    // Each PIT tick is 878807 / (1024*1024) microseconds
+    Some(((tsc as u128 * 878807) / (1024 * 1024 * tsc_per_pit as u128)) as u64)
+}
+
 /// Monotonic count of he number of microseconds since restart
 ///
 /// Uses PIT interrupts to calibrate the TSC
@@ -65,6 +168,12 @@ pub fn microseconds_monotonic() -> u64 {
     // Number of TSC counts per PIT tick
     let tsc_per_pit = TSC_PER_PIT.load(Ordering::Relaxed);
 
+    pit_to_microseconds(pit, tsc, tsc_per_pit)
+}
+
+/// Convert PIT ticks, plus TSC ticks since the last PIT interrupt,
+/// to microseconds
+fn pit_to_microseconds(pit: u64, tsc: u64, tsc_per_pit: u64) -> u64 {
     // PIT frequency is 3_579_545 / 3 = 1_193_181.666 Hz
     //                   each PIT tick is 0.83809534452 microseconds
     //             878807 / (1024*1024) = 0.83809566497
@@ -77,10 +186,206 @@ pub fn microseconds_monotonic() -> u64 {
     //       2**64 / (1024 * 1024 * 2270) microseconds
     //((pit * tsc_per_pit + tsc) * 878807) / (1024*1024 * tsc_per_pit)
 
-    const SCALED_TSC_RATE: u64 = 16;
-    let scaled_tsc = (tsc * SCALED_TSC_RATE) / tsc_per_pit;
+    const SCALED_TSC_RATE: u128 = 16;
+    let scaled_tsc = if tsc_per_pit == 0 {
+        0 // Not yet calibrated
+    } else {
+        // No more than one interrupt period, so that jitter in
+        // tsc_per_pit or a varying TSC rate can't go past the time
+        // of the next interrupt
+        cmp::min((tsc as u128 * SCALED_TSC_RATE) / tsc_per_pit as u128,
+                 PIT_TICKS_PER_INTERRUPT as u128 * SCALED_TSC_RATE)
+    };
 
     // Factorize 878807 = 437 * 2011
-    // This will overflow in about 142 years : 2**64 / 4096 microseconds
-    ((((pit * SCALED_TSC_RATE + scaled_tsc) * 2011) / 4096) * 437) / (256 * SCALED_TSC_RATE)
+    // In u64 the products overflowed after about nine years of PIT
+    // ticks, so use u128 and only truncate the result, which fits
+    // in u64 for over 500000 years
+    (((((pit as u128 * SCALED_TSC_RATE + scaled_tsc) * 2011) / 4096) * 437)
+     / (256 * SCALED_TSC_RATE)) as u64
+}
+
+/// Largest value returned by nanoseconds_monotonic
+static LAST_NANOSECONDS: AtomicU64 = AtomicU64::new(0);
+
+/// Monotonic count of the number of nanoseconds since restart
+///
+/// For timing short sections of code: call before and after, and
+/// take the difference. Differences between calls are meaningful to
+/// a few nanoseconds, but the absolute value is only as accurate as
+/// the PIT calibration of the TSC, like microseconds_monotonic.
+///
+/// The value saturates after about 580 years.
+pub fn nanoseconds_monotonic() -> u64 {
+    let pit = PIT_TICKS.load(Ordering::Relaxed);
+    let tsc = time_stamp_counter() - LAST_TSC.load(Ordering::Relaxed);
+    let tsc_per_pit = TSC_PER_PIT.load(Ordering::Relaxed);
+
+    let nanoseconds = pit_to_nanoseconds(pit, tsc, tsc_per_pit);
+    // An interrupt between reading PIT_TICKS and LAST_TSC can make
+    // the time appear to go backwards
+    cmp::max(LAST_NANOSECONDS.fetch_max(nanoseconds, Ordering::Relaxed),
+             nanoseconds)
+}
+
+/// As pit_to_microseconds, in nanoseconds
+fn pit_to_nanoseconds(pit: u64, tsc: u64, tsc_per_pit: u64) -> u64 {
+    // Fractions of a PIT tick, about 0.8ns
+    const SCALED_TSC_RATE: u128 = 1024;
+    let scaled_tsc = if tsc_per_pit == 0 {
+        0 // Not yet calibrated
+    } else {
+        // No more than one interrupt period, as in pit_to_microseconds
+        cmp::min((tsc as u128 * SCALED_TSC_RATE) / tsc_per_pit as u128,
+                 PIT_TICKS_PER_INTERRUPT as u128 * SCALED_TSC_RATE)
+    };
+    let nanoseconds = ((pit as u128 * SCALED_TSC_RATE + scaled_tsc) * 878807 * 1000)
+        / (1024 * 1024 * SCALED_TSC_RATE);
+    cmp::min(nanoseconds, u64::MAX as u128) as u64
+}
+
+/// Write the timing mode. Used in the sysrq diagnostic dump.
+pub fn report(out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+    writeln!(out, "[time] TSC {} ticks per PIT tick, calibrated by {}{}",
+             TSC_PER_PIT.load(Ordering::Relaxed),
+             if CPUID_CALIBRATED.load(Ordering::Relaxed) { "CPUID" } else { "PIT" },
+             if precise_timing() { "" } else { " (not invariant)" })
+}
+
+/// Items waiting for a deadline, in order of deadline
+struct SleepQueue<T> {
+    entries: Vec<(u64, T)>
+}
+
+impl<T> SleepQueue<T> {
+    const fn new() -> Self {
+        SleepQueue{entries: Vec::new()}
+    }
+
+    /// Add an item. Items with equal deadlines are kept in the
+    /// order they were added.
+    fn insert(&mut self, deadline: u64, item: T) {
+        let index = self.entries.iter()
+            .position(|(d, _)| *d > deadline)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(index, (deadline, item));
+    }
+
+    /// Remove the earliest item if its deadline is not after `now`
+    fn pop_due(&mut self, now: u64) -> Option<T> {
+        match self.entries.first() {
+            Some((deadline, _)) if *deadline <= now =>
+                Some(self.entries.remove(0).1),
+            _ => None
+        }
+    }
+}
+
+/// Sleeping threads, with the time in microseconds to wake them
+static SLEEPING: Mutex<SleepQueue<Box<Thread>>> = Mutex::new(SleepQueue::new());
+
+/// Suspend a thread until microseconds_monotonic() reaches `deadline`
+///
+/// Threads are woken by the timer interrupt, so may sleep for up to
+/// one timer period (about 55ms) longer.
+pub fn sleep(thread: Box<Thread>, deadline: u64) {
+    SLEEPING.lock().insert(deadline, thread);
+}
+
+/// Schedule threads whose deadline has passed
+///
+/// Called from the timer interrupt, like rendezvous::check_send_timeouts,
+/// so uses try_lock to avoid deadlocks.
+pub fn wake_sleeping() {
+    let mut sleeping = match SLEEPING.try_lock() {
+        Some(sleeping) => sleeping,
+        None => return
+    };
+    let now = microseconds_monotonic();
+    let mut woken = Vec::new();
+    while let Some(thread) = sleeping.pop_due(now) {
+        woken.push(thread);
+    }
+    // schedule_thread puts threads at the front of the queue,
+    // so the earliest deadline is scheduled last to run first
+    for thread in woken.into_iter().rev() {
+        process::schedule_thread(thread);
+    }
+}
+
+#[test_case]
+fn microseconds_no_wraparound() {
+    // About 10000 years of PIT ticks
+    let pit: u64 = 1_193_182 * 3600 * 24 * 365 * 10_000;
+    let tsc_per_pit = 2270;
+    let microseconds = pit_to_microseconds(pit, 0, tsc_per_pit);
+    let expected = (pit as u128 * 878807 / (1024 * 1024)) as u64;
+    assert!((microseconds as i128 - expected as i128).abs() <= 1);
+
+    // Still monotonic between PIT interrupts
+    let later = pit_to_microseconds(pit, tsc_per_pit * 1000, tsc_per_pit);
+    assert!(later > microseconds);
+    assert!(pit_to_microseconds(pit + 1000, 0, tsc_per_pit) >= later);
+}
+
+#[test_case]
+fn nanoseconds_resolution() {
+    let tsc_per_pit = 2270;
+    let start = pit_to_nanoseconds(1000, 0, tsc_per_pit);
+    // One PIT tick is about 838ns
+    assert_eq!(pit_to_nanoseconds(1001, 0, tsc_per_pit) - start, 838);
+    // Resolution finer than a PIT tick
+    let delta = pit_to_nanoseconds(1000, tsc_per_pit / 8, tsc_per_pit) - start;
+    assert!(delta > 100 && delta < 110);
+    // No wraparound for as long as the microsecond clock
+    let pit: u64 = 1_193_182 * 3600 * 24 * 365 * 100;
+    assert_eq!(pit_to_nanoseconds(pit, 0, tsc_per_pit) / 1000,
+               pit_to_microseconds(pit, 0, tsc_per_pit));
+
+    let first = nanoseconds_monotonic();
+    assert!(nanoseconds_monotonic() >= first);
+}
+
+#[test_case]
+fn microseconds_between_ticks() {
+    // Wait for an interrupt, so the TSC has been calibrated
+    // and the next interrupt is a full period away
+    let wait_tick = || {
+        let pit = PIT_TICKS.load(Ordering::Relaxed);
+        while PIT_TICKS.load(Ordering::Relaxed) == pit {
+            core::hint::spin_loop();
+        }
+    };
+    wait_tick();
+    wait_tick();
+
+    let pit = PIT_TICKS.load(Ordering::Relaxed);
+    let start = microseconds_monotonic();
+    // Spin for about 100 PIT ticks, much less than an interrupt period
+    let tsc_start = time_stamp_counter();
+    while time_stamp_counter() - tsc_start < TSC_PER_PIT.load(Ordering::Relaxed) * 100 {
+        core::hint::spin_loop();
+    }
+    let end = microseconds_monotonic();
+    assert_eq!(PIT_TICKS.load(Ordering::Relaxed), pit);
+    assert!(end > start);
+    // Not past the time of the next interrupt
+    assert!(end <= pit_to_microseconds(pit + PIT_TICKS_PER_INTERRUPT, 0, 1));
+}
+
+#[test_case]
+fn sleep_queue_order() {
+    let mut queue = SleepQueue::new();
+    queue.insert(30, 'c');
+    queue.insert(10, 'a');
+    queue.insert(20, 'b');
+    queue.insert(10, 'd'); // After 'a', same deadline
+
+    assert_eq!(queue.pop_due(5), None);
+    assert_eq!(queue.pop_due(10), Some('a'));
+    assert_eq!(queue.pop_due(10), Some('d'));
+    assert_eq!(queue.pop_due(10), None);
+    assert_eq!(queue.pop_due(100), Some('b'));
+    assert_eq!(queue.pop_due(100), Some('c'));
+    assert_eq!(queue.pop_due(100), None);
 }
@@ -0,0 +1,165 @@
+//! Thread-local storage
+//!
+//! Programs with `#[thread_local]` statics have an ELF PT_TLS
+//! segment, containing the initial image of their thread-local
+//! data. Each thread gets its own copy of this image, in a TLS
+//! block which uses the x86-64 "variant II" layout:
+//!
+//! ```text
+//!      TLS block                    TCB
+//!  | .tdata | .tbss (zero) | pad | self pointer |
+//!  ^                             ^
+//!  block start                   FS base (thread pointer)
+//! ```
+//!
+//! The block ends at the thread pointer, which is aligned to the
+//! segment's alignment. Thread-local variables are at negative
+//! offsets from FS. The first word of the TCB points to itself, so
+//! code can read the thread pointer with `mov rax, fs:0`.
+//!
+//! The TLS block and TCB are placed at the top of each thread's user
+//! stack, below which the stack starts. They must fit in the stack's
+//! top page, which is always allocated. Binaries without a PT_TLS
+//! segment get no TLS block, and their FS base is zero.
+
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::FsBase;
+use object::elf;
+use object::read::elf::{FileHeader, ProgramHeader};
+
+/// Size of the Thread Control Block: just the self pointer
+const TCB_SIZE: u64 = 8;
+
+/// Largest TLS block plus TCB. This is the user stack page which
+/// is allocated when the stack is created.
+pub const MAX_TLS_SIZE: u64 = 4096;
+
+/// Initial TLS image from a PT_TLS segment, in user memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Template {
+    /// Address of the .tdata image
+    pub address: u64,
+    /// Size of .tdata, copied into each TLS block
+    pub file_size: u64,
+    /// Size of .tdata plus .tbss
+    pub mem_size: u64,
+    /// Alignment of the TLS block, a power of two
+    pub align: u64
+}
+
+/// Addresses of a thread's TLS block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    /// Start of the thread-local data
+    pub data_start: u64,
+    /// Thread pointer, the address of the TCB
+    pub thread_pointer: u64,
+    /// The user stack starts below the TLS block
+    pub stack_end: u64
+}
+
+impl Template {
+    /// Where to put a TLS block which must end below `end`
+    pub fn layout(&self, end: u64) -> Result<Layout, &'static str> {
+        let align = self.align.max(16); // Also keep the stack aligned
+        let thread_pointer = (end - TCB_SIZE) & !(align - 1);
+        let block_size = (self.mem_size + self.align - 1) & !(self.align - 1);
+        let data_start = thread_pointer - block_size;
+        if end - data_start > MAX_TLS_SIZE {
+            return Err("TLS segment too large");
+        }
+        Ok(Layout{data_start, thread_pointer, stack_end: data_start & !15})
+    }
+
+    /// True if the initialized data is between `start` and `end`.
+    /// It may end exactly at `end`.
+    fn is_within(&self, start: u64, end: u64) -> bool {
+        self.address >= start &&
+            self.address.checked_add(self.file_size)
+            .map_or(false, |template_end| template_end <= end)
+    }
+}
+
+/// Find the TLS template in an ELF binary
+///
+/// Returns None if the binary has no PT_TLS segment. The template
+/// must be within user code and data, from `start` to `end`.
+pub fn find_template(bin: &[u8], start: u64, end: u64) -> Result<Option<Template>, &'static str> {
+    let header = elf::FileHeader64::<object::Endianness>::parse(bin)
+        .map_err(|_| "Could not parse ELF")?;
+    let endian = header.endian().map_err(|_| "Could not parse ELF")?;
+    let segments = header.program_headers(endian, bin)
+        .map_err(|_| "Could not parse ELF program headers")?;
+
+    for segment in segments {
+        if segment.p_type(endian) != elf::PT_TLS {
+            continue;
+        }
+        let template = Template{
+            address: segment.p_vaddr(endian),
+            file_size: segment.p_filesz(endian),
+            mem_size: segment.p_memsz(endian),
+            align: segment.p_align(endian).max(1)
+        };
+        if !template.align.is_power_of_two() ||
+            template.file_size > template.mem_size {
+            return Err("Invalid TLS segment");
+        }
+        if !template.is_within(start, end) {
+            return Err("TLS segment outside allowed range");
+        }
+        if template.mem_size > MAX_TLS_SIZE {
+            return Err("TLS segment too large");
+        }
+        return Ok(Some(template));
+    }
+    Ok(None)
+}
+
+/// Create a TLS block for a new thread, ending at `stack_end`
+///
+/// The page table containing the template and stack must be active.
+/// Returns the layout, including the new end of the stack.
+pub fn create_block(template: &Template, stack_end: u64) -> Result<Layout, &'static str> {
+    let layout = template.layout(stack_end)?;
+    unsafe {
+        // Zero .tbss, padding and TCB
+        core::ptr::write_bytes(layout.data_start as *mut u8, 0,
+                               (stack_end - layout.data_start) as usize);
+        core::ptr::copy_nonoverlapping(template.address as *const u8,
+                                       layout.data_start as *mut u8,
+                                       template.file_size as usize);
+        // TCB self pointer
+        core::ptr::write(layout.thread_pointer as *mut u64, layout.thread_pointer);
+    }
+    Ok(layout)
+}
+
+/// Set the FS base register of the CPU. Called on context switch.
+pub fn set_thread_pointer(thread_pointer: u64) {
+    FsBase::write(VirtAddr::new(thread_pointer));
+}
+
+#[test_case]
+fn tls_layout() {
+    let template = Template{address: 0x1000, file_size: 8, mem_size: 20, align: 8};
+    let layout = template.layout(0x10000).unwrap();
+    // Thread pointer aligned, with space for the TCB
+    assert_eq!(layout.thread_pointer, 0x10000 - 16);
+    // Data is mem_size rounded up to the alignment
+    assert_eq!(layout.data_start, 0x10000 - 16 - 24);
+    assert_eq!(layout.stack_end, (0x10000 - 16 - 24) & !15);
+
+    let large = Template{mem_size: MAX_TLS_SIZE, ..template};
+    assert!(large.layout(0x10000).is_err());
+}
+
+#[test_case]
+fn tls_template_range() {
+    let template = Template{address: 0x1000, file_size: 0x100, mem_size: 0x200, align: 8};
+    assert!(template.is_within(0x1000, 0x1100)); // Ends at the segment end
+    assert!(!template.is_within(0x1000, 0x10ff));
+    assert!(!template.is_within(0x1001, 0x2000));
+    let wraps = Template{address: u64::MAX - 8, ..template};
+    assert!(!wraps.is_within(0, u64::MAX));
+}
@@ -146,10 +146,12 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    use crate::irqguard;
 
-    interrupts::without_interrupts(|| {
+    irqguard::without_interrupts(|| {
         WRITER.lock().write_fmt(args).unwrap();
+        // Keep a copy for user programs
+        crate::kmsg::log_fmt(args);
     });
 }
 
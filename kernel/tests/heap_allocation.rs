@@ -69,3 +69,28 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1); // new
 }
+
+#[test_case]
+fn heap_usage_by_category() {
+    let before = memory::memory_stats().heap_messages;
+    let value = memory::with_category(memory::Category::Message,
+                                      || Box::new([0u8; 64]));
+    assert_eq!(memory::memory_stats().heap_messages, before + 64);
+    drop(value);
+    assert_eq!(memory::memory_stats().heap_messages, before);
+}
+
+#[test_case]
+fn heap_fragmentation() {
+    let stats = memory::heap_stats();
+    assert!(stats.largest_free <= stats.total - stats.used);
+
+    // Free every other block, leaving holes between allocations
+    let mut blocks: Vec<Box<[u8; 1024]>> = (0..64).map(|_| Box::new([0u8; 1024])).collect();
+    let mut i = 0;
+    blocks.retain(|_| {i += 1; i % 2 == 0});
+
+    let fragmented = memory::heap_stats();
+    assert!(fragmented.largest_free <= fragmented.total - fragmented.used);
+    assert!(fragmented.fragmentation_permille() > stats.fragmentation_permille());
+}
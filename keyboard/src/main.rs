@@ -1,68 +1,251 @@
 #![no_std]
 #![no_main]
 
-use euralios_std::{debug_print,
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use euralios_std::{debug_print, debug_println,
                    console::sequences,
-                   syscalls::{self, STDOUT},
-                   message, ports};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode};
+                   syscalls::{self, CommHandle, STDIN, STDOUT},
+                   message::{self, keyboard, Message},
+                   thread};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard,
+                  ScancodeSet1, KeyCode, KeyEvent, KeyState};
+
+/// The layout selected with keyboard::SET_LAYOUT
+static LAYOUT: AtomicU64 = AtomicU64::new(keyboard::LAYOUT_US);
+
+/// A keyboard decoder for one of the supported layouts
+///
+/// The layout handles Shift and AltGr, so switching layout also
+/// changes which modified keys produce which characters.
+enum LayoutKeyboard {
+    Us(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Uk(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+    De(Keyboard<layouts::De104Key, ScancodeSet1>),
+    Dvorak(Keyboard<layouts::Dvorak104Key, ScancodeSet1>)
+}
+
+impl LayoutKeyboard {
+    /// None if the layout identifier isn't known
+    fn new(layout: u64) -> Option<Self> {
+        let handle_control = HandleControl::MapLettersToUnicode;
+        Some(match layout {
+            keyboard::LAYOUT_US => LayoutKeyboard::Us(Keyboard::new(handle_control)),
+            keyboard::LAYOUT_UK => LayoutKeyboard::Uk(Keyboard::new(handle_control)),
+            keyboard::LAYOUT_DE => LayoutKeyboard::De(Keyboard::new(handle_control)),
+            keyboard::LAYOUT_DVORAK => LayoutKeyboard::Dvorak(Keyboard::new(handle_control)),
+            _ => return None
+        })
+    }
+
+    /// Add a scancode, returning an event if a key changed state
+    fn add_byte(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let result = match self {
+            LayoutKeyboard::Us(keyboard) => keyboard.add_byte(scancode),
+            LayoutKeyboard::Uk(keyboard) => keyboard.add_byte(scancode),
+            LayoutKeyboard::De(keyboard) => keyboard.add_byte(scancode),
+            LayoutKeyboard::Dvorak(keyboard) => keyboard.add_byte(scancode)
+        };
+        result.ok().flatten()
+    }
+
+    /// Update the modifiers, returning a key if one was pressed
+    fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        match self {
+            LayoutKeyboard::Us(keyboard) => keyboard.process_keyevent(event),
+            LayoutKeyboard::Uk(keyboard) => keyboard.process_keyevent(event),
+            LayoutKeyboard::De(keyboard) => keyboard.process_keyevent(event),
+            LayoutKeyboard::Dvorak(keyboard) => keyboard.process_keyevent(event)
+        }
+    }
+}
+
+/// Keys whose state is kept by the decoder while held down
+const MODIFIER_KEYS: [KeyCode; 5] = [KeyCode::ShiftLeft, KeyCode::ShiftRight,
+                                     KeyCode::ControlLeft, KeyCode::ControlRight,
+                                     KeyCode::AltRight];
+
+/// The state of the modifier and lock keys
+///
+/// The decoder's own state can't be read, so it is followed from
+/// the key events, and replayed into a new decoder when the layout
+/// changes. Otherwise e.g. holding Shift while the layout changed
+/// would leave the new decoder unshifted.
+struct Modifiers {
+    held: [bool; MODIFIER_KEYS.len()],
+    caps_lock: bool,
+    num_lock: bool
+}
+
+impl Modifiers {
+    /// The state of a new decoder
+    fn new() -> Self {
+        Modifiers{held: [false; MODIFIER_KEYS.len()],
+                  caps_lock: false,
+                  num_lock: true}
+    }
+
+    /// Follow the changes which process_keyevent makes
+    fn update(&mut self, event: &KeyEvent) {
+        let down = event.state == KeyState::Down;
+        if let Some(index) = MODIFIER_KEYS.iter().position(|code| *code == event.code) {
+            self.held[index] = down;
+        } else if down && event.code == KeyCode::CapsLock {
+            self.caps_lock = !self.caps_lock;
+        } else if down && event.code == KeyCode::NumpadLock {
+            self.num_lock = !self.num_lock;
+        }
+    }
+
+    /// Put a new decoder into the same state
+    fn apply(&self, keyboard: &mut LayoutKeyboard) {
+        for (code, held) in MODIFIER_KEYS.iter().zip(self.held.iter()) {
+            if *held {
+                keyboard.process_keyevent(KeyEvent::new(*code, KeyState::Down));
+            }
+        }
+        // Lock keys toggle when pressed
+        let init = Modifiers::new();
+        for (code, toggled) in [(KeyCode::CapsLock, self.caps_lock != init.caps_lock),
+                                (KeyCode::NumpadLock, self.num_lock != init.num_lock)] {
+            if toggled {
+                keyboard.process_keyevent(KeyEvent::new(code, KeyState::Down));
+                keyboard.process_keyevent(KeyEvent::new(code, KeyState::Up));
+            }
+        }
+    }
+}
 
 #[no_mangle]
 fn main() {
-    let mut keyboard: Keyboard<layouts::Us104Key, ScancodeSet1> = Keyboard::new(HandleControl::MapLettersToUnicode);
+    if let Err(err) = thread::spawn(control_loop) {
+        debug_println!("[keyboard] Couldn't start control thread: {}", err);
+    }
+
+    let mut layout = keyboard::LAYOUT_US;
+    let mut keyboard = LayoutKeyboard::new(layout).unwrap();
+    let mut modifiers = Modifiers::new();
 
     loop {
-        // Wait for an interrupt to occur
-        syscalls::await_interrupt();
-
-        let scancode: u8 = ports::inportb(0x60);
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                let chars_be: u64 = match key {
-                    DecodedKey::Unicode(character) => {
-                        character as u64 // A single character
-                    },
-                    DecodedKey::RawKey(key) => {
-                        match key {
-                            // These escape sequences follow the VT convention
-                            KeyCode::F1 => sequences::F1,
-                            KeyCode::F2 => sequences::F2,
-                            KeyCode::F3 => sequences::F3,
-                            KeyCode::F4 => sequences::F4,
-                            KeyCode::F5 => sequences::F5,
-                            KeyCode::F6 => sequences::F6,
-                            KeyCode::F7 => sequences::F7,
-                            KeyCode::F8 => sequences::F8,
-                            KeyCode::F9 => sequences::F9,
-                            KeyCode::F10 => sequences::F10,
-                            KeyCode::F11 => sequences::F11,
-                            KeyCode::F12 => sequences::F12,
-
-                            KeyCode::PageUp   => sequences::PageUp,
-                            KeyCode::PageDown => sequences::PageDown,
-                            KeyCode::Home     => sequences::Home,
-                            KeyCode::End      => sequences::End,
-
-                            KeyCode::ArrowUp  => sequences::ArrowUp,
-                            KeyCode::ArrowDown => sequences::ArrowDown,
-                            KeyCode::ArrowRight => sequences::ArrowRight,
-                            KeyCode::ArrowLeft => sequences::ArrowLeft,
-                            _ => {
-                                debug_print!("{:?}", key);
-                                continue;
-                            }
+        // Wait for a key, read by the kernel
+        let scancode: u8 = syscalls::await_interrupt();
+
+        let selected = LAYOUT.load(Ordering::Relaxed);
+        if selected != layout {
+            if let Some(mut new_keyboard) = LayoutKeyboard::new(selected) {
+                // Keep Shift, Ctrl etc. held and Caps Lock on
+                modifiers.apply(&mut new_keyboard);
+                keyboard = new_keyboard;
+                layout = selected;
+            }
+        }
+        let key = keyboard.add_byte(scancode).and_then(|event| {
+            modifiers.update(&event);
+            keyboard.process_keyevent(event)
+        });
+        if let Some(key) = key {
+            let chars_be: u64 = match key {
+                DecodedKey::Unicode(character) => {
+                    character as u64 // A single character
+                },
+                DecodedKey::RawKey(key) => {
+                    match key {
+                        // These escape sequences follow the VT convention
+                        KeyCode::F1 => sequences::F1,
+                        KeyCode::F2 => sequences::F2,
+                        KeyCode::F3 => sequences::F3,
+                        KeyCode::F4 => sequences::F4,
+                        KeyCode::F5 => sequences::F5,
+                        KeyCode::F6 => sequences::F6,
+                        KeyCode::F7 => sequences::F7,
+                        KeyCode::F8 => sequences::F8,
+                        KeyCode::F9 => sequences::F9,
+                        KeyCode::F10 => sequences::F10,
+                        KeyCode::F11 => sequences::F11,
+                        KeyCode::F12 => sequences::F12,
+
+                        KeyCode::PageUp   => sequences::PageUp,
+                        KeyCode::PageDown => sequences::PageDown,
+                        KeyCode::Home     => sequences::Home,
+                        KeyCode::End      => sequences::End,
+
+                        KeyCode::ArrowUp  => sequences::ArrowUp,
+                        KeyCode::ArrowDown => sequences::ArrowDown,
+                        KeyCode::ArrowRight => sequences::ArrowRight,
+                        KeyCode::ArrowLeft => sequences::ArrowLeft,
+                        _ => {
+                            debug_print!("{:?}", key);
+                            continue;
                         }
                     }
+                }
+            };
+            // Send the character(s) in a short message
+            if let Err((err, _msg)) = syscalls::send(&STDOUT,
+                                                     message::Message::Short(
+                                                         message::CHAR,
+                                                         chars_be, 0)) {
+                // Failed to send. Probably not much to be done except panic.
+                panic!("[keyboard] Send: {}", err);
+            }
+        }
+    }
+}
+
+/// Change the layout in response to messages from clients
+fn control_loop() {
+    loop {
+        match syscalls::receive(&STDIN) {
+            Ok(Message::Short(message::OPEN_READWRITE, _, _)) |
+            Ok(Message::Long(message::OPEN_READWRITE, _, _)) => {
+                let (handle, client_handle) = match syscalls::new_rendezvous() {
+                    Ok(handles) => handles,
+                    Err(err) => {
+                        debug_println!("[keyboard] Couldn't create Rendezvous {}", err);
+                        continue;
+                    }
                 };
-                // Send the character(s) in a short message
-                if let Err((err, _msg)) = syscalls::send(&STDOUT,
-                                                         message::Message::Short(
-                                                             message::CHAR,
-                                                             chars_be, 0)) {
-                    // Failed to send. Probably not much to be done except panic.
-                    panic!("[keyboard] Send: {}", err);
+                if let Err(err) = thread::spawn(move || layout_handler(handle)) {
+                    debug_println!("[keyboard] Couldn't start handler: {}", err);
+                    continue;
                 }
+                syscalls::send(&STDIN,
+                               Message::Long(
+                                   message::COMM_HANDLE,
+                                   client_handle.into(), 0.into()));
+            }
+            Ok(message) => {
+                debug_println!("[keyboard] Unknown message {:?}", message);
+            }
+            Err(err) => {
+                debug_println!("[keyboard] Receive error {}", err);
+                // Wait and try again
+                syscalls::thread_yield();
             }
         }
     }
 }
+
+fn layout_handler(comm_handle: CommHandle) {
+    loop {
+        let reply = match syscalls::receive(&comm_handle) {
+            Ok(Message::Short(keyboard::SET_LAYOUT, layout, _)) => {
+                if LayoutKeyboard::new(layout).is_some() {
+                    LAYOUT.store(layout, Ordering::Relaxed);
+                    Message::Short(message::OK, 0, 0)
+                } else {
+                    Message::Short(message::ERROR,
+                                   syscalls::SYSCALL_ERROR_PARAM.as_u64(), 0)
+                }
+            }
+            Ok(_) => Message::Short(message::ERROR_UNKNOWN_MESSAGE, 0, 0),
+            Err(syscalls::SYSCALL_ERROR_CLOSED) => return,
+            Err(err) => {
+                debug_println!("[keyboard] Receive error {}", err);
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+        syscalls::send(&comm_handle, reply);
+    }
+}
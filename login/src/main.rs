@@ -79,9 +79,9 @@ fn main() {
             "root" => VFS::shared(), // Root sees everything
             "user" => {
                 // Open bin directory read-only
-                let bin = OpenOptions::new().open("/ramdisk/bin").unwrap();
+                let bin = OpenOptions::new().directory(true).open("/ramdisk/bin").unwrap();
                 // User's home directory read-write
-                let home = OpenOptions::new().write(true).open("/ramdisk/user").unwrap();
+                let home = OpenOptions::new().write(true).directory(true).open("/ramdisk/user").unwrap();
                 // TCP stack read-write
                 let tcp = OpenOptions::new().write(true).open("/tcp").unwrap();
                 VFS::new()
@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+
+//! PS/2 mouse driver
+//!
+//! The kernel enables the mouse at boot, and reads each byte in its
+//! interrupt handler. This driver assembles the bytes into packets
+//! and sends an event to every handle opened on its input. See
+//! euralios_std::message::mouse for the protocol.
+
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+use euralios_std::{println,
+                   syscalls::{self, CommHandle, STDIN},
+                   message::{self, mouse::{self, Event}, Message},
+                   server,
+                   thread};
+
+/// How long to wait for each client to receive an event
+const SEND_TIMEOUT_US: u64 = 10_000;
+
+#[no_mangle]
+fn main() {
+    println!("[mouse] Starting driver");
+
+    let clients: Arc<RwLock<Vec<CommHandle>>> = Arc::new(RwLock::new(Vec::new()));
+
+    let open_clients = clients.clone();
+    if let Err(err) = thread::spawn(move || open_loop(open_clients)) {
+        println!("[mouse] Couldn't start thread: {}", err);
+        server::signal_failed(err);
+        return;
+    }
+
+    server::signal_ready();
+
+    // Bytes of the current packet
+    let mut packet = [0u8; 3];
+    let mut received = 0;
+    loop {
+        let byte = match syscalls::await_irq(syscalls::IRQ_MOUSE) {
+            Ok(byte) => byte,
+            Err(err) => {
+                println!("[mouse] Couldn't wait for interrupt: {}", err);
+                server::signal_failed(err);
+                return;
+            }
+        };
+        if received == 0 && byte & mouse::PACKET_SYNC == 0 {
+            // Not the start of a packet; a byte was lost
+            continue;
+        }
+        packet[received] = byte;
+        received += 1;
+        if received < packet.len() {
+            continue;
+        }
+        received = 0;
+
+        // Packets which overflowed are discarded
+        if let Some(event) = Event::from_packet(packet) {
+            publish(&clients, event);
+        }
+    }
+}
+
+/// Send an event to all clients, removing closed handles
+fn publish(clients: &RwLock<Vec<CommHandle>>, event: Event) {
+    clients.write().retain(|handle| {
+        match syscalls::send_timeout(handle,
+                                     Message::Short(mouse::MOVE,
+                                                    event.to_value(), 0),
+                                     SEND_TIMEOUT_US) {
+            Err((syscalls::SYSCALL_ERROR_CLOSED, _)) => false,
+            _ => true // Sent, or client missed this event
+        }
+    });
+}
+
+/// Reply to open messages with a new handle to send events to
+fn open_loop(clients: Arc<RwLock<Vec<CommHandle>>>) {
+    loop {
+        match syscalls::receive(&STDIN) {
+            Ok(Message::Short(message::OPEN_READONLY, _, _)) |
+            Ok(Message::Long(message::OPEN_READONLY, _, _)) => {
+                let (handle, client_handle) = match syscalls::new_rendezvous() {
+                    Ok(handles) => handles,
+                    Err(err) => {
+                        println!("[mouse] Couldn't create Rendezvous {}", err);
+                        continue;
+                    }
+                };
+                clients.write().push(handle);
+                syscalls::send(&STDIN,
+                               Message::Long(
+                                   message::COMM_HANDLE,
+                                   client_handle.into(), 0.into()));
+            }
+            Ok(message) => {
+                println!("[mouse] Unknown message {:?}", message);
+            }
+            Err(err) => {
+                println!("[mouse] Receive error {}", err);
+                // Wait and try again
+                syscalls::thread_yield();
+            }
+        }
+    }
+}
@@ -1,8 +1,35 @@
 
 use core::fmt;
+use alloc::{format, string::String};
 
 use crate::ports::PORTS;
 
+/// Header type bits which give the layout of the rest of the header
+pub const HEADER_TYPE_MASK: u8 = 0x7F;
+/// Header type bit set if the device has functions other than 0
+pub const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+
+/// Command register (1) bits
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+
+/// BAR bit 0: Set for I/O space, clear for memory space
+const BAR_IO_SPACE: u32 = 1;
+/// Memory BAR bits 1-2: The type of BAR
+const BAR_MEMORY_TYPE_MASK: u32 = 0b110;
+/// Memory BAR type: 64-bit address, using the next BAR as well
+const BAR_MEMORY_TYPE_64: u32 = 0b100;
+/// Memory BAR bit 3
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// A decoded Base Address Register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io {port: u32, size: u32},
+    Memory {address: u64, size: u64, prefetchable: bool}
+}
+
 #[derive(Clone, Copy)]
 pub struct PciLocation {
     pub bus:  u16,
@@ -26,6 +53,13 @@ impl PciLocation {
         }
     }
 
+    /// Directory name of the device at this location, e.g. "00_1F_3"
+    ///
+    /// Fixed width, so names sort in bus, slot, function order
+    pub fn name(&self) -> String {
+        format!("{:02X}_{:02X}_{:X}", self.bus, self.slot, self.function)
+    }
+
     /// Return PCI bus address
     pub fn address(&self) -> u32 {
         0x8000_0000
@@ -48,6 +82,84 @@ impl PciLocation {
         PORTS.lock().write(addr, value);
     }
 
+    /// The interrupt line (IRQ) routed to the device, or 0xFF if none
+    pub fn interrupt_line(&self) -> u8 {
+        (self.read_register(0xF) & 0xFF) as u8
+    }
+
+    /// Decode a Base Address Register, sizing it by writing all ones
+    /// and reading back which bits are fixed
+    ///
+    /// Returns None if the BAR is not implemented, or is a 64-bit BAR
+    /// without a following register.
+    pub fn read_bar(&self, bar_id: u8) -> Option<Bar> {
+        let register = 4 + bar_id;
+        let value = self.read_register(register);
+
+        // Stop decoding while the BAR holds the wrong address.
+        // The upper 16 bits are the status register, which is
+        // cleared by writing ones
+        let command = self.read_register(1) & 0xFFFF;
+        self.write_register(1, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+        let size_bits = |register: u8, value: u32| {
+            self.write_register(register, 0xFFFF_FFFF);
+            let bits = self.read_register(register);
+            self.write_register(register, value);
+            bits
+        };
+
+        let bar = if value & BAR_IO_SPACE != 0 {
+            // Only the lower 16 bits may be implemented
+            let bits = size_bits(register, value) | 0xFFFF_0000;
+            let size = (!(bits & !0x3)).wrapping_add(1);
+            if bits & !0x3 == 0xFFFF_0000 {
+                None
+            } else {
+                Some(Bar::Io{port: value & !0x3, size})
+            }
+        } else if value & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_TYPE_64 {
+            if bar_id >= 5 {
+                None
+            } else {
+                let upper = self.read_register(register + 1);
+                let bits = ((size_bits(register + 1, upper) as u64) << 32) |
+                    (size_bits(register, value) & !0xF) as u64;
+                if bits == 0 {
+                    None
+                } else {
+                    Some(Bar::Memory{address: ((upper as u64) << 32) | (value & !0xF) as u64,
+                                     size: (!bits).wrapping_add(1),
+                                     prefetchable: value & BAR_PREFETCHABLE != 0})
+                }
+            }
+        } else {
+            let bits = size_bits(register, value) & !0xF;
+            if bits == 0 {
+                None
+            } else {
+                Some(Bar::Memory{address: (value & !0xF) as u64,
+                                 size: (!bits).wrapping_add(1) as u64,
+                                 prefetchable: value & BAR_PREFETCHABLE != 0})
+            }
+        };
+
+        self.write_register(1, command);
+        bar
+    }
+
+    /// Enable bus mastering and decoding of a BAR's address space,
+    /// then decode the BAR
+    pub fn enable_bar(&self, bar_id: u8) -> Option<Bar> {
+        let bar = self.read_bar(bar_id)?;
+        let decode = match bar {
+            Bar::Io{..} => COMMAND_IO_SPACE,
+            Bar::Memory{..} => COMMAND_MEMORY_SPACE
+        };
+        self.write_register(1, (self.read_register(1) & 0xFFFF) | decode | COMMAND_BUS_MASTER);
+        Some(bar)
+    }
+
     /// Return the Device which is at this PCI bus location
     /// May return None if there is no device
     pub fn get_device(&self) -> Option<Device> {
@@ -75,6 +187,18 @@ impl PciLocation {
             ((reg_B >> 16) & 0xFFFF) as u16
         } else { 0 };
 
+        // Base Address Registers are registers 4 to 9 in a general
+        // device (header type 0), and 4 to 5 in a PCI-to-PCI bridge
+        let mut bars = [0; 6];
+        let num_bars = match header_type & HEADER_TYPE_MASK {
+            0 => 6,
+            1 => 2,
+            _ => 0
+        };
+        for (i, bar) in bars.iter_mut().take(num_bars).enumerate() {
+            *bar = self.read_register(4 + i as u8);
+        }
+
         Some(Device {
             location: self.clone(),
             vendor_id,
@@ -84,7 +208,8 @@ impl PciLocation {
             prog_if,
             revision_id,
             header_type,
-            subsystem_id
+            subsystem_id,
+            bars
         })
     }
 }
@@ -99,7 +224,8 @@ pub struct Device {
     pub prog_if: u8, // register-level programming interface, if any
     pub revision_id: u8, // revision identifier. Valid IDs are allocated by the vendor
     pub header_type: u8,
-    pub subsystem_id: u16
+    pub subsystem_id: u16,
+    pub bars: [u32; 6]
 }
 
 impl Device {
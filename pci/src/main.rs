@@ -12,20 +12,20 @@
 
 use core::str;
 
-use euralios_std::{println, syscalls, message::pci,
+use euralios_std::{println, syscalls, message::pci, message::diag,
                    message::{self, Message},
-                   server::{FileLike, DirLike, handle_directory},
+                   server::{self, FileLike, DirLike, handle_directory},
                    syscalls::STDIN};
 
 extern crate alloc;
 use alloc::collections::btree_map::BTreeMap;
-use alloc::{string::String, sync::Arc};
+use alloc::{string::String, sync::Arc, vec::Vec};
 use alloc::format;
 use spin::RwLock;
 
 mod ports;
 mod device;
-use device::{PciLocation, Device};
+use device::{Bar, PciLocation, Device, HEADER_TYPE_MULTIFUNCTION};
 
 impl DirLike for Device {
     fn get_dir(&self, _name: &str) -> Result<Arc<RwLock<dyn DirLike + Send + Sync>>, syscalls::SyscallError> {
@@ -36,43 +36,70 @@ impl DirLike for Device {
     }
     fn query(&self) -> String {
         format!("{{
-\"name\": \"{vendor_id:04X}_{device_id:04X}\",
+\"name\": \"{name}\",
 \"description\": \"{self}\",
 \"address\": \"0x{address:0X}\",
+\"bus\": {bus},
+\"device\": {slot},
+\"function\": {function},
 \"vendor_id\": \"0x{vendor_id:04X}\",
 \"device_id\": \"0x{device_id:04X}\",
 \"class\": {class},
 \"subclass\": {subclass},
+\"prog_if\": {prog_if},
 \"subsystem_id\": {subsystem_id},
+\"bars\": [{bars}],
 \"subdirs\": [],
 \"files\": []}}",
+                name = self.location.name(),
                 address = self.location.address() as u64,
+                bus = self.location.bus,
+                slot = self.location.slot,
+                function = self.location.function,
                 vendor_id = self.vendor_id,
                 device_id = self.device_id,
                 class = self.class,
                 subclass = self.subclass,
-                subsystem_id = self.subsystem_id)
+                prog_if = self.prog_if,
+                subsystem_id = self.subsystem_id,
+                bars = self.bars.iter()
+                    .map(|bar| format!("{}", bar))
+                    .collect::<Vec<String>>()
+                    .join(", "))
     }
 }
 
 struct DeviceCollection {
-    devices: BTreeMap<String, Arc<RwLock<Device>>>
+    /// Keyed by PciLocation::name, so identical devices and the
+    /// functions of a multifunction device are all kept
+    devices: BTreeMap<String, Arc<RwLock<Device>>>,
+    /// Number of FIND_DEVICE requests
+    lookups: u64,
+    /// Number of FIND_DEVICE requests which found no device
+    not_found: u64,
+    /// See message::diag
+    log_level: u64
 }
 
 impl DeviceCollection {
     fn new() -> Self {
-        Self{devices: BTreeMap::new()}
+        Self{devices: BTreeMap::new(),
+             lookups: 0,
+             not_found: 0,
+             log_level: diag::LOG_INFO}
     }
 
     fn insert(&mut self, device: Device) {
-        self.devices.insert(format!("{:04X}_{:04X}",
-                                    device.vendor_id, device.device_id),
+        self.devices.insert(device.location.name(),
                             Arc::new(RwLock::new(device)));
     }
 
-    fn find(&self, vendor_id: u16, device_id:u16) -> Option<PciLocation> {
-        println!("[pci] Finding device [{:04X}:{:04X}]",
-                 vendor_id, device_id);
+    fn find(&mut self, vendor_id: u16, device_id:u16) -> Option<PciLocation> {
+        if self.log_level >= diag::LOG_INFO {
+            println!("[pci] Finding device [{:04X}:{:04X}]",
+                     vendor_id, device_id);
+        }
+        self.lookups += 1;
 
         if let Some((_key, device)) = self.devices.iter().find(
             |&(_key, d)| {
@@ -81,9 +108,21 @@ impl DeviceCollection {
                     d.device_id == device_id}) {
             Some(device.read().location)
         } else {
+            self.not_found += 1;
             None
         }
     }
+
+    /// JSON array of the devices with a class code, or all devices
+    /// if `class` is pci::ANY_CLASS
+    fn query_devices(&self, class: u64) -> String {
+        let devices = self.devices.values()
+            .filter(|device| class == pci::ANY_CLASS ||
+                    device.read().class as u64 == class)
+            .map(|device| device.read().query())
+            .collect::<Vec<String>>();
+        format!("[{}]", devices.join(", "))
+    }
 }
 
 impl DirLike for DeviceCollection {
@@ -118,17 +157,40 @@ impl DirLike for DeviceCollection {
 \"description\": \"PCI bus devices\",
 \"messages\": [{{\"name\": \"find_device\",
                  \"tag\": {find_device_tag}}},
+               {{\"name\": \"query_devices\",
+                 \"tag\": {query_devices_tag}}},
                {{\"name\": \"read_bar\",
                  \"tag\": {read_bar_tag}}},
+               {{\"name\": \"request_bar\",
+                 \"tag\": {request_bar_tag}}},
                {{\"name\": \"query\",
-                 \"tag\": {query_tag}}}],
+                 \"tag\": {query_tag}}},
+               {{\"name\": \"diag\",
+                 \"tag\": {diag_tag}}}],
 \"subdirs\": [{device_list}],
 \"files\": []}}",
                 find_device_tag = pci::FIND_DEVICE,
+                query_devices_tag = pci::QUERY_DEVICES,
                 read_bar_tag = pci::READ_BAR,
+                request_bar_tag = pci::REQUEST_BAR,
                 query_tag = message::QUERY,
+                diag_tag = message::DIAG,
                 device_list = device_list)
     }
+    fn diag(&mut self, command: diag::Command) -> Result<String, syscalls::SyscallError> {
+        match command {
+            diag::Command::DumpState => {}
+            diag::Command::ResetCounters => {
+                self.lookups = 0;
+                self.not_found = 0;
+            }
+            diag::Command::SetLogLevel(level) => {
+                self.log_level = level;
+            }
+        }
+        Ok(format!("{{\"devices\": {}, \"lookups\": {}, \"not_found\": {}, \"log_level\": {}}}",
+                   self.devices.len(), self.lookups, self.not_found, self.log_level))
+    }
 }
 
 #[no_mangle]
@@ -143,14 +205,27 @@ fn main() {
                 PciLocation{bus,
                             slot,
                             function:0}).get_device() {
+                let multifunction = device.header_type & HEADER_TYPE_MULTIFUNCTION != 0;
                 println!("[pci] Device {}", device);
                 devices.insert(device);
+
+                if multifunction {
+                    for function in 1..8 {
+                        if let Some(device) = (
+                            PciLocation{bus, slot, function}).get_device() {
+                            println!("[pci] Device {}", device);
+                            devices.insert(device);
+                        }
+                    }
+                }
             }
         }
     }
 
     let devices = Arc::new(RwLock::new(devices));
 
+    server::signal_ready();
+
     handle_directory(
         devices.clone(),
         STDIN.clone(),
@@ -163,7 +238,7 @@ fn main() {
                 Message::Short(
                     pci::FIND_DEVICE, vendor, device) => {
 
-                    if let Some(location) = devices.read().find((vendor & 0xFFFF) as u16,
+                    if let Some(location) = devices.write().find((vendor & 0xFFFF) as u16,
                                                                 (device & 0xFFFF) as u16) {
                         syscalls::send(&STDIN,
                                        syscalls::Message::Short(
@@ -179,6 +254,17 @@ fn main() {
                     }
                 }
 
+                // List devices as JSON
+                Message::Short(
+                    pci::QUERY_DEVICES, class, _) => {
+                    let json = devices.read().query_devices(class);
+                    syscalls::send(&STDIN,
+                                   syscalls::Message::Long(
+                                       message::JSON,
+                                       (json.len() as u64).into(),
+                                       syscalls::MemoryHandle::from_u8_slice(json.as_bytes()).into()));
+                }
+
                 // Read Base Address Register
                 Message::Short(
                     pci::READ_BAR, address, bar_id) => {
@@ -201,6 +287,47 @@ fn main() {
                                        pci::BAR,
                                        bar_value as u64, bar_id));
                 }
+                // Enable a device, decode a BAR and map memory BARs
+                Message::Short(
+                    pci::REQUEST_BAR, address, bar_id) => {
+
+                    let not_found = syscalls::Message::Short(
+                        pci::NOTFOUND, 0xFFFF_FFFF_FFFF_FFFF, 0);
+                    if address > 0xFFFF_FFFF || bar_id > 5 {
+                        syscalls::send(&STDIN, not_found);
+                        return;
+                    }
+
+                    let location = PciLocation::from_address(address as u32);
+                    let interrupt_line = location.interrupt_line();
+                    let reply = match location.enable_bar(bar_id as u8) {
+                        Some(Bar::Io{port, size}) => syscalls::Message::Short(
+                            pci::IO_BAR,
+                            pci::BarInfo{base: port as u64,
+                                         size: size as u64,
+                                         interrupt_line}.to_value(),
+                            0),
+                        Some(Bar::Memory{address, size, ..}) => {
+                            match syscalls::map_device_memory(address, size) {
+                                Ok(memory) => syscalls::Message::Long(
+                                    pci::MEMORY_BAR,
+                                    pci::BarInfo{base: address,
+                                                 size,
+                                                 interrupt_line}.to_value().into(),
+                                    memory.into()),
+                                Err(err) => {
+                                    println!("[pci] Couldn't map BAR {} of {}: {}",
+                                             bar_id, location, err);
+                                    syscalls::Message::Short(
+                                        message::ERROR, err.as_u64(), 0)
+                                }
+                            }
+                        }
+                        None => not_found
+                    };
+                    syscalls::send(&STDIN, reply);
+                }
+
                 // Enable bus mastering, allowing a device to use DMA
                 // https://github.com/vinc/moros/blob/trunk/src/sys/pci.rs#L74
                 Message::Short(
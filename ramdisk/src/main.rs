@@ -10,7 +10,7 @@ use core::{str, cmp};
 use spin::RwLock;
 
 use euralios_std::{println,
-                   server::{FileLike, DirLike, handle_directory},
+                   server::{self, FileLike, DirLike, handle_directory},
                    message,
                    syscalls::{self, STDIN},
                    sys::path::MAIN_SEP_STR};
@@ -42,13 +42,28 @@ impl FileLike for File {
     }
     fn write(&mut self, start: usize, buffer: &[u8]) -> Result<usize, syscalls::SyscallError> {
         println!("[ramdisk] Writing {} bytes", buffer.len());
-        self.data.extend_from_slice(buffer);
+        let end = start.checked_add(buffer.len())
+            .ok_or(syscalls::SYSCALL_ERROR_PARAM)?;
+        if end > self.data.len() {
+            // Writing past the end fills any gap with zeros
+            self.data.try_reserve(end - self.data.len())
+                .map_err(|_| syscalls::SYSCALL_ERROR_NO_SPACE)?;
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(buffer);
         Ok(buffer.len())
     }
     fn clear(&mut self) -> Result<(), syscalls::SyscallError> {
         self.data.clear();
+        self.data.shrink_to_fit(); // Release reserved space
         Ok(())
     }
+    fn allocate(&mut self, len: usize) -> Result<(), syscalls::SyscallError> {
+        println!("[ramdisk] Reserving {} bytes", len);
+        let additional = len.saturating_sub(self.data.len());
+        self.data.try_reserve_exact(additional)
+            .map_err(|_| syscalls::SYSCALL_ERROR_NO_SPACE)
+    }
 }
 
 /// A tree structure of directories containing File objects
@@ -59,7 +74,7 @@ impl FileLike for File {
 /// - Hard links where multiple files point to the same data
 pub struct Directory {
     subdirs: BTreeMap<String, Arc<RwLock<Directory>>>,
-    files: BTreeMap<String, Arc<RwLock<File>>>
+    files: BTreeMap<String, Arc<RwLock<dyn FileLike + Send + Sync>>>
 }
 
 impl Directory {
@@ -126,6 +141,7 @@ impl DirLike for Directory {
         // Combine into a String and return
         format!("{{
 \"short\": \"Ramdisk directory\",
+\"type\": \"dir\",
 \"messages\": [{{\"name\": \"open\",
                  \"tag\": {open_tag}}},
                {{\"name\": \"query\",
@@ -145,7 +161,7 @@ impl DirLike for Directory {
             // Cannot contain separator
             return Err(syscalls::SYSCALL_ERROR_PARAM);
         }
-        if self.subdirs.contains_key(path) {
+        if self.subdirs.contains_key(path) || self.files.contains_key(path) {
             // Already exists
             return Err(syscalls::SYSCALL_ERROR_EXISTS);
         }
@@ -156,7 +172,7 @@ impl DirLike for Directory {
     /// Create a new file, returning a shared reference
     fn make_file(&mut self, name: &str) -> Result<Arc<RwLock<dyn FileLike + Send + Sync>>, syscalls::SyscallError> {
         println!("[ramdisk] Making file {}", name);
-        let new_file = Arc::new(RwLock::new(File::new()));
+        let new_file: Arc<RwLock<dyn FileLike + Send + Sync>> = Arc::new(RwLock::new(File::new()));
         self.files.insert(String::from(name), new_file.clone());
         Ok(new_file)
     }
@@ -166,11 +182,44 @@ impl DirLike for Directory {
         println!("[ramdisk] Removing file {}", path);
 
         if let Some(file) = self.files.remove(path) {
+            // Any open handles keep the contents until closed
             Ok(file)
+        } else if self.subdirs.contains_key(path) {
+            Err(syscalls::SYSCALL_ERROR_IS_DIR)
         } else {
             Err(syscalls::SYSCALL_ERROR_NOTFOUND)
         }
     }
+    /// Delete an empty sub-directory
+    fn remove_dir(&mut self, path: &str) -> Result<Arc<RwLock<dyn DirLike + Send + Sync>>, syscalls::SyscallError> {
+        let path = path.trim_start_matches('/');
+        println!("[ramdisk] Removing directory {}", path);
+
+        let subdir = match self.subdirs.get(path) {
+            Some(subdir) => subdir,
+            None if self.files.contains_key(path) => return Err(syscalls::SYSCALL_ERROR_NOT_DIR),
+            None => return Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+        };
+        {
+            let subdir = subdir.read();
+            if !subdir.files.is_empty() || !subdir.subdirs.is_empty() {
+                return Err(syscalls::SYSCALL_ERROR_NOT_EMPTY);
+            }
+        }
+        match self.subdirs.remove(path) {
+            Some(subdir) => Ok(subdir),
+            None => Err(syscalls::SYSCALL_ERROR_NOTFOUND)
+        }
+    }
+    /// Add a file moved from another directory
+    fn add_file(&mut self, name: &str, file: Arc<RwLock<dyn FileLike + Send + Sync>>) -> Result<(), syscalls::SyscallError> {
+        println!("[ramdisk] Adding file {}", name);
+        if name.contains(MAIN_SEP_STR) {
+            return Err(syscalls::SYSCALL_ERROR_PARAM);
+        }
+        self.files.insert(String::from(name), file);
+        Ok(())
+    }
 }
 
 #[no_mangle]
@@ -179,6 +228,8 @@ fn main() {
 
     let fs = Directory::new();
 
+    server::signal_ready();
+
     handle_directory(
         Arc::new(RwLock::new(fs)),
         STDIN.clone(),
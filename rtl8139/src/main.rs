@@ -1,11 +1,14 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+use alloc::collections::VecDeque;
 use core::ptr;
 use euralios_std::{println,
                    syscalls::{self, MemoryHandle, STDIN},
                    net::MacAddress,
-                   message::{self, rcall, pci, MessageData},
+                   message::{self, pci, MessageData},
+                   server,
                    ports::{outportb, outportw, outportd,
                            inportb, inportw, inportd}};
 
@@ -17,33 +20,46 @@ fn main() {
 
     let handle = syscalls::open("/pci", message::O_READ).expect("Couldn't open pci");
 
-    // Use PCI program to look for device
-    let (msg_type, md_address, _) = rcall(&handle, pci::FIND_DEVICE,
-                                          0x10EC.into(), 0x8139.into(),
-                                          None).unwrap();
-    let address = md_address.value();
-    if msg_type != pci::ADDRESS {
-        println!("[rtl8139] Device not found. Exiting.");
-        return;
-    }
+    // Use PCI program to look for a network controller
+    let nic = match pci::query_devices(&handle, Some(pci::CLASS_NETWORK)) {
+        Ok(devices) => devices.into_iter().find(
+            |device| device.vendor_id == VENDOR_ID && device.device_id == DEVICE_ID),
+        Err(err) => {
+            println!("[rtl8139] Couldn't list PCI devices: {}", err);
+            None
+        }
+    };
+    let nic = match nic {
+        Some(nic) => nic,
+        None => {
+            println!("[rtl8139] Device not found. Exiting.");
+            server::signal_failed(syscalls::SYSCALL_ERROR_NOTFOUND);
+            return;
+        }
+    };
+    let address = nic.address as u64;
     println!("[rtl8139] Found at address: {:08X}", address);
 
-    // Enable bus mastering so the card can access main memory
-    syscalls::send(&handle, syscalls::Message::Short(
-        pci::ENABLE_BUS_MASTERING, address, 0)).unwrap();
-
-    // Read BAR0 to get the I/O address
-    let (_, md_bar0, _) = rcall(&handle, pci::READ_BAR,
-                                address.into(), 0.into(),
-                                Some(pci::BAR)).unwrap();
-    let bar0 = md_bar0.value();
-    let ioaddr = (bar0 & 0xFFFC) as u16;
-    println!("[rtl8139] BAR0: {:08X}. I/O addr: {:04X}", bar0, ioaddr);
+    // BAR0 is the I/O address. This also enables bus mastering
+    // so the card can access main memory
+    let ioaddr = match pci::request_bar(&handle, address, 0) {
+        Ok((pci::Bar::Io{port, ..}, interrupt_line)) => {
+            println!("[rtl8139] I/O addr: {:04X}. IRQ {}", port, interrupt_line);
+            port
+        }
+        value => {
+            println!("[rtl8139] BAR0 is not I/O space: {:?}", value);
+            server::signal_failed(syscalls::SYSCALL_ERROR_NOTFOUND);
+            return;
+        }
+    };
 
     let mut device = {
         // Allocate memory for receive buffer
+        // With WRAP set a packet can run past the end of the ring
         let (rx_buffer, rx_buffer_physaddr) =
-            syscalls::malloc(8192 + 16, 0xFFFF_FFFF).unwrap();
+            syscalls::malloc(RX_BUFFER_LEN + RX_BUFFER_PAD + RX_MAX_FRAME,
+                             0xFFFF_FFFF).unwrap();
 
         // Allocate transmit buffers
         // Initializing arrays in Rust is awkward, so just repeat 4 times
@@ -57,18 +73,22 @@ fn main() {
                tx_buffer: [tx1, tx2, tx3, tx4],
                tx_buffer_physaddr: [tx1_addr as u32, tx2_addr as u32,
                                     tx3_addr as u32, tx4_addr as u32],
-               active_tx_id: 0}};
+               active_tx_id: 0,
+               pending: VecDeque::new()}};
 
     match device.reset() {
         Ok(()) => println!("[rtl8139] Device reset OK"),
         Err(message) => {
             println!("[rtl8139] Device failed to reset: {}", message);
+            server::signal_failed(syscalls::SYSCALL_ERROR_NOTFOUND);
             return;
         }
     }
 
     println!("[rtl8139] MAC address {}", device.mac_address());
 
+    server::signal_ready();
+
     // Server loop. Note: Single threaded for now
     loop {
         match syscalls::receive(&STDIN) {
@@ -78,7 +98,8 @@ fn main() {
                         message::READ, _, _) => {
 
                         // Check if a packet has been received
-                        if let Some((length, handle)) = device.receive_packet() {
+                        device.receive_all();
+                        if let Some((length, handle)) = device.pending.pop_front() {
                             // Received data in a MemoryHandle
                             // -> Send back
                             syscalls::send(
@@ -133,6 +154,11 @@ fn main() {
     }
 }
 
+/// Realtek PCI vendor ID
+const VENDOR_ID: u16 = 0x10EC;
+/// RTL8139 PCI device ID
+const DEVICE_ID: u16 = 0x8139;
+
 const REG_TBSTART: u16 = 0x20; // 32-bit
 const REG_RBSTART: u16 = 0x30; // 32-bit physical memory address
 const REG_CMD: u16 = 0x37;  // 8-bit
@@ -144,7 +170,14 @@ const REG_TX_CONFIG: u16 = 0x40; // Transmit buffer configuration
 const REG_RX_CONFIG: u16 = 0x44; // Receive buffer configuration
 const REG_CONFIG_1: u16 = 0x52;
 
+/// Size of the receive ring (RBLEN = 00 in RX_CONFIG)
+const RX_BUFFER_LEN: u64 = 8192;
+/// CAPR is 16 bytes behind the read position
 const RX_BUFFER_PAD: u64 = 16;
+/// Space after the ring for a packet which wraps around
+const RX_MAX_FRAME: u64 = 1500;
+/// Maximum number of received packets waiting for READ messages
+const MAX_PENDING_PACKETS: usize = 64;
 const TX_BUFFER_LEN: u16 = 1792; // Maximum data length
 
 // Interframe Gap Time
@@ -164,7 +197,17 @@ const TCR_MXDMA2: u32 = 1 << 10;
 
 const CR_BUFFER_EMPTY: u8 = 1;
 
+/// Receive status: Packet received OK
 const ROK: u16 = 0x01;
+
+/// RX_CONFIG: Write packets past the end of the ring rather than
+/// wrapping to the start
+const RCR_WRAP: u32 = 1 << 7;
+
+/// Interrupt status: Receive OK
+const ISR_ROK: u16 = 0x01;
+/// Interrupt status: Receive buffer overflow
+const ISR_RX_OVERFLOW: u16 = 0x10;
 const TOK: u32 = 1 << 15; // Transmit OK
 
 const TOWN: u32 = 1 << 13; // DMA operation completed
@@ -181,7 +224,10 @@ struct Device {
     tx_buffer_physaddr: [u32; 4],
 
     // The currently active transmit buffer
-    active_tx_id: usize
+    active_tx_id: usize,
+
+    /// Packets read from the receive ring, waiting for READ messages
+    pending: VecDeque<(u16, MemoryHandle)>
 }
 
 impl Device {
@@ -234,7 +280,7 @@ impl Device {
         //       to NIC's MAC address.
         // AAP - Accept All Packets. Accept all packets
         //       (run in promiscuous mode).
-        outportd(self.ioaddr + REG_RX_CONFIG, 0xf); // 0xf is AB+AM+APM+AAP
+        outportd(self.ioaddr + REG_RX_CONFIG, 0xf | RCR_WRAP); // 0xf is AB+AM+APM+AAP
 
         // Configure transmit buffer
         outportd(self.ioaddr + REG_TX_CONFIG,
@@ -277,12 +323,14 @@ impl Device {
         let cbr = inportw(self.ioaddr + REG_CBR);
 
         // CAPR starts at 65520 and with the pad it overflows to 0
-        let offset = ((capr as u64) + RX_BUFFER_PAD) & 0xFFFF;
+        let offset = (((capr as u64) + RX_BUFFER_PAD) & 0xFFFF) % RX_BUFFER_LEN;
 
         let header = unsafe{*((self.rx_buffer.as_u64() + offset) as *const u16)};
         if header & ROK != ROK {
             println!("    => Packet not ok");
-            outportw(self.ioaddr + REG_CAPR, cbr);
+            // Discard everything up to the write pointer
+            outportw(self.ioaddr + REG_CAPR,
+                     cbr.wrapping_sub(RX_BUFFER_PAD as u16));
             return None;
         }
 
@@ -302,14 +350,31 @@ impl Device {
                                      length as usize);
         }
 
-        // Update buffer read pointer
-        let rx_offset = ((offset as u16) + length + 4 + 3) & !3;
+        // Update buffer read pointer: Past the header, length and
+        // packet, rounded up to a dword, wrapping around the ring
+        let rx_offset = ((offset + length as u64 + 4 + 3) & !3) % RX_BUFFER_LEN;
         outportw(self.ioaddr + REG_CAPR,
-                 rx_offset - (RX_BUFFER_PAD as u16));
+                 (rx_offset as u16).wrapping_sub(RX_BUFFER_PAD as u16));
 
         Some((length, mem_handle))
     }
 
+    /// Read every packet in the receive ring into `pending`, then
+    /// acknowledge the receive interrupt status
+    ///
+    /// Several packets can arrive between READ messages. Emptying the
+    /// ring each time makes overflows less likely.
+    fn receive_all(&mut self) {
+        while self.pending.len() < MAX_PENDING_PACKETS {
+            match self.receive_packet() {
+                Some(packet) => self.pending.push_back(packet),
+                None => break
+            }
+        }
+        // Write 1 to clear
+        outportw(self.ioaddr + REG_ISR, ISR_ROK | ISR_RX_OVERFLOW);
+    }
+
     /// Send a packet to the device
     ///
     /// handle should point to memory containing
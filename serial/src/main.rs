@@ -0,0 +1,226 @@
+#![no_std]
+#![no_main]
+
+//! 16550 UART driver for the first serial port (COM1)
+//!
+//! The kernel reads received bytes in its IRQ4 handler, and this
+//! driver passes them on to every handle opened for reading. See
+//! euralios_std::message::serial for the protocol.
+//!
+//! <https://wiki.osdev.org/Serial_Ports>
+
+extern crate alloc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use euralios_std::{println,
+                   syscalls::{self, CommHandle, STDIN},
+                   message::{self, serial, Message, MessageData},
+                   ports::{outportb, inportb},
+                   server,
+                   thread};
+
+/// I/O port of COM1
+const COM1: u16 = 0x3F8;
+
+// Register offsets from the base port
+/// Transmit (write) and receive (read). Divisor low byte if DLAB set
+const DATA: u16 = 0;
+/// Interrupt enable. Divisor high byte if DLAB set
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// Line control: Divisor Latch Access Bit
+const LINE_DLAB: u8 = 0x80;
+/// Line control: 8 data bits, no parity, one stop bit
+const LINE_8N1: u8 = 0x03;
+/// FIFO control: Enable and clear FIFOs, interrupt at 14 bytes
+const FIFO_ENABLE: u8 = 0xC7;
+/// Modem control: DTR, RTS, and OUT2 which connects the IRQ line
+const MODEM_DTR_RTS_OUT2: u8 = 0x0B;
+/// Interrupt enable: Received data available
+const INTERRUPT_RECEIVED: u8 = 0x01;
+/// Line status: Transmit holding register empty
+const STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+/// How long to wait for each reader to receive a byte
+const SEND_TIMEOUT_US: u64 = 100_000;
+
+struct Uart {
+    base: u16
+}
+
+impl Uart {
+    /// Configure for 8N1 with FIFOs, interrupting on received data
+    fn init(&mut self, divisor: u16) {
+        outportb(self.base + INTERRUPT_ENABLE, 0);
+        self.set_divisor(divisor);
+        outportb(self.base + FIFO_CONTROL, FIFO_ENABLE);
+        outportb(self.base + MODEM_CONTROL, MODEM_DTR_RTS_OUT2);
+        outportb(self.base + INTERRUPT_ENABLE, INTERRUPT_RECEIVED);
+    }
+
+    /// Set the baud rate divisor, leaving the line in 8N1
+    fn set_divisor(&mut self, divisor: u16) {
+        outportb(self.base + LINE_CONTROL, LINE_DLAB);
+        outportb(self.base + DATA, divisor as u8);
+        outportb(self.base + INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        outportb(self.base + LINE_CONTROL, LINE_8N1);
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        while inportb(self.base + LINE_STATUS) & STATUS_TRANSMIT_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        outportb(self.base + DATA, byte);
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+    }
+}
+
+#[no_mangle]
+fn main() {
+    println!("[serial] Starting driver");
+
+    let mut uart = Uart{base: COM1};
+    uart.init(serial::baud_divisor(serial::DEFAULT_BAUD).unwrap());
+    let uart = Arc::new(Mutex::new(uart));
+
+    let readers: Arc<RwLock<Vec<CommHandle>>> = Arc::new(RwLock::new(Vec::new()));
+
+    let open_readers = readers.clone();
+    if let Err(err) = thread::spawn(move || open_loop(uart, open_readers)) {
+        println!("[serial] Couldn't start thread: {}", err);
+        server::signal_failed(err);
+        return;
+    }
+
+    server::signal_ready();
+
+    loop {
+        let byte = match syscalls::await_irq(syscalls::IRQ_SERIAL) {
+            Ok(b'\r') => b'\n',
+            Ok(byte) => byte,
+            Err(err) => {
+                println!("[serial] Couldn't wait for interrupt: {}", err);
+                return;
+            }
+        };
+        publish(&readers, byte);
+    }
+}
+
+/// Send a received byte to all readers, removing closed handles
+fn publish(readers: &RwLock<Vec<CommHandle>>, byte: u8) {
+    readers.write().retain(|handle| {
+        match syscalls::send_timeout(handle,
+                                     Message::Short(message::CHAR,
+                                                    byte as u64, 0),
+                                     SEND_TIMEOUT_US) {
+            Err((syscalls::SYSCALL_ERROR_CLOSED, _)) => false,
+            _ => true // Sent, or reader missed this byte
+        }
+    });
+}
+
+/// Reply to open messages with a new handle
+///
+/// Readers are added to the list which received bytes are sent to.
+/// Each writer is served by its own thread.
+fn open_loop(uart: Arc<Mutex<Uart>>, readers: Arc<RwLock<Vec<CommHandle>>>) {
+    loop {
+        let flags = match syscalls::receive(&STDIN) {
+            Ok(Message::Short(tag, _, _)) |
+            Ok(Message::Long(tag, _, _)) if tag & !message::OPEN_FLAGS_MASK == message::OPEN => {
+                tag & message::OPEN_FLAGS_MASK
+            }
+            Ok(message) => {
+                println!("[serial] Unknown message {:?}", message);
+                continue;
+            }
+            Err(err) => {
+                println!("[serial] Receive error {}", err);
+                // Wait and try again
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+
+        let (handle, client_handle) = match syscalls::new_rendezvous() {
+            Ok(handles) => handles,
+            Err(err) => {
+                println!("[serial] Couldn't create Rendezvous {}", err);
+                syscalls::send(&STDIN, Message::Short(message::ERROR, err.as_u64(), 0));
+                continue;
+            }
+        };
+
+        if flags & message::O_WRITE == 0 {
+            readers.write().push(handle);
+        } else {
+            let uart = uart.clone();
+            if let Err(err) = thread::spawn(move || write_handler(uart, handle)) {
+                println!("[serial] Couldn't start writer thread: {}", err);
+                syscalls::send(&STDIN, Message::Short(message::ERROR, err.as_u64(), 0));
+                continue;
+            }
+        }
+        syscalls::send(&STDIN,
+                       Message::Long(
+                           message::COMM_HANDLE,
+                           client_handle.into(), 0.into()));
+    }
+}
+
+fn write_handler(uart: Arc<Mutex<Uart>>, comm_handle: CommHandle) {
+    loop {
+        let reply = match syscalls::receive(&comm_handle) {
+            Ok(Message::Long(message::WRITE,
+                             MessageData::Value(length),
+                             MessageData::MemoryHandle(data))) => {
+                // Don't read past the end of the chunk
+                if data.size().map_or(true, |size| length as usize > size) {
+                    Message::Short(message::ERROR,
+                                   syscalls::SYSCALL_ERROR_PARAM.as_u64(), 0)
+                } else {
+                    uart.lock().write(data.as_slice::<u8>(length as usize));
+                    Message::Short(message::OK, length, 0)
+                }
+            }
+            Ok(Message::Short(message::CHAR, ch, _)) => {
+                // No reply, as for the VGA driver
+                uart.lock().write(&[ch as u8]);
+                continue;
+            }
+            Ok(Message::Short(serial::SET_BAUD, baud, _)) => {
+                match serial::baud_divisor(baud) {
+                    Some(divisor) => {
+                        uart.lock().set_divisor(divisor);
+                        Message::Short(message::OK, 0, 0)
+                    }
+                    None => Message::Short(message::ERROR,
+                                           syscalls::SYSCALL_ERROR_PARAM.as_u64(), 0)
+                }
+            }
+            Ok(_) => Message::Short(message::ERROR_UNKNOWN_MESSAGE, 0, 0),
+            Err(syscalls::SYSCALL_ERROR_CLOSED) => return,
+            Err(err) => {
+                println!("[serial] Receive error {}", err);
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+        syscalls::send(&comm_handle, reply);
+    }
+}
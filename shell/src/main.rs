@@ -6,14 +6,17 @@ use alloc::string::String;
 use alloc::vec::Vec;
 use core::str;
 
-use euralios_std::{path::{Path, PathBuf},
+use euralios_std::{path::Path,
+                   console,
+                   env,
                    fs::{self, File},
                    io,
                    message,
                    print, println,
                    syscalls::{self, SyscallError, VFS}};
 
-fn exec_path(path: &Path) -> Result<(), SyscallError> {
+/// Run a program. The first argument is the command name
+fn exec_path(path: &Path, args: &[&str]) -> Result<(), SyscallError> {
     // Read binary from file
     let bin = {
         let mut bin: Vec<u8> = Vec::new();
@@ -25,12 +28,13 @@ fn exec_path(path: &Path) -> Result<(), SyscallError> {
     // Create a communication handle for the input
     let (exe_input, exe_input2) = syscalls::new_rendezvous()?;
 
-    syscalls::exec(
+    syscalls::exec_args(
         &bin,
         0, // Permission flags
         exe_input2,
         syscalls::STDOUT.clone(),
-        VFS::shared())?;
+        VFS::shared(),
+        args)?;
 
     loop {
         // Wait for keyboard input
@@ -68,29 +72,35 @@ fn help() {
   mount           List mounted filesystems
   umount <path>   Un-mount a filesystem
   mkdir <path>    Make a directory
+  dmesg [-w]      Print kernel messages. -w waits for new messages
+  ps              List threads waiting to run
   exit            Exit shell
+
+* Settings, read from /ramdisk/etc/environment by new shells:
+  TERM_FG, TERM_BG  Console colors (black red green yellow
+                    blue magenta cyan white)
+  PS1               Prompt. \\w is the current directory
 "
     );
 }
 
 /// List a directory
-fn ls(current_directory: &Path, args: Vec<&str>) {
+fn ls(args: Vec<&str>) {
     if args.len() > 1 {
         println!("Usage: ls [path]");
         return;
     }
 
     let option_rd = if args.len() == 0 {
-        fs::read_dir(current_directory)
+        fs::read_dir(syscalls::getcwd())
     } else {
-        let path = fs::canonicalize(PathBuf::from(current_directory).join(args[0])).unwrap();
-        fs::read_dir(path)
+        fs::read_dir(args[0])
     };
 
     if let Ok(rd) = option_rd {
         for entry in rd {
             if let Ok(obj) = entry {
-                if obj.metadata().unwrap().is_dir() {
+                if obj.is_dir() {
                     println!("\x1b[34m{}\x1b[m", obj.file_name());
                 } else {
                     println!("{}", obj.file_name());
@@ -113,47 +123,157 @@ fn umount(args: Vec<&str>) {
 }
 
 /// Delete a file
-fn rm(current_directory: &Path, args: Vec<&str>) {
+fn rm(args: Vec<&str>) {
     if args.len() != 1 {
         println!("Usage: rm <file>");
         return;
     }
     let file = args.first().unwrap();
 
-    let path = fs::canonicalize(PathBuf::from(current_directory).join(file)).unwrap();
-    if let Err(err) = fs::remove_file(path) {
+    if let Err(err) = fs::remove_file(file) {
         // Failed
         println!("rm: cannot remove {}: {}", file, err);
     }
 }
 
 /// Make a directory
-fn mkdir(current_directory: &Path, args: Vec<&str>) {
+fn mkdir(args: Vec<&str>) {
     if args.len() != 1 {
         println!("Usage: mkdir <directory>");
         return;
     }
     let arg = args.first().unwrap();
-    let path = fs::canonicalize(PathBuf::from(current_directory).join(arg)).unwrap();
-    if let Err(err) = fs::create_dir(path) {
+    if let Err(err) = fs::create_dir(arg) {
         // Failed
         println!("mkdir: cannot create {}: {:?}", arg, err);
     }
 }
 
+/// Print kernel messages
+fn dmesg(args: Vec<&str>) {
+    let follow = match args.as_slice() {
+        [] => false,
+        ["-w"] => true,
+        _ => {
+            println!("Usage: dmesg [-w]");
+            return;
+        }
+    };
+    let mut cursor = 0;
+    loop {
+        match syscalls::read_kernel_log(cursor, follow) {
+            Ok((data, start)) => {
+                if start > cursor && cursor != 0 {
+                    println!("dmesg: {} bytes of messages lost", start - cursor);
+                }
+                print!("{}", String::from_utf8_lossy(&data));
+                cursor = start + data.len() as u64;
+            }
+            Err(err) => {
+                println!("dmesg: {}", err);
+                return;
+            }
+        }
+        if !follow {
+            return;
+        }
+    }
+}
+
+/// List threads which are running or waiting to run
+fn ps() {
+    match syscalls::list_threads() {
+        Ok(threads) => {
+            println!("  TID TYPE   RIP");
+            for thread in threads {
+                println!("{:5} {:6} {:#x}{}",
+                         thread.tid,
+                         if thread.is_kernel() { "kernel" } else { "user" },
+                         thread.rip,
+                         if thread.is_current() { " (ps)" } else { "" });
+            }
+        }
+        Err(err) => println!("ps: {}", err)
+    }
+}
+
+/// Console colors used if TERM_FG or TERM_BG are not set.
+/// These are the VGA driver's defaults.
+const DEFAULT_FOREGROUND: &str = "black";
+const DEFAULT_BACKGROUND: &str = "white";
+
+/// Prompt used if PS1 is not set
+const DEFAULT_PROMPT: &str = "$ ";
+
+/// Read a color name from an environment variable
+///
+/// Returns None if not set, or not one of console::COLOR_NAMES
+fn color_var(key: &str) -> Option<u8> {
+    let name = env::var(key).ok()?;
+    let color = console::color_number(&name);
+    if color.is_none() {
+        println!("shell: Ignoring invalid {} '{}'. Valid colors: {}",
+                 key, name, console::COLOR_NAMES.join(" "));
+    }
+    color
+}
+
+/// Set the console colors from the TERM_FG and TERM_BG variables
+fn apply_colors() {
+    let foreground = color_var("TERM_FG");
+    let background = color_var("TERM_BG");
+    if foreground.is_none() && background.is_none() {
+        return;
+    }
+    let foreground = foreground.or(console::color_number(DEFAULT_FOREGROUND)).unwrap();
+    let background = background.or(console::color_number(DEFAULT_BACKGROUND)).unwrap();
+    // Set colors, make them the default, then clear the screen
+    // so that the background is uniform
+    print!("\x1b[3{}m\x1b[4{}m\x1b[8]\x1b[2J\x1b[H", foreground, background);
+}
+
+/// Expand a PS1-style prompt
+///
+///  \w  Current directory
+///  \\  Backslash
+fn prompt(format: &str, current_directory: &Path) -> String {
+    let mut prompt = String::new();
+    let mut chars = format.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            prompt.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => prompt.push_str(
+                current_directory.as_os_str().to_str().unwrap_or("?")),
+            Some(other) => prompt.push(other),
+            None => prompt.push('\\')
+        }
+    }
+    prompt
+}
+
 #[no_mangle]
 fn main() {
+    apply_colors();
+    let prompt_format = env::var("PS1").unwrap_or(String::from(DEFAULT_PROMPT));
+
     println!("Type help [Enter] to see the shell help page.");
 
     let stdin = io::stdin();
     let mut line_buffer = String::new();
 
-    // Current Working Directory
-    let mut current_directory = PathBuf::from("/ramdisk");
+    // Start in the ramdisk unless a directory was inherited
+    if syscalls::getenv(syscalls::CWD_VAR).is_none() {
+        if let Err(err) = env::set_current_dir("/ramdisk") {
+            println!("shell: cd /ramdisk: {}", err);
+        }
+    }
 
     loop {
         // prompt
-        print!("$ ");
+        print!("{}", prompt(&prompt_format, &syscalls::getcwd()));
 
         // Read a line of input
         stdin.read_line(&mut line_buffer);
@@ -167,17 +287,18 @@ fn main() {
                 // Help
                 "help" | "?" => help(),
                 // List directory
-                "ls" => ls(&current_directory, args),
+                "ls" => ls(args),
                 // Print working directory
-                "pwd" => println!("{:?}", current_directory),
+                "pwd" => println!("{:?}", syscalls::getcwd()),
                 // Change directory
                 "cd" => {
                     if args.len() != 1 {
                         println!("Usage: cd <directory>");
                         continue;
                     }
-                    current_directory.push(args.first().unwrap());
-                    current_directory = fs::canonicalize(current_directory).unwrap();
+                    if let Err(err) = env::set_current_dir(args[0]) {
+                        println!("cd: {}: {}", args[0], err);
+                    }
                 },
                 "mount" => {
                     match syscalls::list_mounts() {
@@ -195,14 +316,16 @@ fn main() {
                     }
                 },
                 "umount" => umount(args),
-                "rm" => rm(&current_directory, args),
-                "mkdir" => mkdir(&current_directory, args),
+                "rm" => rm(args),
+                "mkdir" => mkdir(args),
+                "dmesg" => dmesg(args),
+                "ps" => ps(),
                 "exit" => return,
                 cmd => {
-                    let path = fs::canonicalize(current_directory.join(cmd)).unwrap();
-
-                    if let Err(err) = exec_path(&path) {
-                        println!("Couldn't open '{:?}': {}", path, err);
+                    let mut argv = Vec::from([cmd]);
+                    argv.extend_from_slice(&args);
+                    if let Err(err) = exec_path(Path::new(cmd), &argv) {
+                        println!("Couldn't open '{}': {}", cmd, err);
                     }
                 }
             }
@@ -0,0 +1,184 @@
+//! ARP cache
+//!
+//! smoltcp keeps its own neighbor cache, but only learns from ARP
+//! packets addressed to this host, and broadcasts a request whenever
+//! an entry is missing or has expired. This cache sits between
+//! smoltcp and the NIC driver:
+//!  - Every ARP packet received updates the cache, including
+//!    unsolicited replies, requests between other hosts, and
+//!    gratuitous ARP.
+//!  - ARP requests sent by smoltcp for a cached address are answered
+//!    here, rather than sent to the network.
+//!  - New or changed entries which smoltcp wouldn't learn from are
+//!    passed to it as replies, so its neighbor cache follows this one.
+//!
+//! While an address is being resolved, smoltcp keeps the packets
+//! waiting for it in their socket's transmit buffer, and sends them
+//! as soon as a reply fills its neighbor cache. Those buffers have
+//! a fixed size, so packets to an unreachable host can't use
+//! unbounded memory.
+
+extern crate alloc;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use lazy_static::lazy_static;
+
+use smoltcp::wire::{ArpOperation, ArpPacket, ArpRepr,
+                    EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr,
+                    Ipv4Address};
+
+use euralios_std::time;
+
+/// How long an entry is used after the last ARP packet from its host
+const ENTRY_LIFETIME_US: u64 = 60_000_000;
+/// Maximum number of hosts in the cache
+const CACHE_SIZE: usize = 64;
+/// Maximum number of replies waiting to be passed to smoltcp
+const MAX_INJECTED_FRAMES: usize = 16;
+
+struct Entry {
+    hardware_addr: EthernetAddress,
+    /// microseconds_monotonic after which the entry isn't used
+    expires_us: u64
+}
+
+struct ArpCache {
+    entries: BTreeMap<Ipv4Address, Entry>,
+    /// This host's addresses, from the ARP packets smoltcp sends
+    local: Option<(EthernetAddress, Ipv4Address)>,
+    /// Frames to pass to smoltcp as if they had been received
+    injected: VecDeque<Vec<u8>>
+}
+
+lazy_static! {
+    static ref ARP_CACHE: Mutex<ArpCache> = Mutex::new(ArpCache{
+        entries: BTreeMap::new(),
+        local: None,
+        injected: VecDeque::new()});
+}
+
+impl ArpCache {
+    fn lookup(&self, protocol_addr: Ipv4Address, now: u64) -> Option<EthernetAddress> {
+        self.entries.get(&protocol_addr)
+            .filter(|entry| entry.expires_us > now)
+            .map(|entry| entry.hardware_addr)
+    }
+
+    /// Add or refresh an entry
+    ///
+    /// Returns true if the entry is new or its address changed
+    fn learn(&mut self, protocol_addr: Ipv4Address,
+             hardware_addr: EthernetAddress, now: u64) -> bool {
+        if !protocol_addr.is_unicast() || !hardware_addr.is_unicast() ||
+            self.local.map(|(_, local)| local == protocol_addr).unwrap_or(false) {
+                return false;
+            }
+        let changed = self.lookup(protocol_addr, now) != Some(hardware_addr);
+
+        if !self.entries.contains_key(&protocol_addr) &&
+            self.entries.len() >= CACHE_SIZE {
+                // Remove expired entries, or if none the oldest
+                self.entries.retain(|_, entry| entry.expires_us > now);
+                let oldest = self.entries.iter()
+                    .min_by_key(|(_, entry)| entry.expires_us)
+                    .map(|(address, _)| *address);
+                if let (true, Some(oldest)) = (self.entries.len() >= CACHE_SIZE, oldest) {
+                    self.entries.remove(&oldest);
+                }
+            }
+        self.entries.insert(protocol_addr, Entry{
+            hardware_addr,
+            expires_us: now + ENTRY_LIFETIME_US});
+        changed
+    }
+
+    /// Queue an ARP reply from a host to this host, to be received
+    /// by smoltcp
+    fn inject_reply(&mut self, source_hardware_addr: EthernetAddress,
+                    source_protocol_addr: Ipv4Address) {
+        let (local_hardware_addr, local_protocol_addr) = match self.local {
+            Some(local) => local,
+            None => return // Not sent anything yet
+        };
+        let arp = ArpRepr::EthernetIpv4 {
+            operation: ArpOperation::Reply,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_hardware_addr: local_hardware_addr,
+            target_protocol_addr: local_protocol_addr
+        };
+        let ethernet = EthernetRepr {
+            src_addr: source_hardware_addr,
+            dst_addr: local_hardware_addr,
+            ethertype: EthernetProtocol::Arp
+        };
+
+        let mut frame = vec![0; ethernet.buffer_len() + arp.buffer_len()];
+        let mut ethernet_frame = EthernetFrame::new_unchecked(&mut frame[..]);
+        ethernet.emit(&mut ethernet_frame);
+        arp.emit(&mut ArpPacket::new_unchecked(ethernet_frame.payload_mut()));
+
+        if self.injected.len() >= MAX_INJECTED_FRAMES {
+            self.injected.pop_front();
+        }
+        self.injected.push_back(frame);
+    }
+}
+
+/// The ARP packet in an ethernet frame, if any
+fn parse(frame: &[u8]) -> Option<ArpRepr> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Arp {
+        return None;
+    }
+    ArpRepr::parse(&ArpPacket::new_checked(frame.payload()).ok()?).ok()
+}
+
+/// Called with each frame received from the NIC
+pub fn received(frame: &[u8]) {
+    if let Some(ArpRepr::EthernetIpv4{
+        source_hardware_addr, source_protocol_addr, target_protocol_addr, ..}) = parse(frame) {
+
+        let mut cache = ARP_CACHE.lock();
+        let changed = cache.learn(source_protocol_addr, source_hardware_addr,
+                                  time::microseconds_monotonic());
+        // smoltcp learns from packets addressed to this host itself
+        let to_local = cache.local
+            .map(|(_, local)| local == target_protocol_addr)
+            .unwrap_or(false);
+        if changed && !to_local {
+            cache.inject_reply(source_hardware_addr, source_protocol_addr);
+        }
+    }
+}
+
+/// Called with each frame smoltcp transmits
+///
+/// Returns false if the frame was handled here, and shouldn't be
+/// sent to the NIC.
+pub fn transmitting(frame: &[u8]) -> bool {
+    if let Some(ArpRepr::EthernetIpv4{
+        operation, source_hardware_addr, source_protocol_addr, target_protocol_addr, ..}) = parse(frame) {
+
+        let mut cache = ARP_CACHE.lock();
+        if source_protocol_addr.is_unicast() {
+            // May change when DHCP configures the interface
+            cache.local = Some((source_hardware_addr, source_protocol_addr));
+        }
+        if operation == ArpOperation::Request {
+            if let Some(hardware_addr) = cache.lookup(target_protocol_addr,
+                                                      time::microseconds_monotonic()) {
+                cache.inject_reply(hardware_addr, target_protocol_addr);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// The next frame to pass to smoltcp as if received from the NIC
+pub fn next_injected() -> Option<Vec<u8>> {
+    ARP_CACHE.lock().injected.pop_front()
+}
@@ -1,59 +1,129 @@
 //! Dynamic Host Configuration Protocol (DHCP)
 //! Configure IP, DNS and gatway
+//!
+//! The DHCP socket stays in the interface after the address is
+//! configured. smoltcp renews the lease at T1 and rebinds at T2 when
+//! the interface is polled, and `check` applies any new
+//! configuration.
 
-use smoltcp::wire::{IpCidr, Ipv4Cidr, IpAddress};
-use smoltcp::socket::{Dhcpv4Event, Dhcpv4Socket};
+use smoltcp::iface::SocketHandle;
+use smoltcp::wire::{IpCidr, Ipv4Address, Ipv4Cidr, IpAddress};
+use smoltcp::socket::{Dhcpv4Config, Dhcpv4Event, Dhcpv4Socket};
 use smoltcp::time::Instant;
+use spin::Mutex;
 
 use euralios_std::{println, print,
-                   syscalls};
+                   syscalls,
+                   time};
 
 use crate::Interface;
 use crate::dns;
 
+/// How long to wait for a DHCP server before using STATIC_ADDRESS
+const DHCP_TIMEOUT_US: u64 = 10_000_000;
+
+/// Used if no DHCP server responds. The defaults of QEMU user
+/// networking
+const STATIC_ADDRESS: Ipv4Address = Ipv4Address([10, 0, 2, 15]);
+const STATIC_PREFIX_LEN: u8 = 24;
+const STATIC_ROUTER: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
+const STATIC_DNS: Ipv4Address = Ipv4Address([10, 0, 2, 3]);
+
+/// The DHCP socket, once configure() has added it
+static DHCP_HANDLE: Mutex<Option<SocketHandle>> = Mutex::new(None);
+
 /// DHCP configuration on given interface
 ///
+/// Waits up to DHCP_TIMEOUT_US for a lease, then falls back to a
+/// static configuration. A lease which arrives later replaces the
+/// static configuration.
+///
 /// Based on <https://github.com/vinc/moros/blob/trunk/src/usr/dhcp.rs>
 pub fn configure(interface: &mut Interface) {
 
     let dhcp_socket = Dhcpv4Socket::new();
     let dhcp_handle = interface.add_socket(dhcp_socket);
+    *DHCP_HANDLE.lock() = Some(dhcp_handle);
 
-    if let Err(e) = interface.poll(Instant::from_millis(0)) { // This transmits
-        panic!("[tcp] Network Error: {}", e);
-    }
-
+    let start = time::microseconds_monotonic();
     loop {
-        let event = interface.get_socket::<Dhcpv4Socket>(dhcp_handle).poll();
-        match event {
-            None => {}
-            Some(Dhcpv4Event::Configured(config)) => {
-                interface.remove_socket(dhcp_handle);
-
-                print!("[tcp] DHCP: IP {}", config.address);
-                set_ipv4_addr(interface, config.address);
-
-                if let Some(router) = config.router {
-                    print!(" Router {}", router);
-                    interface.routes_mut().add_default_ipv4_route(router).unwrap();
-                }
-
-                for addr in config.dns_servers.iter()
-                    .filter(|addr| addr.is_some()).map(|addr| addr.unwrap()) {
-                        print!(" DNS {}", addr);
-                        dns::add_server(IpAddress::from(addr));
-                    }
-                println!("");
-                break;
-            }
-            Some(Dhcpv4Event::Deconfigured) => {
-            }
+        let now = time::microseconds_monotonic();
+        if let Err(e) = interface.poll(Instant::from_micros(now as i64)) { // This transmits
+            println!("[tcp] Network Error: {}", e);
+        }
+
+        if handle_event(interface, dhcp_handle) {
+            return;
+        }
+
+        if now - start > DHCP_TIMEOUT_US {
+            println!("[tcp] DHCP: No server after {} ms. Using static configuration",
+                     DHCP_TIMEOUT_US / 1000);
+            apply_static(interface);
+            return;
         }
         // Wait and retry
         syscalls::thread_yield();
     }
 }
 
+/// Apply lease changes. Called after each poll of the interface.
+pub fn check(interface: &mut Interface) {
+    let some_handle = *DHCP_HANDLE.lock();
+    if let Some(dhcp_handle) = some_handle {
+        handle_event(interface, dhcp_handle);
+    }
+}
+
+/// Process a DHCP socket event. Returns true if a lease was configured
+fn handle_event(interface: &mut Interface, dhcp_handle: SocketHandle) -> bool {
+    match interface.get_socket::<Dhcpv4Socket>(dhcp_handle).poll() {
+        None => false,
+        Some(Dhcpv4Event::Configured(config)) => {
+            apply_lease(interface, &config);
+            true
+        }
+        Some(Dhcpv4Event::Deconfigured) => {
+            // Lease expired without being renewed
+            println!("[tcp] DHCP: Lease lost");
+            set_ipv4_addr(interface, Ipv4Cidr::new(Ipv4Address::UNSPECIFIED, 0));
+            interface.routes_mut().remove_default_ipv4_route();
+            false
+        }
+    }
+}
+
+fn apply_lease(interface: &mut Interface, config: &Dhcpv4Config) {
+    print!("[tcp] DHCP: IP {}", config.address);
+    set_ipv4_addr(interface, config.address);
+
+    match config.router {
+        Some(router) => {
+            print!(" Router {}", router);
+            interface.routes_mut().add_default_ipv4_route(router).unwrap();
+        }
+        None => {
+            interface.routes_mut().remove_default_ipv4_route();
+        }
+    }
+
+    for addr in config.dns_servers.iter()
+        .filter(|addr| addr.is_some()).map(|addr| addr.unwrap()) {
+            print!(" DNS {}", addr);
+            dns::add_server(IpAddress::from(addr));
+        }
+    println!("");
+}
+
+fn apply_static(interface: &mut Interface) {
+    let cidr = Ipv4Cidr::new(STATIC_ADDRESS, STATIC_PREFIX_LEN);
+    println!("[tcp] Static: IP {} Router {} DNS {}",
+             cidr, STATIC_ROUTER, STATIC_DNS);
+    set_ipv4_addr(interface, cidr);
+    interface.routes_mut().add_default_ipv4_route(STATIC_ROUTER).unwrap();
+    dns::add_server(IpAddress::from(STATIC_DNS));
+}
+
 /// Set the IPv4 address of an interface
 ///
 /// This function from:
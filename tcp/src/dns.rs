@@ -19,9 +19,12 @@ use smoltcp::time::Instant;
 use spin::RwLock;
 use lazy_static::lazy_static;
 
-use euralios_std::{time, syscalls, println};
+use euralios_std::{time, println,
+                   syscalls::{self, CommHandle},
+                   message::{self, MessageData}};
 
 use crate::INTERFACE;
+use crate::udp;
 
 #[repr(u16)]
 enum QueryType {
@@ -52,6 +55,7 @@ pub enum ResponseCode {
 
     UnknownError,
     NetworkError,
+    Timeout,
 }
 
 struct Message {
@@ -139,8 +143,24 @@ lazy_static! {
 }
 
 /// Add a DNS server which can be used to resolve hostnames
+///
+/// If the server is already known it becomes the last added
 pub fn add_server(address: IpAddress) {
-    SERVERS.write().push(address);
+    let mut servers = SERVERS.write();
+    servers.retain(|server| *server != address);
+    servers.push(address);
+}
+
+/// How long to wait for a DNS server to reply
+const DNS_TIMEOUT_US: u64 = 5_000_000;
+
+/// The DNS server last added to SERVERS
+fn server_address() -> Result<IpAddress, ResponseCode> {
+    let servers = SERVERS.read();
+    match servers.last() {
+        Some(addr) => Ok(addr.clone()),
+        None => Err(ResponseCode::NotImplemented)
+    }
 }
 
 /// Find the IP address of a given host name
@@ -157,23 +177,46 @@ pub fn resolve(name: &str) -> Result<IpAddress, ResponseCode> {
         }
     }
 
-    // Get the IP address of a DNS server
-    let dns_address = {
-        let servers = SERVERS.read();
-        match servers.last() {
-            Some(addr) => addr.clone(),
-            None => {return Err(ResponseCode::NotImplemented);}
+    let query = Message::query(name, QueryType::A, QueryClass::IN);
+    let message = Message::from(&exchange(server_address()?, &query.datagram)?);
+    match message.rcode() {
+        ResponseCode::NoError => {
+            // TODO: Parse the datagram instead of
+            // extracting the last 4 bytes.
+            //let rdata = message.answer().rdata();
+            let n = message.datagram.len();
+            if n < 16 {
+                return Err(ResponseCode::FormatError);
+            }
+            let rdata = &message.datagram[(n - 4)..];
+
+            let addr = IpAddress::from(Ipv4Address::from_bytes(rdata));
+            // Put into the cache
+            CACHE.write().insert(String::from(name), addr.clone());
+
+            Ok(addr)
         }
-    };
+        rcode => {
+            Err(rcode)
+        }
+    }
+}
+
+/// Send a query datagram to a DNS server, and wait up to
+/// DNS_TIMEOUT_US for the response with the same transaction ID
+fn exchange(dns_address: IpAddress, query: &[u8]) -> Result<Vec<u8>, ResponseCode> {
+    if query.len() < 12 {
+        return Err(ResponseCode::FormatError);
+    }
+    let query_id = u16::from_be_bytes([query[0], query[1]]);
 
     let server = IpEndpoint::new(dns_address, DNS_PORT);
 
-    // Get a local port for the connection
-    let local_port = crate::ephemeral_port_number();
+    // Get a local port for the connection, reserved so that
+    // UDP clients can't bind it
+    let local_port = udp::reserve_port(0).map_err(|_| ResponseCode::NetworkError)?;
     let client = IpEndpoint::new(IpAddress::Unspecified, local_port);
 
-    let query = Message::query(name, QueryType::A, QueryClass::IN);
-
     // Add the UDP socket to the network interface
     let udp_handle = {
         let udp_rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY], vec![0; 2048]);
@@ -189,6 +232,8 @@ pub fn resolve(name: &str) -> Result<IpAddress, ResponseCode> {
     enum State { Bind, Query, Response }
     let mut state = State::Bind;
 
+    let start = time::microseconds_monotonic();
+
     // Don't keep a reference to INTERFACE because this thread
     // is interleaved with threads servicing other requests.
     loop {
@@ -199,7 +244,15 @@ pub fn resolve(name: &str) -> Result<IpAddress, ResponseCode> {
 
             if let Err(e) = interface.poll(Instant::from_micros(time::microseconds_monotonic() as i64)) {
                 println!("Network Error: {}", e);
-                return Err(ResponseCode::UnknownError);
+                interface.remove_socket(udp_handle);
+                udp::release_port(local_port);
+                return Err(ResponseCode::NetworkError);
+            }
+
+            if time::microseconds_monotonic() - start > DNS_TIMEOUT_US {
+                interface.remove_socket(udp_handle);
+                udp::release_port(local_port);
+                return Err(ResponseCode::Timeout);
             }
 
             let socket = interface.get_socket::<UdpSocket>(udp_handle);
@@ -210,32 +263,16 @@ pub fn resolve(name: &str) -> Result<IpAddress, ResponseCode> {
                     State::Query
                 }
                 State::Query if socket.can_send() => {
-                    socket.send_slice(&query.datagram, server).expect("cannot send");
+                    socket.send_slice(query, server).expect("cannot send");
                     State::Response
                 }
                 State::Response if socket.can_recv() => {
                     let (data, _) = socket.recv().expect("cannot receive");
                     let message = Message::from(data);
-                    if message.id() == query.id() && message.is_response() {
+                    if data.len() >= 12 && message.id() == query_id && message.is_response() {
                         interface.remove_socket(udp_handle);
-                        return match message.rcode() {
-                            ResponseCode::NoError => {
-                                // TODO: Parse the datagram instead of
-                                // extracting the last 4 bytes.
-                                //let rdata = message.answer().rdata();
-                                let n = message.datagram.len();
-                                let rdata = &message.datagram[(n - 4)..];
-
-                                let addr = IpAddress::from(Ipv4Address::from_bytes(rdata));
-                                // Put into the cache
-                                CACHE.write().insert(String::from(name), addr.clone());
-
-                                Ok(addr)
-                            }
-                            rcode => {
-                                Err(rcode)
-                            }
-                        }
+                        udp::release_port(local_port);
+                        return Ok(message.datagram);
                     }
                     state
                 }
@@ -247,3 +284,44 @@ pub fn resolve(name: &str) -> Result<IpAddress, ResponseCode> {
         syscalls::thread_yield();
     }
 }
+
+/// Forward DNS queries from a client to the DNS server
+///
+/// Each Long(WRITE, length, handle) containing a query datagram is
+/// answered with Long(DATA, length, handle) containing the response,
+/// or Short(ERROR, code, 0). Used by euralios_std::net::resolve,
+/// which does its own parsing and caching.
+pub fn relay(comm_handle: CommHandle) {
+    loop {
+        let reply = match syscalls::receive(&comm_handle) {
+            Ok(message::Message::Long(
+                message::WRITE,
+                MessageData::Value(length),
+                MessageData::MemoryHandle(handle)))
+                if handle.size().map_or(false, |size| length as usize <= size) => {
+                let query = handle.as_slice::<u8>(length as usize);
+                match server_address().and_then(|address| exchange(address, query)) {
+                    Ok(response) => message::Message::Long(
+                        message::DATA,
+                        (response.len() as u64).into(),
+                        syscalls::MemoryHandle::from_u8_slice(&response).into()),
+                    Err(ResponseCode::Timeout) => message::Message::Short(
+                        message::ERROR, syscalls::SYSCALL_ERROR_TIMEOUT.as_u64(), 0),
+                    Err(_) => message::Message::Short(
+                        message::ERROR, syscalls::SYSCALL_ERROR_PARAM.as_u64(), 0)
+                }
+            }
+            // Length past the end of the chunk
+            Ok(message::Message::Long(message::WRITE, _, _)) => message::Message::Short(
+                message::ERROR, syscalls::SYSCALL_ERROR_PARAM.as_u64(), 0),
+            Ok(_) => message::Message::Short(message::ERROR_UNKNOWN_MESSAGE, 0, 0),
+            Err(syscalls::SYSCALL_ERROR_CLOSED) => return,
+            Err(code) => {
+                println!("[tcp dns] Receive error {}", code);
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+        syscalls::send(&comm_handle, reply);
+    }
+}
@@ -12,6 +12,7 @@ use alloc::vec;
 use alloc::vec::Vec;
 use alloc::sync::Arc;
 use alloc::string::String;
+use alloc::format;
 use core::str;
 use core::sync::atomic::{AtomicU16, Ordering};
 
@@ -30,10 +31,15 @@ use euralios_std::{println,
                    thread,
                    time,
                    net::MacAddress,
-                   message::{self, rcall, nic, MessageData}};
+                   message::{self, rcall, nic, MessageData},
+                   server};
 
+mod arp;
 mod dhcp;
 mod dns;
+mod ping;
+mod poll;
+mod udp;
 
 /// Represents an ethernet device, which has a driver connected
 /// through a communication handle
@@ -79,7 +85,7 @@ impl smoltcp::phy::TxToken for TxToken {
         // Call function to fill buffer
         let res = f(buffer.as_mut_slice::<u8>(length));
 
-        if res.is_ok() {
+        if res.is_ok() && arp::transmitting(buffer.as_slice::<u8>(length)) {
             // Transmit, sending buffer to NIC driver
             syscalls::send(
                 self.handle.as_ref(),
@@ -100,16 +106,27 @@ impl<'a> smoltcp::phy::Device<'a> for EthernetDevice {
         let mut caps = DeviceCapabilities::default();
         caps.max_transmission_unit = 1500;
         caps.max_burst_size = Some(1);
+        // The default checksum capabilities compute and verify
+        // IPv4, ICMP, TCP and UDP checksums in software
         caps
     }
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        // ARP replies from the cache
+        if let Some(frame) = arp::next_injected() {
+            return Some((RxToken{length: frame.len(),
+                                 data: syscalls::MemoryHandle::from_u8_slice(&frame)},
+                         TxToken{handle: self.handle.clone()}));
+        }
+
         match message::rcall(self.handle.as_ref(),
                              message::READ,
                              0.into(), 0.into(), None) {
             Ok((message::DATA, length, data)) => {
-                Some((RxToken{length:length.value() as usize,
-                              data:data.memory()},
+                let length = length.value() as usize;
+                let data = data.memory();
+                arp::received(data.as_slice::<u8>(length));
+                Some((RxToken{length, data},
                       TxToken{handle: self.handle.clone()}))
             }
             Ok((message::EMPTY, _, _)) => None,
@@ -135,7 +152,14 @@ lazy_static! {
 fn main() {
     println!("[tcp] Starting");
 
-    let handle = syscalls::open("/dev/nic", message::O_READ + message::O_WRITE).expect("Couldn't open /dev/nic");
+    let handle = match syscalls::open("/dev/nic", message::O_READ + message::O_WRITE) {
+        Ok(handle) => handle,
+        Err(err) => {
+            println!("[tcp] Couldn't open /dev/nic: {}", err);
+            server::signal_failed(err);
+            return;
+        }
+    };
 
     // Get the hardware MAC address
     let (_, ret, _) = rcall(&handle, nic::GET_MAC_ADDRESS,
@@ -172,6 +196,11 @@ fn main() {
     // Move the interface into static variable
     *(INTERFACE.write()) = Some(interface);
 
+    // Poll for retransmissions and timeouts
+    poll::start();
+
+    server::signal_ready();
+
     // Server loop
     loop {
         match syscalls::receive(&STDIN) {
@@ -238,10 +267,39 @@ fn main() {
 
 /// Open a path from the root, returning a communication handle
 ///
+/// The path is "host/port" for a TCP connection, "udp" for a UDP
+/// socket, "ping" for ICMP echo requests, or "dns" for a DNS query
+/// relay.
+///
 /// Note: This function spawns a thread which will then attempt
 ///       to open the socket. It is possible that this function
 ///       succeeds but then opening the socket fails.
 fn open_path(path: &str) -> Result<CommHandle, ()> {
+    if path == "dns" {
+        // Relay for euralios_std::net::resolve
+        let (handle, client_handle) = syscalls::new_rendezvous()
+            .map_err(|e| {println!("[tcp] Couldn't create Rendezvous {:?}", e);})?;
+        thread::spawn(move || dns::relay(handle))
+            .map_err(|e| {println!("[tcp] Couldn't start DNS relay: {}", e);})?;
+        return Ok(client_handle);
+    }
+
+    if path == "udp" {
+        let (handle, client_handle) = syscalls::new_rendezvous()
+            .map_err(|e| {println!("[tcp] Couldn't create Rendezvous {:?}", e);})?;
+        thread::spawn(move || udp::serve(handle))
+            .map_err(|e| {println!("[tcp] Couldn't start UDP socket: {}", e);})?;
+        return Ok(client_handle);
+    }
+
+    if path == "ping" {
+        let (handle, client_handle) = syscalls::new_rendezvous()
+            .map_err(|e| {println!("[tcp] Couldn't create Rendezvous {:?}", e);})?;
+        thread::spawn(move || ping::serve(handle))
+            .map_err(|e| {println!("[tcp] Couldn't start ping: {}", e);})?;
+        return Ok(client_handle);
+    }
+
     if let Some(ind) = path.find('/') {
         // Split and copy into Strings which can be moved to a new thread
         let host_str = String::from(&path[..ind]);
@@ -301,7 +359,8 @@ fn open_socket(address: IpAddress, port: u16, comm_handle: CommHandle) {
 
     let tcp_rx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
     let tcp_tx_buffer = TcpSocketBuffer::new(vec![0; 4096]);
-    let tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+    let mut tcp_socket = TcpSocket::new(tcp_rx_buffer, tcp_tx_buffer);
+    poll::configure_socket(&mut tcp_socket);
 
     let tcp_handle = {
         let mut some_interface = INTERFACE.write();
@@ -321,6 +380,7 @@ fn open_socket(address: IpAddress, port: u16, comm_handle: CommHandle) {
             interface.remove_socket(tcp_handle);
             None
         } else {
+            poll::watch(tcp_handle, format!("{}/{}", address, port));
             Some(tcp_handle)
         }
     };
@@ -479,6 +539,7 @@ fn open_socket(address: IpAddress, port: u16, comm_handle: CommHandle) {
                         println!("Network error: {:?}", e);
                     }
 
+                    poll::unwatch(handle);
                     interface.remove_socket(handle);
                 }
                 return;
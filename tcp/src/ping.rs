@@ -0,0 +1,147 @@
+//! ICMP echo (ping)
+//!
+//! Each echo request uses its own IcmpSocket bound to a unique
+//! identifier, so smoltcp delivers the replies to concurrent pings
+//! to the right socket. Incoming echo requests are answered by the
+//! smoltcp Interface itself, so need no socket.
+//!
+//! See euralios_std::message::icmp for the protocol.
+
+extern crate alloc;
+use alloc::vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::{IcmpEndpoint, IcmpPacketMetadata, IcmpSocket, IcmpSocketBuffer};
+use smoltcp::time::Instant;
+use smoltcp::wire::{Icmpv4Packet, Icmpv4Repr, IpAddress, Ipv4Address};
+
+use euralios_std::{println,
+                   syscalls::{self, CommHandle, SyscallError},
+                   message::{self, icmp, Message},
+                   time};
+
+use crate::INTERFACE;
+
+/// Bytes of data in each echo request
+const PING_DATA_LEN: usize = 32;
+/// Packets kept by each socket
+const ICMP_BUFFER_PACKETS: usize = 4;
+/// Bytes kept by each socket
+const ICMP_BUFFER_BYTES: usize = 512;
+
+/// Identifier of the next echo request
+static NEXT_IDENT: AtomicU16 = AtomicU16::new(1);
+
+fn now() -> Instant {
+    Instant::from_micros(time::microseconds_monotonic() as i64)
+}
+
+/// True if `data` is a valid echo reply to (ident, seq_no)
+fn is_reply(data: &[u8], ident: u16, seq_no: u16) -> bool {
+    // Checks the checksum
+    let checksum = ChecksumCapabilities::default();
+    match Icmpv4Packet::new_checked(data)
+        .and_then(|packet| Icmpv4Repr::parse(&packet, &checksum)) {
+            Ok(Icmpv4Repr::EchoReply{ident: reply_ident, seq_no: reply_seq_no, ..}) => {
+                reply_ident == ident && reply_seq_no == seq_no
+            }
+            _ => false
+        }
+}
+
+/// Send an echo request to `address`, and wait up to `timeout_us`
+/// for the reply
+///
+/// Returns the round trip time in microseconds
+fn ping(address: Ipv4Address, timeout_us: u64) -> Result<u64, SyscallError> {
+    let ident = NEXT_IDENT.fetch_add(1, Ordering::Relaxed);
+    let seq_no = 1;
+    let data = [0x45; PING_DATA_LEN]; // 'E'
+    let request = Icmpv4Repr::EchoRequest{ident, seq_no, data: &data};
+
+    let rx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; ICMP_BUFFER_PACKETS],
+                                          vec![0; ICMP_BUFFER_BYTES]);
+    let tx_buffer = IcmpSocketBuffer::new(vec![IcmpPacketMetadata::EMPTY; ICMP_BUFFER_PACKETS],
+                                          vec![0; ICMP_BUFFER_BYTES]);
+    let mut socket = IcmpSocket::new(rx_buffer, tx_buffer);
+    socket.bind(IcmpEndpoint::Ident(ident))
+        .map_err(|_| syscalls::SYSCALL_ERROR_PARAM)?;
+
+    let handle = {
+        let mut some_interface = INTERFACE.write();
+        let interface = (*some_interface).as_mut().unwrap();
+        interface.add_socket(socket)
+    };
+
+    let start = time::microseconds_monotonic();
+    // Time when the request was queued
+    let mut sent: Option<u64> = None;
+    let result = loop {
+        {
+            let mut some_interface = INTERFACE.write();
+            let interface = (*some_interface).as_mut().unwrap();
+
+            if sent.is_none() {
+                let socket = interface.get_socket::<IcmpSocket>(handle);
+                match socket.send(request.buffer_len(), IpAddress::Ipv4(address)) {
+                    Ok(buffer) => {
+                        request.emit(&mut Icmpv4Packet::new_unchecked(buffer),
+                                     &ChecksumCapabilities::default());
+                        sent = Some(time::microseconds_monotonic());
+                    }
+                    Err(e) => {
+                        println!("[tcp ping] Send to {} failed: {:?}", address, e);
+                        break Err(syscalls::SYSCALL_ERROR_PARAM);
+                    }
+                }
+            }
+
+            if let Err(e) = interface.poll(now()) {
+                println!("[tcp ping] Network error: {:?}", e);
+            }
+
+            let socket = interface.get_socket::<IcmpSocket>(handle);
+            let mut replied = false;
+            while let Ok((data, _)) = socket.recv() {
+                replied |= is_reply(data, ident, seq_no);
+            }
+            if let (true, Some(sent)) = (replied, sent) {
+                break Ok(time::microseconds_monotonic() - sent);
+            }
+        } // Release the INTERFACE lock
+
+        if time::microseconds_monotonic() - start >= timeout_us {
+            break Err(syscalls::SYSCALL_ERROR_TIMEOUT);
+        }
+        syscalls::thread_yield();
+    };
+
+    let mut some_interface = INTERFACE.write();
+    (*some_interface).as_mut().unwrap().remove_socket(handle);
+    result
+}
+
+/// Serve echo requests from one client until the handle is closed
+pub fn serve(comm_handle: CommHandle) {
+    loop {
+        let reply = match syscalls::receive(&comm_handle) {
+            Ok(Message::Short(icmp::ECHO, address, timeout_us)) => {
+                let address = Ipv4Address::from_bytes(&(address as u32).to_be_bytes());
+                match ping(address, timeout_us) {
+                    Ok(rtt_us) => Message::Short(message::OK, rtt_us, 0),
+                    Err(err) => Message::Short(message::ERROR, err.as_u64(), 0)
+                }
+            }
+            Ok(Message::Short(message::CLOSE, _, _)) |
+            Err(syscalls::SYSCALL_ERROR_CLOSED) => return,
+            Ok(_) => Message::Short(message::ERROR_UNKNOWN_MESSAGE, 0, 0),
+            Err(err) => {
+                println!("[tcp ping] Receive error {}", err);
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+        syscalls::send(&comm_handle, reply);
+    }
+}
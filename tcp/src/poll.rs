@@ -0,0 +1,109 @@
+//! Background polling of the interface
+//!
+//! smoltcp keeps unacknowledged data in each socket's transmit
+//! buffer and retransmits it with exponential backoff, starting from
+//! an RTO of 1 second and doubling up to 10 seconds. It only does
+//! this when the interface is polled, so this thread polls whenever
+//! smoltcp has a timer due, even if no client is sending messages.
+//! Cumulative ACKs are processed in the same polls.
+//!
+//! A connection which gets no ACK within CONNECTION_TIMEOUT_SECS
+//! (about six retransmissions) is reset.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::RwLock;
+use lazy_static::lazy_static;
+
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::{TcpSocket, TcpState};
+use smoltcp::time::{Duration, Instant};
+
+use euralios_std::{println, syscalls, thread, time};
+
+use crate::INTERFACE;
+use crate::dhcp;
+
+/// Abort a connection if the remote end doesn't respond for this long
+pub const CONNECTION_TIMEOUT_SECS: u64 = 35;
+
+/// Longest time between polls, so that received packets are
+/// handled promptly. Sleeps are rounded up to the timer period.
+const MAX_POLL_INTERVAL_US: u64 = 50_000;
+
+/// A connection whose state changes are logged
+struct Connection {
+    handle: SocketHandle,
+    /// For log messages e.g. "10.0.2.2/80"
+    label: String,
+    state: TcpState
+}
+
+lazy_static! {
+    static ref CONNECTIONS: RwLock<Vec<Connection>> = RwLock::new(Vec::new());
+}
+
+/// Set the timeout on a new socket
+pub fn configure_socket(socket: &mut TcpSocket) {
+    socket.set_timeout(Some(Duration::from_secs(CONNECTION_TIMEOUT_SECS)));
+}
+
+/// Log state transitions of a socket until `unwatch` is called
+pub fn watch(handle: SocketHandle, label: String) {
+    CONNECTIONS.write().push(Connection{handle, label, state: TcpState::Closed});
+}
+
+pub fn unwatch(handle: SocketHandle) {
+    CONNECTIONS.write().retain(|connection| connection.handle != handle);
+}
+
+fn now() -> Instant {
+    Instant::from_micros(time::microseconds_monotonic() as i64)
+}
+
+/// Poll the interface, returning the number of microseconds until
+/// the next poll is needed
+fn poll() -> u64 {
+    let mut some_interface = INTERFACE.write();
+    let interface = match (*some_interface).as_mut() {
+        Some(interface) => interface,
+        None => return MAX_POLL_INTERVAL_US
+    };
+
+    let timestamp = now();
+    if let Err(e) = interface.poll(timestamp) {
+        println!("[tcp] Network error: {:?}", e);
+    }
+    // Lease renewal
+    dhcp::check(interface);
+
+    for connection in CONNECTIONS.write().iter_mut() {
+        let state = interface.get_socket::<TcpSocket>(connection.handle).state();
+        if state != connection.state {
+            println!("[tcp {}] {} -> {}", connection.label, connection.state, state);
+            // A normal close goes through TIME-WAIT or LAST-ACK
+            if state == TcpState::Closed &&
+                !matches!(connection.state, TcpState::TimeWait | TcpState::LastAck) {
+                    println!("[tcp {}] Connection reset", connection.label);
+                }
+            connection.state = state;
+        }
+    }
+
+    match interface.poll_delay(timestamp) {
+        Some(delay) => delay.total_micros().min(MAX_POLL_INTERVAL_US),
+        None => MAX_POLL_INTERVAL_US
+    }
+}
+
+/// Start the polling thread
+pub fn start() {
+    if let Err(err) = thread::spawn(|| {
+        loop {
+            let delay = poll();
+            syscalls::sleep_us(delay);
+        }
+    }) {
+        println!("[tcp] Couldn't start polling thread: {}. Retransmission only on client messages", err);
+    }
+}
@@ -0,0 +1,224 @@
+//! UDP sockets
+//!
+//! Each client handle opened at "udp" is served by its own thread,
+//! and owns one smoltcp UdpSocket. smoltcp demultiplexes received
+//! datagrams by local port, and computes and verifies checksums
+//! (see EthernetDevice::capabilities). BOUND_PORTS stops two clients
+//! from binding the same port, or a port used by the DHCP and DNS
+//! sockets.
+//!
+//! See euralios_std::message::udp for the protocol.
+
+extern crate alloc;
+use alloc::collections::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::RwLock;
+use lazy_static::lazy_static;
+
+use smoltcp::iface::SocketHandle;
+use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::time::Instant;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+use euralios_std::{println,
+                   net::Ipv4Addr,
+                   syscalls::{self, CommHandle, MemoryHandle, SyscallError},
+                   message::{self, udp::{self, Endpoint}, Message, MessageData},
+                   time};
+
+use crate::INTERFACE;
+
+/// Datagrams kept by each socket
+const UDP_BUFFER_PACKETS: usize = 16;
+/// Bytes of payload kept by each socket
+const UDP_BUFFER_BYTES: usize = 16384;
+
+/// Local port of the smoltcp DHCP client socket
+const DHCP_CLIENT_PORT: u16 = 68;
+
+lazy_static! {
+    /// Local ports bound by any client, or by the DHCP and DNS sockets
+    static ref BOUND_PORTS: RwLock<BTreeSet<u16>> =
+        RwLock::new(BTreeSet::from([DHCP_CLIENT_PORT]));
+}
+
+fn now() -> Instant {
+    Instant::from_micros(time::microseconds_monotonic() as i64)
+}
+
+fn error(err: SyscallError) -> Message {
+    Message::Short(message::ERROR, err.as_u64(), 0)
+}
+
+/// Reserve local port `port`, or an ephemeral port if 0
+///
+/// Also used by the DNS module, so that clients can't bind the port
+/// of a query in progress. Release with release_port.
+pub fn reserve_port(port: u16) -> Result<u16, SyscallError> {
+    let mut bound = BOUND_PORTS.write();
+    let port = if port == 0 {
+        // Skip ephemeral ports already bound
+        (0..16384).map(|_| crate::ephemeral_port_number())
+            .find(|port| !bound.contains(port))
+            .ok_or(syscalls::SYSCALL_ERROR_EXISTS)?
+    } else {
+        port
+    };
+    if !bound.insert(port) {
+        return Err(syscalls::SYSCALL_ERROR_EXISTS);
+    }
+    Ok(port)
+}
+
+/// Release a port reserved by reserve_port
+pub fn release_port(port: u16) {
+    BOUND_PORTS.write().remove(&port);
+}
+
+/// Create a socket bound to `port`, or an ephemeral port if 0
+fn bind(port: u16) -> Result<(SocketHandle, u16), SyscallError> {
+    let port = reserve_port(port)?;
+
+    let rx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_BUFFER_PACKETS],
+                                         vec![0; UDP_BUFFER_BYTES]);
+    let tx_buffer = UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; UDP_BUFFER_PACKETS],
+                                         vec![0; UDP_BUFFER_BYTES]);
+    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+    if socket.bind(IpEndpoint::new(IpAddress::Unspecified, port)).is_err() {
+        release_port(port);
+        return Err(syscalls::SYSCALL_ERROR_PARAM);
+    }
+
+    let mut some_interface = INTERFACE.write();
+    let interface = (*some_interface).as_mut().unwrap();
+    Ok((interface.add_socket(socket), port))
+}
+
+fn unbind(handle: SocketHandle, port: u16) {
+    let mut some_interface = INTERFACE.write();
+    let interface = (*some_interface).as_mut().unwrap();
+    interface.remove_socket(handle);
+    release_port(port);
+}
+
+fn send_to(handle: SocketHandle, endpoint: Endpoint, data: &[u8]) -> Result<(), SyscallError> {
+    let remote = IpEndpoint::new(
+        IpAddress::Ipv4(Ipv4Address(endpoint.address.octets())),
+        endpoint.port);
+
+    let mut some_interface = INTERFACE.write();
+    let interface = (*some_interface).as_mut().unwrap();
+    interface.get_socket::<UdpSocket>(handle)
+        .send_slice(data, remote)
+        .map_err(|e| {
+            println!("[tcp udp] Send to {} failed: {:?}", remote, e);
+            match e {
+                smoltcp::Error::Exhausted => syscalls::SYSCALL_ERROR_NO_SPACE,
+                _ => syscalls::SYSCALL_ERROR_PARAM
+            }
+        })?;
+    // Transmit now rather than on the next background poll
+    if let Err(e) = interface.poll(now()) {
+        println!("[tcp udp] Network error: {:?}", e);
+    }
+    Ok(())
+}
+
+/// Wait up to `timeout_us` for a datagram
+fn recv_from(handle: SocketHandle, timeout_us: u64) -> Option<(Endpoint, Vec<u8>)> {
+    let start = time::microseconds_monotonic();
+    loop {
+        {
+            let mut some_interface = INTERFACE.write();
+            let interface = (*some_interface).as_mut().unwrap();
+            if let Err(e) = interface.poll(now()) {
+                println!("[tcp udp] Network error: {:?}", e);
+            }
+            let socket = interface.get_socket::<UdpSocket>(handle);
+            if let Ok((data, remote)) = socket.recv() {
+                if let IpAddress::Ipv4(address) = remote.addr {
+                    let octets = address.0;
+                    return Some((Endpoint{
+                        address: Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]),
+                        port: remote.port,
+                        length: data.len() as u16},
+                                 Vec::from(data)));
+                }
+            }
+        } // Release the INTERFACE lock
+
+        if time::microseconds_monotonic() - start >= timeout_us {
+            return None;
+        }
+        syscalls::thread_yield();
+    }
+}
+
+/// Serve one client's socket until the handle is closed
+pub fn serve(comm_handle: CommHandle) {
+    // The socket and its local port, once bound
+    let mut bound: Option<(SocketHandle, u16)> = None;
+
+    loop {
+        let reply = match (syscalls::receive(&comm_handle), bound) {
+            (Ok(Message::Short(udp::BIND, port, _)), None) if port > u16::MAX as u64 => {
+                error(syscalls::SYSCALL_ERROR_PARAM)
+            }
+            (Ok(Message::Short(udp::BIND, port, _)), None) => {
+                match bind(port as u16) {
+                    Ok((handle, port)) => {
+                        bound = Some((handle, port));
+                        Message::Short(message::OK, port as u64, 0)
+                    }
+                    Err(err) => error(err)
+                }
+            }
+            (Ok(Message::Long(udp::SEND_TO,
+                              MessageData::Value(value),
+                              MessageData::MemoryHandle(data))), Some((handle, _))) => {
+                let endpoint = Endpoint::from_value(value);
+                // The length is chosen by the client, so check it
+                // is inside the chunk before reading
+                if data.size().map_or(true, |size| endpoint.length as usize > size) {
+                    error(syscalls::SYSCALL_ERROR_PARAM)
+                } else {
+                    match send_to(handle, endpoint,
+                                  data.as_slice::<u8>(endpoint.length as usize)) {
+                        Ok(()) => Message::Short(message::OK, 0, 0),
+                        Err(err) => error(err)
+                    }
+                }
+            }
+            (Ok(Message::Short(udp::RECV_FROM, timeout_us, _)), Some((handle, _))) => {
+                // Bounded, so a close or other message isn't
+                // delayed by a long timeout
+                match recv_from(handle, timeout_us.min(udp::MAX_RECV_WAIT_US)) {
+                    Some((endpoint, data)) => Message::Long(
+                        message::DATA,
+                        endpoint.to_value().into(),
+                        MemoryHandle::from_u8_slice(&data).into()),
+                    None => Message::Short(message::EMPTY, 0, 0)
+                }
+            }
+            // Already bound, or not yet bound
+            (Ok(Message::Short(udp::BIND, _, _)), _) |
+            (Ok(Message::Long(udp::SEND_TO, _, _)), _) |
+            (Ok(Message::Short(udp::RECV_FROM, _, _)), _) => error(syscalls::SYSCALL_ERROR_PARAM),
+            (Ok(Message::Short(message::CLOSE, _, _)), _) |
+            (Err(syscalls::SYSCALL_ERROR_CLOSED), _) => {
+                if let Some((handle, port)) = bound {
+                    unbind(handle, port);
+                }
+                return;
+            }
+            (Ok(_), _) => Message::Short(message::ERROR_UNKNOWN_MESSAGE, 0, 0),
+            (Err(err), _) => {
+                println!("[tcp udp] Receive error {}", err);
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+        syscalls::send(&comm_handle, reply);
+    }
+}
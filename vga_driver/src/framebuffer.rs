@@ -0,0 +1,181 @@
+//! Linear framebuffer graphics mode
+//!
+//! Used if the VIDEO_MEMORY message describes a framebuffer rather
+//! than VGA text memory. See euralios_std::message::video for the
+//! protocol and pixel format.
+
+use alloc::sync::Arc;
+use core::ptr;
+use spin::RwLock;
+
+use euralios_std::{debug_println,
+                   syscalls::{self, CommHandle, MemoryHandle, STDIN},
+                   thread,
+                   message::{self, Message, MessageData,
+                             video::{self, Mode, Rect}}};
+
+/// Draws into the framebuffer memory
+pub struct Framebuffer {
+    /// Keeps the framebuffer mapped
+    memory: MemoryHandle,
+    mode: Mode
+}
+
+impl Framebuffer {
+    /// None if the pixel format isn't supported, or the memory
+    /// is smaller than `mode` needs
+    pub fn new(memory: MemoryHandle, mode: Mode) -> Option<Self> {
+        if mode.bits_per_pixel as usize != video::BYTES_PER_PIXEL * 8 ||
+            (mode.pitch as usize) < mode.width as usize * video::BYTES_PER_PIXEL {
+                return None;
+            }
+        let size = (mode.pitch as usize).checked_mul(mode.height as usize)?;
+        if memory.size().ok()? < size {
+            return None;
+        }
+        Some(Framebuffer{memory, mode})
+    }
+
+    /// Pointer to the pixel at (x, y), which must be on the screen
+    fn pixel_ptr(&self, x: usize, y: usize) -> *mut u32 {
+        let offset = y * self.mode.pitch as usize + x * video::BYTES_PER_PIXEL;
+        (self.memory.as_u64() as usize + offset) as *mut u32
+    }
+
+    /// The part of `rect` which is on the screen,
+    /// as (x, y, width, height)
+    fn clip(&self, rect: Rect) -> (usize, usize, usize, usize) {
+        let x = (rect.x as usize).min(self.mode.width as usize);
+        let y = (rect.y as usize).min(self.mode.height as usize);
+        let width = (rect.width as usize).min(self.mode.width as usize - x);
+        let height = (rect.height as usize).min(self.mode.height as usize - y);
+        (x, y, width, height)
+    }
+
+    pub fn set_pixel(&mut self, x: u16, y: u16, color: u32) {
+        if x >= self.mode.width || y >= self.mode.height {
+            return;
+        }
+        unsafe {
+            self.pixel_ptr(x as usize, y as usize).write_volatile(color);
+        }
+    }
+
+    pub fn fill_rect(&mut self, rect: Rect, color: u32) {
+        let (x, y, width, height) = self.clip(rect);
+        for row in y..(y + height) {
+            let row_ptr = self.pixel_ptr(x, row);
+            for col in 0..width {
+                unsafe {
+                    row_ptr.add(col).write_volatile(color);
+                }
+            }
+        }
+    }
+
+    /// Copy `pixels`, width * height rows without padding, to `rect`
+    ///
+    /// Returns false if the slice has too few pixels
+    pub fn blit(&mut self, rect: Rect, pixels: &[u32]) -> bool {
+        if pixels.len() < rect.width as usize * rect.height as usize {
+            return false;
+        }
+        let (x, y, width, height) = self.clip(rect);
+        for row in 0..height {
+            let source = &pixels[row * rect.width as usize..][..width];
+            unsafe {
+                ptr::copy_nonoverlapping(source.as_ptr(),
+                                         self.pixel_ptr(x, y + row),
+                                         width);
+            }
+        }
+        true
+    }
+}
+
+/// Message loop for framebuffer mode
+///
+/// Each handle opened with OPEN_READWRITE draws to the same
+/// framebuffer.
+pub fn main_loop(framebuffer: Framebuffer) -> ! {
+    let framebuffer = Arc::new(RwLock::new(framebuffer));
+    loop {
+        match syscalls::receive(&STDIN) {
+            Ok(Message::Long(
+                message::OPEN_READWRITE, _, _)) |
+            Ok(Message::Short(
+                message::OPEN_READWRITE, _, _)) => {
+
+                let (handle, client_handle) = match syscalls::new_rendezvous() {
+                    Ok(handles) => handles,
+                    Err(err) => {
+                        debug_println!("[vga] Couldn't create Rendezvous {:?}", err);
+                        continue;
+                    }
+                };
+                let framebuffer = framebuffer.clone();
+                thread::spawn(move || {
+                    graphics_handler(framebuffer, handle);
+                });
+                syscalls::send(&STDIN,
+                               Message::Long(
+                                   message::COMM_HANDLE,
+                                   client_handle.into(), 0.into()));
+            }
+            Ok(message) => {
+                debug_println!("[vga] unknown message {:?}", message);
+            }
+            // init never closes STDIN, but a test may
+            Err(syscalls::SYSCALL_ERROR_CLOSED) => syscalls::exit(0),
+            Err(code) => {
+                debug_println!("[vga] Receive error {}", code);
+                // Wait and try again
+                syscalls::thread_yield();
+            }
+        }
+    }
+}
+
+fn graphics_handler(
+    framebuffer: Arc<RwLock<Framebuffer>>,
+    comm_handle: CommHandle) {
+
+    loop {
+        let reply = match syscalls::receive(&comm_handle) {
+            Ok(Message::Short(video::SET_PIXEL, position, color)) => {
+                framebuffer.write().set_pixel(position as u16,
+                                              (position >> 32) as u16,
+                                              color as u32);
+                Message::Short(message::OK, 0, 0)
+            }
+            Ok(Message::Short(video::FILL_RECT, rect, color)) => {
+                framebuffer.write().fill_rect(Rect::from_value(rect), color as u32);
+                Message::Short(message::OK, 0, 0)
+            }
+            Ok(Message::Long(video::BLIT,
+                             MessageData::Value(rect),
+                             MessageData::MemoryHandle(pixels))) => {
+                let rect = Rect::from_value(rect);
+                let count = rect.width as usize * rect.height as usize;
+                // The sender may not have sent enough memory, so
+                // only slice as many pixels as the chunk holds
+                let available = pixels.size().map_or(0, |size| size / video::BYTES_PER_PIXEL);
+                if count <= available && framebuffer.write().blit(
+                    rect, pixels.as_slice::<u32>(count)) {
+                    Message::Short(message::OK, 0, 0)
+                } else {
+                    Message::Short(message::ERROR,
+                                   syscalls::SYSCALL_ERROR_PARAM.as_u64(), 0)
+                }
+            }
+            Ok(_) => Message::Short(message::ERROR_UNKNOWN_MESSAGE, 0, 0),
+            Err(syscalls::SYSCALL_ERROR_CLOSED) => return,
+            Err(code) => {
+                debug_println!("[vga] Receive error {}", code);
+                syscalls::thread_yield();
+                continue;
+            }
+        };
+        syscalls::send(&comm_handle, reply);
+    }
+}
@@ -9,13 +9,16 @@ use lazy_static::lazy_static;
 use euralios_std::{debug_println,
                    syscalls::{self, CommHandle, STDIN},
                    thread,
-                   message::{self, Message, MessageData}};
+                   message::{self, Message, MessageData, video}};
 
 use vga;
 use vga::colors::{Color16, TextModeColor};
 use vga::writers::{Screen, ScreenCharacter,
                    TextWriter, Text80x25};
 
+mod framebuffer;
+use framebuffer::Framebuffer;
+
 /// Represents a writer for rendering text
 ///
 /// Interprets a subset of ANSI escape sequences
@@ -30,13 +33,21 @@ use vga::writers::{Screen, ScreenCharacter,
 ///
 ///  * LF '\n' moves the cursor to the start of the new line
 ///    i.e. includes a CR.
+///  * An escape sequence can be split between writes: the start is
+///    kept until the rest arrives. Unknown sequences are ignored.
 ///
 pub struct Writer<'a, S: Screen + TextWriter> {
     row: usize,
     column: usize,
+    foreground: Color16,
+    background: Color16,
     color: TextModeColor,
     blank: ScreenCharacter,
 
+    /// Colors set by reset sequences, changed by ESC [ 8 ]
+    default_foreground: Color16,
+    default_background: Color16,
+
     /// Represents the physical device
     screen: &'a S,
 
@@ -47,9 +58,21 @@ pub struct Writer<'a, S: Screen + TextWriter> {
     active: bool,
 
     /// Is the cursor visible?
-    cursor_visible: bool
+    cursor_visible: bool,
+
+    /// Start of an escape sequence which ended a previous write
+    partial_escape: Vec<u8>
 }
 
+/// Longest escape sequence kept between writes. Longer ones are dropped
+const MAX_PARTIAL_ESCAPE: usize = 32;
+
+/// Colors in the order of their SGR codes e.g. 31 is red
+const SGR_COLORS: [Color16; 8] = [Color16::Black, Color16::Red,
+                                  Color16::Green, Color16::Yellow,
+                                  Color16::Blue, Color16::Magenta,
+                                  Color16::Cyan, Color16::White];
+
 const DEFAULT_FOREGROUND: Color16 = Color16::Black;
 const DEFAULT_BACKGROUND: Color16 = Color16::White;
 
@@ -74,16 +97,17 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
 
         Writer{column: 0,
                row: 1,
-               color: Self::default_color(),
+               foreground: DEFAULT_FOREGROUND,
+               background: DEFAULT_BACKGROUND,
+               color: TextModeColor::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
                blank,
+               default_foreground: DEFAULT_FOREGROUND,
+               default_background: DEFAULT_BACKGROUND,
                screen,
                buffer,
                active: false,
-               cursor_visible: true}
-    }
-
-    fn default_color() -> TextModeColor {
-        TextModeColor::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND)
+               cursor_visible: true,
+               partial_escape: Vec::new()}
     }
 
     /// Write to video memory
@@ -113,23 +137,52 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
         self.active = false;
     }
 
-    /// Move all characters from row to row-1
+    /// Move all characters in the memory buffer from row to row-1
     ///
     /// Fills the lowest row with the blank character
-    fn scroll_up(&self, buffer: *mut ScreenCharacter) {
-        for row in 1..S::HEIGHT {
-            for col in 0..S::WIDTH {
-                unsafe {
-                    let character = buffer.add(row * S::WIDTH + col).read();
-                    buffer.add((row - 1) * S::WIDTH + col).write_volatile(character);
+    fn scroll_up(&mut self) {
+        self.buffer.copy_within(S::WIDTH.., 0);
+        let blank = self.blank;
+        for character in &mut self.buffer[(S::HEIGHT - 1) * S::WIDTH..] {
+            *character = blank;
+        }
+    }
+
+    /// Move the cursor to the start of the next line
+    ///
+    /// On the last row the screen scrolls up, and the cursor stays on
+    /// the last row. If `frame_buffer` is given then it's updated from
+    /// the memory buffer, so video memory is never read.
+    fn line_feed(&mut self, frame_buffer: Option<*mut ScreenCharacter>) {
+        self.column = 0;
+        self.row += 1;
+        if self.row == S::HEIGHT {
+            self.scroll_up();
+            if let Some(frame_buffer) = frame_buffer {
+                for (i, character) in self.buffer.iter().enumerate() {
+                    unsafe {
+                        frame_buffer.add(i).write_volatile(*character);
+                    }
                 }
             }
+            self.row = S::HEIGHT - 1;
         }
-        // Clear the new row
-        for col in 0..S::WIDTH {
-            unsafe {
-                buffer.add((S::HEIGHT - 1) * S::WIDTH + col).write_volatile(self.blank);
+    }
+
+    /// Apply one Select Graphic Rendition parameter
+    ///
+    /// Only colors and reset are supported; other codes are ignored
+    fn select_graphic_rendition(&mut self, code: u16) {
+        match code {
+            0 => {
+                self.foreground = self.default_foreground;
+                self.background = self.default_background;
             }
+            30..=37 => { self.foreground = SGR_COLORS[(code - 30) as usize]; }
+            39 => { self.foreground = self.default_foreground; }
+            40..=47 => { self.background = SGR_COLORS[(code - 40) as usize]; }
+            49 => { self.background = self.default_background; }
+            _ => {}
         }
     }
 
@@ -140,6 +193,17 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
     /// List of Xterm control sequences
     /// <https://www.xfree86.org/current/ctlseqs.html>
     pub fn write_string(&mut self, s: &[u8]) {
+        // Complete an escape sequence from the last write
+        let joined;
+        let s = if self.partial_escape.is_empty() {
+            s
+        } else {
+            let mut data = core::mem::take(&mut self.partial_escape);
+            data.extend_from_slice(s);
+            joined = data;
+            &joined[..]
+        };
+
         {
             // Contains a lock on the buffer, and a pointer to the data
             let lock_buffer = if self.active {
@@ -147,7 +211,7 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
             } else { None };
 
             let mut bytes = s.iter(); // Iterator over bytes
-            loop {
+            'bytes: loop {
                 match bytes.next() {
                     Some(byte) => {
                         match byte {
@@ -165,17 +229,8 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
                                 }
                                 self.column += 1;
                                 if self.column == S::WIDTH {
-                                    self.column = 0;
-                                    self.row += 1;
-                                    if self.row == S::HEIGHT {
-                                        // Shift upwards
-                                        let mut_buffer = self.buffer.as_mut_ptr();
-                                        self.scroll_up(mut_buffer);
-                                        if let Some((_, frame_buffer)) = lock_buffer {
-                                            self.scroll_up(frame_buffer);
-                                        }
-                                        self.row = S::HEIGHT - 1;
-                                    }
+                                    // Wrap to the next line
+                                    self.line_feed(lock_buffer.as_ref().map(|(_, fb)| *fb));
                                 }
                             }
                             //////////////////////////////////////////
@@ -190,38 +245,35 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
                                 self.column += 8 - (self.column % 8);
                                 if self.column >= S::WIDTH {
                                     // Next line
-                                    self.row += 1;
-                                    if self.row == S::HEIGHT {
-                                        // Shift upwards
-                                        let mut_buffer = self.buffer.as_mut_ptr();
-                                        self.scroll_up(mut_buffer);
-                                        if let Some((_, frame_buffer)) = lock_buffer {
-                                            self.scroll_up(frame_buffer);
-                                        }
-                                        self.row = S::HEIGHT - 1;
-                                    }
-                                    self.column = 0;
+                                    self.line_feed(lock_buffer.as_ref().map(|(_, fb)| *fb));
                                 }
                             }
                             b'\n' => { // New line / Line Feed (LF)
-                                self.row += 1;
-                                if self.row == S::HEIGHT {
-                                    // Shift upwards
-                                    let mut_buffer = self.buffer.as_mut_ptr();
-                                    self.scroll_up(mut_buffer);
-                                    if let Some((_, frame_buffer)) = lock_buffer {
-                                        self.scroll_up(frame_buffer);
-                                    }
-                                    self.row = S::HEIGHT - 1;
-                                }
-                                self.column = 0; // NOTE: Also CR
+                                // NOTE: Also CR
+                                self.line_feed(lock_buffer.as_ref().map(|(_, fb)| *fb));
                             }
                             b'\r' => { // Carriage Return (CR)
                                 self.column = 0;
                             }
                             0x1b => { // Escape. Start of escape sequence
-                                match bytes.next() {
-                                    Some(b'[') => {
+                                // If the write ends before the sequence does
+                                // then keep it for the next write
+                                let start = s.len() - bytes.as_slice().len() - 1;
+                                macro_rules! next_or_keep {
+                                    ($label:lifetime) => {
+                                        match bytes.next() {
+                                            Some(byte) => *byte,
+                                            None => {
+                                                if s.len() - start <= MAX_PARTIAL_ESCAPE {
+                                                    self.partial_escape.extend_from_slice(&s[start..]);
+                                                }
+                                                break $label;
+                                            }
+                                        }
+                                    }
+                                }
+                                match next_or_keep!('bytes) {
+                                    b'[' => {
                                         // Control Sequence Introducer (CSI)
                                         // <https://en.wikipedia.org/wiki/ANSI_escape_code#CSIsection>
                                         // The ESC [ is followed by any number (including none) of
@@ -231,31 +283,23 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
                                         // "final byte" in the range 0x40–0x7E (ASCII @A–Z[\]^_`a–z{|}~).
 
                                         let mut sequence = String::with_capacity(10);
-                                        let mut byte: u8 = match bytes.next() {
-                                            Some(byte) => *byte,
-                                            None => {return;}
-                                        };
+                                        let mut byte: u8 = next_or_keep!('bytes);
                                         // parameter bytes
                                         while byte >= 0x30 && byte <= 0x3F {
                                             sequence.push(byte as char);
-                                            byte = match bytes.next() {
-                                                Some(byte) => *byte,
-                                                None => {return;}
-                                            };
+                                            byte = next_or_keep!('bytes);
                                         }
                                         // intermediate bytes
                                         while byte >= 0x20 && byte <= 0x2F {
                                             sequence.push(byte as char);
-                                            byte = match bytes.next() {
-                                                Some(byte) => *byte,
-                                                None => {return;}
-                                            };
+                                            byte = next_or_keep!('bytes);
                                         }
                                         // Final byte
                                         if byte >= 0x40 && byte <= 0x7E {
                                             sequence.push(byte as char);
                                         } else {
-                                            return;
+                                            // Not a valid sequence
+                                            continue;
                                         }
 
                                         // These mainly based on Xterm control sequences
@@ -360,27 +404,34 @@ impl<'a, S: Screen + TextWriter> Writer<'a, S> {
                                                     self.screen.disable_cursor();
                                                 }
                                             }
-                                            "m" | "0m" => { self.color = Self::default_color(); }
-                                            "30m" => { self.color.set_foreground(Color16::Black); }
-                                            "31m" => { self.color.set_foreground(Color16::Red); }
-                                            "32m" => { self.color.set_foreground(Color16::Green); }
-                                            "33m" => { self.color.set_foreground(Color16::Yellow); }
-                                            "34m" => { self.color.set_foreground(Color16::Blue); }
-                                            "35m" => { self.color.set_foreground(Color16::Magenta); }
-                                            "36m" => { self.color.set_foreground(Color16::Cyan); }
-                                            "37m" => { self.color.set_foreground(Color16::White); }
-                                            "39m" => { self.color.set_foreground(DEFAULT_FOREGROUND); }
-                                            "40m" => { self.color.set_background(Color16::Black); }
-                                            "41m" => { self.color.set_background(Color16::Red); }
-                                            "42m" => { self.color.set_background(Color16::Green); }
-                                            "43m" => { self.color.set_background(Color16::Yellow); }
-                                            "44m" => { self.color.set_background(Color16::Blue); }
-                                            "45m" => { self.color.set_background(Color16::Magenta); }
-                                            "46m" => { self.color.set_background(Color16::Cyan); }
-                                            "47m" => { self.color.set_background(Color16::White); }
-                                            "49m" => { self.color.set_background(DEFAULT_BACKGROUND); }
+                                            seq if seq.ends_with('m') => {
+                                                // Select Graphic Rendition e.g. "0;31m"
+                                                // An empty parameter is 0 (reset)
+                                                for param in seq[..seq.len() - 1].split(';') {
+                                                    self.select_graphic_rendition(
+                                                        param.parse().unwrap_or(0));
+                                                }
+                                            }
+                                            seq if seq.ends_with('H') || seq.ends_with('f') => {
+                                                // Cursor position "<row>;<col>H", counting from 1.
+                                                // Missing parameters are 1
+                                                let mut params = seq[..seq.len() - 1].split(';')
+                                                    .map(|param| param.parse::<usize>().unwrap_or(1).max(1));
+                                                let row = params.next().unwrap_or(1);
+                                                let column = params.next().unwrap_or(1);
+                                                self.row = (row - 1).min(S::HEIGHT - 1);
+                                                self.column = (column - 1).min(S::WIDTH - 1);
+                                            }
+                                            "8]" => { // Make current colors the default (Linux console)
+                                                self.default_foreground = self.foreground;
+                                                self.default_background = self.background;
+                                                self.blank = ScreenCharacter::new(
+                                                    b' ',
+                                                    TextModeColor::new(self.background, self.background));
+                                            }
                                             _ => { }
                                         }
+                                        self.color = TextModeColor::new(self.foreground, self.background);
                                     }
                                     _ => {
                                         // Unknown escape sequence
@@ -418,6 +469,14 @@ fn main() {
             MessageData::Value(length),
             MessageData::MemoryHandle(handle))) => {
 
+            if let Some(mode) = video::Mode::from_value(length) {
+                // Linear framebuffer rather than text mode
+                debug_println!("[vga] Framebuffer {}x{}", mode.width, mode.height);
+                match Framebuffer::new(handle, mode) {
+                    Some(framebuffer) => framebuffer::main_loop(framebuffer),
+                    None => panic!("[vga] Unsupported framebuffer format or size {:?}", mode)
+                }
+            }
             if length != 0x20000 {
                 panic!("[vga] Expected 128k video memory buffer. Received {} bytes", length);
             }